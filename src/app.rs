@@ -13,18 +13,25 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::net::SocketAddr;
-use std::path::Path;
 use std::sync::Arc;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use ansi_term::Colour;
 
 use common::prelude::*;
 use common::state::State;
-use common::config::{Config, HttpConfig};
+use common::config::{
+    BindAddr, Config, HttpConfig, JobsConfig, ScriptsConfig,
+};
 
 use scripts::{Blueprint, Repository, JobContext};
 use processor::{Processor, ProcessorApi};
 use web::WebApp;
+use utils::TimeString;
 
 
 struct InnerApp {
@@ -39,8 +46,9 @@ impl InnerApp {
         let state = Arc::new(State::new());
         let blueprint = Blueprint::new(state.clone());
 
+        // The actual number of threads is set later from the configuration
         let processor = Processor::new(
-            0,
+            1,
             Arc::new(blueprint.repository()),
             JobContext::default(),
             state.clone(),
@@ -60,6 +68,10 @@ impl InnerApp {
             http.stop();
         }
 
+        // The scheduled scripts are subject to the same queue limit as the
+        // webhooks
+        self.processor.set_max_queued_jobs(config.max_queued_jobs)?;
+
         let http = WebApp::new(
             Arc::new(self.scripts_blueprint.repository()),
             config,
@@ -76,30 +88,102 @@ impl InnerApp {
         Ok(())
     }
 
-    fn set_scripts_path<P: AsRef<Path>>(
-        &mut self, path: P, recursive: bool,
-    ) -> Result<()> {
+    fn set_scripts_path(&mut self, config: &ScriptsConfig) -> Result<()> {
         self.scripts_blueprint.clear();
-        self.scripts_blueprint.collect_path(path, recursive)?;
+        self.scripts_blueprint.set_follow_symlinks(config.follow_symlinks);
+        self.scripts_blueprint.set_max_depth(config.max_depth);
+        self.scripts_blueprint.set_skip_invalid(config.skip_invalid);
+        self.scripts_blueprint.set_default_shell(config.default_shell.clone());
+        self.scripts_blueprint.set_public_hooks(config.public_hooks.clone());
+        self.scripts_blueprint
+            .set_strict_permissions(config.strict_permissions);
+        self.scripts_blueprint
+            .set_disable_status_hooks(config.disable_status_hooks);
+        self.scripts_blueprint.collect_path(&config.path, config.recursive)?;
         self.processor.api().cleanup()?;
 
+        let skipped = self.scripts_blueprint.skipped_count();
+        if skipped > 0 {
+            println!(
+                "{} skipped {} invalid scripts in {}",
+                Colour::Yellow.bold().paint("Warning:"),
+                skipped,
+                config.path,
+            );
+        }
+
         Ok(())
     }
 
-    fn set_job_environment(&self, env: HashMap<String, String>) -> Result<()> {
+    fn hooks_count(&self) -> usize {
+        self.scripts_blueprint.repository().iter().count()
+    }
+
+    fn set_job_context(
+        &self, env: HashMap<String, String>, jobs: &JobsConfig,
+    ) -> Result<()> {
+        // A timeout of zero means jobs are never killed
+        let timeout = jobs.default_timeout.as_ref()
+            .map(|timeout| timeout.as_u64())
+            .filter(|timeout| *timeout > 0)
+            .map(Duration::from_secs);
+
         self.processor.api().update_context(JobContext {
             environment: env,
+            default_timeout: timeout,
+            audit_log: jobs.audit_log.as_ref().map(PathBuf::from),
+            cleanup_retries: jobs.cleanup_retries,
+            max_env_size: jobs.max_env_size,
             .. JobContext::default()
         })?;
         Ok(())
     }
 
+    fn set_threads_hard_limit(&self, limit: u16) -> Result<()> {
+        self.processor.api().set_threads_hard_limit(limit)?;
+        Ok(())
+    }
+
+    fn set_status_jobs_limit(&self, limit: Option<u16>) -> Result<()> {
+        self.processor.api().set_status_jobs_limit(limit)?;
+        Ok(())
+    }
+
+    fn set_max_chain_depth(&self, max: Option<usize>) -> Result<()> {
+        self.processor.api().set_max_chain_depth(max)?;
+        Ok(())
+    }
+
+    fn set_max_queue_age(&self, max: &Option<TimeString>) -> Result<()> {
+        let max = max.as_ref().map(|max| Duration::from_secs(max.as_u64()));
+        self.processor.api().set_max_queue_age(max)?;
+        Ok(())
+    }
+
+    fn set_input_capacity(&self, capacity: Option<usize>) {
+        self.processor.api().set_input_capacity(capacity);
+    }
+
+    fn set_runtime_budget(&self, budget: &Option<TimeString>) -> Result<()> {
+        let budget = budget.as_ref()
+            .map(|budget| Duration::from_secs(budget.as_u64()));
+
+        let api = self.processor.api();
+        api.set_runtime_budget(budget)?;
+        api.reset_runtime_budget()?;
+        Ok(())
+    }
+
     fn set_threads_count(&self, count: u16) -> Result<()> {
-        self.processor.api().set_threads_count(count)?;
+        if count == 0 {
+            return Err(ErrorKind::ThreadsCountZero.into());
+        }
+
+        self.processor.api().set_max_threads(count)?;
         Ok(())
     }
 
-    fn http_addr(&self) -> Option<&SocketAddr> {
+    fn http_addr(&self) -> Option<&BindAddr> {
         if let Some(ref http) = self.http {
             Some(http.addr())
         } else {
@@ -129,17 +213,25 @@ impl InnerApp {
         Ok(())
     }
 
-    fn stop(mut self) -> Result<()> {
+    fn stop(mut self, drain_grace: Duration) -> Result<()> {
+        // Reject new hooks while the instance is draining
         if let Some(ref http) = self.http {
             http.lock();
         }
 
-        self.processor.stop()?;
+        // Give load balancers some time to notice the instance is draining
+        // before actually stopping it
+        self.processor.api().drain()?;
+        thread::sleep(drain_grace);
 
+        // The web server must be stopped before the processor, to avoid
+        // in-flight requests reaching a half-stopped processor
         if let Some(http) = self.http.take() {
             http.stop();
         }
 
+        self.processor.stop()?;
+
         Ok(())
     }
 }
@@ -153,11 +245,31 @@ pub struct Fisher {
 impl Fisher {
     pub fn new(config: Config) -> Result<Self> {
         let mut inner = InnerApp::new()?;
-        inner.set_scripts_path(
-            &config.scripts.path, config.scripts.recursive,
-        )?;
-        inner.set_job_environment(config.env.clone())?;
+        inner.set_scripts_path(&config.scripts)?;
+
+        // Starting without hooks is probably a mistake
+        if inner.hooks_count() == 0 {
+            if config.scripts.require_scripts {
+                return Err(ErrorKind::NoScriptsFound(
+                    config.scripts.path.clone(),
+                ).into());
+            }
+
+            println!(
+                "{} no scripts found in {}, no webhooks will be processed",
+                Colour::Yellow.bold().paint("Warning:"),
+                config.scripts.path,
+            );
+        }
+
+        inner.set_job_context(config.env.clone(), &config.jobs)?;
+        inner.set_threads_hard_limit(config.jobs.max_threads_hard_limit)?;
         inner.set_threads_count(config.jobs.threads)?;
+        inner.set_status_jobs_limit(config.jobs.max_status_jobs)?;
+        inner.set_max_chain_depth(config.jobs.max_chain_depth)?;
+        inner.set_max_queue_age(&config.jobs.max_queue_age)?;
+        inner.set_input_capacity(config.jobs.input_capacity);
+        inner.set_runtime_budget(&config.jobs.runtime_budget)?;
         inner.restart_http_server(&config.http)?;
 
         Ok(Fisher {
@@ -166,7 +278,7 @@ impl Fisher {
         })
     }
 
-    pub fn web_address(&self) -> Option<&SocketAddr> {
+    pub fn web_address(&self) -> Option<&BindAddr> {
         self.inner.http_addr()
     }
 
@@ -185,21 +297,46 @@ impl Fisher {
             self.inner.restart_http_server(&new_config.http)?;
         }
 
-        // Update the job context if the environment is different
-        if self.config.env != new_config.env {
-            self.inner.set_job_environment(new_config.env.clone())?;
+        // Update the job context if the environment or the jobs options it
+        // contains changed
+        let (old_jobs, new_jobs) = (&self.config.jobs, &new_config.jobs);
+        if self.config.env != new_config.env
+            || old_jobs.default_timeout != new_jobs.default_timeout
+            || old_jobs.audit_log != new_jobs.audit_log
+            || old_jobs.cleanup_retries != new_jobs.cleanup_retries
+            || old_jobs.max_env_size != new_jobs.max_env_size
+        {
+            self.inner.set_job_context(new_config.env.clone(), new_jobs)?;
         }
 
-        // Update the threads count if it's different
+        // Update the threads limit and count if they're different
+        let new_limit = new_config.jobs.max_threads_hard_limit;
+        if self.config.jobs.max_threads_hard_limit != new_limit {
+            self.inner.set_threads_hard_limit(new_limit)?;
+        }
         if self.config.jobs.threads != new_config.jobs.threads {
             self.inner.set_threads_count(new_config.jobs.threads)?;
         }
+        let new_status_limit = new_config.jobs.max_status_jobs;
+        if self.config.jobs.max_status_jobs != new_status_limit {
+            self.inner.set_status_jobs_limit(new_status_limit)?;
+        }
+        let new_chain_depth = new_config.jobs.max_chain_depth;
+        if self.config.jobs.max_chain_depth != new_chain_depth {
+            self.inner.set_max_chain_depth(new_chain_depth)?;
+        }
+        if self.config.jobs.max_queue_age != new_config.jobs.max_queue_age {
+            self.inner.set_max_queue_age(&new_config.jobs.max_queue_age)?;
+        }
+        if self.config.jobs.input_capacity != new_config.jobs.input_capacity {
+            self.inner.set_input_capacity(new_config.jobs.input_capacity);
+        }
+
+        // Reloading always resets the runtime budget
+        self.inner.set_runtime_budget(&new_config.jobs.runtime_budget)?;
 
         // Reload hooks, changing the script path
-        self.inner.set_scripts_path(
-            &new_config.scripts.path,
-            new_config.scripts.recursive,
-        )?;
+        self.inner.set_scripts_path(&new_config.scripts)?;
 
         self.config = new_config;
 
@@ -207,6 +344,214 @@ impl Fisher {
     }
 
     pub fn stop(self) -> Result<()> {
-        self.inner.stop()
+        let grace = self.config.http.drain_grace.as_u64();
+        let timeout = if let Some(ref timeout) =
+            self.config.jobs.shutdown_timeout
+        {
+            // The drain grace period doesn't count towards the timeout
+            Duration::from_secs(grace + timeout.as_u64())
+        } else {
+            return self.inner.stop(Duration::from_secs(grace));
+        };
+
+        // Stop in another thread, to give up if it takes too long
+        let api = self.inner.processor.api();
+        let (result_send, result_recv) = mpsc::channel();
+        let inner = self.inner;
+        thread::spawn(move || {
+            let _ = result_send.send(inner.stop(Duration::from_secs(grace)));
+        });
+
+        match result_recv.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let running = api.metrics()?.hooks
+                    .into_iter()
+                    .filter(|(_, metrics)| metrics.running > 0)
+                    .map(|(name, _)| name)
+                    .collect();
+                Err(ErrorKind::ShutdownTimedOut(running).into())
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(ErrorKind::BrokenChannel.into())
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use hyper;
+    use hyper::status::StatusCode;
+    use serde_json;
+    use tempdir::TempDir;
+
+    use common::prelude::*;
+    use common::config::Config;
+
+    use super::Fisher;
+
+
+    fn config_for(path: &str, require_scripts: bool) -> Config {
+        let mut config = Config::default();
+        config.http.bind = "127.0.0.1:0".parse().unwrap();
+        config.scripts.path = path.into();
+        config.scripts.require_scripts = require_scripts;
+        config
+    }
+
+
+    #[test]
+    fn test_startup_missing_scripts_path() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let missing = dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+
+        // The startup fails even if scripts aren't required
+        for &require_scripts in &[false, true] {
+            match Fisher::new(config_for(missing, require_scripts)) {
+                Err(Error(ErrorKind::ScriptsPathNotFound(path), _)) => {
+                    assert_eq!(path, missing);
+                }
+                _ => panic!("the missing path wasn't reported"),
+            }
+        }
+    }
+
+
+    #[test]
+    fn test_startup_empty_scripts_path() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        // Empty directories only show a warning by default
+        let app = Fisher::new(config_for(path, false)).unwrap();
+        app.stop().unwrap();
+
+        // Empty directories are rejected if scripts are required
+        match Fisher::new(config_for(path, true)) {
+            Err(Error(ErrorKind::NoScriptsFound(found), _)) => {
+                assert_eq!(found, path);
+            }
+            _ => panic!("the empty directory wasn't reported"),
+        }
+    }
+
+
+    #[test]
+    fn test_startup_zero_threads() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let mut config = config_for(dir.path().to_str().unwrap(), false);
+        config.jobs.threads = 0;
+
+        match Fisher::new(config) {
+            Err(Error(ErrorKind::ThreadsCountZero, _)) => {}
+            _ => panic!("zero threads were accepted"),
+        }
+    }
+
+
+    #[test]
+    fn test_stop_rejects_requests() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let script = dir.path().join("example.sh");
+        fs::write(&script, "#!/bin/bash\necho hello\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))
+            .unwrap();
+
+        let mut config = config_for(dir.path().to_str().unwrap(), false);
+        config.http.drain_grace = 1.into();
+
+        let app = Fisher::new(config).unwrap();
+        let url = format!("http://{}", app.web_address().unwrap());
+
+        // Requests might time out after the server is stopped
+        let mut client = hyper::Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(1)));
+        client.set_write_timeout(Some(Duration::from_secs(1)));
+
+        let draining = || -> bool {
+            let health = client.get(&format!("{}/health", url)).send();
+            let body: serde_json::Value =
+                serde_json::from_reader(health.unwrap()).unwrap();
+            body.pointer("/result/draining").unwrap().as_bool().unwrap()
+        };
+
+        let stopper = thread::spawn(move || app.stop().unwrap());
+        while !draining() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Requests arriving during the shutdown are either cleanly rejected
+        // or refused by the stopped server, without ever reaching a
+        // half-stopped processor
+        loop {
+            let hook = client.post(&format!("{}/hook/example.sh", url)).send();
+            let health = client.get(&format!("{}/health", url)).send();
+
+            match (hook, health) {
+                (Ok(hook), Ok(health)) => {
+                    assert_eq!(hook.status, StatusCode::ServiceUnavailable);
+                    assert_eq!(health.status, StatusCode::Ok);
+
+                    let body: serde_json::Value =
+                        serde_json::from_reader(health).unwrap();
+                    assert_eq!(body.pointer("/result/draining"), Some(
+                        &serde_json::Value::Bool(true),
+                    ));
+                }
+                _ => break,
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        stopper.join().unwrap();
+    }
+
+
+    #[test]
+    fn test_stop_timeout() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let script = dir.path().join("slow.sh");
+        fs::write(&script, "#!/bin/bash\nsleep 5\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))
+            .unwrap();
+
+        let mut config = config_for(dir.path().to_str().unwrap(), false);
+        config.jobs.shutdown_timeout = Some(1.into());
+
+        let app = Fisher::new(config).unwrap();
+        let url = format!("http://{}", app.web_address().unwrap());
+
+        // Wait for the job to start
+        let client = hyper::Client::new();
+        let res = client.post(&format!("{}/hook/slow.sh", url)).send();
+        assert_eq!(res.unwrap().status, StatusCode::Ok);
+        loop {
+            let health = client.get(&format!("{}/health", url)).send();
+            let body: serde_json::Value =
+                serde_json::from_reader(health.unwrap()).unwrap();
+            if body.pointer("/result/busy_threads").unwrap() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Stopping gives up without waiting for the job to end
+        let start = Instant::now();
+        match app.stop() {
+            Err(Error(ErrorKind::ShutdownTimedOut(hooks), _)) => {
+                assert_eq!(hooks, vec!["slow.sh".to_string()]);
+            }
+            _ => panic!("the shutdown didn't time out"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(4));
     }
 }
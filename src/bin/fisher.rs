@@ -41,17 +41,31 @@ fn usage(exit_code: i32, error_msg: &str) -> ! {
     if error_msg.len() > 0 {
         println!("Error: {}\n", error_msg);
     }
-    println!("Usage: fisher <config_file>");
+    println!(concat!(
+        "Usage: fisher [--require-scripts] [--skip-invalid] ",
+        "[--strict-permissions] <config_file>",
+    ));
     println!("Execute `fisher --help` for more details");
     ::std::process::exit(exit_code);
 }
 
 
-fn parse_cli() -> String {
+struct CliArgs {
+    config_path: String,
+    require_scripts: bool,
+    skip_invalid: bool,
+    strict_permissions: bool,
+}
+
+
+fn parse_cli() -> CliArgs {
     // Parse the CLI args
     let mut only_args = false;
     let mut flag_help = false;
     let mut flag_version = false;
+    let mut flag_require_scripts = false;
+    let mut flag_skip_invalid = false;
+    let mut flag_strict_permissions = false;
     let mut config_path = None;
 
     for arg in ::std::env::args().skip(1) {
@@ -60,6 +74,9 @@ fn parse_cli() -> String {
                 "--" => only_args = true,
                 "-h" | "--help" => flag_help = true,
                 "--version" => flag_version = true,
+                "--require-scripts" => flag_require_scripts = true,
+                "--skip-invalid" => flag_skip_invalid = true,
+                "--strict-permissions" => flag_strict_permissions = true,
                 _ => usage(1, &format!("invalid flag: {}", arg)),
             }
         } else if config_path.is_none() {
@@ -74,19 +91,29 @@ fn parse_cli() -> String {
         println!("Simple webhooks catcher\n");
 
         println!("ARGUMENTS");
-        println!("  config_path   The path to the configuration file");
+        println!("  config_path    The path to the configuration file");
         println!();
 
         println!("OPTIONS");
-        println!("  -h | --help   Show this message");
-        println!("  --version     Show the Fisher version");
+        println!("  -h | --help    Show this message");
+        println!("  --version      Show the Fisher version");
+        println!("  --require-scripts");
+        println!("                 Refuse to start if no scripts are found");
+        println!("  --skip-invalid Skip the scripts which can't be loaded");
+        println!("  --strict-permissions");
+        println!("                 Refuse scripts writable by other users");
 
         ::std::process::exit(0);
     } else if flag_version {
         show_version();
         ::std::process::exit(0);
     } else if let Some(path) = config_path {
-        path
+        CliArgs {
+            config_path: path,
+            require_scripts: flag_require_scripts,
+            skip_invalid: flag_skip_invalid,
+            strict_permissions: flag_strict_permissions,
+        }
     } else {
         usage(1, "too few arguments");
     }
@@ -113,9 +140,22 @@ fn app() -> Result<()> {
     signals.add(Signal::SIGUSR1);
     signals.thread_block()?;
 
-    let config_path = parse_cli();
+    let args = parse_cli();
+    let read_config = || -> Result<Config> {
+        let mut config = read_config(&args.config_path)?;
+        if args.require_scripts {
+            config.scripts.require_scripts = true;
+        }
+        if args.skip_invalid {
+            config.scripts.skip_invalid = true;
+        }
+        if args.strict_permissions {
+            config.scripts.strict_permissions = true;
+        }
+        Ok(config)
+    };
 
-    let mut app = Fisher::new(read_config(&config_path)?)?;
+    let mut app = Fisher::new(read_config()?)?;
     println!("HTTP server listening on {}", app.web_address().unwrap());
 
     // Wait for signals while the other threads execute the application
@@ -127,7 +167,7 @@ fn app() -> Result<()> {
 
                 // Don't crash if the reload fails, just show errors
                 // No changes are applied if the reload fails
-                match read_config(&config_path) {
+                match read_config() {
                     Ok(new_config) => {
                         if let Err(err) = app.reload(new_config) {
                             err.pretty_print()
@@ -140,7 +180,8 @@ fn app() -> Result<()> {
         }
     }
 
-    // Stop Fisher
+    // Stop Fisher, which fails if jobs are still running after the shutdown
+    // timeout: the process exits anyway in that case
     app.stop()?;
 
     Ok(())
@@ -19,6 +19,7 @@
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::fmt;
 use std::result::Result as StdResult;
 
@@ -73,29 +74,170 @@ pub struct HttpConfig {
     /// The number of proxies Fisher is behind.
     #[serde(rename="behind-proxies", default="default_behind_proxies")]
     pub behind_proxies: u8,
+    /// The header containing the client IP, instead of X-Forwarded-For
+    #[serde(rename="client-ip-header", default)]
+    pub client_ip_header: Option<String>,
     /// The socket address to bind.
     #[serde(default="default_bind")]
-    pub bind: SocketAddr,
+    pub bind: BindAddr,
     /// The rate limit for bad requests
     #[serde(rename="rate-limit", default)]
     pub rate_limit: RateLimitConfig,
     /// Enable or disable the health endpoint
     #[serde(rename="health-endpoint", default="default_health_endpoint")]
     pub health_endpoint: bool,
+    /// Enable or disable the metrics endpoint
+    #[serde(rename="metrics-endpoint", default)]
+    pub metrics_endpoint: bool,
+    /// Break down the health and metrics outputs by provider
+    #[serde(rename="provider-labels", default)]
+    pub provider_labels: bool,
+    /// The list of accepted values for the Host header
+    #[serde(rename="allowed-hosts", default)]
+    pub allowed_hosts: Option<Vec<String>>,
+    /// The list of accepted values for the Content-Type header
+    #[serde(rename="allowed-content-types", default)]
+    pub allowed_content_types: Option<Vec<String>>,
+    /// The token required to use the management endpoints
+    #[serde(rename="api-token", default)]
+    pub api_token: Option<String>,
+    /// The maximum number of headers allowed in a request
+    #[serde(rename="max-headers", default="default_max_headers")]
+    pub max_headers: usize,
+    /// The maximum size of all the headers of a request
+    #[serde(rename="max-headers-size", default="default_max_headers_size")]
+    pub max_headers_size: usize,
+    /// The maximum size of the body of a request
+    #[serde(rename="max-body-size", default="default_max_body_size")]
+    pub max_body_size: usize,
+    /// The maximum size of all the request bodies in memory at the same time
+    #[serde(rename="max-bodies-size", default="default_max_bodies_size")]
+    pub max_bodies_size: usize,
+    /// How long to report the instance as draining before shutting down
+    #[serde(rename="drain-grace", default="default_drain_grace")]
+    pub drain_grace: utils::TimeString,
+    /// The maximum number of sync hooks running at the same time
+    #[serde(rename="max-sync-jobs", default="default_max_sync_jobs")]
+    pub max_sync_jobs: usize,
+    /// The maximum number of jobs waiting in the queue
+    #[serde(rename="max-queued-jobs", default)]
+    pub max_queued_jobs: Option<usize>,
+    /// Reject invalid requests as if the hook didn't exist
+    #[serde(rename="hide-forbidden-hooks", default)]
+    pub hide_forbidden_hooks: bool,
+    /// Acknowledge pings with an empty 204 response
+    #[serde(rename="ping-no-content", default)]
+    pub ping_no_content: bool,
+    /// Log every accepted hook, with the details used to schedule it
+    #[serde(rename="log-accepted-hooks", default)]
+    pub log_accepted_hooks: bool,
+    /// The query params whose values are hidden in the logs
+    #[serde(rename="redacted-params", default="default_redacted_params")]
+    pub redacted_params: Vec<String>,
+    /// How long to wait for clients to send the body of their requests
+    #[serde(rename="read-timeout", default)]
+    pub read_timeout: Option<utils::TimeString>,
 }
 
 default_fn!(default_behind_proxies: u8 = 0);
-default_fn!(default_bind: SocketAddr = "127.0.0.1:8000".parse().unwrap());
+default_fn!(default_bind: BindAddr = "127.0.0.1:8000".parse().unwrap());
 default_fn!(default_health_endpoint: bool = true);
+default_fn!(default_max_headers: usize = 100);
+default_fn!(default_max_headers_size: usize = 65536);
+default_fn!(default_max_body_size: usize = 26214400);
+default_fn!(default_max_bodies_size: usize = 104857600);
+default_fn!(default_drain_grace: utils::TimeString = 0.into());
+default_fn!(default_max_sync_jobs: usize = 4);
+default_fn!(default_redacted_params: Vec<String> = vec![
+    "secret".into(), "token".into(),
+]);
 
 default!(HttpConfig {
     behind_proxies: default_behind_proxies(),
+    client_ip_header: None,
     bind: default_bind(),
     rate_limit: RateLimitConfig::default(),
     health_endpoint: default_health_endpoint(),
+    metrics_endpoint: false,
+    provider_labels: false,
+    allowed_hosts: None,
+    allowed_content_types: None,
+    api_token: None,
+    max_headers: default_max_headers(),
+    max_headers_size: default_max_headers_size(),
+    max_body_size: default_max_body_size(),
+    max_bodies_size: default_max_bodies_size(),
+    drain_grace: default_drain_grace(),
+    max_sync_jobs: default_max_sync_jobs(),
+    max_queued_jobs: None,
+    hide_forbidden_hooks: false,
+    ping_no_content: false,
+    log_accepted_hooks: false,
+    redacted_params: default_redacted_params(),
+    read_timeout: None,
 });
 
 
+/// The address the HTTP server listens to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddr {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket, prefixed with `unix:`.
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BindAddr> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(ErrorKind::BindUnixPathEmpty.into());
+            }
+
+            Ok(BindAddr::Unix(path.into()))
+        } else {
+            Ok(BindAddr::Tcp(s.parse()?))
+        }
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BindAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            BindAddr::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+struct BindAddrVisitor;
+
+impl<'de> Visitor<'de> for BindAddrVisitor {
+    type Value = BindAddr;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a socket address or a unix:/path string")
+    }
+
+    fn visit_str<E: DeError>(self, s: &str) -> StdResult<BindAddr, E> {
+        match s.parse() {
+            Ok(parsed) => Ok(parsed),
+            Err(e) => Err(E::custom(e.to_string())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddr {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<BindAddr, D::Error> {
+        deserializer.deserialize_str(BindAddrVisitor)
+    }
+}
+
+
 /// Configuration for rate limiting.
 #[derive(Debug, PartialEq, Eq)]
 pub struct RateLimitConfig {
@@ -182,12 +324,69 @@ pub struct JobsConfig {
     /// The number of execution threads to use.
     #[serde(default = "default_threads")]
     pub threads: u16,
+
+    /// The maximum number of execution threads, even if more are requested.
+    #[serde(rename="max-threads-hard-limit",
+            default="default_max_threads_hard_limit")]
+    pub max_threads_hard_limit: u16,
+
+    /// The total CPU time jobs can use before new ones are rejected.
+    #[serde(rename="runtime-budget", default)]
+    pub runtime_budget: Option<utils::TimeString>,
+
+    /// How long jobs can run for, unless the script sets its own timeout.
+    #[serde(rename="default-timeout", default)]
+    pub default_timeout: Option<utils::TimeString>,
+
+    /// The maximum number of status hooks running at the same time.
+    #[serde(rename="max-status-jobs", default)]
+    pub max_status_jobs: Option<u16>,
+
+    /// The maximum number of jobs in a chain of status hooks.
+    #[serde(rename="max-chain-depth", default)]
+    pub max_chain_depth: Option<usize>,
+
+    /// How long jobs can wait in the queue before being dropped.
+    #[serde(rename="max-queue-age", default)]
+    pub max_queue_age: Option<utils::TimeString>,
+
+    /// The maximum size of the environment of a job, in bytes.
+    #[serde(rename="max-env-size", default)]
+    pub max_env_size: Option<usize>,
+
+    /// How many jobs can wait to be received by the scheduler.
+    #[serde(rename="input-capacity", default)]
+    pub input_capacity: Option<usize>,
+
+    /// The file every executed job is recorded in.
+    #[serde(rename="audit-log", default)]
+    pub audit_log: Option<String>,
+
+    /// How many times to retry removing the temporary directories of jobs.
+    #[serde(rename="cleanup-retries", default)]
+    pub cleanup_retries: u32,
+
+    /// How long to wait for the running jobs when shutting down.
+    #[serde(rename="shutdown-timeout", default)]
+    pub shutdown_timeout: Option<utils::TimeString>,
 }
 
 default_fn!(default_threads: u16 = 1);
+default_fn!(default_max_threads_hard_limit: u16 = 128);
 
 default!(JobsConfig {
     threads: default_threads(),
+    max_threads_hard_limit: default_max_threads_hard_limit(),
+    runtime_budget: None,
+    default_timeout: None,
+    max_status_jobs: None,
+    max_chain_depth: None,
+    max_queue_age: None,
+    max_env_size: None,
+    input_capacity: None,
+    audit_log: None,
+    cleanup_retries: 0,
+    shutdown_timeout: None,
 });
 
 
@@ -200,12 +399,47 @@ pub struct ScriptsConfig {
     /// Search subdirectories or not.
     #[serde(default = "default_recursive")]
     pub recursive: bool,
+    /// Search symlinked subdirectories or not.
+    #[serde(rename="follow-symlinks", default = "default_follow_symlinks")]
+    pub follow_symlinks: bool,
+    /// How many levels of subdirectories to search.
+    #[serde(rename="max-depth", default)]
+    pub max_depth: Option<usize>,
+    /// Refuse to start if no scripts are found.
+    #[serde(rename="require-scripts", default = "default_require_scripts")]
+    pub require_scripts: bool,
+    /// Skip invalid scripts instead of refusing to load any of them.
+    #[serde(rename="skip-invalid", default = "default_skip_invalid")]
+    pub skip_invalid: bool,
+    /// The shell used to run scripts without a shebang.
+    #[serde(rename="default-shell", default)]
+    pub default_shell: Option<String>,
+    /// Refuse to load status hooks and never trigger them.
+    #[serde(rename="disable-status-hooks", default)]
+    pub disable_status_hooks: bool,
+    /// The only scripts allowed to be triggered without authentication.
+    #[serde(rename="public-hooks", default)]
+    pub public_hooks: Option<Vec<String>>,
+    /// Refuse to load scripts writable by other users.
+    #[serde(rename="strict-permissions", default)]
+    pub strict_permissions: bool,
 }
 
 default_fn!(default_path: String = ".".into());
 default_fn!(default_recursive: bool = false);
+default_fn!(default_follow_symlinks: bool = true);
+default_fn!(default_require_scripts: bool = false);
+default_fn!(default_skip_invalid: bool = false);
 
 default!(ScriptsConfig {
     path: default_path(),
     recursive: default_recursive(),
+    follow_symlinks: default_follow_symlinks(),
+    max_depth: None,
+    require_scripts: default_require_scripts(),
+    skip_invalid: default_skip_invalid(),
+    default_shell: None,
+    disable_status_hooks: false,
+    public_hooks: None,
+    strict_permissions: false,
 });
@@ -94,6 +94,41 @@ error_chain! {
             description("wrong request kind"),
             display("wrong request kind"),
         }
+        RequestBodyTooLarge(max: usize) {
+            description("request body too large"),
+            display("the request body is bigger than {} bytes", max),
+        }
+        RequestBodyTimeout {
+            description("request body not received in time"),
+            display("the request body wasn't received in time"),
+        }
+        RequestBodyMissing {
+            description("request body is missing"),
+            display("the hook requires a request body"),
+        }
+        SuspiciousHookName(name: String) {
+            description("suspicious hook name"),
+            display("suspicious hook name: {}", name),
+        }
+
+        // Processor errors
+        ThreadsCountZero {
+            description("the number of threads can't be zero"),
+            display("the number of threads can't be zero"),
+        }
+        SchedulerBusy(capacity: usize) {
+            description("the scheduler is busy"),
+            display(
+                "the scheduler has already {} jobs waiting to be received",
+                capacity,
+            ),
+        }
+
+        // Bind address config
+        BindUnixPathEmpty {
+            description("empty unix socket path"),
+            display("the path of the unix socket to bind is empty"),
+        }
 
         // Rate limit config
         RateLimitConfigTooManySlashes {
@@ -101,6 +136,58 @@ error_chain! {
             display("too many slashes present"),
         }
 
+        // Scripts errors
+        ScriptsPathNotFound(path: String) {
+            description("scripts path not found"),
+            display("the scripts path doesn't exist: {}", path),
+        }
+        ScriptPublicNotAllowed(name: String) {
+            description("public script not allowed"),
+            display(
+                "the script {} can be triggered by anyone, but it's not in \
+                 scripts.public-hooks",
+                name,
+            ),
+        }
+        ScriptStatusNotAllowed(name: String) {
+            description("status hook not allowed"),
+            display(
+                "the script {} uses the Status provider, but status hooks \
+                 are disabled by scripts.disable-status-hooks",
+                name,
+            ),
+        }
+        InsecurePermissions(path: String) {
+            description("insecure permissions"),
+            display(
+                "{} is writable by other users (disable \
+                 scripts.strict-permissions to allow it)",
+                path,
+            ),
+        }
+        JobEnvironmentTooLarge(size: usize, max: usize) {
+            description("job environment too large"),
+            display(
+                "the environment of the job is {} bytes, more than the {} \
+                 allowed by jobs.max-env-size",
+                size, max,
+            ),
+        }
+        NoScriptsFound(path: String) {
+            description("no scripts found"),
+            display(
+                "no scripts found in {}, but scripts.require-scripts is set",
+                path,
+            ),
+        }
+        ShutdownTimedOut(hooks: Vec<String>) {
+            description("shutdown timed out"),
+            display(
+                "shutdown timed out, with jobs still running for: {}",
+                hooks.join(", "),
+            ),
+        }
+
         // Providers errors
         ProviderNotFound(name: String) {
             description("provider not found"),
@@ -114,6 +201,24 @@ error_chain! {
             description("invalid GitLab event name"),
             display("invalid GitLab event name: {}", name),
         }
+        ProviderCiNotifyInvalidField(field: String) {
+            description("invalid CiNotify field"),
+            display("invalid CiNotify field (not a JSON pointer): {}", field),
+        }
+        ProviderInvalidEnvName(name: String) {
+            description("invalid environment variable name"),
+            display(
+                "invalid or reserved environment variable name: {}", name,
+            ),
+        }
+        ProviderHmacInvalidComponent(component: String) {
+            description("invalid HMAC component"),
+            display("invalid HMAC component: {}", component),
+        }
+        ProviderStandaloneIncompleteCredentials {
+            description("incomplete basic auth credentials"),
+            display("basic auth needs both a username and a password"),
+        }
 
         // Broken things
         BrokenChannel {
@@ -126,7 +231,7 @@ error_chain! {
         }
 
         // Other errors
-        BoxedError(boxed: Box<::std::error::Error + Send + Sync>) {
+        BoxedError(boxed: Box<dyn std::error::Error + Send + Sync>) {
             description("generic error"),
             display("{}", boxed),
         }
@@ -177,8 +282,8 @@ impl<T> From<::std::sync::PoisonError<T>> for Error {
     }
 }
 
-impl From<Box<::std::error::Error + Send + Sync>> for Error {
-    fn from(err: Box<::std::error::Error + Send + Sync>) -> Error {
+impl From<Box<dyn std::error::Error + Send + Sync>> for Error {
+    fn from(err: Box<dyn std::error::Error + Send + Sync>) -> Error {
         ErrorKind::BoxedError(err).into()
     }
 }
@@ -22,7 +22,7 @@
 //! Due to the limits of the implementation, it's not possible to access
 //! the actual value of a [`Serial`](struct.Serial.html), but you can compare
 //! multiple instances of it to get the greatest or check if they're the same
-//! one.
+//! one. Serials can still be serialized, to inspect them for diagnostics.
 
 use std::cmp::Ordering;
 use std::fmt;
@@ -31,7 +31,7 @@ use std::fmt;
 /// Opaque, infinite serial.
 ///
 /// Check out the [module documentation](index.html) for more details.
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Serialize)]
 pub struct Serial {
     increment: u32,
     alternate: bool,
@@ -70,6 +70,7 @@ impl PartialOrd for UniqueId {
 #[derive(Debug)]
 pub struct State {
     counter: AtomicUsize,
+    leaked_dirs: AtomicUsize,
 }
 
 impl State {
@@ -77,15 +78,26 @@ impl State {
     pub fn new() -> Self {
         State {
             counter: AtomicUsize::new(0),
+            leaked_dirs: AtomicUsize::new(0),
         }
     }
 
+    /// Record that some temporary directories of a job couldn't be removed.
+    pub fn record_leaked_dirs(&self, count: usize) {
+        self.leaked_dirs.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Get how many temporary directories of jobs couldn't be removed so far.
+    pub fn leaked_dirs(&self) -> usize {
+        self.leaked_dirs.load(Ordering::SeqCst)
+    }
+
     /// Get the next ID for a specific [`IdKind`](enum.IdKind.html). The ID is
     /// guaranteed to be unique and greater than the last ID.
     pub fn next_id(&self, kind: IdKind) -> UniqueId {
         UniqueId {
             id: self.counter.fetch_add(1, Ordering::SeqCst),
-            kind: kind,
+            kind,
         }
     }
 }
@@ -109,4 +121,16 @@ mod tests {
         assert!(id1 != id2);
         assert!(id1 != id3);
     }
+
+
+    #[test]
+    fn test_leaked_dirs() {
+        let state = State::new();
+        assert_eq!(state.leaked_dirs(), 0);
+
+        state.record_leaked_dirs(2);
+        state.record_leaked_dirs(0);
+        state.record_leaked_dirs(1);
+        assert_eq!(state.leaked_dirs(), 3);
+    }
 }
@@ -15,10 +15,26 @@
 
 //! Structs used by Fisher.
 
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(any(test, feature = "diagnostics"))]
+use common::serial::Serial;
+
+
+/// The maximum size of the stderr kept for failed jobs.
+const STDERR_TAIL_SIZE: usize = 1024;
+
+/// The upper bounds of the buckets of the wait times histogram, in
+/// milliseconds.
+const WAIT_BUCKETS_MS: [u64; 9] = [
+    100, 500, 1000, 5000, 10000, 30000, 60000, 300000, 900000,
+];
+
 
 /// This struct contains some information about how the processor is feeling.
 
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct HealthDetails {
     /// The number of jobs in the queue, waiting to be processed.
     pub queued_jobs: usize,
@@ -28,4 +44,203 @@ pub struct HealthDetails {
 
     /// The total number of threads running, either waiting or working.
     pub max_threads: u16,
+
+    /// Whether the instance is shutting down, and no new jobs should be sent
+    /// to it.
+    pub draining: bool,
+
+    /// The number of jobs received through each provider, if enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider_jobs: Option<BTreeMap<String, usize>>,
+
+    /// The number of hooks currently loaded, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loaded_hooks: Option<usize>,
+
+    /// How many seconds passed since the instance started, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+
+    /// Whether the instance is locked and rejecting new hooks, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked: Option<bool>,
+}
+
+
+/// This struct contains a snapshot of the load of the processor, meant to
+/// be consumed by external autoscalers.
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LoadSnapshot {
+    /// The number of jobs in the queue, waiting to be processed.
+    pub queued_jobs: usize,
+
+    /// The number of threads currently processing some jobs.
+    pub busy_threads: u16,
+
+    /// The total number of threads running, either waiting or working.
+    pub max_threads: u16,
+
+    /// The number of queued jobs with each priority.
+    pub priorities: BTreeMap<isize, usize>,
+
+    /// The average time the queued jobs have been waiting for, in
+    /// milliseconds.
+    pub average_wait_ms: u64,
+}
+
+
+/// This struct contains the metrics of a single hook.
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HookMetrics {
+    /// The number of jobs of this hook currently running.
+    pub running: usize,
+
+    /// The number of jobs of this hook waiting to be run.
+    pub waiting: usize,
+}
+
+
+/// This struct contains the metrics collected by the processor.
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Metrics {
+    /// The metrics of each hook, by the name of the hook.
+    pub hooks: BTreeMap<String, HookMetrics>,
+
+    /// The number of jobs received through each provider, by the name of the
+    /// provider.
+    pub provider_jobs: BTreeMap<String, usize>,
+
+    /// The number of temporary directories of jobs which couldn't be
+    /// removed.
+    pub leaked_dirs: usize,
+
+    /// How long the jobs waited in the queue before starting.
+    pub wait_times: WaitHistogram,
+}
+
+
+/// This struct contains an histogram of how long the jobs waited between
+/// being queued and starting.
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct WaitHistogram {
+    /// The upper bound of each bucket in milliseconds, along with the number
+    /// of jobs which waited at most that long.
+    pub buckets: Vec<(u64, usize)>,
+
+    /// The number of jobs which started.
+    pub count: usize,
+
+    /// The total time waited by the jobs, in milliseconds.
+    pub sum_ms: u64,
+}
+
+impl WaitHistogram {
+    /// Record a job which waited for the provided time.
+    pub fn record(&mut self, wait: Duration) {
+        let wait_ms = wait.as_millis() as u64;
+
+        for bucket in &mut self.buckets {
+            if wait_ms <= bucket.0 {
+                bucket.1 += 1;
+            }
+        }
+        self.count += 1;
+        self.sum_ms += wait_ms;
+    }
+}
+
+impl Default for WaitHistogram {
+    fn default() -> Self {
+        WaitHistogram {
+            buckets: WAIT_BUCKETS_MS.iter().map(|&max| (max, 0)).collect(),
+            count: 0,
+            sum_ms: 0,
+        }
+    }
+}
+
+
+/// This struct contains the details of a failed job.
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct JobFailure {
+    /// The exit code of the job, if it exited normally.
+    pub exit_code: Option<i32>,
+
+    /// The signal which killed the job, if it was killed.
+    pub signal: Option<i32>,
+
+    /// The end of the standard error of the job, or the error which prevented
+    /// it from running.
+    pub stderr: String,
+
+    /// When the failure happened, as a UNIX timestamp.
+    pub timestamp: u64,
+}
+
+impl JobFailure {
+    pub fn new(
+        exit_code: Option<i32>, signal: Option<i32>, stderr: &str,
+    ) -> Self {
+        // Keep only the end of the stderr, without splitting characters
+        let mut start = stderr.len().saturating_sub(STDERR_TAIL_SIZE);
+        while !stderr.is_char_boundary(start) {
+            start += 1;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+
+        JobFailure {
+            exit_code,
+            signal,
+            stderr: stderr[start..].to_string(),
+            timestamp,
+        }
+    }
+}
+
+
+/// The internal ordering of the scheduler, exposed for diagnostics.
+
+#[cfg(any(test, feature = "diagnostics"))]
+#[derive(Clone, Debug, Serialize)]
+pub struct SerialDetails {
+    /// The serial of the last job received by the scheduler.
+    pub current: Serial,
+
+    /// The serials of the jobs still waiting to be executed, sorted in the
+    /// order they were received.
+    pub queued: Vec<Serial>,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::JobFailure;
+
+
+    #[test]
+    fn test_job_failure_stderr_tail() {
+        let failure = JobFailure::new(Some(1), None, "error");
+        assert_eq!(failure.stderr, "error");
+        assert!(failure.timestamp > 0);
+
+        // Long outputs are truncated, keeping the end
+        let stderr = format!("{}end", "a".repeat(2000));
+        let failure = JobFailure::new(Some(1), None, &stderr);
+        assert_eq!(failure.stderr.len(), 1024);
+        assert!(failure.stderr.ends_with("aend"));
+
+        // Multi-byte characters are never split
+        let stderr = "\u{e0}".repeat(1000);
+        let failure = JobFailure::new(None, Some(9), &stderr);
+        assert_eq!(failure.stderr.len(), 1024);
+    }
 }
@@ -15,12 +15,16 @@
 
 //! Traits used by Fisher.
 
+use std::collections::BTreeMap;
 use std::hash::Hash;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 use std::fmt::Debug;
+use std::time::Duration;
 
 use super::prelude::*;
-use super::structs::HealthDetails;
+use super::structs::{HealthDetails, JobFailure, LoadSnapshot, Metrics};
+#[cfg(feature = "diagnostics")]
+use super::structs::SerialDetails;
 
 
 /// This trait represents a script that can be run by Fisher.
@@ -32,9 +36,23 @@ pub trait ScriptTrait {
     /// the same between calls to the same script.
     fn id(&self) -> Self::Id;
 
+    /// This method returns the name of the script.
+    fn name(&self) -> &str;
+
     /// This method returns if multiple instances of the script can be safely
     /// run in parallel.
     fn can_be_parallel(&self) -> bool;
+
+    /// This method returns the name of the group the script belongs to, if
+    /// any. Scripts in the same group are never run at the same time.
+    fn group(&self) -> Option<&str>;
+
+    /// This method returns how often the script should be executed on its
+    /// own, without being triggered by a request. By default scripts are
+    /// never executed on their own.
+    fn schedule(&self) -> Option<Duration> {
+        None
+    }
 }
 
 
@@ -68,6 +86,24 @@ pub trait ScriptsRepositoryTrait: Send + Sync {
         &self,
         output: <Self::Job as JobTrait<Self::Script>>::Output,
     ) -> Option<Self::JobsIter>;
+
+    /// Return all the jobs generated as a conseguence of another job being
+    /// dropped from the queue, since it waited there for too long.
+    ///
+    /// By default this returns nothing.
+    fn jobs_after_expired(&self, _job: &Self::Job) -> Option<Self::JobsIter> {
+        None
+    }
+
+    /// Return the job executed when a script is run on its schedule, along
+    /// with its priority.
+    ///
+    /// By default this returns nothing, and scheduled scripts are ignored.
+    fn scheduled_job(
+        &self, _script: &Arc<Self::Script>,
+    ) -> Option<(Self::Job, isize)> {
+        None
+    }
 }
 
 
@@ -77,7 +113,7 @@ pub trait JobTrait<S: ScriptTrait> {
     type Context: Debug + Send + Sync;
 
     /// The output that will be returned by the job.
-    type Output: Clone + Send + Sync;
+    type Output: JobOutputTrait + Clone + Send + Sync;
 
     /// Execute the job and return the output of it.
     fn execute(&self, ctx: &Self::Context) -> Result<Self::Output>;
@@ -87,17 +123,70 @@ pub trait JobTrait<S: ScriptTrait> {
 
     /// Get the name of the underlying script.
     fn script_name(&self) -> &str;
+
+    /// Get the group of the underlying script.
+    fn script_group(&self) -> Option<&str>;
+
+    /// Get the key of the job, if any. If the underlying script can't be
+    /// run in parallel, only the jobs with the same key are run one at a
+    /// time.
+    fn parallel_key(&self) -> Option<&str>;
+
+    /// Get the name of the provider which validated the job, if any.
+    fn provider_name(&self) -> Option<&str>;
+
+    /// Record how many jobs were waiting in the queue when this one was
+    /// queued.
+    fn set_queue_depth(&mut self, depth: usize);
+}
+
+
+/// This trait represents the output of a Job.
+pub trait JobOutputTrait {
+    /// Get the details of the failure, if the job failed.
+    fn failure(&self) -> Option<JobFailure>;
+
+    /// Get the CPU time used by the job, if it was measured.
+    fn cpu_time(&self) -> Option<Duration>;
+
+    /// Get how many temporary directories of the job couldn't be removed.
+    fn leaked_dirs(&self) -> usize;
 }
 
 
+/// Where the output of a job queued with
+/// [`queue_sync`](trait.ProcessorApiTrait.html#tymethod.queue_sync) is sent.
+pub type SyncJobOutput<S> = mpsc::Receiver<Option<
+    <<S as ScriptsRepositoryTrait>::Job as JobTrait<
+        <S as ScriptsRepositoryTrait>::Script,
+    >>::Output,
+>>;
+
+
 /// This trait represents the API of the processor
 pub trait ProcessorApiTrait<S: ScriptsRepositoryTrait>: Send {
     /// Queue a new job into the processor.
     fn queue(&self, job: S::Job, priority: isize) -> Result<()>;
 
+    /// Queue a new job into the processor, returning where its output will
+    /// be sent once it's executed. `None` is sent if the execution failed,
+    /// and nothing is sent if the job is dropped before being executed.
+    fn queue_sync(
+        &self, job: S::Job, priority: isize,
+    ) -> Result<SyncJobOutput<S>>;
+
     /// Get some insights about the health of the processor.
     fn health_details(&self) -> Result<HealthDetails>;
 
+    /// Get a snapshot of the current load of the processor.
+    fn load_snapshot(&self) -> Result<LoadSnapshot>;
+
+    /// Get the metrics collected by the processor.
+    fn metrics(&self) -> Result<Metrics>;
+
+    /// Get the last failure of each hook, if its last job failed.
+    fn last_errors(&self) -> Result<BTreeMap<String, JobFailure>>;
+
     /// Execute periodic cleanup tasks on the processor.
     fn cleanup(&self) -> Result<()>;
 
@@ -106,4 +195,17 @@ pub trait ProcessorApiTrait<S: ScriptsRepositoryTrait>: Send {
 
     /// Unlock the processor, allowing new jobs to be run.
     fn unlock(&self) -> Result<()>;
+
+    /// Change the maximum number of threads used to run jobs.
+    fn set_max_threads(&self, max: u16) -> Result<()>;
+
+    /// Check if new jobs are accepted, or if the runtime budget is used up.
+    fn accepting_jobs(&self) -> Result<bool>;
+
+    /// Reset the runtime used by the jobs, accepting new jobs again.
+    fn reset_runtime_budget(&self) -> Result<()>;
+
+    /// Get the serials used internally to order the jobs.
+    #[cfg(feature = "diagnostics")]
+    fn serials(&self) -> Result<SerialDetails>;
 }
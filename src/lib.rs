@@ -16,6 +16,7 @@
 #![recursion_limit="256"]
 
 extern crate ansi_term;
+extern crate base64;
 #[macro_use]
 extern crate error_chain;
 #[cfg(test)]
@@ -27,6 +28,7 @@ extern crate rand;
 extern crate regex;
 extern crate hmac;
 extern crate sha1;
+extern crate sha2;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -13,16 +13,21 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::BTreeMap;
 use std::sync::{mpsc, Arc};
+use std::time::Duration;
 
 use common::prelude::*;
 use common::state::State;
-use common::structs::HealthDetails;
+use common::structs::{HealthDetails, JobFailure, LoadSnapshot, Metrics};
+#[cfg(feature = "diagnostics")]
+use common::structs::SerialDetails;
 
 use processor::scheduler::{Scheduler, SchedulerInput};
-#[cfg(test)]
+#[cfg(any(test, feature = "diagnostics"))]
 use processor::scheduler::DebugDetails;
-use processor::types::{Job, JobContext};
+use processor::timer::{self, TimerInput};
+use processor::types::{Job, JobContext, JobReply, PendingJobs};
 
 
 /// This struct allows you to spawn a new processor, stop it and get its
@@ -31,7 +36,9 @@ use processor::types::{Job, JobContext};
 #[derive(Debug)]
 pub struct Processor<S: ScriptsRepositoryTrait + 'static> {
     input: mpsc::Sender<SchedulerInput<S>>,
+    pending: Arc<PendingJobs>,
     wait: mpsc::Receiver<()>,
+    timer: mpsc::Sender<TimerInput>,
 }
 
 impl<S: ScriptsRepositoryTrait> Processor<S> {
@@ -43,13 +50,19 @@ impl<S: ScriptsRepositoryTrait> Processor<S> {
         ctx: JobContext<S>,
         state: Arc<State>,
     ) -> Result<Self> {
+        // Without threads the queued jobs would never be executed
+        if max_threads == 0 {
+            return Err(ErrorKind::ThreadsCountZero.into());
+        }
+
         // Retrieve wanted information from the spawned thread
         let (input_send, input_recv) = mpsc::sync_channel(0);
         let (wait_send, wait_recv) = mpsc::channel();
 
+        let timer_hooks = hooks.clone();
         ::std::thread::spawn(move || {
             let inner = Scheduler::new(max_threads, hooks, ctx, state);
-            input_send.send(inner.input()).unwrap();
+            input_send.send((inner.input(), inner.pending_jobs())).unwrap();
 
             inner.run().unwrap();
 
@@ -57,15 +70,33 @@ impl<S: ScriptsRepositoryTrait> Processor<S> {
             wait_send.send(()).unwrap();
         });
 
+        // Scheduled scripts are queued by a separate thread
+        let (input, pending) = input_recv.recv()?;
+        let timer = timer::start(timer_hooks, ProcessorApi {
+            input: input.clone(),
+            pending: pending.clone(),
+        });
+
         Ok(Processor {
-            input: input_recv.recv()?,
+            input,
+            pending,
             wait: wait_recv,
+            timer,
         })
     }
 
+    /// Set how many jobs can be in the queue before the runs of the
+    /// scheduled scripts are skipped, or remove the limit.
+    pub fn set_max_queued_jobs(&self, max: Option<usize>) -> Result<()> {
+        self.timer.send(TimerInput::SetMaxQueuedJobs(max))?;
+        Ok(())
+    }
+
     /// Stop this processor, and return only when the processor is stopped.
     pub fn stop(self) -> Result<()> {
-        // Ask the processor to stop
+        // Ask the processor to stop, after the scheduled scripts stopped
+        // being queued
+        drop(self.timer);
         self.input.send(SchedulerInput::StopSignal)?;
         self.wait.recv()?;
 
@@ -76,6 +107,7 @@ impl<S: ScriptsRepositoryTrait> Processor<S> {
     pub fn api(&self) -> ProcessorApi<S> {
         ProcessorApi {
             input: self.input.clone(),
+            pending: self.pending.clone(),
         }
     }
 }
@@ -86,10 +118,11 @@ impl<S: ScriptsRepositoryTrait> Processor<S> {
 #[derive(Debug, Clone)]
 pub struct ProcessorApi<S: ScriptsRepositoryTrait> {
     input: mpsc::Sender<SchedulerInput<S>>,
+    pending: Arc<PendingJobs>,
 }
 
 impl<S: ScriptsRepositoryTrait> ProcessorApi<S> {
-    #[cfg(test)]
+    #[cfg(any(test, feature = "diagnostics"))]
     pub fn debug_details(&self) -> Result<DebugDetails<S>> {
         let (res_send, res_recv) = mpsc::channel();
         self.input.send(SchedulerInput::DebugDetails(res_send))?;
@@ -101,16 +134,83 @@ impl<S: ScriptsRepositoryTrait> ProcessorApi<S> {
         Ok(())
     }
 
-    pub fn set_threads_count(&self, count: u16) -> Result<()> {
-        self.input.send(SchedulerInput::SetThreadsCount(count))?;
+    /// Set the maximum number of threads the processor is allowed to spawn,
+    /// regardless of the requested number of threads.
+    pub fn set_threads_hard_limit(&self, limit: u16) -> Result<()> {
+        self.input.send(SchedulerInput::SetThreadsHardLimit(limit))?;
+        Ok(())
+    }
+
+    /// Set the maximum number of jobs triggered by status events running at
+    /// the same time, or remove the limit.
+    pub fn set_status_jobs_limit(&self, limit: Option<u16>) -> Result<()> {
+        self.input.send(SchedulerInput::SetStatusJobsLimit(limit))?;
+        Ok(())
+    }
+
+    /// Set the maximum number of jobs in a chain of jobs triggered by status
+    /// events, or remove the limit. Jobs past the limit are dropped.
+    pub fn set_max_chain_depth(&self, max: Option<usize>) -> Result<()> {
+        self.input.send(SchedulerInput::SetMaxChainDepth(max))?;
+        Ok(())
+    }
+
+    /// Set how long jobs can wait in the queue before being dropped, or
+    /// remove the limit.
+    pub fn set_max_queue_age(&self, max: Option<Duration>) -> Result<()> {
+        self.input.send(SchedulerInput::SetMaxQueueAge(max))?;
+        Ok(())
+    }
+
+    /// Set the total time the jobs are allowed to run for, after which new
+    /// jobs are rejected until the budget is reset.
+    pub fn set_runtime_budget(&self, budget: Option<Duration>) -> Result<()> {
+        self.input.send(SchedulerInput::SetRuntimeBudget(budget))?;
+        Ok(())
+    }
+
+    /// Set how many jobs can wait to be received by the scheduler, or remove
+    /// the limit. New jobs are rejected while the limit is reached.
+    pub fn set_input_capacity(&self, capacity: Option<usize>) {
+        self.pending.set_capacity(capacity);
+    }
+
+    /// Mark the processor as draining in its health details, before it's
+    /// actually stopped.
+    pub fn drain(&self) -> Result<()> {
+        self.input.send(SchedulerInput::Drain)?;
+        Ok(())
+    }
+
+    fn send_job(
+        &self, job: Job<S>, priority: isize, reply: Option<JobReply<S>>,
+    ) -> Result<()> {
+        if !self.pending.reserve() {
+            let capacity = self.pending.capacity();
+            return Err(ErrorKind::SchedulerBusy(capacity).into());
+        }
+
+        let input = SchedulerInput::Job(job, priority, reply);
+        if let Err(err) = self.input.send(input) {
+            self.pending.release();
+            return Err(err.into());
+        }
+
         Ok(())
     }
 }
 
 impl<S: ScriptsRepositoryTrait> ProcessorApiTrait<S> for ProcessorApi<S> {
     fn queue(&self, job: Job<S>, priority: isize) -> Result<()> {
-        self.input.send(SchedulerInput::Job(job, priority))?;
-        Ok(())
+        self.send_job(job, priority, None)
+    }
+
+    fn queue_sync(
+        &self, job: Job<S>, priority: isize,
+    ) -> Result<SyncJobOutput<S>> {
+        let (reply_send, reply_recv) = mpsc::channel();
+        self.send_job(job, priority, Some(reply_send))?;
+        Ok(reply_recv)
     }
 
     fn health_details(&self) -> Result<HealthDetails> {
@@ -119,6 +219,24 @@ impl<S: ScriptsRepositoryTrait> ProcessorApiTrait<S> for ProcessorApi<S> {
         Ok(res_recv.recv()?)
     }
 
+    fn load_snapshot(&self) -> Result<LoadSnapshot> {
+        let (res_send, res_recv) = mpsc::channel();
+        self.input.send(SchedulerInput::LoadSnapshot(res_send))?;
+        Ok(res_recv.recv()?)
+    }
+
+    fn metrics(&self) -> Result<Metrics> {
+        let (res_send, res_recv) = mpsc::channel();
+        self.input.send(SchedulerInput::Metrics(res_send))?;
+        Ok(res_recv.recv()?)
+    }
+
+    fn last_errors(&self) -> Result<BTreeMap<String, JobFailure>> {
+        let (res_send, res_recv) = mpsc::channel();
+        self.input.send(SchedulerInput::LastErrors(res_send))?;
+        Ok(res_recv.recv()?)
+    }
+
     fn cleanup(&self) -> Result<()> {
         self.input.send(SchedulerInput::Cleanup)?;
         Ok(())
@@ -133,4 +251,115 @@ impl<S: ScriptsRepositoryTrait> ProcessorApiTrait<S> for ProcessorApi<S> {
         self.input.send(SchedulerInput::Unlock)?;
         Ok(())
     }
+
+    fn set_max_threads(&self, max: u16) -> Result<()> {
+        self.input.send(SchedulerInput::SetThreadsCount(max))?;
+        Ok(())
+    }
+
+    fn accepting_jobs(&self) -> Result<bool> {
+        let (res_send, res_recv) = mpsc::channel();
+        self.input.send(SchedulerInput::AcceptingJobs(res_send))?;
+        Ok(res_recv.recv()?)
+    }
+
+    fn reset_runtime_budget(&self) -> Result<()> {
+        self.input.send(SchedulerInput::ResetRuntimeBudget)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "diagnostics")]
+    fn serials(&self) -> Result<SerialDetails> {
+        Ok(self.debug_details()?.serials)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    use common::prelude::*;
+    use common::state::State;
+
+    use processor::test_utils::*;
+    use processor::types::PendingJobs;
+
+    use super::{Processor, ProcessorApi};
+
+
+    #[test]
+    fn test_queue_fails_when_the_input_is_full() {
+        let repo = Repository::<&'static str>::new();
+        repo.add_script("job", true, |_| Ok(()));
+
+        // Nothing receives the jobs, so they pile up in the input
+        let (input, _input_recv) = mpsc::channel();
+        let api: ProcessorApi<Repository<&str>> = ProcessorApi {
+            input,
+            pending: Arc::new(PendingJobs::new()),
+        };
+        api.set_input_capacity(Some(2));
+
+        for _ in 0..2 {
+            api.queue(repo.job("job", "").unwrap(), 0).unwrap();
+        }
+
+        // The third job is rejected right away instead of blocking
+        let err = api.queue(repo.job("job", "").unwrap(), 0).unwrap_err();
+        if let ErrorKind::SchedulerBusy(2) = *err.kind() {
+        } else {
+            panic!("wrong error: {:?}", err);
+        }
+
+        // Removing the limit allows new jobs again
+        api.set_input_capacity(None);
+        api.queue(repo.job("job", "").unwrap(), 0).unwrap();
+    }
+
+
+    #[test]
+    fn test_queue_sync() {
+        test_wrapper(|| {
+            let repo = Repository::<bool>::new();
+            repo.add_script("job", true, |fail| if fail {
+                Err("failed!".into())
+            } else {
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1, repo.clone(), (), Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // The output of the job is sent back once it's executed
+            let output = api.queue_sync(repo.job("job", false).unwrap(), 0)?;
+            let timeout = Duration::from_secs(5);
+            assert_eq!(output.recv_timeout(timeout).unwrap(), Some(()));
+
+            // Failed jobs send back nothing, but they're still recorded
+            let output = api.queue_sync(repo.job("job", true).unwrap(), 0)?;
+            assert_eq!(output.recv_timeout(timeout).unwrap(), None);
+            assert!(api.last_errors()?.contains_key("job"));
+
+            // Sync jobs trigger status hooks like every other job
+            let (status_send, status_recv) = mpsc::channel();
+            let status_send = Mutex::new(status_send);
+            repo.add_script("status", true, move |_| {
+                status_send.lock()?.send(())?;
+                Ok(())
+            });
+            repo.after_next_output(vec![repo.job("status", false).unwrap()]);
+            let output = api.queue_sync(repo.job("job", false).unwrap(), 0)?;
+            assert_eq!(output.recv_timeout(timeout).unwrap(), Some(()));
+            status_recv.recv_timeout(timeout).unwrap();
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
 }
@@ -22,6 +22,7 @@ mod api;
 mod scheduled_job;
 mod scheduler;
 mod thread;
+mod timer;
 mod types;
 #[cfg(test)]
 mod test_utils;
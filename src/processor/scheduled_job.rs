@@ -14,11 +14,14 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::cmp::Ordering;
+use std::time::{Duration, Instant};
 
 use common::prelude::*;
 use common::serial::Serial;
 
-use super::types::{Job, JobContext, JobOutput, ScriptId};
+use super::types::{
+    ExclusiveKey, Job, JobContext, JobOutput, JobReply, ScriptId,
+};
 
 
 #[derive(Debug)]
@@ -26,17 +29,73 @@ pub struct ScheduledJob<S: ScriptsRepositoryTrait> {
     job: Job<S>,
     priority: isize,
     serial: Serial,
+    status: bool,
+    depth: usize,
+    queued_at: Instant,
+    reply: Option<JobReply<S>>,
 }
 
 impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
     pub fn new(job: Job<S>, priority: isize, serial: Serial) -> Self {
         ScheduledJob {
-            job: job,
-            priority: priority,
-            serial: serial,
+            job,
+            priority,
+            serial,
+            status: false,
+            depth: 0,
+            queued_at: Instant::now(),
+            reply: None,
         }
     }
 
+    /// Send the output of the job to `reply` once it's executed.
+    pub fn set_reply(&mut self, reply: Option<JobReply<S>>) {
+        self.reply = reply;
+    }
+
+    /// Send the output of the job to whoever is waiting for it, if anyone.
+    pub fn reply(&self, output: Option<JobOutput<S>>) {
+        if let Some(ref reply) = self.reply {
+            // The receiver might not be interested in the output anymore
+            let _ = reply.send(output);
+        }
+    }
+
+    /// Create a job triggered by a status event, after a chain of `depth`
+    /// other jobs.
+    pub fn status(
+        job: Job<S>, priority: isize, serial: Serial, depth: usize,
+    ) -> Self {
+        ScheduledJob {
+            status: true,
+            depth,
+            .. ScheduledJob::new(job, priority, serial)
+        }
+    }
+
+    pub fn is_status(&self) -> bool {
+        self.status
+    }
+
+    /// Get how many jobs were executed in the chain which triggered this
+    /// one.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn job(&self) -> &Job<S> {
+        &self.job
+    }
+
+    pub fn priority(&self) -> isize {
+        self.priority
+    }
+
+    /// Get how long the job has been waiting since it was queued.
+    pub fn waiting_for(&self) -> Duration {
+        self.queued_at.elapsed()
+    }
+
     pub fn execute(&self, ctx: &JobContext<S>) -> Result<JobOutput<S>> {
         self.job.execute(ctx)
             .chain_err(|| {
@@ -51,6 +110,21 @@ impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
     pub fn hook_name(&self) -> &str {
         self.job.script_name()
     }
+
+    pub fn hook_group(&self) -> Option<&str> {
+        self.job.script_group()
+    }
+
+    /// Get the key used to prevent jobs of non-parallel hooks from running
+    /// at the same time.
+    pub fn exclusive_key(&self) -> ExclusiveKey<S> {
+        (self.hook_id(), self.job.parallel_key().map(|key| key.to_string()))
+    }
+
+    #[cfg(any(test, feature = "diagnostics"))]
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
 }
 
 impl<S: ScriptsRepositoryTrait> Ord for ScheduledJob<S> {
@@ -13,79 +13,141 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::{BinaryHeap, HashMap, HashSet};
-use std::time::Instant;
+use std::cmp;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::time::{Duration, Instant};
 use std::sync::{mpsc, Arc, RwLock};
 
+use ansi_term::Colour;
+
 use common::prelude::*;
 use common::state::{State, UniqueId};
 use common::serial::Serial;
-use common::structs::HealthDetails;
+use common::structs::{
+    HealthDetails, JobFailure, LoadSnapshot, Metrics, WaitHistogram,
+};
+#[cfg(any(test, feature = "diagnostics"))]
+use common::structs::SerialDetails;
 
 use super::thread::{ProcessResult, Thread, ThreadCompleter};
 use super::scheduled_job::ScheduledJob;
-use super::types::{Job, JobContext, JobOutput, ScriptId};
+use super::types::{
+    ExclusiveKey, Job, JobContext, JobOutput, JobReply, PendingJobs,
+};
+#[cfg(any(test, feature = "diagnostics"))]
+use super::types::ScriptId;
 
 
 const STATUS_EVENTS_PRIORITY: isize = 1000;
 
 
-#[cfg(test)]
+#[cfg(any(test, feature = "diagnostics"))]
 #[derive(Debug)]
 pub struct DebugDetails<S: ScriptsRepositoryTrait> {
     pub waiting: HashMap<ScriptId<S>, usize>,
+    pub threads: usize,
+    pub serials: SerialDetails,
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "diagnostics"))]
 impl<S: ScriptsRepositoryTrait> DebugDetails<S> {
     fn from_scheduler(scheduler: &Scheduler<S>) -> Self {
-        let waiting = scheduler
-            .waiting
+        // Jobs waiting for different keys of the same hook are counted
+        // together
+        let mut waiting = HashMap::new();
+        for (&(hook_id, _), jobs) in &scheduler.waiting {
+            *waiting.entry(hook_id).or_insert(0) += jobs.len();
+        }
+
+        // Jobs can be queued in multiple places, depending on their hook
+        let mut queued: Vec<_> = scheduler
+            .queue
             .iter()
-            .map(|(key, value)| (*key, value.len()))
+            .chain(scheduler.waiting.values().flat_map(|q| q.iter()))
+            .chain(scheduler.groups_waiting.values().flat_map(|q| q.iter()))
+            .chain(scheduler.status_waiting.iter())
+            .map(|job| job.serial())
             .collect();
+        queued.sort();
 
-        DebugDetails { waiting: waiting }
+        DebugDetails {
+            waiting: waiting,
+            threads: scheduler.threads.len(),
+            serials: SerialDetails {
+                current: scheduler.serial,
+                queued: queued,
+            },
+        }
     }
 }
 
 
 pub enum SchedulerInput<S: ScriptsRepositoryTrait> {
-    Job(Job<S>, isize),
+    Job(Job<S>, isize, Option<JobReply<S>>),
     HealthStatus(mpsc::Sender<HealthDetails>),
-    ProcessOutput(JobOutput<S>),
+    LoadSnapshot(mpsc::Sender<LoadSnapshot>),
+    Metrics(mpsc::Sender<Metrics>),
+    LastErrors(mpsc::Sender<BTreeMap<String, JobFailure>>),
+    ProcessOutput(String, JobOutput<S>, Duration, usize),
+    ProcessFailed(String, String, Duration),
 
     Cleanup,
 
-    #[cfg(test)] DebugDetails(mpsc::Sender<DebugDetails<S>>),
+    #[cfg(any(test, feature = "diagnostics"))]
+    DebugDetails(mpsc::Sender<DebugDetails<S>>),
 
     Lock,
     Unlock,
 
     UpdateContext(JobContext<S>),
     SetThreadsCount(u16),
-
+    SetThreadsHardLimit(u16),
+    SetStatusJobsLimit(Option<u16>),
+    SetMaxChainDepth(Option<usize>),
+    SetMaxQueueAge(Option<Duration>),
+    SetRuntimeBudget(Option<Duration>),
+    ResetRuntimeBudget,
+    AcceptingJobs(mpsc::Sender<bool>),
+
+    Drain,
     StopSignal,
-    JobEnded(ScriptId<S>, ThreadCompleter),
+    JobEnded(ExclusiveKey<S>, Option<String>, bool, ThreadCompleter),
 }
 
 
 #[derive(Debug)]
 pub struct Scheduler<S: ScriptsRepositoryTrait + 'static> {
     max_threads: u16,
+    threads_hard_limit: u16,
     hooks: Arc<S>,
     jobs_context: Arc<RwLock<Arc<JobContext<S>>>>,
     state: Arc<State>,
 
     locked: bool,
+    draining: bool,
     should_stop: bool,
     queue: BinaryHeap<ScheduledJob<S>>,
-    waiting: HashMap<ScriptId<S>, BinaryHeap<ScheduledJob<S>>>,
+    waiting: HashMap<ExclusiveKey<S>, BinaryHeap<ScheduledJob<S>>>,
+    running: HashSet<ExclusiveKey<S>>,
+    groups_waiting: HashMap<String, BinaryHeap<ScheduledJob<S>>>,
+    running_groups: HashSet<String>,
+    status_waiting: BinaryHeap<ScheduledJob<S>>,
+    running_status: usize,
+    status_jobs_limit: Option<u16>,
+    max_chain_depth: Option<usize>,
+    max_queue_age: Option<Duration>,
     threads: HashMap<UniqueId, Thread<S>>,
+    last_errors: HashMap<String, JobFailure>,
+    provider_jobs: BTreeMap<String, usize>,
+    runtime_budget: Option<Duration>,
+    runtime_used: Duration,
+    serial: Serial,
 
     input_send: mpsc::Sender<SchedulerInput<S>>,
     input_recv: mpsc::Receiver<SchedulerInput<S>>,
+    pending: Arc<PendingJobs>,
 
+    wait_times: WaitHistogram,
     last_cleanup: Instant,
 }
 
@@ -102,25 +164,42 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         let mut waiting = HashMap::new();
         for hook in hooks.iter() {
             if !hook.can_be_parallel() {
-                waiting.insert(hook.id(), BinaryHeap::new());
+                waiting.insert((hook.id(), None), BinaryHeap::new());
             }
         }
 
         Scheduler {
-            max_threads: max_threads,
-            hooks: hooks,
+            max_threads,
+            threads_hard_limit: u16::MAX,
+            hooks,
             jobs_context: Arc::new(RwLock::new(Arc::new(ctx))),
-            state: state,
+            state,
 
             locked: false,
+            draining: false,
             should_stop: false,
             queue: BinaryHeap::new(),
-            waiting: waiting,
+            waiting,
+            running: HashSet::new(),
+            groups_waiting: HashMap::new(),
+            running_groups: HashSet::new(),
+            status_waiting: BinaryHeap::new(),
+            running_status: 0,
+            status_jobs_limit: None,
+            max_chain_depth: None,
+            max_queue_age: None,
             threads: HashMap::with_capacity(max_threads as usize),
+            last_errors: HashMap::new(),
+            provider_jobs: BTreeMap::new(),
+            runtime_budget: None,
+            runtime_used: Duration::from_secs(0),
+            serial: Serial::zero(),
 
-            input_send: input_send,
-            input_recv: input_recv,
+            input_send,
+            input_recv,
+            pending: Arc::new(PendingJobs::new()),
 
+            wait_times: WaitHistogram::default(),
             last_cleanup: Instant::now(),
         }
     }
@@ -129,13 +208,17 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         self.input_send.clone()
     }
 
+    /// Get the counter of the jobs sent to the scheduler, which must be
+    /// reserved before sending them to the input.
+    pub fn pending_jobs(&self) -> Arc<PendingJobs> {
+        self.pending.clone()
+    }
+
     pub fn run(mut self) -> Result<()> {
         for _ in 0..self.max_threads {
             self.spawn_thread();
         }
 
-        let mut serial = Serial::zero();
-        let mut to_schedule = Vec::new();
         while let Ok(input) = self.input_recv.recv() {
             // Check if the periodic cleanup should be done now
             if self.last_cleanup.elapsed().as_secs() > 30 {
@@ -146,10 +229,25 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
             }
 
             match input {
-                SchedulerInput::Job(job, priority) => {
-                    self.queue_job(
-                        ScheduledJob::new(job, priority, serial.incr()),
-                    );
+                SchedulerInput::Job(mut job, priority, reply) => {
+                    self.pending.release();
+
+                    // New jobs are not accepted after the budget is used up
+                    if !self.accepting_jobs() {
+                        continue;
+                    }
+
+                    if let Some(provider) = job.provider_name() {
+                        *self.provider_jobs
+                            .entry(provider.to_string())
+                            .or_insert(0) += 1;
+                    }
+
+                    job.set_queue_depth(self.queued_jobs());
+                    let serial = self.serial.incr();
+                    let mut job = ScheduledJob::new(job, priority, serial);
+                    job.set_reply(reply);
+                    self.queue_job(job);
                     self.run_jobs();
                 }
 
@@ -160,34 +258,61 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                         .filter(|thread| thread.busy())
                         .count();
 
-                    let mut queued_jobs = self.queue.len();
-                    for waiting in self.waiting.values() {
-                        queued_jobs += waiting.len();
-                    }
-
                     return_to.send(HealthDetails {
-                        queued_jobs: queued_jobs,
+                        queued_jobs: self.queued_jobs(),
                         busy_threads: busy_threads as u16,
                         max_threads: self.max_threads,
+                        draining: self.draining,
+                        provider_jobs: Some(self.provider_jobs.clone()),
+                        loaded_hooks: None,
+                        uptime_secs: None,
+                        locked: None,
                     })?;
                 }
 
-                SchedulerInput::ProcessOutput(output) => {
-                    if let Some(jobs) = self.hooks.jobs_after_output(output) {
-                        for job in jobs {
-                            to_schedule.push(ScheduledJob::new(
-                                job,
-                                STATUS_EVENTS_PRIORITY,
-                                serial.incr(),
-                            ));
-                        }
-                    }
+                SchedulerInput::LoadSnapshot(return_to) => {
+                    return_to.send(self.load_snapshot())?;
+                }
 
-                    // This is a separated step due to mutable borrows
-                    for job in to_schedule.drain(..) {
-                        self.queue_job(job);
+                SchedulerInput::Metrics(return_to) => {
+                    return_to.send(self.metrics())?;
+                }
+
+                SchedulerInput::LastErrors(return_to) => {
+                    let errors = self.last_errors
+                        .iter()
+                        .map(|(name, failure)| {
+                            (name.clone(), failure.clone())
+                        })
+                        .collect();
+                    return_to.send(errors)?;
+                }
+
+                SchedulerInput::ProcessFailed(name, error, took) => {
+                    // The CPU time of jobs which failed to execute isn't
+                    // known, so they're charged for how long they took
+                    self.runtime_used += took;
+
+                    let failure = JobFailure::new(None, None, &error);
+                    self.last_errors.insert(name, failure);
+                }
+
+                SchedulerInput::ProcessOutput(name, output, took, depth) => {
+                    // Jobs are charged for the CPU time they used, or for how
+                    // long they took if it wasn't measured
+                    self.runtime_used += output.cpu_time().unwrap_or(took);
+                    self.state.record_leaked_dirs(output.leaked_dirs());
+
+                    // Keep only the last failure of each hook
+                    if let Some(failure) = output.failure() {
+                        self.last_errors.insert(name.clone(), failure);
+                    } else {
+                        self.last_errors.remove(&name);
                     }
 
+                    let jobs = self.hooks.jobs_after_output(output);
+                    self.queue_status_jobs(jobs, &name, depth);
+
                     self.run_jobs();
                 }
 
@@ -196,7 +321,7 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                     self.cleanup_hooks();
                 }
 
-                #[cfg(test)]
+                #[cfg(any(test, feature = "diagnostics"))]
                 SchedulerInput::DebugDetails(return_to) => {
                     let details = DebugDetails::from_scheduler(&self);
                     let _ = return_to.send(details);
@@ -217,21 +342,64 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                 }
 
                 SchedulerInput::SetThreadsCount(max) => {
-                    self.max_threads = max;
+                    self.set_threads_count(max);
+                }
 
-                    // Spawn new threads if the new maximum is higher, else
-                    // start cleaning up old ones
-                    if self.max_threads as usize > self.threads.len() {
-                        for _ in self.threads.len()..self.max_threads as usize {
-                            self.spawn_thread();
-                        }
-                    } else {
-                        self.cleanup_threads();
+                SchedulerInput::SetMaxChainDepth(max) => {
+                    self.max_chain_depth = max;
+                }
+
+                SchedulerInput::SetMaxQueueAge(max) => {
+                    self.max_queue_age = max;
+                }
+
+                SchedulerInput::SetStatusJobsLimit(limit) => {
+                    self.status_jobs_limit = limit;
+
+                    // Give the waiting jobs a chance with the new limit
+                    let waiting = ::std::mem::take(&mut self.status_waiting);
+                    for job in waiting {
+                        self.queue_job(job);
                     }
+                    self.run_jobs();
+                }
+
+                SchedulerInput::SetRuntimeBudget(budget) => {
+                    self.runtime_budget = budget;
                 }
 
-                SchedulerInput::JobEnded(hook_id, completer) => {
+                SchedulerInput::ResetRuntimeBudget => {
+                    self.runtime_used = Duration::from_secs(0);
+                }
+
+                SchedulerInput::AcceptingJobs(return_to) => {
+                    return_to.send(self.accepting_jobs())?;
+                }
+
+                SchedulerInput::SetThreadsHardLimit(limit) => {
+                    self.threads_hard_limit = limit;
+
+                    // Apply the new limit to the current threads
+                    let max = self.max_threads;
+                    self.set_threads_count(max);
+                }
+
+                SchedulerInput::JobEnded(
+                    key, group, status, completer,
+                ) => {
                     completer.manual_complete();
+                    self.running.remove(&key);
+                    if let Some(ref group) = group {
+                        self.running_groups.remove(group);
+                    }
+
+                    // Let the next status job run, if there is a limit
+                    if status {
+                        self.running_status -= 1;
+                        if let Some(job) = self.status_waiting.pop() {
+                            self.queue_job(job);
+                        }
+                    }
 
                     // Cleanup threads if there are more than enough
                     if self.threads.len() > self.max_threads as usize {
@@ -239,15 +407,39 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                     }
 
                     // Put the highest-priority waiting job for this hook
-                    // back in the queue
+                    // (and key) back in the queue
                     let mut push_back = None;
-                    if let Some(waiting) = self.waiting.get_mut(&hook_id) {
+                    let mut empty = false;
+                    if let Some(waiting) = self.waiting.get_mut(&key) {
                         push_back = waiting.pop();
+                        empty = waiting.is_empty();
+                    }
+                    // Only the keys of the jobs waiting are tracked
+                    if empty && key.1.is_some() {
+                        self.waiting.remove(&key);
                     }
                     if let Some(job) = push_back {
                         self.queue_job(job);
                     }
 
+                    // Do the same for the group of the hook, if it has one
+                    if let Some(group) = group {
+                        let mut push_back = None;
+                        let mut empty = false;
+                        if let Some(waiting) =
+                            self.groups_waiting.get_mut(&group)
+                        {
+                            push_back = waiting.pop();
+                            empty = waiting.is_empty();
+                        }
+                        if empty {
+                            self.groups_waiting.remove(&group);
+                        }
+                        if let Some(job) = push_back {
+                            self.queue_job(job);
+                        }
+                    }
+
                     self.run_jobs();
 
                     if self.should_stop {
@@ -259,7 +451,12 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                     }
                 }
 
+                SchedulerInput::Drain => {
+                    self.draining = true;
+                }
+
                 SchedulerInput::StopSignal => {
+                    self.draining = true;
                     self.should_stop = true;
                     self.cleanup_threads();
 
@@ -274,6 +471,28 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
     }
 
     #[inline]
+    fn set_threads_count(&mut self, max: u16) {
+        if max > self.threads_hard_limit {
+            println!(
+                "{} requested {} threads, but only {} are allowed",
+                Colour::Yellow.bold().paint("Warning:"),
+                max,
+                self.threads_hard_limit,
+            );
+        }
+        self.max_threads = cmp::min(max, self.threads_hard_limit);
+
+        // Spawn new threads if the new maximum is higher, else start cleaning
+        // up old ones
+        if self.max_threads as usize > self.threads.len() {
+            for _ in self.threads.len()..self.max_threads as usize {
+                self.spawn_thread();
+            }
+        } else {
+            self.cleanup_threads();
+        }
+    }
+
     fn spawn_thread(&mut self) {
         let ctx_lock = self.jobs_context.clone();
         let input = self.input_send.clone();
@@ -283,18 +502,35 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                 completer.manual_mode();
 
                 let ctx = ctx_lock.read().unwrap().clone();
+                let started = Instant::now();
                 let result = job.execute(&ctx);
+                let took = started.elapsed();
 
+                // The scheduler is notified before replying, so the result
+                // is already recorded when the waiting request is answered
+                let name = job.hook_name().to_string();
                 match result {
                     Ok(output) => {
-                        input.send(SchedulerInput::ProcessOutput(output))?;
+                        input.send(SchedulerInput::ProcessOutput(
+                            name, output.clone(), took, job.depth(),
+                        ))?;
+                        job.reply(Some(output));
                     }
                     Err(error) => {
                         error.pretty_print();
+                        input.send(SchedulerInput::ProcessFailed(
+                            name, error.to_string(), took,
+                        ))?;
+                        job.reply(None);
                     }
                 }
 
-                input.send(SchedulerInput::JobEnded(job.hook_id(), completer))?;
+                input.send(SchedulerInput::JobEnded(
+                    job.exclusive_key(),
+                    job.hook_group().map(|group| group.to_string()),
+                    job.is_status(),
+                    completer,
+                ))?;
 
                 Ok(())
             },
@@ -328,22 +564,42 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
     }
 
     fn cleanup_hooks(&mut self) {
+        self.drop_removed_jobs();
+
+        // Forget the last errors of the removed hooks. The errors are kept by
+        // name, so the ones of hooks recreated by a reload are preserved
+        let names: HashSet<_> = self.hooks
+            .iter()
+            .map(|hook| hook.name().to_string())
+            .collect();
+        self.last_errors.retain(|name, _| names.contains(name));
+
         // Get a set of all the queued hooks
         let mut queued = HashSet::with_capacity(self.queue.len());
         for job in self.queue.iter() {
             queued.insert(job.hook_id());
         }
 
+        // Get a set of the hooks with jobs waiting, for any key
+        let mut waiting_hooks = HashSet::with_capacity(self.waiting.len());
+        for (&(hook_id, _), waiting) in self.waiting.iter() {
+            if !waiting.is_empty() {
+                waiting_hooks.insert(hook_id);
+            }
+        }
+
         // Remove old hooks from self.waiting
         let mut to_remove = Vec::with_capacity(self.waiting.len());
-        for (hook_id, waiting) in self.waiting.iter() {
+        for key in self.waiting.keys() {
+            let hook_id = key.0;
+
             // This hook wasn't deleted
             if self.hooks.id_exists(&hook_id) {
                 continue;
             }
 
             // There are jobs waiting
-            if !waiting.is_empty() {
+            if waiting_hooks.contains(&hook_id) {
                 continue;
             }
 
@@ -352,10 +608,10 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                 continue;
             }
 
-            to_remove.push(*hook_id);
+            to_remove.push(key.clone());
         }
-        for hook_id in &to_remove {
-            let _ = self.waiting.remove(&hook_id);
+        for key in &to_remove {
+            let _ = self.waiting.remove(key);
         }
 
         // Add new hooks
@@ -363,11 +619,50 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
             if hook.can_be_parallel() {
                 continue;
             }
-            if self.waiting.contains_key(&hook.id()) {
+            let key = (hook.id(), None);
+            if self.waiting.contains_key(&key) {
                 continue;
             }
 
-            self.waiting.insert(hook.id(), BinaryHeap::new());
+            self.waiting.insert(key, BinaryHeap::new());
+        }
+    }
+
+    /// Drop the jobs which didn't start yet of the hooks removed from the
+    /// repository. Reloading recreates every hook, so jobs of the hooks with
+    /// the same name are kept, and the jobs already running are not affected.
+    fn drop_removed_jobs(&mut self) {
+        let names: HashSet<_> = self.hooks
+            .iter()
+            .map(|hook| hook.name().to_string())
+            .collect();
+
+        let hooks = &self.hooks;
+        let keep = |job: &ScheduledJob<S>| {
+            hooks.id_exists(&job.hook_id()) || names.contains(job.hook_name())
+        };
+
+        let mut dropped = 0;
+        let queues = self.waiting.values_mut()
+            .chain(self.groups_waiting.values_mut())
+            .chain(Some(&mut self.queue))
+            .chain(Some(&mut self.status_waiting));
+        for queue in queues {
+            let before = queue.len();
+            queue.retain(&keep);
+            dropped += before - queue.len();
+        }
+        self.groups_waiting.retain(|_, waiting| !waiting.is_empty());
+        self.waiting.retain(|key, waiting| {
+            key.1.is_none() || !waiting.is_empty()
+        });
+
+        if dropped > 0 {
+            println!(
+                "{} dropped {} queued jobs of removed hooks",
+                Colour::Yellow.bold().paint("Warning:"),
+                dropped,
+            );
         }
     }
 
@@ -381,11 +676,29 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         // all of them are processed
         'main: loop {
             if let Some(mut job) = self.get_job() {
+                let group = job.hook_group().map(|group| group.to_string());
+                let status = job.is_status();
+                let key = job.exclusive_key();
+                let exclusive = self.is_exclusive(&key);
+                let waited = job.waiting_for();
+
                 // Try to run the job in a thread
                 for thread in self.threads.values_mut() {
                     match thread.process(job) {
                         ProcessResult::Rejected(j) => job = j,
-                        ProcessResult::Executing => continue 'main,
+                        ProcessResult::Executing => {
+                            self.wait_times.record(waited);
+                            if exclusive {
+                                self.running.insert(key);
+                            }
+                            if let Some(group) = group {
+                                self.running_groups.insert(group);
+                            }
+                            if status {
+                                self.running_status += 1;
+                            }
+                            continue 'main;
+                        }
                     }
                 }
                 self.queue_job(job);
@@ -395,57 +708,207 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
     }
 
     fn queue_job(&mut self, job: ScheduledJob<S>) {
-        let hook_id = job.hook_id();
+        if let Some(job) = self.wait_if_busy(job) {
+            self.queue.push(job);
+        }
+    }
+
+    fn get_job(&mut self) -> Option<ScheduledJob<S>> {
+        while let Some(job) = self.queue.pop() {
+            // Old jobs are probably stale, so they're not executed
+            if self.max_queue_age.is_some_and(|max| job.waiting_for() > max) {
+                self.expire_job(job);
+                continue;
+            }
 
-        // Put the job in waiting if it can't be parallel and
-        // it's already running
-        if self.is_running(hook_id) {
-            if let Some(waiting) = self.waiting.get_mut(&hook_id) {
-                waiting.push(job);
-                return;
+            if let Some(job) = self.wait_if_busy(job) {
+                return Some(job);
             }
         }
 
-        self.queue.push(job);
+        None
     }
 
-    fn get_job(&mut self) -> Option<ScheduledJob<S>> {
-        loop {
-            if let Some(job) = self.queue.pop() {
-                let hook_id = job.hook_id();
-
-                // Put the job in waiting if it can't be parallel and
-                // it's already running
-                if self.is_running(hook_id) {
-                    if let Some(waiting) = self.waiting.get_mut(&hook_id) {
-                        waiting.push(job);
-                        continue;
-                    }
-                }
+    fn expire_job(&mut self, job: ScheduledJob<S>) {
+        println!(
+            "{} dropped a job of {}, since it was queued for too long",
+            Colour::Yellow.bold().paint("Warning:"),
+            job.hook_name(),
+        );
 
-                return Some(job);
-            } else {
+        let jobs = self.hooks.jobs_after_expired(job.job());
+        self.queue_status_jobs(jobs, job.hook_name(), job.depth());
+    }
+
+    /// Queue the status jobs triggered by a job of the `trigger` hook, which
+    /// was at the given depth of a chain of jobs.
+    fn queue_status_jobs(
+        &mut self, jobs: Option<S::JobsIter>, trigger: &str, depth: usize,
+    ) {
+        for mut job in jobs.into_iter().flatten() {
+            // Stop chains of jobs which are too long, since they're probably
+            // cycles. The first job is at depth zero, so with the new one the
+            // chain would contain depth + 2 jobs
+            if self.max_chain_depth.is_some_and(|max| depth + 2 > max) {
+                println!(
+                    "{} dropped a job of {} triggered by {}, since the chain \
+                     of jobs is too long",
+                    Colour::Yellow.bold().paint("Warning:"),
+                    job.script_name(),
+                    trigger,
+                );
+                continue;
+            }
+
+            job.set_queue_depth(self.queued_jobs());
+            let serial = self.serial.incr();
+            self.queue_job(ScheduledJob::status(
+                job, STATUS_EVENTS_PRIORITY, serial, depth + 1,
+            ));
+        }
+    }
+
+    /// Put the job in waiting if it can't be run right now, or give it back
+    /// if it can.
+    fn wait_if_busy(
+        &mut self, job: ScheduledJob<S>,
+    ) -> Option<ScheduledJob<S>> {
+        let key = job.exclusive_key();
+
+        // Put the job in waiting if it can't be parallel and another job
+        // with the same key is already running
+        if self.is_exclusive(&key) && self.running.contains(&key) {
+            self.waiting
+                .entry(key)
+                .or_default()
+                .push(job);
+            return None;
+        }
+
+        // Put the job in waiting if another hook of its group is running
+        let group = job.hook_group().map(|group| group.to_string());
+        if let Some(group) = group {
+            if self.running_groups.contains(&group) {
+                self.groups_waiting
+                    .entry(group)
+                    .or_default()
+                    .push(job);
+                return None;
+            }
+        }
+
+        // Put status jobs in waiting if too many of them are running, so
+        // they can't take all the threads
+        if job.is_status() {
+            let limit = self.status_jobs_limit;
+            if limit.is_some_and(|max| self.running_status >= max as usize) {
+                self.status_waiting.push(job);
                 return None;
             }
         }
+
+        Some(job)
     }
 
-    fn is_running(&self, hook: ScriptId<S>) -> bool {
+    fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics {
+            leaked_dirs: self.state.leaked_dirs(),
+            ..Metrics::default()
+        };
+
+        // Every hook is included, even if nothing is running
+        let mut names = HashMap::new();
+        for hook in self.hooks.iter() {
+            names.insert(hook.id(), hook.name().to_string());
+            metrics.hooks.insert(hook.name().to_string(), Default::default());
+        }
+
         for thread in self.threads.values() {
-            if thread.currently_running() == Some(hook) {
-                return true;
+            let name = thread
+                .currently_running()
+                .and_then(|id| names.get(&id));
+            if let Some(name) = name {
+                metrics.hooks.get_mut(name).unwrap().running += 1;
             }
         }
 
-        return false;
+        let waiting = self.queue.iter()
+            .chain(self.waiting.values().flat_map(|jobs| jobs.iter()))
+            .chain(self.groups_waiting.values().flat_map(|jobs| jobs.iter()));
+        for job in waiting {
+            metrics.hooks.entry(job.hook_name().to_string())
+                .or_insert_with(Default::default)
+                .waiting += 1;
+        }
+
+        metrics.provider_jobs = self.provider_jobs.clone();
+        metrics.wait_times = self.wait_times.clone();
+        metrics
+    }
+
+    /// Count the jobs waiting to be executed, including the ones waiting
+    /// for another job to finish.
+    fn queued_jobs(&self) -> usize {
+        let mut queued = self.queue.len();
+        for waiting in self.waiting.values() {
+            queued += waiting.len();
+        }
+        for waiting in self.groups_waiting.values() {
+            queued += waiting.len();
+        }
+        queued + self.status_waiting.len()
+    }
+
+    fn load_snapshot(&self) -> LoadSnapshot {
+        let mut snapshot = LoadSnapshot {
+            busy_threads: self.threads
+                .values()
+                .filter(|thread| thread.busy())
+                .count() as u16,
+            max_threads: self.max_threads,
+            .. LoadSnapshot::default()
+        };
+
+        let queued = self.queue.iter()
+            .chain(self.waiting.values().flat_map(|jobs| jobs.iter()))
+            .chain(self.groups_waiting.values().flat_map(|jobs| jobs.iter()))
+            .chain(self.status_waiting.iter());
+
+        let mut total_wait = Duration::from_secs(0);
+        for job in queued {
+            snapshot.queued_jobs += 1;
+            *snapshot.priorities.entry(job.priority()).or_insert(0) += 1;
+            total_wait += job.waiting_for();
+        }
+
+        if snapshot.queued_jobs > 0 {
+            snapshot.average_wait_ms = total_wait.as_millis() as u64
+                / snapshot.queued_jobs as u64;
+        }
+
+        snapshot
+    }
+
+    /// Check if the runtime budget still allows new jobs to be queued.
+    fn accepting_jobs(&self) -> bool {
+        self.runtime_budget.is_none_or(|budget| self.runtime_used < budget)
+    }
+
+    /// Check if the jobs with this key can't run at the same time, which
+    /// happens if their hook can't be parallel.
+    fn is_exclusive(&self, key: &ExclusiveKey<S>) -> bool {
+        self.waiting.contains_key(&(key.0, None))
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use std::collections::VecDeque;
+    use std::collections::{BTreeMap, VecDeque};
     use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
 
     use common::prelude::*;
     use common::state::State;
@@ -469,6 +932,19 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_processor_zero_threads() {
+        let repo = Arc::new(Repository::<()>::new());
+
+        // No job would ever be executed without threads
+        let result = Processor::new(0, repo, (), Arc::new(State::new()));
+        match result {
+            Err(Error(ErrorKind::ThreadsCountZero, _)) => {}
+            _ => panic!("zero threads were accepted"),
+        }
+    }
+
+
     #[test]
     fn test_processor_clean_stop() {
         test_wrapper(|| {
@@ -636,13 +1112,61 @@ mod tests {
     }
 
     #[test]
-    fn test_health_details() {
+    fn test_grouped_hooks_processing() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+
+            // Keep track of how many hooks of the group are running
+            let running = Arc::new(AtomicUsize::new(0));
+            let max_running = Arc::new(AtomicUsize::new(0));
+            let executed = Arc::new(AtomicUsize::new(0));
+            for name in &["first", "second"] {
+                let running = running.clone();
+                let max_running = max_running.clone();
+                let executed = executed.clone();
+                repo.add_grouped_script(name, true, Some("group"), move |_| {
+                    let current = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(current, Ordering::SeqCst);
+
+                    thread::sleep(Duration::from_millis(10));
+
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    executed.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                });
+            }
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                4,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            for _ in 0..5 {
+                api.queue(repo.job("first", ()).unwrap(), 0)?;
+                api.queue(repo.job("second", ()).unwrap(), 0)?;
+            }
+
+            processor.stop()?;
+
+            // All the jobs were executed, but never at the same time
+            assert_eq!(executed.load(Ordering::SeqCst), 10);
+            assert_eq!(max_running.load(Ordering::SeqCst), 1);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_parallel_key_processing() {
         test_wrapper(|| {
             let repo =
                 Repository::<Option<Arc<Mutex<mpsc::Receiver<()>>>>>::new();
 
-            repo.add_script("noop", true, |_| Ok(()));
-            repo.add_script("wait", true, |recv| {
+            repo.add_script("deploy", false, |recv| {
                 let recv = recv.unwrap();
                 recv.lock()?.recv()?;
                 Ok(())
@@ -650,36 +1174,963 @@ mod tests {
 
             let repo = Arc::new(repo);
             let processor = Processor::new(
-                1,
+                4,
                 repo.clone(),
                 (),
                 Arc::new(State::new()),
             )?;
             let api = processor.api();
 
-            // Queue a wait job
-            let (waiting_send, waiting_recv) = mpsc::channel();
-            api.queue(
-                repo.job("wait", Some(Arc::new(Mutex::new(waiting_recv))))
-                    .unwrap(),
-                0,
+            // Queue two jobs for the "a" key and one for the "b" key
+            let (send, recv) = mpsc::channel();
+            let recv = Arc::new(Mutex::new(recv));
+            for key in &["a", "a", "b"] {
+                let job = repo.job("deploy", Some(recv.clone())).unwrap();
+                api.queue(job.with_parallel_key(key), 0)?;
+            }
+
+            // The jobs with different keys are executed at the same time,
+            // while the second "a" job waits for the first one
+            let status = api.health_details()?;
+            assert_eq!(status.busy_threads, 2);
+            assert_eq!(status.queued_jobs, 1);
+
+            for _ in 0..3 {
+                send.send(())?;
+            }
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_ordered_processing() {
+        test_wrapper(|| {
+            let repo = Repository::<Option<usize>>::new();
+
+            // The hooks of ordered scripts can't be parallel
+            let (order_send, order_recv) = mpsc::channel();
+            let order_send = Mutex::new(order_send);
+            repo.add_script("ordered", false, move |number| {
+                thread::sleep(Duration::from_millis(10));
+                order_send.lock()?.send(number.unwrap())?;
+                Ok(())
+            });
+
+            let (slow_send, slow_recv) = mpsc::channel();
+            let slow_recv = Mutex::new(slow_recv);
+            repo.add_script("slow", true, move |_| {
+                slow_recv.lock()?.recv()?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                4,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
             )?;
+            let api = processor.api();
 
-            // Queue ten extra jobs
+            // Keep a thread busy with another hook, and send a burst of jobs
+            // to the ordered one
+            api.queue(repo.job("slow", None).unwrap(), 0)?;
+            for number in 0..10 {
+                api.queue(repo.job("ordered", Some(number)).unwrap(), 0)?;
+            }
+
+            // Jobs with the same priority are executed in the order they
+            // were queued, while the other hook is still running
+            let mut order = Vec::new();
             for _ in 0..10 {
-                api.queue(repo.job("noop", None).unwrap(), 0)?;
+                let timeout = Duration::from_secs(5);
+                order.push(order_recv.recv_timeout(timeout).unwrap());
             }
+            assert_eq!(order, (0..10).collect::<Vec<_>>());
 
-            // Get the health status of the processor
-            let status = api.health_details()?;
+            // The last ordered job might still be finishing
+            timeout_until_true(
+                || api.health_details().unwrap().busy_threads == 1,
+                "the ordered jobs didn't finish",
+            );
 
-            // Check if the health details are correct
-            assert_eq!(status.queued_jobs, 10);
-            assert_eq!(status.busy_threads, 1);
-            assert_eq!(status.max_threads, 1);
+            slow_send.send(())?;
+            processor.stop()?;
 
-            // Create the file the first job is waiting for
-            waiting_send.send(())?;
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_ordered_processing_priorities() {
+        test_wrapper(|| {
+            let repo = Repository::<usize>::new();
+
+            let (order_send, order_recv) = mpsc::channel();
+            let order_send = Mutex::new(order_send);
+            let (unlock_send, unlock_recv) = mpsc::channel();
+            let unlock_recv = Mutex::new(unlock_recv);
+            repo.add_script("ordered", false, move |number| {
+                // The first job blocks the others until it's unlocked
+                if number == 0 {
+                    unlock_recv.lock()?.recv()?;
+                }
+                order_send.lock()?.send(number)?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                4,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            api.queue(repo.job("ordered", 0).unwrap(), 0)?;
+            timeout_until_true(
+                || api.health_details().unwrap().busy_threads == 1,
+                "the first job didn't start",
+            );
+
+            // Queue jobs with mixed priorities while the first one runs
+            let jobs = [(1, 0), (2, 0), (3, 5), (4, 5), (5, 0)];
+            for &(number, priority) in &jobs {
+                api.queue(repo.job("ordered", number).unwrap(), priority)?;
+            }
+            unlock_send.send(())?;
+
+            // Higher priorities go first, and jobs with the same priority
+            // are executed in the order they were queued
+            let mut order = Vec::new();
+            for _ in 0..6 {
+                let timeout = Duration::from_secs(5);
+                order.push(order_recv.recv_timeout(timeout).unwrap());
+            }
+            assert_eq!(order, vec![0, 3, 4, 1, 2, 5]);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_max_queue_age() {
+        test_wrapper(|| {
+            let (events_send, events_recv) = mpsc::channel();
+            let events_send = Mutex::new(events_send);
+
+            let repo = Repository::<&'static str>::new();
+            repo.add_script("hook", true, move |name| {
+                events_send.lock()?.send(name)?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+            api.set_max_queue_age(Some(Duration::from_millis(200)))?;
+
+            // Let a job age in the queue before queueing a fresh one
+            api.lock()?;
+            api.queue(repo.job("hook", "old").unwrap(), 0)?;
+            thread::sleep(Duration::from_millis(300));
+            api.queue(repo.job("hook", "fresh").unwrap(), 0)?;
+            api.unlock()?;
+
+            // Only the fresh job is executed
+            let timeout = Duration::from_secs(5);
+            assert_eq!(events_recv.recv_timeout(timeout).unwrap(), "fresh");
+            assert!(events_recv
+                .recv_timeout(Duration::from_millis(200))
+                .is_err());
+            assert_eq!(api.health_details()?.queued_jobs, 0);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_health_details() {
+        test_wrapper(|| {
+            let repo =
+                Repository::<Option<Arc<Mutex<mpsc::Receiver<()>>>>>::new();
+
+            repo.add_script("noop", true, |_| Ok(()));
+            repo.add_script("wait", true, |recv| {
+                let recv = recv.unwrap();
+                recv.lock()?.recv()?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Queue a wait job
+            let (waiting_send, waiting_recv) = mpsc::channel();
+            api.queue(
+                repo.job("wait", Some(Arc::new(Mutex::new(waiting_recv))))
+                    .unwrap(),
+                0,
+            )?;
+
+            // Queue ten extra jobs
+            for _ in 0..10 {
+                api.queue(repo.job("noop", None).unwrap(), 0)?;
+            }
+
+            // Get the health status of the processor
+            let status = api.health_details()?;
+
+            // Check if the health details are correct
+            assert_eq!(status.queued_jobs, 10);
+            assert_eq!(status.busy_threads, 1);
+            assert_eq!(status.max_threads, 1);
+
+            // Create the file the first job is waiting for
+            waiting_send.send(())?;
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_serials() {
+        test_wrapper(|| {
+            let repo =
+                Repository::<Option<Arc<Mutex<mpsc::Receiver<()>>>>>::new();
+
+            repo.add_script("noop", true, |_| Ok(()));
+            repo.add_script("wait", true, |recv| {
+                let recv = recv.unwrap();
+                recv.lock()?.recv()?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Keep the only thread busy, so the next jobs stay queued
+            let (waiting_send, waiting_recv) = mpsc::channel();
+            api.queue(
+                repo.job("wait", Some(Arc::new(Mutex::new(waiting_recv))))
+                    .unwrap(),
+                0,
+            )?;
+
+            let mut previous = api.debug_details()?.serials.current;
+            for _ in 0..5 {
+                api.queue(repo.job("noop", None).unwrap(), 0)?;
+
+                // The serial increases with each job, and it's assigned to
+                // the job which was just queued
+                let serials = api.debug_details()?.serials;
+                assert!(serials.current > previous);
+                assert_eq!(serials.queued.last(), Some(&serials.current));
+                previous = serials.current;
+            }
+
+            let queued = api.debug_details()?.serials.queued;
+            assert!(queued.len() >= 5);
+            assert!(queued.windows(2).all(|pair| pair[0] < pair[1]));
+
+            waiting_send.send(())?;
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_max_chain_depth() {
+        test_wrapper(|| {
+            let (events_send, events_recv) = mpsc::channel();
+            let events_send = Mutex::new(events_send);
+
+            let repo = Repository::<()>::new();
+            repo.add_script("cycle", true, move |_| {
+                events_send.lock()?.send(())?;
+                Ok(())
+            });
+
+            // Every job of the hook triggers another one, forever
+            repo.after_every_output(vec![repo.job("cycle", ()).unwrap()]);
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+            api.set_max_chain_depth(Some(3))?;
+
+            // The first job and the two triggered by it are executed
+            api.queue(repo.job("cycle", ()).unwrap(), 0)?;
+            for _ in 0..3 {
+                events_recv.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+            assert!(events_recv
+                .recv_timeout(Duration::from_millis(200))
+                .is_err());
+            assert_eq!(api.health_details()?.queued_jobs, 0);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_status_jobs_limit() {
+        test_wrapper(|| {
+            let (events_send, events_recv) = mpsc::channel();
+            let events_send = Arc::new(Mutex::new(events_send));
+            let (release_send, release_recv) = mpsc::channel::<()>();
+            let release_recv = Arc::new(Mutex::new(release_recv));
+
+            let repo = Repository::<()>::new();
+            repo.add_script("trigger", true, |_| Ok(()));
+
+            let events = events_send.clone();
+            repo.add_script("status", true, move |_| {
+                events.lock()?.send("status")?;
+                release_recv.lock()?.recv()?;
+                Ok(())
+            });
+
+            let events = events_send.clone();
+            repo.add_script("regular", true, move |_| {
+                events.lock()?.send("regular")?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                3,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+            api.set_status_jobs_limit(Some(1))?;
+
+            // Two status jobs are triggered, but only one is executed
+            repo.after_next_output(vec![
+                repo.job("status", ()).unwrap(),
+                repo.job("status", ()).unwrap(),
+            ]);
+            api.queue(repo.job("trigger", ()).unwrap(), 0)?;
+
+            let timeout = Duration::from_secs(5);
+            assert_eq!(events_recv.recv_timeout(timeout), Ok("status"));
+
+            // Regular jobs still run while status jobs are waiting
+            api.queue(repo.job("regular", ()).unwrap(), 0)?;
+            assert_eq!(events_recv.recv_timeout(timeout), Ok("regular"));
+            assert!(events_recv
+                .recv_timeout(Duration::from_millis(200))
+                .is_err());
+            assert_eq!(api.health_details()?.queued_jobs, 1);
+
+            // The waiting status job runs once the other one is done
+            release_send.send(())?;
+            assert_eq!(events_recv.recv_timeout(timeout), Ok("status"));
+            release_send.send(())?;
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_provider_jobs() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+            repo.add_script("noop", true, |_| Ok(()));
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Jobs are counted separately for each provider
+            let job = || repo.job("noop", ()).unwrap();
+            api.queue(job().with_provider("GitHub"), 0)?;
+            api.queue(job().with_provider("GitHub"), 0)?;
+            api.queue(job().with_provider("Standalone"), 0)?;
+
+            // Jobs without a provider are not counted
+            api.queue(job(), 0)?;
+
+            let mut expected = BTreeMap::new();
+            expected.insert("GitHub".to_string(), 2);
+            expected.insert("Standalone".to_string(), 1);
+            assert_eq!(api.metrics()?.provider_jobs, expected);
+            assert_eq!(api.health_details()?.provider_jobs, Some(expected));
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_metrics() {
+        test_wrapper(|| {
+            let repo = Repository::<Option<Arc<Mutex<mpsc::Receiver<()>>>>>
+                ::new();
+
+            repo.add_script("idle", true, |_| Ok(()));
+            repo.add_script("noop", true, |_| Ok(()));
+            repo.add_script("wait", false, |recv| {
+                if let Some(recv) = recv {
+                    recv.lock()?.recv()?;
+                }
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Keep the only thread busy with a wait job
+            let (waiting_send, waiting_recv) = mpsc::channel();
+            let waiting_recv = Arc::new(Mutex::new(waiting_recv));
+            api.queue(repo.job("wait", Some(waiting_recv.clone())).unwrap(), 0)?;
+            timeout_until_true(
+                || api.health_details().unwrap().busy_threads == 1,
+                "the job didn't start",
+            );
+
+            // Queue some extra jobs, which can't be run
+            api.queue(repo.job("wait", None).unwrap(), 0)?;
+            for _ in 0..3 {
+                api.queue(repo.job("noop", None).unwrap(), 0)?;
+            }
+
+            let metrics = api.metrics()?;
+            assert_eq!(metrics.hooks.len(), 3);
+            assert_eq!(metrics.hooks["idle"].running, 0);
+            assert_eq!(metrics.hooks["idle"].waiting, 0);
+            assert_eq!(metrics.hooks["noop"].running, 0);
+            assert_eq!(metrics.hooks["noop"].waiting, 3);
+            assert_eq!(metrics.hooks["wait"].running, 1);
+            assert_eq!(metrics.hooks["wait"].waiting, 1);
+
+            waiting_send.send(())?;
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_wait_times() {
+        test_wrapper(|| {
+            let repo = Repository::<u64>::new();
+            repo.add_script("sleep", false, |ms| {
+                thread::sleep(Duration::from_millis(ms));
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // The second job waits for the first one to finish
+            api.queue(repo.job("sleep", 300).unwrap(), 0)?;
+            api.queue(repo.job("sleep", 0).unwrap(), 0)?;
+
+            timeout_until_true(
+                || api.metrics().unwrap().wait_times.count == 2,
+                "the jobs weren't executed",
+            );
+            let wait_times = api.metrics()?.wait_times;
+
+            // Only the first job started within the first bucket, while
+            // every job is in the last one
+            assert_eq!(wait_times.buckets[0], (100, 1));
+            assert_eq!(wait_times.buckets.last().unwrap().1, 2);
+            assert!(wait_times.sum_ms >= 300);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_queue_depth() {
+        test_wrapper(|| {
+            let repo = Repository::<u64>::new();
+            repo.add_script("sleep", true, |ms| {
+                thread::sleep(Duration::from_millis(ms));
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // The first job keeps the only thread busy, so the other ones
+            // pile up in the queue
+            api.queue(repo.job("sleep", 300).unwrap(), 0)?;
+            for _ in 0..3 {
+                api.queue(repo.job("sleep", 0).unwrap(), 0)?;
+            }
+
+            timeout_until_true(
+                || repo.queue_depths_of("sleep").len() == 4,
+                "the jobs weren't executed",
+            );
+
+            // Each job sees the jobs queued before it and not started yet
+            assert_eq!(repo.queue_depths_of("sleep"), vec![0, 0, 1, 2]);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_last_errors() {
+        test_wrapper(|| {
+            let repo = Repository::<bool>::new();
+            repo.add_script("flaky", true, |fail| {
+                if fail {
+                    Err("failed!".into())
+                } else {
+                    Ok(())
+                }
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+            assert!(api.last_errors()?.is_empty());
+
+            // A failing job records its error
+            api.queue(repo.job("flaky", true).unwrap(), 0)?;
+            timeout_until_true(
+                || !api.last_errors().unwrap().is_empty(),
+                "the error wasn't recorded",
+            );
+            let errors = api.last_errors()?;
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors["flaky"].exit_code, None);
+            assert_eq!(
+                errors["flaky"].stderr,
+                "execution of the 'flaky' script failed"
+            );
+
+            // A subsequent successful job clears it
+            api.queue(repo.job("flaky", false).unwrap(), 0)?;
+            timeout_until_true(
+                || api.last_errors().unwrap().is_empty(),
+                "the error wasn't cleared",
+            );
+
+            // Errors of hooks recreated by a reload are kept
+            api.queue(repo.job("flaky", true).unwrap(), 0)?;
+            timeout_until_true(
+                || !api.last_errors().unwrap().is_empty(),
+                "the error wasn't recorded",
+            );
+            repo.recreate_scripts();
+            api.cleanup()?;
+            assert_eq!(api.last_errors()?.len(), 1);
+
+            // Errors of removed hooks are forgotten
+            repo.remove_script("flaky");
+            api.cleanup()?;
+            assert!(api.last_errors()?.is_empty());
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_load_snapshot() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+            repo.add_script("noop", true, |_| Ok(()));
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                2,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Nothing is queued yet
+            let snapshot = api.load_snapshot()?;
+            assert_eq!(snapshot.queued_jobs, 0);
+            assert!(snapshot.priorities.is_empty());
+            assert_eq!(snapshot.average_wait_ms, 0);
+            assert_eq!(snapshot.max_threads, 2);
+
+            // Keep the jobs in the queue
+            api.lock()?;
+            for priority in &[0, 0, 5] {
+                api.queue(repo.job("noop", ()).unwrap(), *priority)?;
+            }
+
+            // The jobs are timestamped when the scheduler receives them
+            timeout_until_true(
+                || api.load_snapshot().unwrap().queued_jobs == 3,
+                "the jobs were not queued",
+            );
+            thread::sleep(Duration::from_millis(200));
+
+            let snapshot = api.load_snapshot()?;
+            assert_eq!(snapshot.queued_jobs, 3);
+            assert_eq!(snapshot.busy_threads, 0);
+            assert_eq!(snapshot.priorities.get(&0), Some(&2));
+            assert_eq!(snapshot.priorities.get(&5), Some(&1));
+            assert_eq!(snapshot.priorities.len(), 2);
+            assert!(snapshot.average_wait_ms >= 200);
+            assert!(snapshot.average_wait_ms < 5000);
+
+            api.unlock()?;
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_health_details_draining() {
+        test_wrapper(|| {
+            let repo = Repository::<Arc<Mutex<mpsc::Receiver<()>>>>::new();
+
+            repo.add_script("wait", true, |recv| {
+                recv.lock()?.recv()?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Start a job which blocks the processor from stopping
+            let (unlock_send, unlock_recv) = mpsc::channel();
+            api.queue(
+                repo.job("wait", Arc::new(Mutex::new(unlock_recv))).unwrap(),
+                0,
+            )?;
+            assert!(!api.health_details()?.draining);
+
+            // Explicitly start draining the processor
+            api.drain()?;
+            assert!(api.health_details()?.draining);
+
+            // Stop the processor in another thread, since it blocks
+            let stopper = ::std::thread::spawn(move || processor.stop());
+
+            // The processor still answers while the job is running
+            let status = api.health_details()?;
+            assert!(status.draining);
+            assert_eq!(status.busy_threads, 1);
+
+            unlock_send.send(())?;
+            stopper.join().unwrap()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_health_details_draining_on_stop() {
+        test_wrapper(|| {
+            let repo = Repository::<Arc<Mutex<mpsc::Receiver<()>>>>::new();
+
+            repo.add_script("wait", true, |recv| {
+                recv.lock()?.recv()?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            let (unlock_send, unlock_recv) = mpsc::channel();
+            api.queue(
+                repo.job("wait", Arc::new(Mutex::new(unlock_recv))).unwrap(),
+                0,
+            )?;
+            assert!(!api.health_details()?.draining);
+
+            // The flag flips as soon as the stop begins
+            let stopper = ::std::thread::spawn(move || processor.stop());
+            timeout_until_true(
+                || api.health_details().unwrap().draining,
+                "the processor isn't draining",
+            );
+
+            unlock_send.send(())?;
+            stopper.join().unwrap()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_set_max_threads() {
+        test_wrapper(|| {
+            let repo = Arc::new(Repository::<()>::new());
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            assert_eq!(api.health_details()?.max_threads, 1);
+            assert_eq!(api.debug_details()?.threads, 1);
+
+            // New threads are spawned when the maximum is raised
+            api.set_max_threads(4)?;
+            assert_eq!(api.health_details()?.max_threads, 4);
+            assert_eq!(api.debug_details()?.threads, 4);
+
+            // Idle threads are reaped when the maximum is lowered
+            api.set_max_threads(2)?;
+            assert_eq!(api.health_details()?.max_threads, 2);
+            assert_eq!(api.debug_details()?.threads, 2);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_threads_hard_limit() {
+        test_wrapper(|| {
+            let repo = Arc::new(Repository::<()>::new());
+            let processor = Processor::new(
+                4,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Lowering the limit also reaps the threads above it
+            api.set_threads_hard_limit(3)?;
+            assert_eq!(api.health_details()?.max_threads, 3);
+            assert_eq!(api.debug_details()?.threads, 3);
+
+            // Requested counts above the limit are clamped
+            api.set_max_threads(1000)?;
+            assert_eq!(api.health_details()?.max_threads, 3);
+            assert_eq!(api.debug_details()?.threads, 3);
+
+            api.set_max_threads(2)?;
+            assert_eq!(api.health_details()?.max_threads, 2);
+            assert_eq!(api.debug_details()?.threads, 2);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_runtime_budget() {
+        test_wrapper(|| {
+            let runs = Arc::new(AtomicUsize::new(0));
+
+            let repo = Repository::<()>::new();
+            let runs_inner = runs.clone();
+            repo.add_script("slow", true, move |_| {
+                thread::sleep(Duration::from_millis(20));
+                runs_inner.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+            api.set_runtime_budget(Some(Duration::from_millis(10)))?;
+            assert!(api.accepting_jobs()?);
+
+            // Use up the whole budget with a single job
+            api.queue(repo.job("slow", ()).unwrap(), 0)?;
+            while api.accepting_jobs()? {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+            // New jobs are rejected
+            api.queue(repo.job("slow", ()).unwrap(), 0)?;
+            let health = api.health_details()?;
+            assert_eq!(health.queued_jobs, 0);
+            assert_eq!(health.busy_threads, 0);
+
+            // Resetting the budget allows new jobs again
+            api.reset_runtime_budget()?;
+            assert!(api.accepting_jobs()?);
+            api.queue(repo.job("slow", ()).unwrap(), 0)?;
+            while runs.load(Ordering::SeqCst) < 2 {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_cleanup_removed_hooks() {
+        test_wrapper(|| {
+            let (events_send, events_recv) = mpsc::channel();
+            let events_send = Arc::new(Mutex::new(events_send));
+            let (release_send, release_recv) = mpsc::channel::<()>();
+            let release_recv = Arc::new(Mutex::new(release_recv));
+
+            let repo = Repository::<()>::new();
+            for name in &["removed", "kept"] {
+                let events = events_send.clone();
+                repo.add_script(name, true, move |_| {
+                    events.lock()?.send(*name)?;
+                    Ok(())
+                });
+            }
+            let events = events_send.clone();
+            repo.add_script("running", true, move |_| {
+                release_recv.lock()?.recv()?;
+                events.lock()?.send("running")?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Keep the only thread busy while the other jobs are queued
+            api.queue(repo.job("running", ()).unwrap(), 0)?;
+            for _ in 0..3 {
+                api.queue(repo.job("removed", ()).unwrap(), 0)?;
+            }
+            api.queue(repo.job("kept", ()).unwrap(), 0)?;
+            timeout_until_true(
+                || api.health_details().unwrap().queued_jobs == 4,
+                "the jobs weren't queued",
+            );
+
+            // Remove a hook while its jobs are still queued
+            repo.remove_script("running");
+            repo.remove_script("removed");
+            api.cleanup()?;
+            assert_eq!(api.health_details()?.queued_jobs, 1);
+
+            // The running job completes, and the queued ones are discarded
+            release_send.send(())?;
+            let timeout = Duration::from_secs(5);
+            assert_eq!(events_recv.recv_timeout(timeout), Ok("running"));
+            assert_eq!(events_recv.recv_timeout(timeout), Ok("kept"));
+            assert!(events_recv
+                .recv_timeout(Duration::from_millis(200))
+                .is_err());
 
             processor.stop()?;
 
@@ -17,15 +17,20 @@ use std::collections::{HashMap, VecDeque};
 use std::fmt::{self, Debug};
 use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use common::prelude::*;
+use common::structs::JobFailure;
 
 
 pub struct Script<I: Send + Sync + Debug + Clone> {
     id: usize,
     name: String,
     can_be_parallel: bool,
+    group: Option<String>,
+    schedule: Option<(Duration, I)>,
     func: Arc<Mutex<Box<Fn(I) -> Result<()> + Send>>>,
+    queue_depths: Mutex<Vec<usize>>,
 }
 
 impl<I: Send + Sync + Debug + Clone> ScriptTrait for Script<I> {
@@ -35,9 +40,21 @@ impl<I: Send + Sync + Debug + Clone> ScriptTrait for Script<I> {
         self.id
     }
 
+    fn name(&self) -> &str {
+        &self.name
+    }
+
     fn can_be_parallel(&self) -> bool {
         self.can_be_parallel
     }
+
+    fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    fn schedule(&self) -> Option<Duration> {
+        self.schedule.as_ref().map(|&(interval, _)| interval)
+    }
 }
 
 impl<I: Send + Sync + Debug + Clone> Debug for Script<I> {
@@ -57,13 +74,44 @@ impl<I: Send + Sync + Debug + Clone> Debug for Script<I> {
 pub struct Job<I: Send + Sync + Debug + Clone> {
     script: Arc<Script<I>>,
     args: I,
+    provider: Option<String>,
+    parallel_key: Option<String>,
+    queue_depth: usize,
 }
 
+impl<I: Send + Sync + Debug + Clone> Job<I> {
+    pub fn with_provider(mut self, provider: &str) -> Self {
+        self.provider = Some(provider.to_string());
+        self
+    }
+
+    pub fn with_parallel_key(mut self, key: &str) -> Self {
+        self.parallel_key = Some(key.to_string());
+        self
+    }
+}
+
+impl JobOutputTrait for () {
+    fn failure(&self) -> Option<JobFailure> {
+        None
+    }
+
+    fn cpu_time(&self) -> Option<Duration> {
+        None
+    }
+
+    fn leaked_dirs(&self) -> usize {
+        0
+    }
+}
+
+
 impl<I: Send + Sync + Debug + Clone> JobTrait<Script<I>> for Job<I> {
     type Context = ();
     type Output = ();
 
     fn execute(&self, _: &()) -> Result<()> {
+        self.script.queue_depths.lock().unwrap().push(self.queue_depth);
         (self.script.func.lock().unwrap())(self.args.clone())
     }
 
@@ -74,6 +122,22 @@ impl<I: Send + Sync + Debug + Clone> JobTrait<Script<I>> for Job<I> {
     fn script_name(&self) -> &str {
         &self.script.name
     }
+
+    fn script_group(&self) -> Option<&str> {
+        self.script.group()
+    }
+
+    fn parallel_key(&self) -> Option<&str> {
+        self.parallel_key.as_ref().map(|key| key.as_str())
+    }
+
+    fn provider_name(&self) -> Option<&str> {
+        self.provider.as_ref().map(|provider| provider.as_str())
+    }
+
+    fn set_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth;
+    }
 }
 
 
@@ -81,6 +145,8 @@ pub struct Repository<I: Send + Sync + Debug + Clone> {
     last_id: AtomicUsize,
     scripts: RwLock<HashMap<String, Arc<Script<I>>>>,
     ids: RwLock<Vec<usize>>,
+    after_output: Mutex<Vec<Job<I>>>,
+    after_every_output: Mutex<Vec<Job<I>>>,
 }
 
 impl<I: Send + Sync + Debug + Clone> Repository<I> {
@@ -89,14 +155,58 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
             last_id: AtomicUsize::new(0),
             ids: RwLock::new(Vec::new()),
             scripts: RwLock::new(HashMap::new()),
+            after_output: Mutex::new(Vec::new()),
+            after_every_output: Mutex::new(Vec::new()),
         }
     }
 
+    /// Schedule the jobs as status jobs after the next job completes.
+    pub fn after_next_output(&self, jobs: Vec<Job<I>>) {
+        *self.after_output.lock().unwrap() = jobs;
+    }
+
+    /// Schedule the jobs as status jobs after every job completes.
+    pub fn after_every_output(&self, jobs: Vec<Job<I>>) {
+        *self.after_every_output.lock().unwrap() = jobs;
+    }
+
     pub fn add_script<F: Fn(I) -> Result<()> + 'static + Send>(
         &self,
         name: &str,
         parallel: bool,
         func: F,
+    ) {
+        self.insert_script(name, parallel, None, None, func);
+    }
+
+    /// Add a script executed every `interval` with the `args` argument.
+    pub fn add_scheduled_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self,
+        name: &str,
+        interval: Duration,
+        args: I,
+        func: F,
+    ) {
+        self.insert_script(name, true, None, Some((interval, args)), func);
+    }
+
+    pub fn add_grouped_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self,
+        name: &str,
+        parallel: bool,
+        group: Option<&str>,
+        func: F,
+    ) {
+        self.insert_script(name, parallel, group, None, func);
+    }
+
+    fn insert_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self,
+        name: &str,
+        parallel: bool,
+        group: Option<&str>,
+        schedule: Option<(Duration, I)>,
+        func: F,
     ) {
         self.ids
             .write()
@@ -108,7 +218,10 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
                 id: self.last_id.fetch_add(1, Ordering::SeqCst),
                 name: name.to_string(),
                 can_be_parallel: parallel,
+                group: group.map(|group| group.to_string()),
+                schedule,
                 func: Arc::new(Mutex::new(Box::new(func))),
+                queue_depths: Mutex::new(Vec::new()),
             }),
         );
     }
@@ -119,7 +232,24 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
             .unwrap()
             .get(name)
             .cloned()
-            .map(|script| Job { script, args })
+            .map(|script| Job {
+                script,
+                args,
+                provider: None,
+                parallel_key: None,
+                queue_depth: 0,
+            })
+    }
+
+    /// Get the queue depths of the executed jobs of a script, in the order
+    /// they were executed.
+    pub fn queue_depths_of(&self, name: &str) -> Vec<usize> {
+        self.scripts
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|script| script.queue_depths.lock().unwrap().clone())
+            .unwrap_or_default()
     }
 
     pub fn script_id_of(&self, name: &str) -> Option<usize> {
@@ -130,6 +260,12 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
             .map(|script| script.id())
     }
 
+    pub fn remove_script(&self, name: &str) {
+        if let Some(script) = self.scripts.write().unwrap().remove(name) {
+            self.ids.write().unwrap().retain(|id| *id != script.id);
+        }
+    }
+
     pub fn recreate_scripts(&self) {
         let mut scripts: Vec<_> =
             self.scripts.read().unwrap().values().cloned().collect();
@@ -138,7 +274,12 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
         self.scripts.write().unwrap().clear();
 
         for script in scripts.drain(..) {
-            self.add_script(&script.name, script.can_be_parallel, |_| Ok(()));
+            self.add_grouped_script(
+                &script.name,
+                script.can_be_parallel,
+                script.group(),
+                |_| Ok(()),
+            );
         }
     }
 }
@@ -160,7 +301,28 @@ impl<I: Send + Sync + Debug + Clone> ScriptsRepositoryTrait for Repository<I> {
     }
 
     fn jobs_after_output(&self, _: ()) -> Option<Self::JobsIter> {
-        None
+        let mut jobs: VecDeque<_> =
+            self.after_output.lock().unwrap().drain(..).collect();
+        jobs.extend(self.after_every_output.lock().unwrap().iter().cloned());
+
+        if jobs.is_empty() {
+            None
+        } else {
+            Some(SimpleIter::new(jobs))
+        }
+    }
+
+    fn scheduled_job(
+        &self, script: &Arc<Script<I>>,
+    ) -> Option<(Job<I>, isize)> {
+        let &(_, ref args) = script.schedule.as_ref()?;
+        Some((Job {
+            script: script.clone(),
+            args: args.clone(),
+            provider: None,
+            parallel_key: None,
+            queue_depth: 0,
+        }, 0))
     }
 }
 
@@ -190,3 +352,17 @@ pub fn test_wrapper<F: Fn() -> Result<()>>(func: F) {
         panic!("{}", error);
     }
 }
+
+
+pub fn timeout_until_true<F: Fn() -> bool>(func: F, error: &'static str) {
+    let start = Instant::now();
+    loop {
+        if start.elapsed().as_secs() > 10 {
+            panic!(error);
+        }
+
+        if func() {
+            return;
+        }
+    }
+}
@@ -240,7 +240,6 @@ mod tests {
     use std::sync::{Arc, Mutex};
     use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use std::sync::mpsc;
-    use std::time::Instant;
 
     use common::state::State;
     use common::serial::Serial;
@@ -269,20 +268,6 @@ mod tests {
     }
 
 
-    fn timeout_until_true<F: Fn() -> bool>(func: F, error: &'static str) {
-        let start = Instant::now();
-        loop {
-            if start.elapsed().as_secs() > 10 {
-                panic!(error);
-            }
-
-            if func() {
-                return;
-            }
-        }
-    }
-
-
     #[test]
     fn test_thread_executes_a_job() {
         test_wrapper(|| {
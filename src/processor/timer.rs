@@ -0,0 +1,314 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ansi_term::Colour;
+
+use common::prelude::*;
+
+use super::api::ProcessorApi;
+use super::types::{Job, ScriptId};
+
+
+/// The maximum time the timer waits before checking the scripts again, so
+/// scripts added by a reload are picked up quickly.
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+
+/// Keep track of the scripts executed periodically, returning their jobs
+/// when they're due.
+pub struct Timer<S: ScriptsRepositoryTrait> {
+    hooks: Arc<S>,
+    last_runs: HashMap<ScriptId<S>, (Instant, Duration)>,
+}
+
+impl<S: ScriptsRepositoryTrait> Timer<S> {
+    pub fn new(hooks: Arc<S>) -> Self {
+        Timer {
+            hooks,
+            last_runs: HashMap::new(),
+        }
+    }
+
+    /// Get the jobs which are due at `now`, along with their priority.
+    pub fn tick(&mut self, now: Instant) -> Vec<(Job<S>, isize)> {
+        let mut jobs = Vec::new();
+        let mut last_runs = HashMap::new();
+
+        for script in self.hooks.iter() {
+            let interval = if let Some(interval) = script.schedule() {
+                interval
+            } else {
+                continue;
+            };
+
+            // Scripts are executed for the first time one interval after
+            // they're found, instead of as soon as Fisher starts
+            let mut last_run = self.last_runs.get(&script.id())
+                .map(|&(last_run, _)| last_run)
+                .unwrap_or(now);
+            if now.duration_since(last_run) >= interval {
+                if let Some(job) = self.hooks.scheduled_job(&script) {
+                    jobs.push(job);
+                }
+                last_run = now;
+            }
+
+            last_runs.insert(script.id(), (last_run, interval));
+        }
+
+        // Scripts removed or not scheduled anymore are forgotten
+        self.last_runs = last_runs;
+
+        jobs
+    }
+
+    /// Get when the next job will be due, if any script is scheduled.
+    pub fn next_run(&self) -> Option<Instant> {
+        self.last_runs.values()
+            .map(|&(last_run, interval)| last_run + interval)
+            .min()
+    }
+}
+
+
+/// Check if the processor accepts new jobs, with the same limits applied to
+/// the jobs queued by the webhooks.
+fn can_queue<S: ScriptsRepositoryTrait>(
+    processor: &ProcessorApi<S>, max_queued_jobs: Option<usize>,
+) -> Result<bool> {
+    if !processor.accepting_jobs()? {
+        return Ok(false);
+    }
+
+    if let Some(max) = max_queued_jobs {
+        Ok(processor.health_details()?.queued_jobs < max)
+    } else {
+        Ok(true)
+    }
+}
+
+
+/// Messages sent to the thread queueing the jobs of the scheduled scripts.
+pub enum TimerInput {
+    /// Skip the scheduled runs while the queue has at least this many jobs,
+    /// or remove the limit.
+    SetMaxQueuedJobs(Option<usize>),
+}
+
+
+/// Start the thread queueing the jobs of the scheduled scripts into the
+/// processor. The thread stops when the returned sender is dropped.
+pub fn start<S: ScriptsRepositoryTrait + 'static>(
+    hooks: Arc<S>, processor: ProcessorApi<S>,
+) -> mpsc::Sender<TimerInput> {
+    let (input_send, input_recv) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut timer = Timer::new(hooks);
+        let mut max_queued_jobs = None;
+
+        loop {
+            for (job, priority) in timer.tick(Instant::now()) {
+                let name = job.script_name().to_string();
+
+                // The scheduled runs are rejected like the webhooks when the
+                // runtime budget is used up or the queue is full
+                match can_queue(&processor, max_queued_jobs) {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        println!(
+                            "{} skipped the scheduled run of {}: the \
+                             processor isn't accepting new jobs",
+                            Colour::Yellow.bold().paint("Warning:"),
+                            name,
+                        );
+                        continue;
+                    },
+                    Err(..) => return,
+                }
+
+                if let Err(err) = processor.queue(job, priority) {
+                    if let ErrorKind::SchedulerBusy(..) = *err.kind() {
+                        // The job will be queued again at the next interval
+                        println!(
+                            "{} skipped the scheduled run of {}: {}",
+                            Colour::Yellow.bold().paint("Warning:"),
+                            name,
+                            err,
+                        );
+                        continue;
+                    }
+
+                    return;
+                }
+            }
+
+            let wait = timer.next_run()
+                .map(|next| next.saturating_duration_since(Instant::now()))
+                .map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT));
+            match input_recv.recv_timeout(wait) {
+                Ok(TimerInput::SetMaxQueuedJobs(max)) => {
+                    max_queued_jobs = max;
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    });
+
+    input_send
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use common::prelude::*;
+    use common::state::State;
+
+    use processor::api::Processor;
+    use processor::test_utils::*;
+
+    use super::Timer;
+
+
+    #[test]
+    fn test_timer_tick() {
+        let repo = Arc::new(Repository::<&'static str>::new());
+        repo.add_scheduled_script(
+            "scheduled", Duration::from_secs(10), "tick", |_| Ok(()),
+        );
+        repo.add_script("normal", true, |_| Ok(()));
+
+        let start = Instant::now();
+        let mut timer = Timer::new(repo.clone());
+        let at = |secs| start + Duration::from_secs(secs);
+        let names = |jobs: Vec<(Job<&'static str>, isize)>| {
+            jobs.iter()
+                .map(|&(ref job, _)| job.script_name().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // The script isn't executed as soon as it's found
+        assert!(timer.tick(at(0)).is_empty());
+        assert_eq!(timer.next_run(), Some(at(10)));
+
+        // The script is executed once every interval
+        assert!(timer.tick(at(5)).is_empty());
+        assert_eq!(names(timer.tick(at(10))), vec!["scheduled"]);
+        assert!(timer.tick(at(15)).is_empty());
+        assert_eq!(timer.next_run(), Some(at(20)));
+        assert_eq!(names(timer.tick(at(21))), vec!["scheduled"]);
+        assert_eq!(timer.next_run(), Some(at(31)));
+
+        // Removed scripts are forgotten
+        repo.remove_script("scheduled");
+        assert!(timer.tick(at(40)).is_empty());
+        assert_eq!(timer.next_run(), None);
+    }
+
+
+    #[test]
+    fn test_scheduled_scripts_are_queued() {
+        test_wrapper(|| {
+            let (ticks_send, ticks_recv) = mpsc::channel();
+            let ticks_send = Mutex::new(ticks_send);
+
+            let repo = Repository::<&'static str>::new();
+            repo.add_scheduled_script(
+                "scheduled", Duration::from_millis(50), "tick", move |arg| {
+                    ticks_send.lock()?.send(arg)?;
+                    Ok(())
+                },
+            );
+
+            let processor = Processor::new(
+                1,
+                Arc::new(repo),
+                (),
+                Arc::new(State::new()),
+            )?;
+
+            // The job is queued again after every interval
+            for _ in 0..3 {
+                let timeout = Duration::from_secs(5);
+                assert_eq!(ticks_recv.recv_timeout(timeout).unwrap(), "tick");
+            }
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_scheduled_scripts_admission() {
+        test_wrapper(|| {
+            let (ticks_send, ticks_recv) = mpsc::channel();
+            let ticks_send = Mutex::new(ticks_send);
+
+            let repo = Repository::<&'static str>::new();
+            repo.add_scheduled_script(
+                "scheduled", Duration::from_millis(50), "tick", move |arg| {
+                    ticks_send.lock()?.send(arg)?;
+                    Ok(())
+                },
+            );
+
+            let processor = Processor::new(
+                1,
+                Arc::new(repo),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+            let wait = Duration::from_millis(300);
+            let timeout = Duration::from_secs(5);
+
+            // Ignore the runs queued before the limits are applied
+            let skip_queued = || {
+                thread::sleep(wait);
+                while ticks_recv.try_recv().is_ok() {}
+            };
+
+            // The runs are skipped while the queue is full
+            processor.set_max_queued_jobs(Some(0))?;
+            skip_queued();
+            assert!(ticks_recv.recv_timeout(wait).is_err());
+            processor.set_max_queued_jobs(None)?;
+            assert_eq!(ticks_recv.recv_timeout(timeout).unwrap(), "tick");
+
+            // The runs are skipped while the runtime budget is used up
+            api.set_runtime_budget(Some(Duration::from_millis(0)))?;
+            skip_queued();
+            assert!(ticks_recv.recv_timeout(wait).is_err());
+            api.set_runtime_budget(None)?;
+            assert_eq!(ticks_recv.recv_timeout(timeout).unwrap(), "tick");
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+}
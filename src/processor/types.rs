@@ -13,6 +13,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use common::prelude::*;
 
 
@@ -28,6 +31,61 @@ pub type JobOutput<S> =
         <S as ScriptsRepositoryTrait>::Script,
     >>::Output;
 
+/// Where the output of a job is sent once it's executed, if anything is
+/// waiting for it.
+pub type JobReply<S> = mpsc::Sender<Option<JobOutput<S>>>;
+
 pub type ScriptId<S> = <
     <S as ScriptsRepositoryTrait>::Script as ScriptTrait
 >::Id;
+
+/// The key identifying the jobs which can't run at the same time: the ID of
+/// the script, and the parallel key of the job.
+pub type ExclusiveKey<S> = (ScriptId<S>, Option<String>);
+
+
+/// Keep track of the jobs sent to the scheduler but not received by it yet,
+/// so new jobs can be rejected when it can't keep up with them.
+#[derive(Debug)]
+pub struct PendingJobs {
+    count: AtomicUsize,
+    capacity: AtomicUsize,
+}
+
+impl PendingJobs {
+    pub fn new() -> Self {
+        PendingJobs {
+            count: AtomicUsize::new(0),
+            capacity: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    /// Set how many jobs can be pending at the same time, or remove the
+    /// limit.
+    pub fn set_capacity(&self, capacity: Option<usize>) {
+        self.capacity.store(
+            capacity.unwrap_or(usize::MAX), Ordering::SeqCst,
+        );
+    }
+
+    /// Get how many jobs can be pending at the same time.
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::SeqCst)
+    }
+
+    /// Reserve a spot for a new job, returning false if there is none left.
+    pub fn reserve(&self) -> bool {
+        let previous = self.count.fetch_add(1, Ordering::SeqCst);
+        if previous >= self.capacity() {
+            self.count.fetch_sub(1, Ordering::SeqCst);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Release the spot of a job, after the scheduler received it.
+    pub fn release(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde_json;
+
+use providers::prelude::*;
+use providers::parse_json_body;
+use web::WebRequest;
+use utils;
+
+
+#[derive(Debug, Deserialize)]
+pub struct CiNotifyProvider {
+    secret: Option<String>,
+
+    status_field: Option<String>,
+    branch_field: Option<String>,
+    #[serde(default)]
+    empty_body_as_object: bool,
+}
+
+impl CiNotifyProvider {
+    fn status_field(&self) -> &str {
+        match self.status_field {
+            Some(ref field) => field,
+            None => "/status",
+        }
+    }
+
+    fn branch_field(&self) -> &str {
+        match self.branch_field {
+            Some(ref field) => field,
+            None => "/branch",
+        }
+    }
+
+    fn check_secret(&self, req: &WebRequest) -> bool {
+        let correct_secret = if let Some(ref secret) = self.secret {
+            secret
+        } else {
+            return true;
+        };
+
+        let secret = if let Some(found) = req.params.get("secret") {
+            // Secret in the request parameters
+            found
+        } else if let Some(found) = req.header("X-Fisher-Secret") {
+            // Secret in the HTTP headers
+            found
+        } else {
+            return false;
+        };
+
+        utils::constant_time_eq(secret.as_bytes(), correct_secret.as_bytes())
+    }
+}
+
+impl ProviderTrait for CiNotifyProvider {
+    fn new(config: &str) -> Result<Self> {
+        let inst: CiNotifyProvider = serde_json::from_str(config)?;
+
+        // JSON pointers must start with a slash
+        for field in &[inst.status_field(), inst.branch_field()] {
+            if !field.starts_with('/') {
+                return Err(ErrorKind::ProviderCiNotifyInvalidField(
+                    field.to_string(),
+                ).into());
+            }
+        }
+
+        Ok(inst)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        if !self.check_secret(req) {
+            return RequestType::Invalid;
+        }
+
+        // The body must be a JSON object
+        match parse_json_body(&req.body, self.empty_body_as_object) {
+            Ok(ref body) if body.is_object() => RequestType::ExecuteHook,
+            _ => RequestType::Invalid,
+        }
+    }
+
+    fn build_env(&self, r: &Request, b: &mut EnvBuilder) -> Result<()> {
+        let req;
+        if let Request::Web(ref inner) = *r {
+            req = inner;
+        } else {
+            return Ok(());
+        }
+
+        let body = parse_json_body(&req.body, self.empty_body_as_object)?;
+
+        // Fields missing from the body are not exported
+        let fields = [
+            ("STATUS", self.status_field()),
+            ("BRANCH", self.branch_field()),
+        ];
+        for &(name, pointer) in &fields {
+            let value = match body.pointer(pointer) {
+                Some(value) if value.is_string() => {
+                    value.as_str().unwrap().to_string()
+                }
+                Some(value) if value.is_number() || value.is_boolean() => {
+                    value.to_string()
+                }
+                _ => continue,
+            };
+
+            b.add_env(name, value);
+        }
+
+        Ok(())
+    }
+
+    fn secret_params(&self) -> Vec<String> {
+        if self.secret.is_some() {
+            vec!["secret".into()]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use utils::testing::*;
+    use requests::{Request, RequestType};
+    use web::WebRequest;
+    use providers::ProviderTrait;
+    use scripts::EnvBuilder;
+
+    use super::CiNotifyProvider;
+
+
+    fn base_request() -> WebRequest {
+        let mut req = dummy_web_request();
+        req.body = r#"{"status": "passed", "branch": "master"}"#.into();
+        req
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{}"#,
+            r#"{"secret": "abcde"}"#,
+            r#"{"status_field": "/payload/status"}"#,
+            r#"{"secret": "abcde", "branch_field": "/payload/branch"}"#,
+            r#"{"empty_body_as_object": true}"#,
+        ] {
+            assert!(CiNotifyProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{"secret": 12345}"#,
+            r#"{"secret": true}"#,
+            r#"{"status_field": 12345}"#,
+            r#"{"status_field": "status"}"#,
+            r#"{"branch_field": "payload/branch"}"#,
+            r#"{"empty_body_as_object": "yes"}"#,
+        ] {
+            assert!(CiNotifyProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_validate_body() {
+        let provider = CiNotifyProvider::new("{}").unwrap();
+
+        assert_eq!(
+            provider.validate(&base_request().into()),
+            RequestType::ExecuteHook
+        );
+
+        for body in &["", "not json", "[1, 2, 3]", r#""status""#] {
+            let mut req = base_request();
+            req.body = body.to_string();
+            assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+        }
+    }
+
+
+    #[test]
+    fn test_empty_body_as_object() {
+        let provider = CiNotifyProvider::new(
+            r#"{"empty_body_as_object": true}"#,
+        ).unwrap();
+
+        let mut req = base_request();
+        req.body = String::new();
+        let req: Request = req.into();
+
+        // The empty body is valid, but no field is extracted from it
+        assert_eq!(provider.validate(&req), RequestType::ExecuteHook);
+
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&req, &mut b).unwrap();
+        assert_eq!(b.dummy_data().env, HashMap::new());
+
+        // Invalid bodies are still rejected
+        let mut req = base_request();
+        req.body = "not json".into();
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_validate_secret() {
+        let provider = CiNotifyProvider::new(r#"{"secret": "abcde"}"#).unwrap();
+
+        // Without the secret
+        assert_eq!(
+            provider.validate(&base_request().into()),
+            RequestType::Invalid
+        );
+
+        // With a wrong secret
+        let mut req = base_request();
+        req.params.insert("secret".into(), "12345".into());
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        let mut req = base_request();
+        req.headers.insert("X-Fisher-Secret".into(), "12345".into());
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        // With the right secret, either in the params or in the headers
+        let mut req = base_request();
+        req.params.insert("secret".into(), "abcde".into());
+        assert_eq!(provider.validate(&req.into()), RequestType::ExecuteHook);
+
+        let mut req = base_request();
+        req.headers.insert("X-Fisher-Secret".into(), "abcde".into());
+        assert_eq!(provider.validate(&req.into()), RequestType::ExecuteHook);
+    }
+
+
+    #[test]
+    fn test_build_env() {
+        let provider = CiNotifyProvider::new("{}").unwrap();
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&base_request().into(), &mut b).unwrap();
+
+        assert_eq!(b.dummy_data().env, hashmap! {
+            "STATUS".into() => "passed".into(),
+            "BRANCH".into() => "master".into(),
+        });
+        assert_eq!(b.dummy_data().files, HashMap::new());
+
+        // Missing fields are not exported
+        let mut req = base_request();
+        req.body = r#"{"status": 0}"#.into();
+
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&req.into(), &mut b).unwrap();
+        assert_eq!(b.dummy_data().env, hashmap! {
+            "STATUS".into() => "0".into(),
+        });
+    }
+
+
+    #[test]
+    fn test_build_env_custom_fields() {
+        let provider = CiNotifyProvider::new(concat!(
+            r#"{"status_field": "/payload/status","#,
+            r#" "branch_field": "/payload/branch"}"#,
+        )).unwrap();
+
+        let mut req = dummy_web_request();
+        req.body = r#"{"payload": {"status": "failed", "branch": "dev"}}"#
+            .into();
+
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&req.into(), &mut b).unwrap();
+        assert_eq!(b.dummy_data().env, hashmap! {
+            "STATUS".into() => "failed".into(),
+            "BRANCH".into() => "dev".into(),
+        });
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+
+use ansi_term::Colour;
+use serde_json;
+
+use providers::prelude::*;
+use web::WebRequest;
+
+
+/// The names of the variables set by Fisher and by the providers, which can't
+/// be overridden by the templates.
+static RESERVED_NAMES: &[&str] = &[
+    "HOME", "EVENT", "DELIVERY_ID", "INSTALLATION_ID", "PUSH_REF",
+    "PUSH_HEAD", "SUB", "STATUS", "BRANCH", "SCRIPT_NAME", "SUMMARY",
+    "SUCCESS", "EXIT_CODE", "SIGNAL", "CPU_MS", "MAXRSS", "TRIGGERED_BY",
+    "STDOUT", "STDERR",
+];
+
+
+#[derive(Debug, Default, Deserialize)]
+struct TemplatesConfig {
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+
+/// Extra environment variables set in the configuration of a provider, with
+/// `{{...}}` tokens in their values replaced by fields of the request.
+#[derive(Debug, Default)]
+pub struct EnvTemplates {
+    templates: BTreeMap<String, String>,
+}
+
+impl EnvTemplates {
+    /// Load the templates from the `env` key of a provider configuration.
+    /// Configurations which aren't JSON objects don't have any template.
+    pub fn new(config: &str) -> Result<Self> {
+        let value: serde_json::Value = match serde_json::from_str(config) {
+            Ok(value) => value,
+            Err(_) => return Ok(EnvTemplates::default()),
+        };
+        if !value.is_object() {
+            return Ok(EnvTemplates::default());
+        }
+
+        let config: TemplatesConfig = serde_json::from_value(value)?;
+
+        // The names must be valid, and they can't override the variables
+        // set by Fisher or by the providers
+        for name in config.env.keys() {
+            let valid = !name.is_empty() && name.chars().all(|c| {
+                c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'
+            });
+            if !valid
+                || name.starts_with("FISHER")
+                || RESERVED_NAMES.contains(&name.as_str())
+            {
+                return Err(
+                    ErrorKind::ProviderInvalidEnvName(name.clone()).into(),
+                );
+            }
+        }
+
+        Ok(EnvTemplates {
+            templates: config.env,
+        })
+    }
+
+    pub fn build_env(&self, req: &Request, b: &mut EnvBuilder) -> Result<()> {
+        if self.templates.is_empty() {
+            return Ok(());
+        }
+
+        let req = if let Request::Web(ref inner) = *req {
+            inner
+        } else {
+            return Ok(());
+        };
+
+        // The body is parsed only once for all the templates
+        let body = serde_json::from_str(&req.body).ok();
+        for (name, template) in &self.templates {
+            b.add_env(name, expand(template, req, &body));
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Replace the `{{...}}` tokens in the template, expanding unknown ones to
+/// an empty string.
+fn expand(
+    template: &str, req: &WebRequest, body: &Option<serde_json::Value>,
+) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            // Unterminated tokens are kept as they are
+            None => break,
+        };
+
+        result.push_str(&rest[..start]);
+
+        let token = rest[start + 2..end].trim();
+        if let Some(value) = lookup(token, req, body) {
+            result.push_str(&value);
+        } else {
+            println!(
+                "{} unknown token in the environment template: {{{{{}}}}}",
+                Colour::Yellow.bold().paint("Warning:"),
+                token,
+            );
+        }
+
+        rest = &rest[end + 2..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+
+fn lookup(
+    token: &str, req: &WebRequest, body: &Option<serde_json::Value>,
+) -> Option<String> {
+    if let Some(name) = token.strip_prefix("headers.") {
+        return req.header(name).map(|value| value.to_string());
+    }
+
+    let path = token.strip_prefix("body.")?;
+    let mut current = body.as_ref()?;
+    for part in path.split('.') {
+        current = match *current {
+            serde_json::Value::Object(ref map) => map.get(part)?,
+            serde_json::Value::Array(ref list) => {
+                list.get(part.parse::<usize>().ok()?)?
+            }
+            _ => return None,
+        };
+    }
+
+    match *current {
+        serde_json::Value::String(ref string) => Some(string.clone()),
+        serde_json::Value::Null => None,
+        ref other => Some(other.to_string()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use scripts::EnvBuilder;
+
+    use super::EnvTemplates;
+
+
+    fn env_for(config: &str) -> ::std::collections::HashMap<String, String> {
+        let mut req = dummy_web_request();
+        req.body = r#"{"repository": {"name": "fisher", "stars": 42}, "#
+            .to_string() + r#""commits": [{"id": "abcdef"}]}"#;
+        req.headers.insert("X-Event".into(), "push".into());
+
+        let templates = EnvTemplates::new(config).unwrap();
+        let mut b = EnvBuilder::dummy();
+        templates.build_env(&req.into(), &mut b).unwrap();
+        b.dummy_data().env.clone()
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{}"#,
+            r#"{"secret": "abcde"}"#,
+            r#"{"env": {"REPO": "{{body.repository.name}}"}}"#,
+            "",
+            "SOMETHING",
+        ] {
+            assert!(EnvTemplates::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{"env": "REPO"}"#,
+            r#"{"env": {"REPO": 1}}"#,
+            r#"{"env": {"": "a"}}"#,
+            r#"{"env": {"repo": "a"}}"#,
+            r#"{"env": {"REPO=A": "a"}}"#,
+            r#"{"env": {"FISHER_REQUEST_BODY": "a"}}"#,
+            r#"{"env": {"EVENT": "a"}}"#,
+            r#"{"env": {"HOME": "a"}}"#,
+        ] {
+            assert!(EnvTemplates::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_body_field() {
+        assert_eq!(env_for(concat!(
+            r#"{"env": {"#,
+            r#""REPO": "repo-{{ body.repository.name }}", "#,
+            r#""STARS": "{{body.repository.stars}}", "#,
+            r#""COMMIT": "{{body.commits.0.id}}", "#,
+            r#""KIND": "{{headers.x-event}}"}}"#,
+        )), hashmap! {
+            "REPO".into() => "repo-fisher".into(),
+            "STARS".into() => "42".into(),
+            "COMMIT".into() => "abcdef".into(),
+            "KIND".into() => "push".into(),
+        });
+    }
+
+
+    #[test]
+    fn test_unknown_token() {
+        assert_eq!(env_for(concat!(
+            r#"{"env": {"#,
+            r#""MISSING": "a{{body.repository.owner}}b", "#,
+            r#""INVALID": "{{something}}", "#,
+            r#""HEADER": "{{headers.X-Missing}}", "#,
+            r#""UNTERMINATED": "{{body.repository.name"}}"#,
+        )), hashmap! {
+            "MISSING".into() => "ab".into(),
+            "INVALID".into() => "".into(),
+            "HEADER".into() => "".into(),
+            "UNTERMINATED".into() => "{{body.repository.name".into(),
+        });
+    }
+}
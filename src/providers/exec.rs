@@ -0,0 +1,286 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ansi_term::Colour;
+use serde_json;
+
+use providers::prelude::*;
+use web::WebRequest;
+
+
+#[derive(Debug, Deserialize)]
+pub struct ExecProvider {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    timeout: Option<u64>,
+}
+
+impl ExecProvider {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(1))
+    }
+
+    /// Run the validator with the request, returning whether it accepted it.
+    fn run(&self, req: &WebRequest) -> Result<bool> {
+        let mut command = Command::new(&self.command);
+        command.args(&self.args);
+
+        // The validator only gets the details of the request
+        command.env_clear();
+        if let Some(path) = env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        command.env("FISHER_REQUEST_METHOD", &req.method);
+        command.env("FISHER_REQUEST_PATH", &req.path);
+        command.env("FISHER_REQUEST_IP", req.source.to_string());
+        for (name, value) in &req.headers {
+            command.env(header_env_name(name), value);
+        }
+
+        command.stdin(Stdio::piped());
+        command.stdout(Stdio::null());
+        command.stderr(Stdio::null());
+        let mut child = command.spawn()?;
+
+        // The body is written from another thread, so the timeout is
+        // enforced even if the validator doesn't read it. The thread isn't
+        // joined: it stops as soon as the pipe is closed, which might never
+        // happen if the validator leaves a background process behind
+        let mut stdin = child.stdin.take().unwrap();
+        let body = req.body_bytes().to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&body);
+        });
+
+        // Wait for the validator, killing it if it doesn't exit before the
+        // timeout expires
+        let deadline = Instant::now() + self.timeout();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status.success());
+            }
+
+            if Instant::now() >= deadline {
+                // The validator might have exited in the meantime
+                let _ = child.kill();
+                child.wait()?;
+                return Ok(false);
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl ProviderTrait for ExecProvider {
+    fn new(config: &str) -> Result<Self> {
+        Ok(serde_json::from_str(config)?)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        match self.run(req) {
+            Ok(true) => RequestType::ExecuteHook,
+            Ok(false) => RequestType::Invalid,
+            Err(err) => {
+                println!(
+                    "{} failed to run the validator {}: {}",
+                    Colour::Red.bold().paint("Error:"),
+                    self.command,
+                    err,
+                );
+                RequestType::Invalid
+            }
+        }
+    }
+
+    fn blocking_validation(&self) -> bool {
+        true
+    }
+
+    fn build_env(&self, _: &Request, _: &mut EnvBuilder) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Get the name of the environment variable containing an header, for
+/// example `FISHER_HEADER_X_REQUEST_ID` for `X-Request-Id`.
+fn header_env_name(name: &str) -> String {
+    let name = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() {
+            c.to_ascii_uppercase()
+        } else {
+            '_'
+        })
+        .collect::<String>();
+    format!("FISHER_HEADER_{}", name)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    use tempdir::TempDir;
+
+    use utils::testing::*;
+    use requests::RequestType;
+    use providers::{ProviderTrait, StatusEvent};
+
+    use super::{header_env_name, ExecProvider};
+
+
+    fn validator(dir: &Path, name: &str, content: &str) -> ExecProvider {
+        let path = dir.join(name);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(0o755)
+            .open(&path)
+            .unwrap();
+        writeln!(file, "#!/bin/bash\n{}", content).unwrap();
+
+        ExecProvider::new(&json!({
+            "command": path.to_str().unwrap(),
+            "timeout": 1,
+        }).to_string()).unwrap()
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"command": "validator"}"#,
+            r#"{"command": "validator", "args": ["-v"], "timeout": 10}"#,
+        ] {
+            assert!(ExecProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"command": 12345}"#,
+            r#"{"command": "validator", "args": "-v"}"#,
+            r#"{"command": "validator", "timeout": -1}"#,
+        ] {
+            assert!(ExecProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_validate() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+
+        let accept = validator(dir.path(), "accept.sh", concat!(
+            r#"[[ "${FISHER_HEADER_X_TOKEN}" == "secret" ]] || exit 1"#, "\n",
+            r#"[[ "$(cat)" == "hello" ]] || exit 1"#,
+        ));
+        let reject = validator(dir.path(), "reject.sh", "exit 1");
+
+        let mut req = dummy_web_request();
+        req.headers.insert("X-Token".into(), "secret".into());
+        req.body = "hello".into();
+
+        assert_eq!(
+            accept.validate(&req.clone().into()),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(reject.validate(&req.clone().into()), RequestType::Invalid);
+
+        // The validator receives the real details of the request
+        req.headers.insert("X-Token".into(), "wrong".into());
+        assert_eq!(accept.validate(&req.into()), RequestType::Invalid);
+
+        // Non-web requests are always rejected
+        assert_eq!(
+            accept.validate(
+                &StatusEvent::JobCompleted(dummy_job_output()).into(),
+            ),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_validate_timeout() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let slow = validator(dir.path(), "slow.sh", "exec sleep 10");
+
+        // The validator is killed once the timeout expires
+        let started = Instant::now();
+        let req = dummy_web_request().into();
+        assert_eq!(slow.validate(&req), RequestType::Invalid);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+
+    #[test]
+    fn test_validate_background_process() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let detach = validator(dir.path(), "detach.sh", "sleep 10 &\nexit 0");
+
+        // The body doesn't fit in the pipe, and the background process keeps
+        // it open without reading it
+        let mut req = dummy_web_request();
+        req.body = "a".repeat(1024 * 1024);
+
+        let started = Instant::now();
+        assert_eq!(
+            detach.validate(&req.into()),
+            RequestType::ExecuteHook
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+
+    #[test]
+    fn test_validate_missing_command() {
+        let provider = ExecProvider::new(
+            r#"{"command": "/this/does/not/exist"}"#,
+        ).unwrap();
+
+        let req = dummy_web_request().into();
+        assert_eq!(provider.validate(&req), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_header_env_name() {
+        assert_eq!(
+            header_env_name("X-Request-Id"), "FISHER_HEADER_X_REQUEST_ID",
+        );
+        assert_eq!(
+            header_env_name("content-type"), "FISHER_HEADER_CONTENT_TYPE",
+        );
+    }
+}
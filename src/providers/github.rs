@@ -18,6 +18,7 @@ use hmac::{Hmac, Mac};
 use sha1;
 
 use providers::prelude::*;
+use providers::parse_json_body;
 use utils;
 use common::prelude::*;
 
@@ -53,10 +54,37 @@ struct PushCommit<'src> {
 }
 
 
+/// The installation of the GitHub App the webhook was sent for, if any.
+#[derive(Deserialize)]
+struct InstallationEvent {
+    installation: Option<Installation>,
+}
+
+#[derive(Deserialize)]
+struct Installation {
+    id: u64,
+}
+
+
+/// The user who triggered the event, included in most of them.
+#[derive(Deserialize)]
+struct SenderEvent {
+    sender: Option<Sender>,
+}
+
+#[derive(Deserialize)]
+struct Sender {
+    login: String,
+}
+
+
 #[derive(Debug, Deserialize)]
 pub struct GitHubProvider {
     secret: Option<String>,
     events: Option<Vec<String>>,
+    installation_target_types: Option<Vec<String>>,
+    #[serde(default)]
+    empty_body_as_object: bool,
 }
 
 impl ProviderTrait for GitHubProvider {
@@ -97,7 +125,7 @@ impl ProviderTrait for GitHubProvider {
         if let Some(ref secret) = self.secret {
             // Check if the signature is valid
             let signature = &req.headers["X-Hub-Signature"];
-            if !verify_signature(secret, &req.body, signature) {
+            if !verify_signature(secret, req.body_bytes(), signature) {
                 return RequestType::Invalid;
             }
         }
@@ -115,8 +143,17 @@ impl ProviderTrait for GitHubProvider {
             }
         }
 
+        // Check if the webhook was sent for an allowed installation target
+        if let Some(ref types) = self.installation_target_types {
+            let target = req.header("X-GitHub-Hook-Installation-Target-Type");
+            if !target.is_some_and(|target| types.iter().any(|t| t == target))
+            {
+                return RequestType::Invalid;
+            }
+        }
+
         // Check if the JSON in the body is valid
-        if serde_json::from_str::<serde_json::Value>(&req.body).is_err() {
+        if parse_json_body(&req.body, self.empty_body_as_object).is_err() {
             return RequestType::Invalid;
         }
 
@@ -129,6 +166,11 @@ impl ProviderTrait for GitHubProvider {
         RequestType::ExecuteHook
     }
 
+    fn allowed_methods(&self) -> Option<&'static [&'static str]> {
+        // GitHub always sends webhooks with POST requests
+        Some(&["POST"])
+    }
+
     fn build_env(&self, r: &Request, b: &mut EnvBuilder) -> Result<()> {
         let req;
         if let Request::Web(ref inner) = *r {
@@ -140,10 +182,19 @@ impl ProviderTrait for GitHubProvider {
         b.add_env("EVENT", &req.headers["X-GitHub-Event"]);
         b.add_env("DELIVERY_ID", &req.headers["X-GitHub-Delivery"]);
 
+        // Webhooks of GitHub Apps include the installation they're sent for
+        let installation = serde_json::from_str::<InstallationEvent>(&req.body)
+            .ok()
+            .and_then(|parsed| parsed.installation);
+        if let Some(installation) = installation {
+            b.add_env("INSTALLATION_ID", installation.id.to_string());
+        }
+
         // Add specific environment variables for the `push` event
         let event = &req.headers["X-GitHub-Event"];
         if self.events.as_ref().and_then(|e| Some(e.contains(event))).unwrap_or(false) {
-            if *event == "push" {
+            // Empty bodies don't carry any information about the push
+            if *event == "push" && !req.body.is_empty() {
                 let parsed: PushEvent = serde_json::from_str(&req.body)?;
                 b.add_env("PUSH_REF", parsed.git_ref);
                 b.add_env("PUSH_HEAD", parsed.head_commit.id);
@@ -152,10 +203,39 @@ impl ProviderTrait for GitHubProvider {
 
         Ok(())
     }
+
+    fn normalized_event(&self, r: &Request) -> Option<String> {
+        if let Request::Web(ref req) = *r {
+            // GitHub event names are already lowercase and snake_case
+            req.header("X-GitHub-Event").map(|event| event.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn triggered_by(&self, r: &Request) -> Option<String> {
+        if let Request::Web(ref req) = *r {
+            serde_json::from_str::<SenderEvent>(&req.body).ok()?
+                .sender
+                .map(|sender| sender.login)
+        } else {
+            None
+        }
+    }
+
+    fn delivery_key(&self, r: &Request) -> Option<String> {
+        if let Request::Web(ref req) = *r {
+            req.header("X-GitHub-Delivery").map(|id| id.to_string())
+        } else {
+            None
+        }
+    }
 }
 
 
-fn verify_signature(secret: &str, payload: &str, raw_signature: &str) -> bool {
+fn verify_signature(
+    secret: &str, payload: &[u8], raw_signature: &str,
+) -> bool {
     type HmacSha1 = Hmac<sha1::Sha1>;
 
     // The signature must have a =
@@ -188,7 +268,7 @@ fn verify_signature(secret: &str, payload: &str, raw_signature: &str) -> bool {
 
     // Verify the HMAC signature
     let mut mac = HmacSha1::new_varkey(secret.as_bytes()).unwrap();
-    mac.input(payload.as_bytes());
+    mac.input(payload);
     mac.verify(&signature).is_ok()
 }
 
@@ -212,6 +292,8 @@ mod tests {
             r#"{"secret": "abcde"}"#,
             r#"{"events": ["push", "fork"]}"#,
             r#"{"secret": "abcde", "events": ["push", "fork"]}"#,
+            r#"{"empty_body_as_object": true}"#,
+            r#"{"installation_target_types": ["repository"]}"#,
         ] {
             assert!(GitHubProvider::new(right).is_ok(), right.to_string());
         }
@@ -227,6 +309,8 @@ mod tests {
             r#"{"events": [12345]}"#,
             r#"{"events": [true]}"#,
             r#"{"events": ["invalid_event"]}"#,
+            r#"{"empty_body_as_object": "yes"}"#,
+            r#"{"installation_target_types": "repository"}"#,
         ] {
             assert!(GitHubProvider::new(wrong).is_err(), wrong.to_string());
         }
@@ -266,6 +350,61 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_installation_target_types() {
+        let mut req = dummy_push_event_request("push");
+        req.headers.insert("X-Hub-Signature".into(), "invalid".into());
+
+        // Without the option every installation target is accepted
+        let provider = GitHubProvider::new("{}").unwrap();
+        assert_eq!(
+            provider.validate(&req.clone().into()),
+            RequestType::ExecuteHook
+        );
+
+        // With the option webhooks without the header are rejected
+        let provider = GitHubProvider::new(
+            r#"{"installation_target_types": ["integration"]}"#
+        ).unwrap();
+        assert_eq!(
+            provider.validate(&req.clone().into()),
+            RequestType::Invalid
+        );
+
+        for &(target, expected) in &[
+            ("integration", RequestType::ExecuteHook),
+            ("repository", RequestType::Invalid),
+            ("Integration", RequestType::Invalid),
+        ] {
+            req.headers.insert(
+                "X-GitHub-Hook-Installation-Target-Type".into(),
+                target.into(),
+            );
+            assert_eq!(provider.validate(&req.clone().into()), expected);
+        }
+    }
+
+
+    #[test]
+    fn test_build_env_installation_id() {
+        let mut req = dummy_push_event_request("issues");
+        req.body = r#"{"installation": {"id": 42}}"#.into();
+
+        let provider = GitHubProvider::new("{}").unwrap();
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&req.into(), &mut b).unwrap();
+        assert_eq!(
+            b.dummy_data().env.get("INSTALLATION_ID"), Some(&"42".into())
+        );
+
+        // Webhooks not sent by GitHub Apps don't have an installation
+        let req = dummy_push_event_request("push");
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&req.into(), &mut b).unwrap();
+        assert_eq!(b.dummy_data().env.get("INSTALLATION_ID"), None);
+    }
+
+
     #[test]
     fn test_build_env() {
         let mut req = dummy_web_request();
@@ -300,6 +439,21 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_triggered_by() {
+        let provider = GitHubProvider::new("{}").unwrap();
+
+        let mut req = dummy_push_event_request("push");
+        assert_eq!(provider.triggered_by(&req.clone().into()), None);
+
+        req.body = r#"{"sender": {"login": "octocat"}}"#.into();
+        assert_eq!(
+            provider.triggered_by(&req.into()),
+            Some("octocat".to_string())
+        );
+    }
+
+
     #[test]
     fn test_build_env_event_push_wrong_event() {
         let req = dummy_push_event_request("ping");
@@ -345,6 +499,36 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_empty_body_as_object() {
+        let mut req = dummy_push_event_request("push");
+        req.headers.insert("X-Hub-Signature".into(), "invalid".into());
+        req.body = String::new();
+
+        // Without the option empty bodies are rejected
+        let provider = GitHubProvider::new(r#"{"events": ["push"]}"#).unwrap();
+        assert_eq!(
+            provider.validate(&req.clone().into()),
+            RequestType::Invalid
+        );
+
+        // With the option the body is treated as an empty object
+        let provider = GitHubProvider::new(
+            r#"{"events": ["push"], "empty_body_as_object": true}"#
+        ).unwrap();
+        assert_eq!(
+            provider.validate(&req.clone().into()),
+            RequestType::ExecuteHook
+        );
+
+        // Nothing is extracted from the body, without errors
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&req.into(), &mut b).unwrap();
+        assert_eq!(b.dummy_data().env.get("PUSH_REF"), None);
+        assert_eq!(b.dummy_data().env.get("PUSH_HEAD"), None);
+    }
+
+
     #[test]
     fn test_verify_signature() {
         // Check if the function allows invalid signatures
@@ -356,7 +540,7 @@ mod tests {
             "sha1=e75efc0f29bf50c23f99b30b86f7c78fdaf5f11d",
         ] {
             assert!(
-                !verify_signature("secret", "payload", signature),
+                !verify_signature("secret", b"payload", signature),
                 signature.to_string()
             );
         }
@@ -364,8 +548,30 @@ mod tests {
         // This is known to be right
         assert!(verify_signature(
             "secret",
-            "payload",
+            b"payload",
             "sha1=f75efc0f29bf50c23f99b30b86f7c78fdaf5f11d"
         ));
     }
+
+
+    #[test]
+    fn test_signature_of_non_utf8_bodies() {
+        let provider = GitHubProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        let mut req = dummy_push_event_request("ping");
+        req.headers.insert(
+            "X-Hub-Signature".into(),
+            "sha1=b23510941b65b0090ca2c8733ade4f677892f896".into(),
+        );
+
+        // The signature is checked against the original bytes, not against
+        // the body with the invalid sequences replaced
+        let raw = b"{\"data\": \"\xff\xfe\"}".to_vec();
+        req.body = String::from_utf8_lossy(&raw).into_owned();
+        req.raw_body = Some(raw);
+        assert_eq!(provider.validate(&req.clone().into()), RequestType::Ping);
+
+        req.raw_body = None;
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+    }
 }
@@ -16,6 +16,7 @@
 use serde_json;
 
 use providers::prelude::*;
+use providers::parse_json_body;
 use common::prelude::*;
 
 
@@ -25,16 +26,39 @@ lazy_static! {
         "Build", "Pipeline", "Confidential Issue",
     ];
 
+    /// GitLab events named differently than their GitHub counterparts,
+    /// mapped to the GitHub name when normalizing them.
+    static ref NORMALIZED_EVENTS: Vec<(&'static str, &'static str)> = vec![
+        ("merge_request", "pull_request"),
+        ("tag_push", "push"),
+    ];
+
     static ref GITLAB_HEADERS: Vec<&'static str> = vec![
         "X-Gitlab-Event",
     ];
 }
 
 
+/// The user who triggered the event: push events only include the username,
+/// while the other events include the details of the whole user.
+#[derive(Deserialize)]
+struct UserEvent {
+    user_username: Option<String>,
+    user: Option<User>,
+}
+
+#[derive(Deserialize)]
+struct User {
+    username: String,
+}
+
+
 #[derive(Debug, Deserialize)]
 pub struct GitLabProvider {
     secret: Option<String>,
     events: Option<Vec<String>>,
+    #[serde(default)]
+    empty_body_as_object: bool,
 }
 
 impl ProviderTrait for GitLabProvider {
@@ -96,7 +120,7 @@ impl ProviderTrait for GitLabProvider {
         }
 
         // Check if the JSON body is valid
-        if !serde_json::from_str::<serde_json::Value>(&req.body).is_ok() {
+        if parse_json_body(&req.body, self.empty_body_as_object).is_err() {
             return RequestType::Invalid;
         }
 
@@ -119,6 +143,43 @@ impl ProviderTrait for GitLabProvider {
 
         Ok(())
     }
+
+    fn normalized_event(&self, r: &Request) -> Option<String> {
+        if let Request::Web(ref req) = *r {
+            // "Merge Request Hook" becomes "merge_request", and then
+            // "pull_request" to match the GitHub name
+            req.header("X-Gitlab-Event").map(|event| {
+                let event = normalize_event_name(event)
+                    .to_lowercase()
+                    .replace(' ', "_");
+
+                NORMALIZED_EVENTS.iter()
+                    .find(|&&(gitlab, _)| gitlab == event)
+                    .map(|&(_, normalized)| normalized.to_string())
+                    .unwrap_or(event)
+            })
+        } else {
+            None
+        }
+    }
+
+    fn triggered_by(&self, r: &Request) -> Option<String> {
+        if let Request::Web(ref req) = *r {
+            let event = serde_json::from_str::<UserEvent>(&req.body).ok()?;
+            let user = event.user;
+            event.user_username.or_else(|| user.map(|user| user.username))
+        } else {
+            None
+        }
+    }
+
+    fn delivery_key(&self, r: &Request) -> Option<String> {
+        if let Request::Web(ref req) = *r {
+            req.header("X-Gitlab-Event-UUID").map(|id| id.to_string())
+        } else {
+            None
+        }
+    }
 }
 
 
@@ -164,6 +225,7 @@ mod tests {
             r#"{"secret": "abcde"}"#,
             r#"{"events": ["Push", "Issue"]}"#,
             r#"{"secret": "abcde", "events": ["Push", "Issue"]}"#,
+            r#"{"empty_body_as_object": true}"#,
         ] {
             assert!(GitLabProvider::new(right).is_ok(), right.to_string());
         }
@@ -178,6 +240,7 @@ mod tests {
             r#"{"events": [12345]}"#,
             r#"{"events": [true]}"#,
             r#"{"events": ["invalid_event"]}"#,
+            r#"{"empty_body_as_object": "yes"}"#,
         ] {
             assert!(GitLabProvider::new(wrong).is_err(), wrong.to_string());
         }
@@ -304,6 +367,33 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_validate_empty_body_as_object() {
+        let mut req = base_request();
+        req.body = String::new();
+
+        // Without the option empty bodies are rejected
+        let provider = GitLabProvider::new("{}").unwrap();
+        assert_eq!(
+            provider.validate(&req.clone().into()),
+            RequestType::Invalid
+        );
+
+        // With the option the body is treated as an empty object
+        let config = r#"{"empty_body_as_object": true}"#;
+        let provider = GitLabProvider::new(config).unwrap();
+        assert_eq!(
+            provider.validate(&req.clone().into()),
+            RequestType::ExecuteHook
+        );
+
+        // Invalid JSON is still rejected
+        let mut req = base_request();
+        req.body = "not json".into();
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+    }
+
+
     #[test]
     fn test_build_env() {
         let mut req = base_request();
@@ -326,4 +416,62 @@ mod tests {
         assert_eq!(normalize_event_name("Push Hook"), "Push");
         assert_eq!(normalize_event_name("Push Hook Hook"), "Push Hook");
     }
+
+
+    #[test]
+    fn test_normalized_event() {
+        let provider = GitLabProvider::new("{}").unwrap();
+
+        for &(header, expected) in &[
+            ("Push Hook", "push"),
+            ("Tag Push Hook", "push"),
+            ("Merge Request Hook", "pull_request"),
+            ("Wiki Page Hook", "wiki_page"),
+        ] {
+            let mut req = base_request();
+            req.headers.insert("X-Gitlab-Event".into(), header.into());
+            assert_eq!(
+                provider.normalized_event(&req.into()),
+                Some(expected.to_string())
+            );
+        }
+
+        // Requests without the header don't have an event
+        let req = dummy_web_request();
+        assert_eq!(provider.normalized_event(&req.into()), None);
+    }
+
+
+    #[test]
+    fn test_delivery_key() {
+        let provider = GitLabProvider::new("{}").unwrap();
+
+        let mut req = base_request();
+        assert_eq!(provider.delivery_key(&req.clone().into()), None);
+
+        req.headers.insert("X-Gitlab-Event-UUID".into(), "abcd-1234".into());
+        assert_eq!(
+            provider.delivery_key(&req.into()), Some("abcd-1234".into()),
+        );
+    }
+
+
+    #[test]
+    fn test_triggered_by() {
+        let provider = GitLabProvider::new("{}").unwrap();
+
+        for &(body, expected) in &[
+            (r#"{"user_username": "alice"}"#, Some("alice")),
+            (r#"{"user": {"username": "bob"}}"#, Some("bob")),
+            (r#"{}"#, None),
+            (r#"not json"#, None),
+        ] {
+            let mut req = base_request();
+            req.body = body.into();
+            assert_eq!(
+                provider.triggered_by(&req.into()),
+                expected.map(|user| user.to_string()),
+            );
+        }
+    }
 }
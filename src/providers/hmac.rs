@@ -0,0 +1,353 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde_json;
+use hmac::{Hmac, Mac};
+use sha1;
+
+use providers::prelude::*;
+use web::WebRequest;
+use utils;
+
+
+/// A part of the request included in the signed string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Component {
+    Method,
+    Path,
+    Body,
+    Header(String),
+}
+
+impl Component {
+    fn parse(raw: &str) -> Result<Self> {
+        match raw {
+            "method" => Ok(Component::Method),
+            "path" => Ok(Component::Path),
+            "body" => Ok(Component::Body),
+            _ => match raw.strip_prefix("header:") {
+                Some(name) if !name.is_empty() => {
+                    Ok(Component::Header(name.to_string()))
+                }
+                _ => Err(ErrorKind::ProviderHmacInvalidComponent(
+                    raw.to_string(),
+                ).into()),
+            },
+        }
+    }
+
+    fn value<'a>(&self, req: &'a WebRequest) -> Option<&'a [u8]> {
+        match *self {
+            Component::Method => Some(req.method.as_bytes()),
+            Component::Path => Some(req.path.as_bytes()),
+            Component::Body => Some(req.body_bytes()),
+            Component::Header(ref name) => {
+                req.header(name).map(|value| value.as_bytes())
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct HmacProvider {
+    secret: String,
+    header: Option<String>,
+
+    components: Option<Vec<String>>,
+    separator: Option<String>,
+
+    #[serde(skip)]
+    parsed_components: Vec<Component>,
+}
+
+impl HmacProvider {
+    fn header(&self) -> &str {
+        match self.header {
+            Some(ref header) => header,
+            None => "X-Signature",
+        }
+    }
+
+    fn separator(&self) -> &str {
+        match self.separator {
+            Some(ref separator) => separator,
+            None => "\n",
+        }
+    }
+
+    /// Build the string to sign from the configured components, returning
+    /// None if some of them are missing from the request.
+    fn string_to_sign(&self, req: &WebRequest) -> Option<Vec<u8>> {
+        let mut parts = Vec::with_capacity(self.parsed_components.len());
+        for component in &self.parsed_components {
+            parts.push(component.value(req)?);
+        }
+
+        Some(parts.join(self.separator().as_bytes()))
+    }
+}
+
+impl ProviderTrait for HmacProvider {
+    fn new(config: &str) -> Result<Self> {
+        let mut inst: HmacProvider = serde_json::from_str(config)?;
+
+        // Only the body is signed by default
+        inst.parsed_components = match inst.components {
+            Some(ref components) => components
+                .iter()
+                .map(|component| Component::parse(component))
+                .collect::<Result<_>>()?,
+            None => vec![Component::Body],
+        };
+
+        Ok(inst)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        let raw_signature = if let Some(found) = req.header(self.header()) {
+            found
+        } else {
+            return RequestType::Invalid;
+        };
+
+        let payload = if let Some(payload) = self.string_to_sign(req) {
+            payload
+        } else {
+            return RequestType::Invalid;
+        };
+
+        if verify_signature(&self.secret, &payload, raw_signature) {
+            RequestType::ExecuteHook
+        } else {
+            RequestType::Invalid
+        }
+    }
+
+    fn build_env(&self, _req: &Request, _b: &mut EnvBuilder) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+fn verify_signature(
+    secret: &str, payload: &[u8], raw_signature: &str,
+) -> bool {
+    type HmacSha1 = Hmac<sha1::Sha1>;
+
+    // The algorithm prefix is optional
+    let hex_signature = raw_signature.trim();
+    let hex_signature = hex_signature
+        .strip_prefix("sha1=")
+        .unwrap_or(hex_signature);
+
+    let signature = if let Ok(converted) = utils::from_hex(hex_signature) {
+        converted
+    } else {
+        return false;
+    };
+
+    let mut mac = HmacSha1::new_varkey(secret.as_bytes()).unwrap();
+    mac.input(payload);
+    mac.verify(&signature).is_ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::RequestType;
+    use web::WebRequest;
+    use providers::ProviderTrait;
+
+    use super::{Component, HmacProvider};
+
+
+    // Signatures computed with:
+    // printf '<payload>' | openssl dgst -sha1 -hmac secret
+    fn signed_request(signature: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.method = "POST".into();
+        req.path = "/hook/example.sh".into();
+        req.body = "hello".into();
+        req.headers.insert("X-Timestamp".into(), "1500000000".into());
+        req.headers.insert("X-Signature".into(), signature.into());
+        req
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"secret": "abcde"}"#,
+            r#"{"secret": "abcde", "header": "X-Hub-Signature"}"#,
+            r#"{"secret": "abcde", "components": ["method", "path"]}"#,
+            r#"{"secret": "abcde", "components": ["header:X-Date", "body"]}"#,
+            r#"{"secret": "abcde", "separator": "|"}"#,
+        ] {
+            assert!(HmacProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"secret": 12345}"#,
+            r#"{"secret": "abcde", "components": "body"}"#,
+            r#"{"secret": "abcde", "components": ["query"]}"#,
+            r#"{"secret": "abcde", "components": ["header:"]}"#,
+            r#"{"secret": "abcde", "separator": 1}"#,
+        ] {
+            assert!(HmacProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_component_parse() {
+        assert_eq!(Component::parse("method").unwrap(), Component::Method);
+        assert_eq!(Component::parse("path").unwrap(), Component::Path);
+        assert_eq!(Component::parse("body").unwrap(), Component::Body);
+        assert_eq!(
+            Component::parse("header:X-Date").unwrap(),
+            Component::Header("X-Date".into())
+        );
+        assert!(Component::parse("header").is_err());
+        assert!(Component::parse("Method").is_err());
+    }
+
+
+    #[test]
+    fn test_string_to_sign() {
+        let req = signed_request("");
+
+        let provider = HmacProvider::new(r#"{"secret": "secret"}"#).unwrap();
+        assert_eq!(provider.string_to_sign(&req).unwrap(), b"hello");
+
+        let provider = HmacProvider::new(concat!(
+            r#"{"secret": "secret", "components": "#,
+            r#"["method", "path", "header:x-timestamp", "body"]}"#,
+        )).unwrap();
+        assert_eq!(
+            provider.string_to_sign(&req).unwrap(),
+            b"POST\n/hook/example.sh\n1500000000\nhello"
+        );
+
+        let provider = HmacProvider::new(concat!(
+            r#"{"secret": "secret", "separator": "|", "#,
+            r#""components": ["header:X-Timestamp", "body"]}"#,
+        )).unwrap();
+        assert_eq!(
+            provider.string_to_sign(&req).unwrap(),
+            b"1500000000|hello"
+        );
+
+        // Missing headers make the string impossible to build
+        let provider = HmacProvider::new(
+            r#"{"secret": "secret", "components": ["header:X-Missing"]}"#,
+        ).unwrap();
+        assert!(provider.string_to_sign(&req).is_none());
+    }
+
+
+    #[test]
+    fn test_validate_body() {
+        let provider = HmacProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        for valid in &[
+            "5112055c05f944f85755efc5cd8970e194e9f45b",
+            "sha1=5112055c05f944f85755efc5cd8970e194e9f45b",
+        ] {
+            assert_eq!(
+                provider.validate(&signed_request(valid).into()),
+                RequestType::ExecuteHook
+            );
+        }
+
+        for invalid in &[
+            "0000000000000000000000000000000000000000",
+            "sha1=invalid",
+            "",
+        ] {
+            assert_eq!(
+                provider.validate(&signed_request(invalid).into()),
+                RequestType::Invalid
+            );
+        }
+
+        // Requests without the signature are rejected
+        let mut req = signed_request("");
+        req.headers.remove("X-Signature");
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_validate_components() {
+        let provider = HmacProvider::new(concat!(
+            r#"{"secret": "secret", "components": "#,
+            r#"["method", "path", "header:X-Timestamp", "body"]}"#,
+        )).unwrap();
+
+        let signature = "f1c9a4aadaa5ddacf3a27e6152480642d1feeceb";
+        assert_eq!(
+            provider.validate(&signed_request(signature).into()),
+            RequestType::ExecuteHook
+        );
+
+        // Changing any of the components invalidates the signature
+        let mut req = signed_request(signature);
+        req.headers.insert("X-Timestamp".into(), "1500000001".into());
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        let mut req = signed_request(signature);
+        req.method = "GET".into();
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        // The signature of the body alone is not valid anymore
+        assert_eq!(
+            provider.validate(&signed_request(
+                "5112055c05f944f85755efc5cd8970e194e9f45b",
+            ).into()),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_validate_non_utf8_body() {
+        let provider = HmacProvider::new(r#"{"secret": "secret"}"#).unwrap();
+        let mut req =
+            signed_request("b23510941b65b0090ca2c8733ade4f677892f896");
+
+        // The signature is checked against the original bytes, not against
+        // the body with the invalid sequences replaced
+        let raw = b"{\"data\": \"\xff\xfe\"}".to_vec();
+        req.body = String::from_utf8_lossy(&raw).into_owned();
+        req.raw_body = Some(raw);
+        assert_eq!(
+            provider.validate(&req.clone().into()),
+            RequestType::ExecuteHook
+        );
+
+        req.raw_body = None;
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use serde_json;
+use hmac::{Hmac, Mac};
+use sha2;
+
+use providers::prelude::*;
+use web::WebRequest;
+
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+
+/// The `aud` claim can either be a single audience or a list of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match *self {
+            Audience::Single(ref single) => single == audience,
+            Audience::Multiple(ref list) => list.iter().any(|a| a == audience),
+        }
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+struct JwtClaims {
+    sub: Option<String>,
+    iss: Option<String>,
+    aud: Option<Audience>,
+    exp: Option<u64>,
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct JwtProvider {
+    secret: String,
+
+    issuer: Option<String>,
+    audience: Option<String>,
+    #[serde(default)]
+    allow_no_expiration: bool,
+}
+
+impl JwtProvider {
+    /// Get the claims of the token in the request, if its signature is valid.
+    fn verified_claims(&self, req: &WebRequest) -> Option<JwtClaims> {
+        type HmacSha256 = Hmac<sha2::Sha256>;
+
+        let token = req.header("Authorization")?.strip_prefix("Bearer ")?;
+        let mut parts = token.trim().split('.');
+        let (header, payload, signature) =
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(h), Some(p), Some(s), None) => (h, p, s),
+                _ => return None,
+            };
+
+        // Only HS256 is supported, which also rejects unsigned tokens
+        let parsed: JwtHeader =
+            serde_json::from_slice(&decode(header)?).ok()?;
+        if parsed.alg != "HS256" {
+            return None;
+        }
+
+        let mut mac = HmacSha256::new_varkey(self.secret.as_bytes()).unwrap();
+        mac.input(header.as_bytes());
+        mac.input(b".");
+        mac.input(payload.as_bytes());
+        mac.verify(&decode(signature)?).ok()?;
+
+        serde_json::from_slice(&decode(payload)?).ok()
+    }
+
+    fn check_claims(&self, claims: &JwtClaims) -> bool {
+        // Expired tokens are rejected, and tokens without an expiration are
+        // accepted only if explicitly allowed
+        if let Some(exp) = claims.exp {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+            if exp <= now {
+                return false;
+            }
+        } else if !self.allow_no_expiration {
+            return false;
+        }
+
+        if let Some(ref issuer) = self.issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return false;
+            }
+        }
+
+        if let Some(ref audience) = self.audience {
+            match claims.aud {
+                Some(ref aud) if aud.contains(audience) => {}
+                _ => return false,
+            }
+        }
+
+        true
+    }
+}
+
+impl ProviderTrait for JwtProvider {
+    fn new(config: &str) -> Result<Self> {
+        Ok(serde_json::from_str(config)?)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        match self.verified_claims(req) {
+            Some(ref claims) if self.check_claims(claims) => {
+                RequestType::ExecuteHook
+            }
+            _ => RequestType::Invalid,
+        }
+    }
+
+    fn build_env(&self, r: &Request, b: &mut EnvBuilder) -> Result<()> {
+        let req;
+        if let Request::Web(ref inner) = *r {
+            req = inner;
+        } else {
+            return Ok(());
+        }
+
+        let sub = self.verified_claims(req).and_then(|claims| claims.sub);
+        if let Some(sub) = sub {
+            b.add_env("SUB", sub);
+        }
+
+        Ok(())
+    }
+}
+
+
+fn decode(input: &str) -> Option<Vec<u8>> {
+    base64::decode_config(input, base64::URL_SAFE_NO_PAD).ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::RequestType;
+    use web::WebRequest;
+    use providers::ProviderTrait;
+    use scripts::EnvBuilder;
+
+    use super::JwtProvider;
+
+
+    // Tokens signed with the "secret" key, by the "ci" issuer for the
+    // "fisher" audience, and expiring in 2100 (unless noted otherwise)
+    const HEADER: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9";
+    const VALID: &str = concat!(
+        "eyJzdWIiOiJkZXBsb3ktYm90IiwiaXNzIjoiY2kiLCJhdWQiOiJmaXNoZXIiLCJleHAi",
+        "OjQxMDI0NDQ4MDB9.kC8Y8Dhci3Qjnee6E7CY0617v4sR-mLIlfgsCgDhmTo",
+    );
+    // Expired in 2017
+    const EXPIRED: &str = concat!(
+        "eyJzdWIiOiJkZXBsb3ktYm90IiwiaXNzIjoiY2kiLCJhdWQiOiJmaXNoZXIiLCJleHAi",
+        "OjE1MDAwMDAwMDB9.-pxQgvJFGPch4_0r2UX6O4KaZjGjSZTqPqeonQDDMYw",
+    );
+    // Issued by "other"
+    const WRONG_ISSUER: &str = concat!(
+        "eyJzdWIiOiJkZXBsb3ktYm90IiwiaXNzIjoib3RoZXIiLCJhdWQiOiJmaXNoZXIiLCJl",
+        "eHAiOjQxMDI0NDQ4MDB9.hKwTbtB0z7MBwjpbUuV3jt08bssMpk1RUtQJHBIn4Fk",
+    );
+    // Signed with the "wrong" key
+    const WRONG_SECRET: &str = concat!(
+        "eyJzdWIiOiJkZXBsb3ktYm90IiwiaXNzIjoiY2kiLCJhdWQiOiJmaXNoZXIiLCJleHAi",
+        "OjQxMDI0NDQ4MDB9.7olt3nn2vmH0Ysm5xh07mtP9LOhPweNvg7L6mzFgLKQ",
+    );
+    // Without an issuer and an expiration, for ["other", "fisher"]
+    const AUDIENCES: &str = concat!(
+        "eyJzdWIiOiJkZXBsb3ktYm90IiwiYXVkIjpbIm90aGVyIiwiZmlzaGVyIl19.",
+        "Q7GAeTEG1GLE1Ve05ZBK0u59Avn1YgP5sCNsFrzIYzU",
+    );
+
+
+    fn bearer(token: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.headers.insert(
+            "Authorization".into(),
+            format!("Bearer {}.{}", HEADER, token),
+        );
+        req
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"secret": "abcde"}"#,
+            r#"{"secret": "abcde", "issuer": "ci"}"#,
+            r#"{"secret": "abcde", "audience": "fisher"}"#,
+            r#"{"secret": "abcde", "allow_no_expiration": true}"#,
+        ] {
+            assert!(JwtProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"secret": 12345}"#,
+            r#"{"secret": "abcde", "issuer": 1}"#,
+            r#"{"secret": "abcde", "audience": ["fisher"]}"#,
+            r#"{"secret": "abcde", "allow_no_expiration": 1}"#,
+        ] {
+            assert!(JwtProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_validate() {
+        let provider = JwtProvider::new(
+            r#"{"secret": "secret", "issuer": "ci", "audience": "fisher"}"#,
+        ).unwrap();
+
+        assert_eq!(
+            provider.validate(&bearer(VALID).into()),
+            RequestType::ExecuteHook
+        );
+
+        for invalid in &[EXPIRED, WRONG_ISSUER, WRONG_SECRET, AUDIENCES] {
+            assert_eq!(
+                provider.validate(&bearer(invalid).into()),
+                RequestType::Invalid
+            );
+        }
+
+        // Requests without a bearer token are rejected
+        let mut req = bearer(VALID);
+        req.headers.remove("Authorization");
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        let mut req = bearer(VALID);
+        req.headers.insert("Authorization".into(), "Basic abcde".into());
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        // Tokens signed with other algorithms are rejected
+        let mut req = dummy_web_request();
+        req.headers.insert("Authorization".into(), concat!(
+            "Bearer eyJhbGciOiJIUzUxMiIsInR5cCI6IkpXVCJ9.",
+            "eyJzdWIiOiJkZXBsb3ktYm90In0.",
+            "s66aPlPiT2OCXaz6rG5gbdpZ3ddLZhwMCsIHVyYJmYg",
+        ).into());
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_validate_optional_claims() {
+        let provider = JwtProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        // The issuer and the audience are not checked if not configured
+        for valid in &[VALID, WRONG_ISSUER] {
+            assert_eq!(
+                provider.validate(&bearer(valid).into()),
+                RequestType::ExecuteHook
+            );
+        }
+
+        // Expired tokens and tokens without an expiration are rejected
+        for invalid in &[EXPIRED, AUDIENCES] {
+            assert_eq!(
+                provider.validate(&bearer(invalid).into()),
+                RequestType::Invalid
+            );
+        }
+
+        // Tokens without an expiration can be explicitly allowed
+        let provider = JwtProvider::new(
+            r#"{"secret": "secret", "allow_no_expiration": true}"#,
+        ).unwrap();
+        assert_eq!(
+            provider.validate(&bearer(AUDIENCES).into()),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            provider.validate(&bearer(EXPIRED).into()),
+            RequestType::Invalid
+        );
+
+        // The audience can be one of many
+        let provider = JwtProvider::new(concat!(
+            r#"{"secret": "secret", "audience": "fisher", "#,
+            r#""allow_no_expiration": true}"#,
+        )).unwrap();
+        assert_eq!(
+            provider.validate(&bearer(AUDIENCES).into()),
+            RequestType::ExecuteHook
+        );
+    }
+
+
+    #[test]
+    fn test_build_env() {
+        let provider = JwtProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&bearer(VALID).into(), &mut b).unwrap();
+        assert_eq!(b.dummy_data().env, hashmap! {
+            "SUB".into() => "deploy-bot".into(),
+        });
+    }
+}
@@ -17,6 +17,12 @@ mod status;
 mod standalone;
 mod github;
 mod gitlab;
+mod ci_notify;
+mod hmac;
+mod jwt;
+mod stripe;
+mod exec;
+mod env_templates;
 #[cfg(test)]
 pub mod testing;
 
@@ -29,14 +35,44 @@ pub mod prelude {
 }
 
 
-pub use self::status::{StatusEvent, StatusEventKind, StatusProvider};
+pub use self::status::{
+    ExpiredJob, StatusEvent, StatusEventKind, StatusProvider,
+};
+use self::env_templates::EnvTemplates;
 
 
+use serde_json;
+
 use requests::{Request, RequestType};
 use common::prelude::*;
 use scripts::EnvBuilder;
 
 
+/// Parse the JSON body of a request, treating an empty body as an empty
+/// object if `empty_as_object` is true.
+pub fn parse_json_body(
+    body: &str, empty_as_object: bool,
+) -> Result<serde_json::Value> {
+    if empty_as_object && body.is_empty() {
+        Ok(json!({}))
+    } else {
+        Ok(serde_json::from_str(body)?)
+    }
+}
+
+
+/// Check if the method of the request is accepted by the provider. Requests
+/// not coming from the web are always accepted.
+fn method_allowed(provider: &dyn ProviderTrait, req: &Request) -> bool {
+    if let Request::Web(ref web) = *req {
+        if let Some(methods) = provider.allowed_methods() {
+            return methods.contains(&web.method.as_str());
+        }
+    }
+    true
+}
+
+
 /// This trait should be implemented by every Fisher provider
 /// The objects implementing this trait must also implement Clone and Debug
 pub trait ProviderTrait: ::std::fmt::Debug {
@@ -50,10 +86,68 @@ pub trait ProviderTrait: ::std::fmt::Debug {
     /// type if the request is valid
     fn validate(&self, &Request) -> RequestType;
 
+    /// This method should return the HTTP methods accepted by the provider,
+    /// or `None` if all of them are accepted. Requests with other methods are
+    /// rejected before calling `validate`
+    fn allowed_methods(&self) -> Option<&'static [&'static str]> {
+        None
+    }
+
     /// This method should build the environment to process an incoming
     /// request
     fn build_env(&self, req: &Request, builder: &mut EnvBuilder) -> Result<()>;
 
+    /// This method should return the name of the event of the request, in a
+    /// format shared by all the providers (lowercase words separated by
+    /// underscores, like `push` or `merge_request`). By default providers
+    /// don't have any event
+    fn normalized_event(&self, _req: &Request) -> Option<String> {
+        None
+    }
+
+    /// This method should return the name of the user who triggered the
+    /// request, if the provider is able to identify them. By default the
+    /// user is unknown
+    fn triggered_by(&self, _req: &Request) -> Option<String> {
+        None
+    }
+
+    /// This method should return the unique ID the sender assigned to the
+    /// delivery of the request, if it has one, so scripts can recognize
+    /// deliveries sent more than once. By default there is no ID
+    fn delivery_key(&self, _req: &Request) -> Option<String> {
+        None
+    }
+
+    /// This method should return if the provider accepts every request
+    /// without authenticating it, making the hook public. By default
+    /// providers are not public
+    fn is_public(&self) -> bool {
+        false
+    }
+
+    /// This method should return the names of the query string params
+    /// containing the secrets checked by the provider, which are hidden when
+    /// the URL of a request is logged. By default there are none
+    fn secret_params(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// This method should return if validating a request might block for a
+    /// while, for example because it runs an external program. Those requests
+    /// are validated outside of the thread accepting new requests. By default
+    /// validation is quick
+    fn blocking_validation(&self) -> bool {
+        false
+    }
+
+    /// This method should return if the provider reads the path segment
+    /// after the hook name (for example `/hook/name.sh/segment`). Requests
+    /// with that segment are rejected if no provider reads it
+    fn reads_path_segment(&self) -> bool {
+        false
+    }
+
     /// This method tells the scheduler if the hook should trigger status hooks
     /// after the request is processed. By default this returns true, change it
     /// only if you really know what you're doing
@@ -70,7 +164,7 @@ macro_rules! ProviderEnum {
         pub enum Provider {
             $(
                 #[cfg($cfg)]
-                $name($provider),
+                $name($provider, EnvTemplates),
             )*
         }
 
@@ -82,10 +176,9 @@ macro_rules! ProviderEnum {
                         #[cfg($cfg)]
                         stringify!($name) => {
                             use $provider as InnerProvider;
-                            match InnerProvider::new(config) {
-                                Ok(prov) => Ok(Provider::$name(prov)),
-                                Err(err) => Err(err),
-                            }
+                            let prov = InnerProvider::new(config)?;
+                            let env = EnvTemplates::new(config)?;
+                            Ok(Provider::$name(prov, env))
                         },
                     )*
                     _ => Err(
@@ -98,8 +191,12 @@ macro_rules! ProviderEnum {
                 match *self {
                     $(
                         #[cfg($cfg)]
-                        Provider::$name(ref prov) => {
-                            (prov as &ProviderTrait).validate(req)
+                        Provider::$name(ref prov, _) => {
+                            let prov = prov as &dyn ProviderTrait;
+                            if !method_allowed(prov, req) {
+                                return RequestType::Invalid;
+                            }
+                            prov.validate(req)
                         },
                     )*
                 }
@@ -111,19 +208,99 @@ macro_rules! ProviderEnum {
                 match *self {
                     $(
                         #[cfg($cfg)]
-                        Provider::$name(ref prov) => {
-                            (prov as &ProviderTrait).build_env(req, builder)
+                        Provider::$name(ref prov, ref env) => {
+                            let prov = prov as &dyn ProviderTrait;
+                            prov.build_env(req, builder)?;
+                            env.build_env(req, builder)
                         },
                     )*
                 }
             }
 
+            pub fn normalized_event(&self, req: &Request) -> Option<String> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov, _) => {
+                            (prov as &dyn ProviderTrait).normalized_event(req)
+                        }
+                    )*
+                }
+            }
+
+            pub fn triggered_by(&self, req: &Request) -> Option<String> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov, _) => {
+                            (prov as &dyn ProviderTrait).triggered_by(req)
+                        }
+                    )*
+                }
+            }
+
+            pub fn delivery_key(&self, req: &Request) -> Option<String> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov, _) => {
+                            (prov as &dyn ProviderTrait).delivery_key(req)
+                        }
+                    )*
+                }
+            }
+
+            pub fn is_public(&self) -> bool {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov, _) => {
+                            (prov as &dyn ProviderTrait).is_public()
+                        }
+                    )*
+                }
+            }
+
+            pub fn secret_params(&self) -> Vec<String> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov, _) => {
+                            (prov as &dyn ProviderTrait).secret_params()
+                        }
+                    )*
+                }
+            }
+
+            pub fn blocking_validation(&self) -> bool {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov, _) => {
+                            (prov as &dyn ProviderTrait).blocking_validation()
+                        }
+                    )*
+                }
+            }
+
+            pub fn reads_path_segment(&self) -> bool {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov, _) => {
+                            (prov as &dyn ProviderTrait).reads_path_segment()
+                        }
+                    )*
+                }
+            }
+
             pub fn trigger_status_hooks(&self, req: &Request) -> bool {
                 match *self {
                     $(
                         #[cfg($cfg)]
-                        Provider::$name(ref prov) => {
-                            (prov as &ProviderTrait).trigger_status_hooks(req)
+                        Provider::$name(ref prov, _) => {
+                            let prov = prov as &dyn ProviderTrait;
+                            prov.trigger_status_hooks(req)
                         }
                     )*
                 }
@@ -148,5 +325,10 @@ ProviderEnum! {
     any(test, not(test)) | Status => self::status::StatusProvider,
     any(test, not(test)) | GitHub => self::github::GitHubProvider,
     any(test, not(test)) | GitLab => self::gitlab::GitLabProvider,
+    any(test, not(test)) | CiNotify => self::ci_notify::CiNotifyProvider,
+    any(test, not(test)) | Hmac => self::hmac::HmacProvider,
+    any(test, not(test)) | Jwt => self::jwt::JwtProvider,
+    any(test, not(test)) | Stripe => self::stripe::StripeProvider,
+    any(test, not(test)) | Exec => self::exec::ExecProvider,
     test | Testing => self::testing::TestingProvider
 }
@@ -15,9 +15,22 @@
 
 use std::net::IpAddr;
 
+use base64;
 use serde_json;
 
 use providers::prelude::*;
+use web::WebRequest;
+use utils;
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AuthMode {
+    /// All the configured checks must pass.
+    All,
+    /// At least one of the configured checks must pass.
+    Any,
+}
 
 
 #[derive(Debug, Deserialize)]
@@ -27,6 +40,13 @@ pub struct StandaloneProvider {
 
     param_name: Option<String>,
     header_name: Option<String>,
+    #[serde(default)]
+    secret_in_path: bool,
+
+    username: Option<String>,
+    password: Option<String>,
+
+    auth_mode: Option<AuthMode>,
 }
 
 impl StandaloneProvider {
@@ -43,12 +63,88 @@ impl StandaloneProvider {
             None => "X-Fisher-Secret".into(),
         }
     }
+
+    fn auth_mode(&self) -> AuthMode {
+        self.auth_mode.unwrap_or(AuthMode::All)
+    }
+
+    /// Check if the request contains the correct secret, returning None if
+    /// no secret is configured.
+    fn check_secret(&self, req: &WebRequest) -> Option<bool> {
+        let correct_secret = self.secret.as_ref()?;
+
+        let secret = if let Some(found) = req.params.get(&self.param_name()) {
+            // Secret in the request parameters
+            found
+        } else if let Some(found) = req.headers.get(&self.header_name()) {
+            // Secret in the HTTP headers
+            found
+        } else if let (true, Some(found)) =
+            (self.secret_in_path, req.path_segment.as_ref())
+        {
+            // Secret in the path, after the name of the hook
+            found
+        } else {
+            // No secret present, abort!
+            return Some(false);
+        };
+
+        Some(utils::constant_time_eq(
+            secret.as_bytes(), correct_secret.as_bytes(),
+        ))
+    }
+
+    /// Check if the request contains the correct basic auth credentials,
+    /// returning None if no credentials are configured.
+    fn check_basic_auth(&self, req: &WebRequest) -> Option<bool> {
+        let (username, password) = match (&self.username, &self.password) {
+            (Some(username), Some(password)) => (username, password),
+            _ => return None,
+        };
+
+        let credentials = req.header("Authorization")
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok());
+        let credentials = if let Some(credentials) = credentials {
+            credentials
+        } else {
+            return Some(false);
+        };
+
+        // The password can contain colons, while the username can't
+        let mut parts = credentials.splitn(2, |byte| *byte == b':');
+        let (found_username, found_password) =
+            match (parts.next(), parts.next()) {
+                (Some(u), Some(p)) => (u, p),
+                _ => return Some(false),
+            };
+
+        // Both are always compared, to avoid leaking which one is wrong
+        let username_ok =
+            utils::constant_time_eq(found_username, username.as_bytes());
+        let password_ok =
+            utils::constant_time_eq(found_password, password.as_bytes());
+        Some(username_ok & password_ok)
+    }
+
+    /// Check if the request comes from an allowed IP address, returning None
+    /// if no address is configured.
+    fn check_from(&self, req: &WebRequest) -> Option<bool> {
+        self.from.as_ref().map(|allowed| allowed.contains(&req.source))
+    }
 }
 
 impl ProviderTrait for StandaloneProvider {
     fn new(config: &str) -> Result<Self> {
         // Check if it's possible to create a new instance and return it
-        let inst = serde_json::from_str(config)?;
+        let inst: StandaloneProvider = serde_json::from_str(config)?;
+
+        if inst.username.is_some() != inst.password.is_some() {
+            return Err(
+                ErrorKind::ProviderStandaloneIncompleteCredentials.into(),
+            );
+        }
+
         Ok(inst)
     }
 
@@ -60,38 +156,49 @@ impl ProviderTrait for StandaloneProvider {
             return RequestType::Invalid;
         }
 
-        // Check if the secret code is valid
-        if let Some(ref correct_secret) = self.secret {
-            let secret = if let Some(found) = req.params.get(&self.param_name()) {
-                // Secret in the request parameters
-                found
-            } else if let Some(found) = req.headers.get(&self.header_name()) {
-                // Secret in the HTTP headers
-                found
-            } else {
-                // No secret present, abort!
-                return RequestType::Invalid;
-            };
+        // Only the configured checks are considered
+        let checks = [
+            self.check_secret(req),
+            self.check_from(req),
+            self.check_basic_auth(req),
+        ];
+        let mut results = checks.iter().filter_map(|check| *check).peekable();
 
-            // Abort if the secret doesn't match
-            if secret != correct_secret {
-                return RequestType::Invalid;
+        let valid = match self.auth_mode() {
+            AuthMode::All => results.all(|result| result),
+            // Requests are allowed if there is nothing to check
+            AuthMode::Any => {
+                results.peek().is_none() || results.any(|result| result)
             }
-        }
+        };
 
-        // Check if the IP address is allowed
-        if let Some(ref allowed) = self.from {
-            if !allowed.contains(&req.source) {
-                return RequestType::Invalid;
-            }
+        if valid {
+            RequestType::ExecuteHook
+        } else {
+            RequestType::Invalid
         }
-
-        RequestType::ExecuteHook
     }
 
     fn build_env(&self, _: &Request, _: &mut EnvBuilder) -> Result<()> {
         Ok(())
     }
+
+    fn is_public(&self) -> bool {
+        // Without any check every request is accepted
+        self.secret.is_none() && self.from.is_none() && self.username.is_none()
+    }
+
+    fn secret_params(&self) -> Vec<String> {
+        if self.secret.is_some() {
+            vec![self.param_name()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn reads_path_segment(&self) -> bool {
+        self.secret.is_some() && self.secret_in_path
+    }
 }
 
 
@@ -100,7 +207,7 @@ mod tests {
     use std::collections::HashMap;
 
     use utils::testing::*;
-    use requests::RequestType;
+    use requests::{Request, RequestType};
     use providers::ProviderTrait;
     use scripts::EnvBuilder;
 
@@ -118,6 +225,9 @@ mod tests {
             r#"{"secret": "abcde", "param_name": "a", "header_name": "b"}"#,
             r#"{"from": ["127.0.0.1", "192.168.1.1", "10.0.0.2"]}"#,
             r#"{"from": ["127.0.0.1"], "secret": "abcde"}"#,
+            r#"{"secret": "abcde", "auth_mode": "all"}"#,
+            r#"{"secret": "abcde", "auth_mode": "any"}"#,
+            r#"{"username": "ci", "password": "abcde"}"#,
         ];
         for one in &right {
             assert!(StandaloneProvider::new(one).is_ok(), "Should be valid: {}", one);
@@ -130,6 +240,9 @@ mod tests {
             r#"{"secret": {"a": "b"}}"#,
             r#"{"from": "127.0.0.1"}"#,
             r#"{"from": ["256.0.0.1"]}"#,
+            r#"{"auth_mode": "none"}"#,
+            r#"{"username": "ci"}"#,
+            r#"{"password": "abcde"}"#,
         ];
         for one in &wrong {
             assert!(StandaloneProvider::new(one).is_err(), "Should be invalid: {}", one);
@@ -204,6 +317,133 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_validate_basic_auth() {
+        fn req(header: &str) -> Request {
+            let mut req = dummy_web_request();
+            req.headers.insert("Authorization".into(), header.into());
+            req.into()
+        }
+
+        let p = StandaloneProvider::new(
+            r#"{"username": "ci", "password": "ab:cde"}"#,
+        ).unwrap();
+
+        // "ci:ab:cde"
+        assert_eq!(p.validate(&req("Basic Y2k6YWI6Y2Rl")), RequestType::ExecuteHook);
+
+        for invalid in &[
+            "Basic Y2k6YWJjZGU=",  // "ci:abcde"
+            "Basic Y2kyOmFiOmNkZQ==",  // "ci2:ab:cde"
+            "Basic Y2k=",  // "ci"
+            "Basic not-base64",
+            "Bearer Y2k6YWI6Y2Rl",
+        ] {
+            assert_eq!(p.validate(&req(invalid)), RequestType::Invalid);
+        }
+
+        // Requests without credentials are rejected
+        assert_eq!(
+            p.validate(&dummy_web_request().into()),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_validate_auth_mode() {
+        fn req(secret: bool, ip: &str) -> Request {
+            let mut req = dummy_web_request();
+            req.source = ip.parse().unwrap();
+            if secret {
+                req.params.insert("secret".into(), "abcde".into());
+            }
+            req.into()
+        }
+
+        let base = r#""secret": "abcde", "from": ["10.0.0.1"]"#;
+        let all = StandaloneProvider::new(
+            &format!(r#"{{{}, "auth_mode": "all"}}"#, base),
+        ).unwrap();
+        let any = StandaloneProvider::new(
+            &format!(r#"{{{}, "auth_mode": "any"}}"#, base),
+        ).unwrap();
+
+        // All the checks must pass with the "all" mode
+        assert_eq!(all.validate(&req(true, "10.0.0.1")), RequestType::ExecuteHook);
+        assert_eq!(all.validate(&req(true, "127.0.0.1")), RequestType::Invalid);
+        assert_eq!(all.validate(&req(false, "10.0.0.1")), RequestType::Invalid);
+        assert_eq!(all.validate(&req(false, "127.0.0.1")), RequestType::Invalid);
+
+        // Partial matches are enough with the "any" mode
+        assert_eq!(any.validate(&req(true, "10.0.0.1")), RequestType::ExecuteHook);
+        assert_eq!(any.validate(&req(true, "127.0.0.1")), RequestType::ExecuteHook);
+        assert_eq!(any.validate(&req(false, "10.0.0.1")), RequestType::ExecuteHook);
+        assert_eq!(any.validate(&req(false, "127.0.0.1")), RequestType::Invalid);
+
+        // Without any check every request is allowed with both modes
+        for mode in &["all", "any"] {
+            let p = StandaloneProvider::new(
+                &format!(r#"{{"auth_mode": "{}"}}"#, mode),
+            ).unwrap();
+            assert_eq!(p.validate(&req(false, "127.0.0.1")), RequestType::ExecuteHook);
+        }
+    }
+
+
+    #[test]
+    fn test_validate_secret_in_path() {
+        let p = StandaloneProvider::new(
+            r#"{"secret": "abcde", "secret_in_path": true}"#,
+        ).unwrap();
+        assert!(p.reads_path_segment());
+
+        let req = |segment: Option<&str>| -> Request {
+            let mut req = dummy_web_request();
+            req.path_segment = segment.map(|s| s.to_string());
+            req.into()
+        };
+
+        assert_eq!(p.validate(&req(Some("abcde"))), RequestType::ExecuteHook);
+        assert_eq!(p.validate(&req(Some("12345"))), RequestType::Invalid);
+        assert_eq!(p.validate(&req(None)), RequestType::Invalid);
+
+        // The path segment is ignored unless it's enabled
+        let p = StandaloneProvider::new(r#"{"secret": "abcde"}"#).unwrap();
+        assert!(!p.reads_path_segment());
+        assert_eq!(p.validate(&req(Some("abcde"))), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_is_public() {
+        for (config, public) in vec![
+            (r#"{}"#, true),
+            (r#"{"auth_mode": "any"}"#, true),
+            (r#"{"secret": "abcde"}"#, false),
+            (r#"{"from": ["127.0.0.1"]}"#, false),
+            (r#"{"username": "ci", "password": "abcde"}"#, false),
+        ] {
+            let p = StandaloneProvider::new(config).unwrap();
+            assert_eq!(p.is_public(), public, "{}", config);
+        }
+    }
+
+
+    #[test]
+    fn test_secret_params() {
+        for (config, params) in vec![
+            (r#"{}"#, vec![]),
+            (r#"{"param_name": "key"}"#, vec![]),
+            (r#"{"secret": "abcde"}"#, vec!["secret"]),
+            (r#"{"secret": "abcde", "param_name": "key"}"#, vec!["key"]),
+        ] {
+            let p = StandaloneProvider::new(config).unwrap();
+            assert_eq!(p.secret_params(), params, "{}", config);
+        }
+    }
+
+
     #[test]
     fn test_build_env() {
         let p = StandaloneProvider::new(r#"{"secret": "abcde"}"#).unwrap();
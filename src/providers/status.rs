@@ -13,7 +13,6 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::slice::Iter as SliceIter;
 use std::net::IpAddr;
 
 use serde_json;
@@ -22,10 +21,21 @@ use providers::prelude::*;
 use scripts::JobOutput;
 
 
+/// A job dropped from the queue, since it waited there for too long.
+#[derive(Debug, Clone)]
+pub struct ExpiredJob {
+    pub script_name: String,
+    pub request_ip: IpAddr,
+}
+
+
+// The variants are named after the events' names
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
 pub enum StatusEvent {
     JobCompleted(JobOutput),
     JobFailed(JobOutput),
+    JobExpired(ExpiredJob),
 }
 
 impl StatusEvent {
@@ -34,6 +44,7 @@ impl StatusEvent {
         match *self {
             StatusEvent::JobCompleted(..) => StatusEventKind::JobCompleted,
             StatusEvent::JobFailed(..) => StatusEventKind::JobFailed,
+            StatusEvent::JobExpired(..) => StatusEventKind::JobExpired,
         }
     }
 
@@ -42,6 +53,7 @@ impl StatusEvent {
         match *self {
             StatusEvent::JobCompleted(ref output) |
             StatusEvent::JobFailed(ref output) => &output.script_name,
+            StatusEvent::JobExpired(ref expired) => &expired.script_name,
         }
     }
 
@@ -50,6 +62,7 @@ impl StatusEvent {
         match *self {
             StatusEvent::JobCompleted(ref output) |
             StatusEvent::JobFailed(ref output) => output.request_ip,
+            StatusEvent::JobExpired(ref expired) => expired.request_ip,
         }
     }
 }
@@ -57,9 +70,11 @@ impl StatusEvent {
 
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone, Deserialize)]
 #[serde(rename_all = "kebab-case")]
+#[allow(clippy::enum_variant_names)]
 pub enum StatusEventKind {
     JobCompleted,
     JobFailed,
+    JobExpired,
 }
 
 impl StatusEventKind {
@@ -67,20 +82,23 @@ impl StatusEventKind {
         match *self {
             StatusEventKind::JobCompleted => "job-completed",
             StatusEventKind::JobFailed => "job-failed",
+            StatusEventKind::JobExpired => "job-expired",
         }
     }
 }
 
 
+/// A set of events the status hook subscribes to, optionally only for some
+/// scripts.
 #[derive(Debug, Deserialize)]
-pub struct StatusProvider {
+struct StatusRule {
     events: Vec<StatusEventKind>,
     scripts: Option<Vec<String>>,
 }
 
-impl StatusProvider {
+impl StatusRule {
     #[inline]
-    pub fn script_allowed(&self, name: &str) -> bool {
+    fn script_allowed(&self, name: &str) -> bool {
         // Check if it's allowed only if a whitelist was provided
         if let Some(ref scripts) = self.scripts {
             scripts.contains(&name.into())
@@ -89,15 +107,50 @@ impl StatusProvider {
         }
     }
 
-    #[inline]
-    pub fn events(&self) -> SliceIter<StatusEventKind> {
-        self.events.iter()
+    fn matches(&self, event: &StatusEvent) -> bool {
+        self.events.contains(&event.kind())
+            && self.script_allowed(event.script_name())
+    }
+}
+
+
+/// The configuration can either be a single rule or a list of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StatusConfig {
+    Single(StatusRule),
+    Multiple(Vec<StatusRule>),
+}
+
+
+#[derive(Debug)]
+pub struct StatusProvider {
+    rules: Vec<StatusRule>,
+}
+
+impl StatusProvider {
+    /// Get all the events the hook subscribes to, without duplicates.
+    pub fn events(&self) -> Vec<StatusEventKind> {
+        let mut events = Vec::new();
+        for rule in &self.rules {
+            for event in &rule.events {
+                if !events.contains(event) {
+                    events.push(*event);
+                }
+            }
+        }
+        events
     }
 }
 
 impl ProviderTrait for StatusProvider {
     fn new(config: &str) -> Result<Self> {
-        Ok(serde_json::from_str(config)?)
+        let rules = match serde_json::from_str(config)? {
+            StatusConfig::Single(rule) => vec![rule],
+            StatusConfig::Multiple(rules) => rules,
+        };
+
+        Ok(StatusProvider { rules })
     }
 
     fn validate(&self, request: &Request) -> RequestType {
@@ -108,17 +161,12 @@ impl ProviderTrait for StatusProvider {
             return RequestType::Invalid;
         }
 
-        // The hook name must be allowed
-        if !self.script_allowed(req.script_name()) {
-            return RequestType::Invalid;
-        }
-
-        // The event must be allowed
-        if !self.events.contains(&req.kind()) {
-            return RequestType::Invalid;
+        // Both the event and the hook name must be allowed by a rule
+        if self.rules.iter().any(|rule| rule.matches(req)) {
+            RequestType::ExecuteHook
+        } else {
+            RequestType::Invalid
         }
-
-        RequestType::ExecuteHook
     }
 
     fn build_env(&self, req: &Request, b: &mut EnvBuilder) -> Result<()> {
@@ -130,18 +178,21 @@ impl ProviderTrait for StatusProvider {
 
         b.add_env("EVENT", req.kind().name());
         b.add_env("SCRIPT_NAME", req.script_name());
+        b.add_env("SUMMARY", match *req {
+            StatusEvent::JobCompleted(ref out)
+            | StatusEvent::JobFailed(ref out) => {
+                out.summary.as_deref().unwrap_or("")
+            }
+            StatusEvent::JobExpired(..) => "",
+        });
 
         match *req {
-            StatusEvent::JobCompleted(ref out) => {
-                b.add_env("SUCCESS", "1");
-                b.add_env("EXIT_CODE", "0");
-                b.add_env("SIGNAL", "");
+            StatusEvent::JobCompleted(ref out)
+            | StatusEvent::JobFailed(ref out) => {
+                b.add_env("SUCCESS", if out.success { "1" } else { "0" });
 
-                write!(b.data_file("stdout")?, "{}", out.stdout)?;
-                write!(b.data_file("stderr")?, "{}", out.stderr)?;
-            }
-            StatusEvent::JobFailed(ref out) => {
-                b.add_env("SUCCESS", "0");
+                // The exit code and signal are always taken from the job
+                // output, instead of assuming them from the event
                 b.add_env("EXIT_CODE", if let Some(c) = out.exit_code {
                     c.to_string()
                 } else {
@@ -153,8 +204,25 @@ impl ProviderTrait for StatusProvider {
                     String::with_capacity(0)
                 });
 
-                write!(b.data_file("stdout")?, "{}", out.stdout)?;
-                write!(b.data_file("stderr")?, "{}", out.stderr)?;
+                b.add_env("CPU_MS", out.cpu_ms.map(|ms| ms.to_string())
+                    .unwrap_or_default());
+                b.add_env("MAXRSS", out.max_rss_kb.map(|kb| kb.to_string())
+                    .unwrap_or_default());
+                b.add_env(
+                    "TRIGGERED_BY", out.triggered_by.as_deref().unwrap_or(""),
+                );
+
+                b.data_file("stdout")?.write_all(out.stdout_bytes())?;
+                b.data_file("stderr")?.write_all(out.stderr_bytes())?;
+            }
+            StatusEvent::JobExpired(..) => {
+                // The job never ran, so there is no output
+                b.add_env("SUCCESS", "0");
+                b.add_env("EXIT_CODE", "");
+                b.add_env("SIGNAL", "");
+                b.add_env("CPU_MS", "");
+                b.add_env("MAXRSS", "");
+                b.add_env("TRIGGERED_BY", "");
             }
         }
 
@@ -176,14 +244,16 @@ mod tests {
     use providers::ProviderTrait;
     use scripts::EnvBuilder;
 
-    use super::{StatusEvent, StatusProvider};
+    use super::{
+        ExpiredJob, StatusEvent, StatusEventKind, StatusProvider, StatusRule,
+    };
 
 
     #[test]
     fn config_script_allowed() {
         macro_rules! assert_custom {
             ($scripts:expr, $check:expr, $expected:expr) => {{
-                let provider = StatusProvider {
+                let provider = StatusRule {
                     scripts: $scripts,
                     events: vec![],
                 };
@@ -209,6 +279,9 @@ mod tests {
             r#"{"events": ["job-completed", "job-failed"]}"#,
             r#"{"events": [], "scripts": []}"#,
             r#"{"events": [], "scripts": ["abc"]}"#,
+            r#"[]"#,
+            r#"[{"events": ["job-failed"], "scripts": ["abc"]}]"#,
+            r#"[{"events": ["job-failed"]}, {"events": ["job-completed"]}]"#,
         ] {
             assert!(StatusProvider::new(&right).is_ok());
         }
@@ -227,6 +300,9 @@ mod tests {
             r#"{"events": [true]}"#,
             r#"{"events": ["invalid_event"]}"#,
             r#"{"events": ["job-completed", "invalid_event"]}"#,
+            r#"[{"scripts": ["abc"]}]"#,
+            r#"[{"events": ["job-failed"]}, {"events": ["invalid_event"]}]"#,
+            r#"["job-failed"]"#,
         ] {
             assert!(StatusProvider::new(&wrong).is_err());
         }
@@ -272,6 +348,66 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_validate_multiple_rules() {
+        let provider = StatusProvider::new(concat!(
+            r#"[{"events": ["job-completed"], "scripts": ["a.sh"]},"#,
+            r#" {"events": ["job-failed"], "scripts": ["b.sh"]}]"#,
+        )).unwrap();
+
+        let event = |name: &str, failed: bool| {
+            let mut output = dummy_job_output();
+            output.script_name = name.into();
+            if failed {
+                StatusEvent::JobFailed(output).into()
+            } else {
+                StatusEvent::JobCompleted(output).into()
+            }
+        };
+
+        // Each rule only matches its own events and scripts
+        assert_eq!(
+            provider.validate(&event("a.sh", false)),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            provider.validate(&event("b.sh", true)),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            provider.validate(&event("a.sh", true)),
+            RequestType::Invalid
+        );
+        assert_eq!(
+            provider.validate(&event("b.sh", false)),
+            RequestType::Invalid
+        );
+        assert_eq!(
+            provider.validate(&event("c.sh", true)),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_events() {
+        let provider = StatusProvider::new(
+            r#"{"events": ["job-failed"]}"#,
+        ).unwrap();
+        assert_eq!(provider.events(), vec![StatusEventKind::JobFailed]);
+
+        // Events subscribed by multiple rules are returned only once
+        let provider = StatusProvider::new(concat!(
+            r#"[{"events": ["job-failed"], "scripts": ["a.sh"]},"#,
+            r#" {"events": ["job-failed", "job-completed"]}]"#,
+        )).unwrap();
+        assert_eq!(provider.events(), vec![
+            StatusEventKind::JobFailed,
+            StatusEventKind::JobCompleted,
+        ]);
+    }
+
+
     #[test]
     fn test_env_builder_job_completed() {
         let provider = StatusProvider::new(
@@ -288,6 +424,10 @@ mod tests {
             "SUCCESS".into() => "1".into(),
             "EXIT_CODE".into() => "0".into(),
             "SIGNAL".into() => "".into(),
+            "SUMMARY".into() => "".into(),
+            "CPU_MS".into() => "150".into(),
+            "MAXRSS".into() => "2048".into(),
+            "TRIGGERED_BY".into() => "".into(),
 
             // File paths
             "STDOUT".into() => "stdout".into(),
@@ -321,6 +461,10 @@ mod tests {
             "SUCCESS".into() => "0".into(),
             "EXIT_CODE".into() => "".into(),
             "SIGNAL".into() => "9".into(),
+            "SUMMARY".into() => "".into(),
+            "CPU_MS".into() => "150".into(),
+            "MAXRSS".into() => "2048".into(),
+            "TRIGGERED_BY".into() => "".into(),
 
             // File paths
             "STDOUT".into() => "stdout".into(),
@@ -331,4 +475,101 @@ mod tests {
             "stderr".into() => "something happened".into(),
         });
     }
+
+
+    #[test]
+    fn test_env_builder_job_expired() {
+        let provider = StatusProvider::new(
+            r#"{"events": ["job-expired"]}"#,
+        ).unwrap();
+
+        let event = StatusEvent::JobExpired(ExpiredJob {
+            script_name: "test".into(),
+            request_ip: "127.0.0.1".parse().unwrap(),
+        });
+        assert_eq!(
+            provider.validate(&event.clone().into()),
+            RequestType::ExecuteHook
+        );
+
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&event.into(), &mut b).unwrap();
+
+        // The job never ran, so there is no output
+        assert_eq!(b.dummy_data().env, hashmap! {
+            "EVENT".into() => "job-expired".into(),
+            "SCRIPT_NAME".into() => "test".into(),
+            "SUCCESS".into() => "0".into(),
+            "EXIT_CODE".into() => "".into(),
+            "SIGNAL".into() => "".into(),
+            "SUMMARY".into() => "".into(),
+            "CPU_MS".into() => "".into(),
+            "MAXRSS".into() => "".into(),
+            "TRIGGERED_BY".into() => "".into(),
+        });
+        assert!(b.dummy_data().files.is_empty());
+    }
+
+
+    #[test]
+    fn test_env_builder_summary() {
+        let provider = StatusProvider::new(
+            r#"{"events": ["job-completed"]}"#,
+        ).unwrap();
+
+        let mut output = dummy_job_output();
+        output.summary = Some("deployed 3 commits".into());
+
+        let event = StatusEvent::JobCompleted(output);
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&event.into(), &mut b).unwrap();
+
+        assert_eq!(
+            b.dummy_data().env.get("SUMMARY"),
+            Some(&"deployed 3 commits".into())
+        );
+    }
+
+
+    #[test]
+    fn test_env_builder_triggered_by() {
+        let provider = StatusProvider::new(
+            r#"{"events": ["job-completed"]}"#,
+        ).unwrap();
+
+        let mut output = dummy_job_output();
+        output.triggered_by = Some("octocat".into());
+
+        let event = StatusEvent::JobCompleted(output);
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&event.into(), &mut b).unwrap();
+
+        assert_eq!(
+            b.dummy_data().env.get("TRIGGERED_BY"),
+            Some(&"octocat".into())
+        );
+    }
+
+
+    #[test]
+    fn test_env_builder_binary_output() {
+        let provider = StatusProvider::new(
+            r#"{"events": ["job-completed"]}"#,
+        ).unwrap();
+
+        let mut output = dummy_job_output();
+        output.stdout = "a\u{fffd}b".into();
+        output.raw_stdout = Some(b"a\xffb".to_vec());
+        output.raw_stderr = Some(Vec::new());
+
+        let event = StatusEvent::JobCompleted(output);
+        let mut b = EnvBuilder::dummy();
+        provider.build_env(&event.into(), &mut b).unwrap();
+
+        // The exact output is written in the data files
+        assert_eq!(b.dummy_data().files, hashmap! {
+            "stdout".into() => b"a\xffb".to_vec(),
+            "stderr".into() => Vec::new(),
+        });
+    }
 }
@@ -0,0 +1,293 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+use hmac::{Hmac, Mac};
+use sha2;
+
+use providers::prelude::*;
+use web::WebRequest;
+use utils;
+
+
+#[derive(Debug, Deserialize)]
+pub struct StripeProvider {
+    signing_secret: String,
+    tolerance: Option<u64>,
+}
+
+impl StripeProvider {
+    fn tolerance(&self) -> u64 {
+        // Stripe's own libraries use five minutes as the default
+        self.tolerance.unwrap_or(300)
+    }
+
+    /// Check the signature of the request, with the current time being
+    /// `now` seconds after the epoch.
+    fn verify(&self, req: &WebRequest, now: u64) -> bool {
+        type HmacSha256 = Hmac<sha2::Sha256>;
+
+        let header = if let Some(header) = req.header("Stripe-Signature") {
+            header
+        } else {
+            return false;
+        };
+
+        // The header contains the timestamp and one or more signatures,
+        // since there could be multiple secrets while rolling them
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for part in header.split(',') {
+            let mut split = part.trim().splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some("t"), Some(value)) => timestamp = value.parse().ok(),
+                (Some("v1"), Some(value)) => signatures.push(value),
+                _ => {}
+            }
+        }
+
+        // Old requests are rejected to prevent replay attacks
+        let timestamp: u64 = if let Some(timestamp) = timestamp {
+            timestamp
+        } else {
+            return false;
+        };
+        if now.abs_diff(timestamp) > self.tolerance() {
+            return false;
+        }
+
+        signatures.iter().any(|signature| {
+            let signature = match utils::from_hex(signature) {
+                Ok(converted) => converted,
+                Err(_) => return false,
+            };
+
+            let mut mac =
+                HmacSha256::new_varkey(self.signing_secret.as_bytes())
+                    .unwrap();
+            mac.input(timestamp.to_string().as_bytes());
+            mac.input(b".");
+            mac.input(req.body_bytes());
+            mac.verify(&signature).is_ok()
+        })
+    }
+}
+
+impl ProviderTrait for StripeProvider {
+    fn new(config: &str) -> Result<Self> {
+        Ok(serde_json::from_str(config)?)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        if self.verify(req, now) {
+            RequestType::ExecuteHook
+        } else {
+            RequestType::Invalid
+        }
+    }
+
+    fn build_env(&self, r: &Request, b: &mut EnvBuilder) -> Result<()> {
+        let req;
+        if let Request::Web(ref inner) = *r {
+            req = inner;
+        } else {
+            return Ok(());
+        }
+
+        let body: serde_json::Value = serde_json::from_str(&req.body)?;
+        if let Some(event) = body.get("type").and_then(|t| t.as_str()) {
+            b.add_env("EVENT", event);
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::RequestType;
+    use web::WebRequest;
+    use providers::ProviderTrait;
+    use scripts::EnvBuilder;
+
+    use super::StripeProvider;
+
+
+    const TIMESTAMP: u64 = 1500000000;
+    const BODY: &str = r#"{"type": "charge.succeeded"}"#;
+
+    // Signatures computed with:
+    // printf '1500000000.<body>' | openssl dgst -sha256 -hmac whsec_secret
+    const SIGNATURE: &str =
+        "497ceceefc8021420dbb87f1e0c26cb68536dc37fa5ee5d6ab471d0fba1b68fb";
+    const OTHER_BODY_SIGNATURE: &str =
+        "af457ee6752339472ce7f109c632f51a304a4b9c4ba9e76cf33553b4f819dcfd";
+    const NON_UTF8_SIGNATURE: &str =
+        "7f61730d71cebffd636805bbcea170a794c4935bffbb0c8878e6eb270e27bb4d";
+
+
+    fn signed_request(header: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.body = BODY.into();
+        req.headers.insert("Stripe-Signature".into(), header.into());
+        req
+    }
+
+
+    fn provider() -> StripeProvider {
+        StripeProvider::new(r#"{"signing_secret": "whsec_secret"}"#).unwrap()
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"signing_secret": "abcde"}"#,
+            r#"{"signing_secret": "abcde", "tolerance": 60}"#,
+        ] {
+            assert!(StripeProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"signing_secret": 12345}"#,
+            r#"{"signing_secret": "abcde", "tolerance": -1}"#,
+            r#"{"signing_secret": "abcde", "tolerance": "5m"}"#,
+        ] {
+            assert!(StripeProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_verify_valid() {
+        let provider = provider();
+
+        for header in &[
+            format!("t={},v1={}", TIMESTAMP, SIGNATURE),
+            // Other schemes and signatures are ignored
+            format!("t={},v0=abcdef,v1=0000,v1={}", TIMESTAMP, SIGNATURE),
+        ] {
+            let req = signed_request(header);
+            assert!(provider.verify(&req, TIMESTAMP), header.to_string());
+            assert!(provider.verify(&req, TIMESTAMP + 300));
+        }
+    }
+
+
+    #[test]
+    fn test_verify_stale_timestamp() {
+        let provider = provider();
+        let req = signed_request(&format!("t={},v1={}", TIMESTAMP, SIGNATURE));
+
+        // Requests too far away from the current time are rejected
+        assert!(!provider.verify(&req, TIMESTAMP + 301));
+        assert!(!provider.verify(&req, TIMESTAMP - 301));
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        // The tolerance can be changed
+        let provider = StripeProvider::new(
+            r#"{"signing_secret": "whsec_secret", "tolerance": 1000}"#,
+        ).unwrap();
+        let req = signed_request(&format!("t={},v1={}", TIMESTAMP, SIGNATURE));
+        assert!(provider.verify(&req, TIMESTAMP + 1000));
+        assert!(!provider.verify(&req, TIMESTAMP + 1001));
+    }
+
+
+    #[test]
+    fn test_verify_tampered() {
+        let provider = provider();
+
+        // The signature of another body is not valid
+        let req = signed_request(
+            &format!("t={},v1={}", TIMESTAMP, OTHER_BODY_SIGNATURE),
+        );
+        assert!(!provider.verify(&req, TIMESTAMP));
+
+        // Changing the body invalidates the signature
+        let mut req =
+            signed_request(&format!("t={},v1={}", TIMESTAMP, SIGNATURE));
+        req.body = r#"{"type": "charge.failed"}"#.into();
+        assert!(!provider.verify(&req, TIMESTAMP));
+
+        // Changing the timestamp invalidates the signature
+        let req = signed_request(
+            &format!("t={},v1={}", TIMESTAMP + 1, SIGNATURE),
+        );
+        assert!(!provider.verify(&req, TIMESTAMP));
+
+        // Malformed or missing headers are rejected
+        for header in &[
+            format!("v1={}", SIGNATURE),
+            format!("t={}", TIMESTAMP),
+            format!("t=abc,v1={}", SIGNATURE),
+            format!("t={},v1=invalid", TIMESTAMP),
+            "".to_string(),
+        ] {
+            let req = signed_request(header);
+            assert!(!provider.verify(&req, TIMESTAMP), header.to_string());
+        }
+
+        let mut req = signed_request("");
+        req.headers.remove("Stripe-Signature");
+        assert!(!provider.verify(&req, TIMESTAMP));
+    }
+
+
+    #[test]
+    fn test_verify_non_utf8_body() {
+        let provider = provider();
+        let mut req = signed_request(
+            &format!("t={},v1={}", TIMESTAMP, NON_UTF8_SIGNATURE),
+        );
+
+        // The signature is checked against the original bytes, not against
+        // the body with the invalid sequences replaced
+        let raw = b"{\"data\": \"\xff\xfe\"}".to_vec();
+        req.body = String::from_utf8_lossy(&raw).into_owned();
+        req.raw_body = Some(raw);
+        assert!(provider.verify(&req, TIMESTAMP));
+
+        req.raw_body = None;
+        assert!(!provider.verify(&req, TIMESTAMP));
+    }
+
+
+    #[test]
+    fn test_build_env() {
+        let mut b = EnvBuilder::dummy();
+        provider().build_env(&signed_request("").into(), &mut b).unwrap();
+        assert_eq!(b.dummy_data().env, hashmap! {
+            "EVENT".into() => "charge.succeeded".into(),
+        });
+    }
+}
@@ -13,8 +13,10 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use common::prelude::*;
-use web::WebRequest;
+use web::{random_request_id, WebRequest};
 use providers::StatusEvent;
 
 
@@ -26,10 +28,32 @@ pub enum RequestType {
 }
 
 
+/// The request of a job started by Fisher itself on the schedule of its
+/// script, instead of by a webhook.
+#[derive(Debug, Clone)]
+pub struct ScheduledRequest {
+    pub id: String,
+    pub received_at: u64,
+}
+
+impl ScheduledRequest {
+    pub fn new() -> Self {
+        ScheduledRequest {
+            id: random_request_id(),
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+
 #[derive(Debug, Clone)]
 pub enum Request {
     Web(WebRequest),
     Status(StatusEvent),
+    Scheduled(ScheduledRequest),
 }
 
 impl Request {
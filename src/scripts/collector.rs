@@ -13,11 +13,15 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::fs::{canonicalize, read_dir, ReadDir};
+use std::fs::{canonicalize, read_dir, File, ReadDir};
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::collections::VecDeque;
-use std::os::unix::fs::PermissionsExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::sync::Arc;
+use std::time::SystemTime;
+
+use ansi_term::Colour;
 
 use common::prelude::*;
 use common::state::State;
@@ -25,11 +29,27 @@ use common::state::State;
 use scripts::Script;
 
 
+/// The scripts already loaded, along with the modification times of their
+/// files and of their sidecar files, indexed by the path they were collected
+/// from.
+pub(in scripts) type ScriptsCache =
+    HashMap<PathBuf, (Vec<Option<SystemTime>>, Arc<Script>)>;
+
+
 pub(in scripts) struct Collector {
-    dirs: VecDeque<ReadDir>,
+    dirs: VecDeque<(ReadDir, usize)>,
     state: Arc<State>,
     base: PathBuf,
     recursive: bool,
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+    default_shell: Option<String>,
+    public_hooks: Option<Vec<String>>,
+    strict_permissions: bool,
+    disable_status_hooks: bool,
+    visited: HashSet<(u64, u64)>,
+    previous: ScriptsCache,
+    collected: ScriptsCache,
 }
 
 impl Collector {
@@ -37,32 +57,153 @@ impl Collector {
         base: P,
         state: Arc<State>,
         recursive: bool,
+        follow_symlinks: bool,
     ) -> Result<Self> {
+        if !base.as_ref().exists() {
+            return Err(ErrorKind::ScriptsPathNotFound(
+                base.as_ref().to_string_lossy().into(),
+            ).into());
+        }
+
         let mut dirs = VecDeque::new();
-        dirs.push_front(read_dir(&base)?);
+        dirs.push_front((read_dir(&base)?, 0));
+
+        // Mark the base directory as visited, to avoid symlinks pointing to
+        // it being searched again
+        let metadata = base.as_ref().metadata()?;
+        let mut visited = HashSet::new();
+        visited.insert((metadata.dev(), metadata.ino()));
 
         Ok(Collector {
-            dirs: dirs,
-            state: state,
+            dirs,
+            state,
             base: base.as_ref().to_path_buf(),
-            recursive: recursive,
+            recursive,
+            follow_symlinks,
+            max_depth: None,
+            default_shell: None,
+            public_hooks: None,
+            strict_permissions: false,
+            disable_status_hooks: false,
+            visited,
+            previous: HashMap::new(),
+            collected: HashMap::new(),
         })
     }
 
+    /// Reuse the scripts loaded by a previous collection, if their files
+    /// weren't modified since then.
+    pub(in scripts) fn reuse_scripts(&mut self, previous: ScriptsCache) {
+        self.previous = previous;
+    }
+
+    /// Get the scripts collected so far, to be reused by the next collection.
+    pub(in scripts) fn into_cache(self) -> ScriptsCache {
+        self.collected
+    }
+
+    /// Set the shell used to run the scripts without a shebang. If it's not
+    /// set, those scripts are rejected.
+    pub(in scripts) fn set_default_shell(&mut self, shell: Option<String>) {
+        self.default_shell = shell;
+    }
+
+    /// Set the only scripts allowed to be triggered without authentication,
+    /// rejecting the other public ones. If it's not set, every script is
+    /// allowed to be public.
+    pub(in scripts) fn set_public_hooks(
+        &mut self, hooks: Option<Vec<String>>,
+    ) {
+        self.public_hooks = hooks;
+    }
+
+    /// Reject the directories and the scripts writable by other users,
+    /// instead of only warning about them.
+    pub(in scripts) fn set_strict_permissions(&mut self, strict: bool) {
+        self.strict_permissions = strict;
+    }
+
+    /// Reject the scripts using the Status provider, so no status hook is
+    /// ever loaded.
+    pub(in scripts) fn set_disable_status_hooks(&mut self, disable: bool) {
+        self.disable_status_hooks = disable;
+    }
+
+    /// Check if other users can write to the base directory, which would
+    /// allow them to add new scripts.
+    pub(in scripts) fn check_base_permissions(&self) -> Result<()> {
+        let mode = self.base.metadata()?.permissions().mode();
+        check_permissions(&self.base, mode, self.strict_permissions)
+    }
+
+    /// Set how many levels of subdirectories are searched when the
+    /// collection is recursive, or remove the limit.
+    pub(in scripts) fn set_max_depth(&mut self, max: Option<usize>) {
+        self.max_depth = max;
+    }
+
+    fn collect_dir(&mut self, e: &Path) -> Result<()> {
+        if !self.recursive {
+            return Ok(());
+        }
+
+        // Don't descend past the maximum depth
+        let depth = self.dirs.front().map_or(0, |&(_, depth)| depth) + 1;
+        if self.max_depth.is_some_and(|max| depth > max) {
+            println!(
+                "{} not searching {}, since it's deeper than {} directories",
+                Colour::Yellow.bold().paint("Warning:"),
+                e.to_string_lossy(),
+                depth - 1,
+            );
+            return Ok(());
+        }
+
+        // Skip symlinked directories if they shouldn't be followed
+        if !self.follow_symlinks
+            && e.symlink_metadata()?.file_type().is_symlink()
+        {
+            return Ok(());
+        }
+
+        // Skip directories already visited, which prevents symlink loops
+        let metadata = e.metadata()?;
+        if self.visited.insert((metadata.dev(), metadata.ino())) {
+            // Other users could add scripts to the subdirectories too
+            let mode = metadata.permissions().mode();
+            check_permissions(e, mode, self.strict_permissions)?;
+
+            self.dirs.push_back((read_dir(e)?, depth));
+        }
+
+        Ok(())
+    }
+
     fn collect_file(&mut self, e: PathBuf) -> Result<Option<Arc<Script>>> {
         if e.is_dir() {
-            if self.recursive {
-                self.dirs.push_back(read_dir(&e)?);
-            }
+            self.collect_dir(&e)?;
             return Ok(None);
         }
 
         // Check if the file is executable and readable
-        let mode = e.metadata()?.permissions().mode();
+        let metadata = e.metadata()?;
+        let mode = metadata.permissions().mode();
         if !((mode & 0o111) != 0 && (mode & 0o444) != 0) {
             // Skip files with wrong permissions
             return Ok(None);
         }
+        check_permissions(&e, mode, self.strict_permissions)?;
+
+        // Don't parse the script again if neither it nor its sidecar files
+        // changed since the previous collection, keeping its ID stable
+        let modified = metadata.modified()?;
+        if let Some((previous, script)) = self.previous.remove(&e) {
+            let current = modification_times(modified, &script);
+            if previous == current {
+                self.collected.insert(e, (current, script.clone()));
+                return Ok(Some(script));
+            }
+        }
 
         // Try to remove the prefix from the path
         let name = match e.strip_prefix(&self.base) {
@@ -74,8 +215,83 @@ impl Collector {
 
         let exec = canonicalize(&e)?.to_str().unwrap().into();
 
-        Ok(Some(Arc::new(Script::load(name, exec, &self.state)?)))
+        // Scripts without a shebang can't be executed directly by the
+        // kernel, but they're still executed as before if there is no
+        // default shell to run them with
+        let runnable = is_directly_executable(&e)?;
+        if !runnable && self.default_shell.is_none() {
+            println!(
+                "{} the script {} doesn't start with a shebang (like \
+                 #!/bin/bash), and no scripts.default-shell is configured",
+                Colour::Yellow.bold().paint("Warning:"),
+                name,
+            );
+        }
+
+        let mut script = Script::load(name, exec, &self.state)?;
+        if let Some(ref allowed) = self.public_hooks {
+            let name = script.name().to_string();
+            if script.is_public() && !allowed.contains(&name) {
+                return Err(ErrorKind::ScriptPublicNotAllowed(name).into());
+            }
+        }
+        if self.disable_status_hooks && script.uses_status_provider() {
+            let name = script.name().to_string();
+            return Err(ErrorKind::ScriptStatusNotAllowed(name).into());
+        }
+        if !runnable {
+            script.set_interpreter(self.default_shell.clone());
+        }
+
+        let script = Arc::new(script);
+        let current = modification_times(modified, &script);
+        self.collected.insert(e, (current, script.clone()));
+
+        Ok(Some(script))
+    }
+}
+
+
+/// Check if other users can write to a path, which would allow them to
+/// change or add the scripts executed by Fisher. The path is rejected when
+/// `strict` is enabled, otherwise only a warning is shown.
+fn check_permissions(path: &Path, mode: u32, strict: bool) -> Result<()> {
+    if mode & 0o022 == 0 {
+        return Ok(());
+    }
+
+    let path = path.to_string_lossy().to_string();
+    if strict {
+        return Err(ErrorKind::InsecurePermissions(path).into());
+    }
+
+    println!(
+        "{} {} is writable by other users, who could change the scripts",
+        Colour::Yellow.bold().paint("Warning:"),
+        path,
+    );
+    Ok(())
+}
+
+
+/// Check if a file starts with a shebang, or if it's a native executable.
+fn is_directly_executable(path: &Path) -> Result<bool> {
+    let mut magic = Vec::with_capacity(4);
+    File::open(path)?.take(4).read_to_end(&mut magic)?;
+
+    Ok(magic.starts_with(b"#!") || magic == b"\x7fELF")
+}
+
+/// Get the modification times of a script and of its sidecar files, which
+/// are `None` if a sidecar file can't be read anymore.
+fn modification_times(
+    modified: SystemTime, script: &Script,
+) -> Vec<Option<SystemTime>> {
+    let mut times = vec![Some(modified)];
+    for sidecar in script.sidecars() {
+        times.push(sidecar.metadata().and_then(|m| m.modified()).ok());
     }
+    times
 }
 
 impl Iterator for Collector {
@@ -83,7 +299,9 @@ impl Iterator for Collector {
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let entry = if let Some(iter) = self.dirs.get_mut(0) {
+            let entry = if let Some(&mut (ref mut iter, _)) =
+                self.dirs.get_mut(0)
+            {
                 iter.next()
             } else {
                 // No more directories to search in
@@ -122,7 +340,8 @@ impl Iterator for Collector {
 
 #[cfg(test)]
 mod tests {
-    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::os::unix::fs as unix_fs;
     use std::fs;
 
     use common::prelude::*;
@@ -136,9 +355,25 @@ mod tests {
         recurse: bool,
         expected: &[&str],
     ) -> Result<()> {
-        let mut found = 0;
+        assert_collected_with(env, recurse, true, expected)
+    }
+
+
+    fn assert_collected_with(
+        env: &TestEnv,
+        recurse: bool,
+        follow_symlinks: bool,
+        expected: &[&str],
+    ) -> Result<()> {
+        let c = Collector::new(
+            &env.scripts_dir(), env.state(), recurse, follow_symlinks,
+        )?;
+        assert_collected_by(c, expected)
+    }
 
-        let c = Collector::new(&env.scripts_dir(), env.state(), recurse)?;
+
+    fn assert_collected_by(c: Collector, expected: &[&str]) -> Result<()> {
+        let mut found = 0;
         for script in c {
             found += 1;
 
@@ -185,6 +420,71 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_scripts_collection_follows_symlinks_only_if_enabled() {
+        test_wrapper(|env| {
+            env.create_script("first.sh", &[])?;
+
+            // Create a directory outside of the scripts one, and link it
+            let dir = env.tempdir()?;
+            env.create_script_into(&dir, "linked.sh", &[])?;
+            unix_fs::symlink(&dir, env.scripts_dir().join("link"))?;
+
+            // Create a symlink loop, which must not be followed
+            let base = env.scripts_dir();
+            unix_fs::symlink(&base, base.join("loop"))?;
+
+            assert_collected_with(
+                &env, true, true, &["first.sh", "link/linked.sh"],
+            )?;
+            assert_collected_with(&env, true, false, &["first.sh"])?;
+
+            // Symlinks are not followed if the collection is not recursive
+            assert_collected_with(&env, false, true, &["first.sh"])?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_scripts_collection_stops_at_the_max_depth() {
+        test_wrapper(|env| {
+            env.create_script("top.sh", &[])?;
+
+            // Create a script at each level of a deeply nested tree
+            let mut dir = env.scripts_dir();
+            for level in 1..6 {
+                dir = dir.join(format!("d{}", level));
+                fs::create_dir(&dir)?;
+                env.create_script_into(
+                    &dir, &format!("level{}.sh", level), &[],
+                )?;
+            }
+
+            let collector = |max| -> Result<Collector> {
+                let mut c = Collector::new(
+                    &env.scripts_dir(), env.state(), true, true,
+                )?;
+                c.set_max_depth(max);
+                Ok(c)
+            };
+
+            assert_collected_by(collector(Some(0))?, &["top.sh"])?;
+            assert_collected_by(collector(Some(2))?, &[
+                "top.sh", "d1/level1.sh", "d1/d2/level2.sh",
+            ])?;
+            assert_collected_by(collector(None)?, &[
+                "top.sh", "d1/level1.sh", "d1/d2/level2.sh",
+                "d1/d2/d3/level3.sh", "d1/d2/d3/d4/level4.sh",
+                "d1/d2/d3/d4/d5/level5.sh",
+            ])?;
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_scripts_collection_with_invalid_scripts_fails() {
         test_wrapper(|env| {
@@ -219,4 +519,167 @@ mod tests {
             Ok(())
         })
     }
+
+
+    #[test]
+    fn test_scripts_collection_without_shebang() {
+        test_wrapper(|env| {
+            env.create_script("valid.sh", &[r#"#!/bin/bash"#])?;
+            env.create_script("no-shebang.sh", &[r#"echo "Hello world""#])?;
+
+            // Without a default shell the script is executed as before
+            let c = Collector::new(
+                &env.scripts_dir(), env.state(), false, true,
+            )?;
+            for script in c {
+                assert_eq!(script?.interpreter(), None);
+            }
+
+            // With a default shell the script is executed with it
+            let mut c = Collector::new(
+                &env.scripts_dir(), env.state(), false, true,
+            )?;
+            c.set_default_shell(Some("/bin/sh".into()));
+            for script in c {
+                let script = script?;
+                match script.name() {
+                    "valid.sh" => assert_eq!(script.interpreter(), None),
+                    "no-shebang.sh" => {
+                        assert_eq!(script.interpreter(), Some("/bin/sh"));
+                    }
+                    other => panic!("Unexpected script collected: {}", other),
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_scripts_collection_with_public_hooks() {
+        test_wrapper(|env| {
+            env.create_script("public.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Standalone: {}"#,
+            ])?;
+            env.create_script("secret.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Standalone: {"secret": "abcde"}"#,
+            ])?;
+
+            // Without a list every script is allowed to be public
+            assert_collected(&env, false, &["public.sh", "secret.sh"])?;
+
+            // Public scripts not in the list are rejected
+            let mut c = Collector::new(
+                &env.scripts_dir(), env.state(), false, true,
+            )?;
+            c.set_public_hooks(Some(vec!["other.sh".into()]));
+            let error = c.find(|result| result.is_err()).unwrap().unwrap_err();
+            match *error.kind() {
+                ErrorKind::ScriptPublicNotAllowed(ref name) => {
+                    assert_eq!(name, "public.sh");
+                }
+                ref other => panic!("wrong error returned: {}", other),
+            }
+
+            // The scripts in the list are loaded
+            let mut c = Collector::new(
+                &env.scripts_dir(), env.state(), false, true,
+            )?;
+            c.set_public_hooks(Some(vec!["public.sh".into()]));
+            let mut names = c
+                .map(|script| script.map(|s| s.name().to_string()))
+                .collect::<Result<Vec<_>>>()?;
+            names.sort();
+            assert_eq!(names, vec!["public.sh", "secret.sh"]);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_scripts_collection_with_strict_permissions() {
+        test_wrapper(|env| {
+            env.create_script("valid.sh", &[r#"#!/bin/bash"#])?;
+            env.create_script("writable.sh", &[r#"#!/bin/bash"#])?;
+            let writable = env.scripts_dir().join("writable.sh");
+            fs::set_permissions(&writable, fs::Permissions::from_mode(0o777))?;
+
+            // By default writable scripts are loaded with a warning
+            assert_collected(&env, false, &["valid.sh", "writable.sh"])?;
+
+            // With strict permissions they're rejected
+            let mut c = Collector::new(
+                &env.scripts_dir(), env.state(), false, true,
+            )?;
+            c.set_strict_permissions(true);
+            c.check_base_permissions()?;
+            let error = c.find(|result| result.is_err()).unwrap().unwrap_err();
+            match *error.kind() {
+                ErrorKind::InsecurePermissions(ref path) => {
+                    assert_eq!(path, writable.to_str().unwrap());
+                }
+                ref other => panic!("wrong error returned: {}", other),
+            }
+
+            // The base directory is checked too
+            fs::remove_file(&writable)?;
+            fs::set_permissions(
+                &env.scripts_dir(), fs::Permissions::from_mode(0o777),
+            )?;
+            let mut c = Collector::new(
+                &env.scripts_dir(), env.state(), false, true,
+            )?;
+            c.check_base_permissions()?;
+            c.set_strict_permissions(true);
+            assert!(c.check_base_permissions().is_err());
+
+            // The subdirectories searched by the collector are checked too
+            fs::set_permissions(
+                &env.scripts_dir(), fs::Permissions::from_mode(0o755),
+            )?;
+            let subdir = env.scripts_dir().join("subdir");
+            fs::create_dir(&subdir)?;
+            env.create_script_into(&subdir, "nested.sh", &["#!/bin/bash"])?;
+            fs::set_permissions(&subdir, fs::Permissions::from_mode(0o777))?;
+
+            assert_collected(&env, true, &["valid.sh", "subdir/nested.sh"])?;
+
+            let mut c = Collector::new(
+                &env.scripts_dir(), env.state(), true, true,
+            )?;
+            c.set_strict_permissions(true);
+            c.check_base_permissions()?;
+            let error = c.find(|result| result.is_err()).unwrap().unwrap_err();
+            match *error.kind() {
+                ErrorKind::InsecurePermissions(ref path) => {
+                    assert_eq!(path, subdir.to_str().unwrap());
+                }
+                ref other => panic!("wrong error returned: {}", other),
+            }
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_scripts_collection_fails_if_the_path_is_missing() {
+        test_wrapper(|env| {
+            let missing = env.scripts_dir().join("missing");
+            let result = Collector::new(&missing, env.state(), false, true);
+
+            match result {
+                Err(Error(ErrorKind::ScriptsPathNotFound(path), _)) => {
+                    assert_eq!(path, missing.to_str().unwrap());
+                }
+                _ => panic!("the missing path wasn't reported"),
+            }
+
+            Ok(())
+        })
+    }
 }
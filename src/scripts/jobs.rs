@@ -13,40 +13,72 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::Write;
-use std::net::IpAddr;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::io::FromRawFd;
 use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
-use std::sync::Arc;
-
-use nix::unistd::{setpgid, Pid};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ansi_term::Colour;
+use nix::libc;
+use regex::Regex;
+use serde_json;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::mount::{mount, MsFlags};
+use nix::pty::openpty;
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet};
+use nix::unistd::{self, close, setpgid, setsid, Pid};
 use tempdir::TempDir;
 use users;
 
 use common::prelude::*;
 use common::state::UniqueId;
+use common::structs::JobFailure;
 
-use scripts::Script;
+use scripts::{Script, SigpipeHandling};
 use requests::Request;
 use providers::Provider;
 
 
-static DEFAULT_ENV: &[&'static str] = &[
+static DEFAULT_ENV: &[&str] = &[
     "PATH", "LC_ALL", "LANG",
 ];
 
-static ENV_PREFIX: &'static str = "FISHER";
+static ENV_PREFIX: &str = "FISHER";
+static SUMMARY_MARKER: &str = "FISHER_SUMMARY:";
+
+/// How long to wait before trying again to remove a temporary directory.
+const CLEANUP_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+
+lazy_static! {
+    // CSI sequences (like colors), OSC sequences (like window titles) and
+    // the other two-characters escape sequences
+    static ref ANSI_ESCAPE_RE: Regex = Regex::new(concat!(
+        r"\x1b\[[0-?]*[ -/]*[@-~]",
+        r"|\x1b\][^\x07\x1b]*(\x07|\x1b\\)",
+        r"|\x1b[@-Z\\-_]",
+    )).unwrap();
+}
 
 
 #[derive(Debug)]
 pub struct Context {
     pub environment: HashMap<String, String>,
     pub username: String,
+    pub default_timeout: Option<Duration>,
+    pub audit_log: Option<PathBuf>,
+    pub cleanup_retries: u32,
+    pub max_env_size: Option<usize>,
 }
 
 impl Default for Context {
@@ -63,6 +95,10 @@ impl Default for Context {
         Context {
             environment: HashMap::new(),
             username,
+            default_timeout: None,
+            audit_log: None,
+            cleanup_retries: 0,
+            max_env_size: None,
         }
     }
 }
@@ -183,7 +219,7 @@ impl<'job> EnvBuilder<'job> {
 
     pub fn data_file<'a, P: AsRef<Path>>(
         &'a mut self, path: P,
-    ) -> Result<&'a mut Write> {
+    ) -> Result<&'a mut dyn Write> {
         let env = path.as_ref().to_str().unwrap()
             .chars()
             .map(|c| c.to_uppercase().to_string())
@@ -196,7 +232,7 @@ impl<'job> EnvBuilder<'job> {
                 inner.command.env(name, &dest);
 
                 inner.last_file = Some(fs::File::create(&dest)?);
-                Ok(inner.last_file.as_mut().unwrap() as &mut Write)
+                Ok(inner.last_file.as_mut().unwrap() as &mut dyn Write)
             }
             #[cfg(test)]
             EnvBuilderInner::Dummy(ref mut inner) => {
@@ -216,6 +252,8 @@ pub struct Job {
     script: Arc<Script>,
     provider: Option<Arc<Provider>>,
     request: Request,
+    parallel_key: Option<String>,
+    queue_depth: usize,
 }
 
 impl Job {
@@ -224,10 +262,14 @@ impl Job {
         provider: Option<Arc<Provider>>,
         request: Request,
     ) -> Job {
+        let parallel_key = script.parallel_key(&request);
+
         Job {
             script,
             provider,
             request,
+            parallel_key,
+            queue_depth: 0,
         }
     }
 
@@ -235,9 +277,34 @@ impl Job {
         match self.request {
             Request::Web(ref req) => req.source,
             Request::Status(ref req) => req.source_ip(),
+            // Scheduled jobs are started by Fisher itself
+            Request::Scheduled(..) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        }
+    }
+
+    /// Get the ID of the request, if it's a web request or a scheduled run.
+    pub fn request_id(&self) -> Option<&str> {
+        match self.request {
+            Request::Web(ref req) => Some(&req.id),
+            Request::Scheduled(ref req) => Some(&req.id),
+            Request::Status(..) => None,
         }
     }
 
+    /// Get the name of the user who triggered the job, if the provider was
+    /// able to identify them.
+    pub fn triggered_by(&self) -> Option<String> {
+        self.provider.as_ref()
+            .and_then(|provider| provider.triggered_by(&self.request))
+    }
+
+    /// Get the ID the sender assigned to the delivery of the request, if the
+    /// provider knows about it.
+    pub fn delivery_key(&self) -> Option<String> {
+        self.provider.as_ref()
+            .and_then(|provider| provider.delivery_key(&self.request))
+    }
+
     pub fn trigger_status_hooks(&self) -> bool {
         if let Some(ref provider) = self.provider {
             provider.trigger_status_hooks(&self.request)
@@ -247,7 +314,13 @@ impl Job {
     }
 
     fn process(&self, ctx: &Context) -> Result<JobOutput> {
-        let mut command = Command::new(&self.script.exec());
+        let mut command = if let Some(shell) = self.script.interpreter() {
+            let mut command = Command::new(shell);
+            command.arg(self.script.exec());
+            command
+        } else {
+            Command::new(&self.script.exec())
+        };
 
         // Use random directories
         let working_directory = TempDir::new("fisher")?;
@@ -267,32 +340,208 @@ impl Job {
         // Set the request IP
         command.env("FISHER_REQUEST_IP", self.request_ip().to_string());
 
+        // Set the priority of the hook
+        command.env("FISHER_HOOK_PRIORITY", self.script.priority().to_string());
+
+        // Set the path of the script, to locate the files next to it
+        command.env("FISHER_SCRIPT_PATH", self.script.exec());
+
+        // Set the delivery ID, for scripts recognizing repeated deliveries
+        if let Some(key) = self.delivery_key() {
+            command.env("FISHER_DELIVERY_KEY", key);
+        }
+
+        // Set the queue depth, for scripts adapting to the load
+        command.env("FISHER_QUEUE_DEPTH", self.queue_depth.to_string());
+
         // Save the request body
         let request_body = self.save_request_body(data_directory.path())?;
         if let Some(path) = request_body {
             command.env("FISHER_REQUEST_BODY", path.to_str().unwrap());
         }
 
+        // Set the details only web requests and scheduled runs have
+        let details = match self.request {
+            Request::Web(ref req) => {
+                command.env(
+                    "FISHER_REQUEST_SIZE", req.body_bytes().len().to_string(),
+                );
+                Some((&req.id, req.received_at))
+            },
+            Request::Scheduled(ref req) => Some((&req.id, req.received_at)),
+            Request::Status(..) => None,
+        };
+        if let Some((id, received_at)) = details {
+            command.env("FISHER_REQUEST_ID", id);
+            command.env("FISHER_REQUEST_TIME", received_at.to_string());
+
+            // The ID is also saved in the working directory, so the files
+            // created by the script can be tied back to the request
+            let mut file = fs::File::create(
+                working_directory.path().join("FISHER_REQUEST_ID"),
+            )?;
+            writeln!(file, "{}", id)?;
+        }
+
         // Apply the custom environment
         for (key, value) in ctx.environment.iter() {
             command.env(&key, &value);
         }
 
+        // The OS refuses to execute the job with an unhelpful error if the
+        // environment is too big, so it's checked in advance
+        if let Some(max) = ctx.max_env_size {
+            let size = environment_size(&command);
+            if size > max {
+                return Err(
+                    ErrorKind::JobEnvironmentTooLarge(size, max).into(),
+                );
+            }
+        }
+
         // Make sure the process is isolated
-        command.before_exec(|| {
-            // If a new process group is not created, the job still works fine
-            let _ = setpgid(Pid::this(), Pid::from_raw(0));
+        let sigpipe = self.script.sigpipe();
+        let isolate = self.script.isolate();
+        let new_session = self.script.new_session();
+        command.before_exec(move || {
+            // If a new process group is not created, the job still works fine.
+            // A new session also creates a new process group, and detaches
+            // the job from the controlling terminal.
+            if new_session {
+                let _ = setsid();
+            } else {
+                let _ = setpgid(Pid::this(), Pid::from_raw(0));
+            }
+
+            // Like the process group, the job runs anyway if it can't get its
+            // own mount namespace, but a warning is added to its output
+            if isolate && isolate_mounts().is_err() {
+                let _ = unistd::write(
+                    libc::STDERR_FILENO,
+                    b"fisher: unable to isolate the job's mounts\n",
+                );
+            }
+
+            // The SIGPIPE handler is already reset to the default one when
+            // the process is spawned, so it only needs to be changed if the
+            // script wants to ignore it
+            if sigpipe == SigpipeHandling::Ignore {
+                let action = SigAction::new(
+                    SigHandler::SigIgn, SaFlags::empty(), SigSet::empty(),
+                );
+                unsafe {
+                    signal::sigaction(signal::SIGPIPE, &action)
+                        .map_err(|_| ::std::io::Error::last_os_error())?;
+                }
+            }
 
             Ok(())
         });
 
-        // Execute the hook
-        let output = command.output()?;
+        // Execute the hook, killing it if it runs for too long
+        let timeout = self.timeout(ctx);
+        let (output, usage) = if self.script.pty() {
+            output_with_pty(&mut command, timeout)?
+        } else {
+            command.stdin(Stdio::null());
+            command.stdout(Stdio::piped());
+            command.stderr(Stdio::piped());
+
+            let mut child = command.spawn()?;
+            let _watchdog = Watchdog::start(child.id(), timeout);
+
+            // The output is read while waiting for the child, since it could
+            // otherwise fill the pipes and block forever
+            let stdout = read_in_background(child.stdout.take().unwrap());
+            let stderr = read_in_background(child.stderr.take().unwrap());
+            let (status, usage) = wait_with_usage(&child)?;
+
+            (Output {
+                status,
+                stdout: stdout.join().unwrap()?,
+                stderr: stderr.join().unwrap()?,
+            }, usage)
+        };
+
+        // Remove the temp directories, keeping track of the ones which can't
+        // be removed (for example if a background process is still using
+        // some files in them)
+        let leaked_dirs = vec![working_directory, data_directory]
+            .into_iter()
+            .filter(|directory| {
+                !remove_temp_dir(directory.path(), ctx.cleanup_retries)
+            })
+            .count();
+
+        let mut output = JobOutput::new(self, output);
+        output.leaked_dirs = leaked_dirs;
+        output.cpu_ms = Some(usage.cpu_ms);
+        output.max_rss_kb = Some(usage.max_rss_kb);
+        // The job was executed anyway, so failing to record it in the audit
+        // log doesn't fail the job
+        if let Some(ref path) = ctx.audit_log {
+            if let Err(err) = self.write_audit_entry(path, &output) {
+                println!(
+                    "{} failed to write to the audit log {}: {}",
+                    Colour::Yellow.bold().paint("Warning:"),
+                    path.display(),
+                    err,
+                );
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Append a line describing the executed job to the audit log.
+    fn write_audit_entry(
+        &self, path: &Path, output: &JobOutput,
+    ) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+
+        let result = match (output.exit_code, output.signal) {
+            (Some(code), _) => format!("exit={}", code),
+            (None, Some(signal)) => format!("signal={}", signal),
+            (None, None) => "exit=unknown".into(),
+        };
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        // The name of the user comes from the request, so it must not be
+        // able to add fields or lines to the log
+        let user = output.triggered_by.as_ref().map(|user| {
+            user.replace(|c: char| c.is_whitespace() || c.is_control(), "_")
+        });
+
+        // The whole line is written at once, so the lines of jobs finishing
+        // at the same time aren't interleaved
+        let line = format!(
+            "{} request={} hook={} ip={} {} user={}\n",
+            timestamp,
+            self.request_id().unwrap_or("-"),
+            self.script.name(),
+            output.request_ip,
+            result,
+            user.as_deref().unwrap_or("-"),
+        );
+        file.write_all(line.as_bytes())?;
 
-        // The temp directory is dropped - and removed - here
+        Ok(())
+    }
 
-        // Return the job output
-        Ok(JobOutput::new(self, output))
+    /// Get how long the job is allowed to run for: the script's own timeout
+    /// overrides the default one, and zero means no timeout at all.
+    fn timeout(&self, ctx: &Context) -> Option<Duration> {
+        match self.script.timeout() {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => ctx.default_timeout,
+        }
     }
 
     fn prepare_env(
@@ -307,43 +556,259 @@ impl Job {
         // Apply the default environment
         // This is done (instead of the automatic inheritage) to whitelist
         // which environment variables we want
-        for (key, value) in env::vars() {
-            // Set only whitelisted keys
-            if !DEFAULT_ENV.contains(&key.as_str()) {
-                continue;
-            }
+        // Scripts with a clean environment don't get even those
+        if !self.script.clean_env() {
+            for (key, value) in env::vars() {
+                // Set only whitelisted keys
+                if !DEFAULT_ENV.contains(&key.as_str()) {
+                    continue;
+                }
 
-            builder.add_env_unprefixed(key, value);
+                builder.add_env_unprefixed(key, value);
+            }
         }
 
         if let Some(ref provider) = self.provider {
             builder.set_prefix(Some(provider.name()));
             provider.build_env(&self.request, builder)?;
+
+            // The event is also exported without the provider name, so
+            // scripts accepting multiple providers can use a single variable
+            if self.script.normalize_event() {
+                let event = provider.normalized_event(&self.request);
+
+                builder.set_prefix(None);
+                if let Some(event) = event {
+                    builder.add_env("EVENT", event);
+                }
+            }
         }
 
         builder.set_prefix(None);
 
+        // Provide all the headers of the request if the script asks for them
+        if self.script.request_headers() {
+            if let Request::Web(ref req) = self.request {
+                let headers = req.headers.iter().collect::<BTreeMap<_, _>>();
+                let file = builder.data_file("request_headers")?;
+                serde_json::to_writer(file, &headers)?;
+            }
+        }
+
         Ok(())
     }
 
     fn save_request_body(&self, base: &Path) -> Result<Option<PathBuf>> {
         // Get the request body, even if some request kinds don't have one
         let body = match self.request {
-            Request::Web(ref req) => &req.body,
-            Request::Status(..) => return Ok(None),
+            Request::Web(ref req) => req.body_bytes(),
+            Request::Status(..) | Request::Scheduled(..) => return Ok(None),
         };
 
         let mut path = base.to_path_buf();
         path.push("request_body");
 
-        // Write the request body on disk
+        // Write the request body on disk, adding a trailing newline unless
+        // the script wants the exact body
         let mut file = fs::File::create(&path)?;
-        write!(file, "{}\n", body)?;
+        file.write_all(body)?;
+        if !self.script.raw_body() {
+            file.write_all(b"\n")?;
+        }
 
         Ok(Some(path))
     }
 }
 
+/// Move the current process to a new mount namespace, without propagating
+/// the mount events between it and the host one.
+fn isolate_mounts() -> ::nix::Result<()> {
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    mount(
+        None::<&str>, "/", None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>,
+    )
+}
+
+
+/// Kill a running job, along with its process group, if it's still running
+/// when the timeout expires. The job is left alone once this is dropped.
+struct Watchdog {
+    _stop: Option<mpsc::Sender<()>>,
+}
+
+impl Watchdog {
+    fn start(pid: u32, timeout: Option<Duration>) -> Self {
+        let timeout = if let Some(timeout) = timeout {
+            timeout
+        } else {
+            return Watchdog { _stop: None };
+        };
+
+        let (stop_send, stop_recv) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            // Dropping the sender disconnects the channel, stopping the wait
+            if let Err(mpsc::RecvTimeoutError::Timeout) =
+                stop_recv.recv_timeout(timeout)
+            {
+                // The job is in its own process group, unless creating it
+                // failed before the job started: negative PIDs are groups
+                let pid = pid as libc::pid_t;
+                let group = Pid::from_raw(-pid);
+                if signal::kill(group, signal::SIGKILL).is_err() {
+                    let _ = signal::kill(Pid::from_raw(pid), signal::SIGKILL);
+                }
+            }
+        });
+
+        Watchdog {
+            _stop: Some(stop_send),
+        }
+    }
+}
+
+
+/// Resources used by the process of a job.
+struct ChildUsage {
+    cpu_ms: u64,
+    max_rss_kb: u64,
+}
+
+/// Wait for the child to exit, returning the resources it used. They're
+/// collected with wait4, so they include the processes the child waited for,
+/// but not the ones of the other jobs running at the same time.
+fn wait_with_usage(child: &Child) -> Result<(ExitStatus, ChildUsage)> {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { ::std::mem::zeroed() };
+    loop {
+        let pid = unsafe {
+            libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage)
+        };
+        if pid != -1 {
+            break;
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err.into());
+        }
+    }
+
+    let ms = |time: libc::timeval| {
+        time.tv_sec as u64 * 1000 + time.tv_usec as u64 / 1000
+    };
+    Ok((ExitStatus::from_raw(status), ChildUsage {
+        cpu_ms: ms(usage.ru_utime) + ms(usage.ru_stime),
+        max_rss_kb: usage.ru_maxrss as u64,
+    }))
+}
+
+
+/// Read everything from `reader` in another thread.
+fn read_in_background<R: Read + Send + 'static>(
+    mut reader: R,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        Ok(content)
+    })
+}
+
+
+/// Get the size in bytes of the environment the command will be executed
+/// with, as counted by the OS. The environment of jobs is always cleared
+/// before being built, so only the variables set on the command are counted.
+fn environment_size(command: &Command) -> usize {
+    command.get_envs()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        // Each variable is stored as `KEY=VALUE` followed by a NUL byte,
+        // along with a pointer to it
+        .map(|(key, value)| {
+            key.len() + value.len() + 2 + ::std::mem::size_of::<usize>()
+        })
+        .sum()
+}
+
+
+/// Remove a temporary directory, trying again up to `retries` times if it
+/// fails. Returns false if the directory couldn't be removed.
+fn remove_temp_dir(path: &Path, retries: u32) -> bool {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match fs::remove_dir_all(path) {
+            Ok(()) => return true,
+            Err(_) if attempts <= retries => {
+                thread::sleep(CLEANUP_RETRY_DELAY);
+            }
+            Err(err) => {
+                println!(
+                    "{} failed to remove the temporary directory {} \
+                     after {} attempt(s): {}",
+                    Colour::Yellow.bold().paint("Warning:"),
+                    path.display(),
+                    attempts,
+                    err,
+                );
+                return false;
+            }
+        }
+    }
+}
+
+
+/// Execute the command with a pseudo-terminal as its standard streams, for
+/// scripts expecting a TTY. The terminal merges stdout and stderr, so all the
+/// output is returned as stdout.
+fn output_with_pty(
+    command: &mut Command, timeout: Option<Duration>,
+) -> Result<(Output, ChildUsage)> {
+    let pty = openpty(None, None)?;
+
+    // The terminal must not leak into the processes spawned by other jobs
+    // in the meantime, nor into the script itself
+    fcntl(pty.master, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    fcntl(pty.slave, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+
+    // Copies made with dup() would lose the flag
+    let slave = || fcntl(pty.slave, FcntlArg::F_DUPFD_CLOEXEC(0));
+    unsafe {
+        command.stdin(Stdio::from_raw_fd(slave()?));
+        command.stdout(Stdio::from_raw_fd(slave()?));
+        command.stderr(Stdio::from_raw_fd(slave()?));
+    }
+    let master = unsafe { fs::File::from_raw_fd(pty.master) };
+
+    let child = command.spawn();
+
+    // The parent must not keep the terminal open, otherwise reading from it
+    // never ends: this also drops the copies owned by the command
+    close(pty.slave)?;
+    command.stdin(Stdio::null());
+    command.stdout(Stdio::null());
+    command.stderr(Stdio::null());
+    let child = child?;
+    let _watchdog = Watchdog::start(child.id(), timeout);
+
+    // Reading fails with EIO once the child closes the terminal, and the
+    // output read so far is kept in the buffer
+    let mut stdout = Vec::new();
+    if let Err(err) = (&master).read_to_end(&mut stdout) {
+        if err.raw_os_error() != Some(libc::EIO) {
+            return Err(err.into());
+        }
+    }
+
+    let (status, usage) = wait_with_usage(&child)?;
+    Ok((Output {
+        status,
+        stdout,
+        stderr: Vec::new(),
+    }, usage))
+}
+
+
 impl JobTrait<Script> for Job {
     type Context = Context;
     type Output = JobOutput;
@@ -359,6 +824,22 @@ impl JobTrait<Script> for Job {
     fn script_name(&self) -> &str {
         self.script.name()
     }
+
+    fn script_group(&self) -> Option<&str> {
+        self.script.group()
+    }
+
+    fn parallel_key(&self) -> Option<&str> {
+        self.parallel_key.as_deref()
+    }
+
+    fn provider_name(&self) -> Option<&str> {
+        self.provider.as_ref().map(|provider| provider.name())
+    }
+
+    fn set_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth;
+    }
 }
 
 
@@ -366,29 +847,75 @@ impl JobTrait<Script> for Job {
 pub struct JobOutput {
     pub stdout: String,
     pub stderr: String,
+    pub summary: Option<String>,
+
+    /// The exact output of the job, only kept for scripts with binary output
+    pub raw_stdout: Option<Vec<u8>>,
+    pub raw_stderr: Option<Vec<u8>>,
 
     pub success: bool,
     pub exit_code: Option<i32>,
     pub signal: Option<i32>,
 
+    /// The CPU time used by the job, in milliseconds
+    pub cpu_ms: Option<u64>,
+    /// The maximum resident set size of the biggest process executed by
+    /// Fisher so far, in kilobytes: it's an upper bound for the job's one
+    pub max_rss_kb: Option<u64>,
+    /// How many temporary directories of the job couldn't be removed
+    pub leaked_dirs: usize,
+
     pub script_name: String,
     pub request_ip: IpAddr,
+    /// The user who triggered the job, if the provider identified them
+    pub triggered_by: Option<String>,
 
     pub trigger_status_hooks: bool,
 }
 
 impl JobOutput {
     fn new<'a>(job: &'a Job, output: Output) -> Self {
+        let mut stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let mut stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+        // Colors and other terminal escapes are useless outside a terminal
+        if job.script.strip_ansi() {
+            stdout = strip_ansi(&stdout);
+            stderr = strip_ansi(&stderr);
+        }
+
+        // Invalid UTF-8 is replaced in the strings, so the original bytes
+        // must be kept if the script outputs binary data
+        let (raw_stdout, raw_stderr) = if job.script.binary_output() {
+            (Some(output.stdout), Some(output.stderr))
+        } else {
+            (None, None)
+        };
+
+        // Jobs killed by some signals can be considered successful, for
+        // example when they're stopped during a graceful restart
+        let success = output.status.success() || output.status.signal()
+            .is_some_and(|s| job.script.success_signals().contains(&s));
+
         JobOutput {
-            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
-            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            summary: parse_summary(&stdout),
+            stdout,
+            stderr,
 
-            success: output.status.success(),
+            raw_stdout,
+            raw_stderr,
+
+            success,
             exit_code: output.status.code(),
             signal: output.status.signal(),
 
+            cpu_ms: None,
+            max_rss_kb: None,
+            leaked_dirs: 0,
+
             script_name: job.script_name().into(),
             request_ip: job.request_ip(),
+            triggered_by: job.triggered_by(),
 
             trigger_status_hooks: job.trigger_status_hooks(),
         }
@@ -396,24 +923,84 @@ impl JobOutput {
 }
 
 
+impl JobOutputTrait for JobOutput {
+    fn failure(&self) -> Option<JobFailure> {
+        if self.success {
+            None
+        } else {
+            Some(JobFailure::new(self.exit_code, self.signal, &self.stderr))
+        }
+    }
+
+    fn cpu_time(&self) -> Option<Duration> {
+        self.cpu_ms.map(Duration::from_millis)
+    }
+
+    fn leaked_dirs(&self) -> usize {
+        self.leaked_dirs
+    }
+}
+
+impl JobOutput {
+    /// Get the standard output as it was returned by the job, if it was kept.
+    pub fn stdout_bytes(&self) -> &[u8] {
+        match self.raw_stdout {
+            Some(ref raw) => raw,
+            None => self.stdout.as_bytes(),
+        }
+    }
+
+    /// Get the standard error as it was returned by the job, if it was kept.
+    pub fn stderr_bytes(&self) -> &[u8] {
+        match self.raw_stderr {
+            Some(ref raw) => raw,
+            None => self.stderr.as_bytes(),
+        }
+    }
+}
+
+
+/// Remove all the ANSI escape sequences from the output of a job.
+fn strip_ansi(output: &str) -> String {
+    ANSI_ESCAPE_RE.replace_all(output, "").into_owned()
+}
+
+
+/// Get the summary of a job from its stdout, marked by the last line starting
+/// with `FISHER_SUMMARY:`.
+fn parse_summary(stdout: &str) -> Option<String> {
+    stdout.lines()
+        .rev()
+        .find(|line| line.starts_with(SUMMARY_MARKER))
+        .map(|line| line[SUMMARY_MARKER.len()..].trim().to_string())
+}
+
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
     use std::env;
     use std::ffi::OsString;
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::Read;
     use std::path::{Path, PathBuf};
     use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+    use nix::mount::{mount, umount, MsFlags};
+    use serde_json;
     use users;
 
     use common::prelude::*;
-    use requests::Request;
+    use requests::{Request, ScheduledRequest};
     use scripts::test_utils::*;
     use utils;
 
-    use super::{Job, Context, DEFAULT_ENV};
+    use super::{
+        parse_summary, remove_temp_dir, Job, Context, EnvBuilder,
+        DEFAULT_ENV,
+    };
 
 
     fn parse_env(content: &str) -> HashMap<&str, &str> {
@@ -566,8 +1153,11 @@ mod tests {
             let extra_env = vec![
                 // Variables set by Fisher
                 "FISHER_TESTING_ENV", "FISHER_REQUEST_IP",
-                "FISHER_REQUEST_BODY", "FISHER_TESTING_PREPARED", "HOME",
-                "USER",
+                "FISHER_REQUEST_BODY", "FISHER_REQUEST_ID",
+                "FISHER_REQUEST_SIZE", "FISHER_REQUEST_TIME",
+                "FISHER_HOOK_PRIORITY", "FISHER_SCRIPT_PATH",
+                "FISHER_QUEUE_DEPTH", "FISHER_TESTING_PREPARED",
+                "HOME", "USER",
                 // Variables set by bash
                 "PWD", "SHLVL", "_",
             ];
@@ -595,7 +1185,25 @@ mod tests {
             // Ensure environment variables are correct
             assert_eq!(&env_vars["FISHER_TESTING_ENV"], &out.to_str().unwrap());
             assert_eq!(&env_vars["FISHER_REQUEST_IP"], &"127.0.0.1");
+            assert_eq!(&env_vars["FISHER_REQUEST_ID"], &"dummy-request-id");
+            assert_eq!(
+                &env_vars["FISHER_REQUEST_SIZE"],
+                &"a body!".len().to_string().as_str()
+            );
+            assert_eq!(&env_vars["FISHER_HOOK_PRIORITY"], &"0");
             assert_eq!(&env_vars["HOME"], &working_directory.trim());
+            assert_eq!(
+                fs::canonicalize(env_vars["FISHER_SCRIPT_PATH"])?,
+                fs::canonicalize(env.scripts_dir().join("dump.sh"))?,
+            );
+
+            // The request was received just before the job started
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let received_at: u64 = env_vars["FISHER_REQUEST_TIME"].parse()?;
+            assert!(received_at <= now && now - received_at < 60);
             assert_eq!(
                 &env_vars["USER"],
                 &users::get_current_username().unwrap()
@@ -637,6 +1245,43 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_job_environment_clean() {
+        test_wrapper(|env| {
+            env.create_script("normal.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Testing: {}"#,
+            ])?;
+            env.create_script("clean.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"clean_env": true}"#,
+                r#"## Fisher-Testing: {}"#,
+            ])?;
+
+            let prepare = |name: &str| -> Result<HashMap<String, String>> {
+                let job = create_job(env, name, dummy_web_request().into())?;
+                let mut builder = EnvBuilder::dummy();
+                job.prepare_env(&mut builder, &Context::default())?;
+                Ok(builder.dummy_data().env.clone())
+            };
+
+            // The default environment is inherited by normal scripts
+            let normal = prepare("normal.sh")?;
+            assert_eq!(normal.get("PATH"), env::var("PATH").ok().as_ref());
+
+            // Scripts with a clean environment only get Fisher's variables
+            let clean = prepare("clean.sh")?;
+            for key in DEFAULT_ENV {
+                assert!(!clean.contains_key(*key), "{} is present", key);
+            }
+            assert!(clean.contains_key("USER"));
+            assert!(clean.contains_key("FISHER_TESTING_PREPARED"));
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_job_environment_with_altered_user() {
         test_wrapper(|mut env| {
@@ -673,4 +1318,788 @@ mod tests {
             Ok(())
         });
     }
+
+
+    #[test]
+    fn test_job_sigpipe_handling() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            // `yes` writes to the pipe until `head` exits, and then it
+            // receives a SIGPIPE (or a write error if the signal is ignored)
+            for &(name, pref) in &[
+                ("default.sh", ""),
+                ("ignore.sh", r#"## Fisher: {"sigpipe": "ignore"}"#),
+            ] {
+                env.create_script(name, &[
+                    "#!/bin/bash",
+                    pref,
+                    "yes | head -n 1 > /dev/null",
+                    r#"exit "${PIPESTATUS[0]}""#,
+                ])?;
+            }
+
+            // With the default handling `yes` is killed by the signal
+            let job = create_job(env, "default.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.exit_code, Some(128 + 13));
+
+            // When the signal is ignored `yes` exits on its own instead
+            let job = create_job(env, "ignore.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(result.exit_code.is_some());
+            assert_ne!(result.exit_code, Some(128 + 13));
+            assert!(result.signal.is_none());
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_summary() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            env.create_script("summary.sh", &[
+                "#!/bin/bash",
+                "echo 'FISHER_SUMMARY: first'",
+                "echo 'Deploying...'",
+                "echo 'FISHER_SUMMARY: deployed 3 commits'",
+                "echo 'Done'",
+            ])?;
+            env.create_script("no-summary.sh", &[
+                "#!/bin/bash",
+                "echo 'Done'",
+            ])?;
+
+            // The last marker line is used as the summary
+            let job = create_job(env, "summary.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.summary, Some("deployed 3 commits".into()));
+
+            let job = create_job(env, "no-summary.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.summary, None);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_new_session() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            let scripts = &[("new.sh", true), ("old.sh", false)];
+            for &(name, new_session) in scripts {
+                env.create_script(name, &[
+                    "#!/bin/bash",
+                    &format!(
+                        r#"## Fisher: {{"new_session": {}}}"#, new_session,
+                    ),
+                    // The session ID is the sixth field
+                    r#"read -r -a stat < "/proc/$$/stat""#,
+                    r#"echo "$$ ${stat[5]}""#,
+                ])?;
+            }
+
+            // The job leads its own session only if it asks for it
+            for &(name, new_session) in scripts {
+                let output = create_job(env, name, req.clone())?
+                    .process(&ctx)?;
+                let ids = output.stdout.split_whitespace().collect::<Vec<_>>();
+                assert_eq!(ids.len(), 2);
+                assert_eq!(ids[0] == ids[1], new_session);
+            }
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_isolate() {
+        // Mounting filesystems requires root
+        if users::get_current_uid() != 0 {
+            return;
+        }
+
+        test_wrapper(|env| {
+            let base = env.tempdir()?;
+            let mnt = base.join("mnt");
+            fs::create_dir(&mnt)?;
+
+            let ctx = || Context {
+                environment: hashmap! {
+                    "TEST_DIR".into() => base.to_str().unwrap().into(),
+                },
+                .. Context::default()
+            };
+            let req: Request = dummy_web_request().into();
+
+            let scripts = &[("isolated.sh", true), ("host.sh", false)];
+            for &(name, isolate) in scripts {
+                env.create_script(name, &[
+                    "#!/bin/bash",
+                    &format!(r#"## Fisher: {{"isolate": {}}}"#, isolate),
+                    r#"touch "${TEST_DIR}/started""#,
+                    r#"while ! [[ -f "${TEST_DIR}/go" ]]; do sleep 0.01; done"#,
+                    r#"ls "${TEST_DIR}/mnt""#,
+                ])?;
+            }
+
+            // Mount a filesystem on the host while the isolated job runs
+            let job = create_job(env, "isolated.sh", req.clone())?;
+            let thread_ctx = ctx();
+            let handle = thread::spawn(move || job.process(&thread_ctx));
+
+            while !base.join("started").exists() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            mount(
+                Some("tmpfs"), &mnt, Some("tmpfs"), MsFlags::empty(),
+                None::<&str>,
+            )?;
+            File::create(mnt.join("host-only"))?;
+            File::create(base.join("go"))?;
+
+            let isolated = handle.join().unwrap();
+
+            // Jobs which are not isolated see the new mount
+            let host = create_job(env, "host.sh", req)?.process(&ctx());
+            umount(&mnt)?;
+
+            assert_eq!(isolated?.stdout, "");
+            assert_eq!(host?.stdout, "host-only\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_hook_priority() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            env.create_script("prioritized.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"priority": 5}"#,
+                r#"echo "${FISHER_HOOK_PRIORITY}""#,
+            ])?;
+
+            let job = create_job(env, "prioritized.sh", req)?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.stdout, "5\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_raw_body() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let mut req = dummy_web_request();
+            req.body = "{\"a\": 1}\r\n\tend".into();
+            let req: Request = req.into();
+
+            env.create_script_pair(
+                "text.sh", "raw.sh", r#"{"raw_body": true}"#,
+                &[r#"cat "${FISHER_REQUEST_BODY}""#],
+            )?;
+
+            // A newline is added by default
+            let job = create_job(env, "text.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.stdout, "{\"a\": 1}\r\n\tend\n");
+
+            // The body is saved byte-for-byte when raw
+            let job = create_job(env, "raw.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.stdout, "{\"a\": 1}\r\n\tend");
+
+            // Bodies which aren't valid UTF-8 are saved as they were received
+            let mut req = dummy_web_request();
+            req.body = "a\u{FFFD}".into();
+            req.raw_body = Some(b"a\xff".to_vec());
+            env.create_script("bytes.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"raw_body": true}"#,
+                r#"od -An -tx1 "${FISHER_REQUEST_BODY}""#,
+            ])?;
+            let job = create_job(env, "bytes.sh", req.into())?;
+            assert_eq!(job.process(&ctx)?.stdout.trim(), "61 ff");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_request_headers() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let mut req = dummy_web_request();
+            req.headers.insert("X-Custom".into(), "value".into());
+            req.headers.insert("User-Agent".into(), "fisher-test".into());
+            let req: Request = req.into();
+
+            env.create_script_pair(
+                "without.sh", "with.sh", r#"{"request_headers": true}"#,
+                &[r#"cat "${FISHER_REQUEST_HEADERS:-/dev/null}""#],
+            )?;
+
+            // The headers are not provided by default
+            let job = create_job(env, "without.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.stdout, "");
+
+            let job = create_job(env, "with.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            let headers: HashMap<String, String> =
+                serde_json::from_str(&result.stdout)?;
+            assert_eq!(headers, hashmap! {
+                "X-Custom".into() => "value".into(),
+                "User-Agent".into() => "fisher-test".into(),
+            });
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_normalized_event() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+
+            env.create_script("multiple.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"normalize_event": true}"#,
+                r#"## Fisher-GitHub: {}"#,
+                r#"## Fisher-GitLab: {}"#,
+                r#"echo "${FISHER_EVENT}""#,
+            ])?;
+            env.create_script("disabled.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-GitLab: {}"#,
+                r#"echo "${FISHER_EVENT-unset}""#,
+            ])?;
+
+            let mut github = dummy_web_request();
+            github.method = "POST".into();
+            github.headers.insert(
+                "X-GitHub-Event".into(), "pull_request".into(),
+            );
+            github.headers.insert("X-GitHub-Delivery".into(), "1".into());
+            github.headers.insert("X-Hub-Signature".into(), "".into());
+            github.body = "{}".into();
+
+            let mut gitlab = dummy_web_request();
+            gitlab.headers.insert(
+                "X-Gitlab-Event".into(), "Merge Request Hook".into(),
+            );
+            gitlab.body = "{}".into();
+
+            // The same event is exported regardless of the matched provider
+            for req in vec![github, gitlab.clone()] {
+                let job = create_job(env, "multiple.sh", req.into())?;
+                let result = job.process(&ctx)?;
+                assert!(result.success);
+                assert_eq!(result.stdout, "pull_request\n");
+            }
+
+            // The event isn't exported if the script didn't ask for it
+            let job = create_job(env, "disabled.sh", gitlab.into())?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+            assert_eq!(result.stdout, "unset\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_delivery_key() {
+        test_wrapper(|env| {
+            env.create_script("github.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-GitHub: {}"#,
+                r#"echo -n "${FISHER_DELIVERY_KEY-unset}""#,
+            ])?;
+
+            let mut req = dummy_web_request();
+            req.method = "POST".into();
+            req.headers.insert("X-GitHub-Event".into(), "push".into());
+            req.headers.insert("X-GitHub-Delivery".into(), "1234".into());
+            req.headers.insert("X-Hub-Signature".into(), "".into());
+            req.body = "{}".into();
+
+            // The GitHub delivery ID is exported to the script
+            let job = create_job(env, "github.sh", req.into())?;
+            let result = job.process(&Context::default())?;
+            assert!(result.success);
+            assert_eq!(result.stdout, "1234");
+
+            // Requests without a provider don't have a delivery key
+            env.create_script("plain.sh", &[
+                r#"#!/bin/bash"#,
+                r#"echo -n "${FISHER_DELIVERY_KEY-unset}""#,
+            ])?;
+            let job = create_job(env, "plain.sh", dummy_web_request().into())?;
+            assert_eq!(job.process(&Context::default())?.stdout, "unset");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_scheduled_request() {
+        test_wrapper(|env| {
+            env.create_script("scheduled.sh", &[
+                r#"#!/bin/bash"#,
+                r#"echo "${FISHER_REQUEST_ID}""#,
+                r#"echo "${FISHER_REQUEST_IP}""#,
+                r#"echo "${FISHER_REQUEST_BODY-unset}""#,
+            ])?;
+
+            let req = ScheduledRequest::new();
+            let id = req.id.clone();
+
+            // Scheduled runs have an ID, but no request body
+            let req = Request::Scheduled(req);
+            let job = create_job(env, "scheduled.sh", req)?;
+            assert_eq!(job.request_id(), Some(id.as_str()));
+            let result = job.process(&Context::default())?;
+            assert!(result.success);
+            assert_eq!(result.stdout, format!("{}\n127.0.0.1\nunset\n", id));
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_queue_depth() {
+        test_wrapper(|env| {
+            env.create_script("depth.sh", &[
+                r#"#!/bin/bash"#,
+                r#"echo -n "${FISHER_QUEUE_DEPTH}""#,
+            ])?;
+
+            // Jobs not queued in the processor have an empty queue
+            let mut job =
+                create_job(env, "depth.sh", dummy_web_request().into())?;
+            assert_eq!(job.process(&Context::default())?.stdout, "0");
+
+            // The depth recorded by the scheduler is exported
+            job.set_queue_depth(42);
+            assert_eq!(job.process(&Context::default())?.stdout, "42");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_triggered_by() {
+        test_wrapper(|env| {
+            let out = env.tempdir()?;
+            let ctx = Context {
+                audit_log: Some(out.join("audit.log")),
+                .. Context::default()
+            };
+
+            env.create_script("github.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-GitHub: {}"#,
+            ])?;
+
+            let mut req = dummy_web_request();
+            req.method = "POST".into();
+            req.headers.insert("X-GitHub-Event".into(), "push".into());
+            req.headers.insert("X-GitHub-Delivery".into(), "1".into());
+            req.headers.insert("X-Hub-Signature".into(), "".into());
+            req.body = serde_json::to_string(&json!({
+                "ref": "refs/heads/master",
+                "head_commit": {"id": "deadbeef"},
+                "sender": {"login": "octocat"},
+            }))?;
+
+            // The user is recorded in the output and in the audit log
+            let job = create_job(env, "github.sh", req.into())?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+            assert_eq!(result.triggered_by, Some("octocat".into()));
+            assert!(content(&out, "audit.log")?
+                .ends_with(" exit=0 user=octocat\n"));
+
+            // Requests without a provider don't have an user
+            env.create_script("plain.sh", &[r#"#!/bin/bash"#])?;
+            let job = create_job(env, "plain.sh", dummy_web_request().into())?;
+            assert_eq!(job.process(&Context::default())?.triggered_by, None);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_audit_log_not_writable() {
+        test_wrapper(|env| {
+            let out = env.tempdir()?;
+            let ctx = Context {
+                audit_log: Some(out.join("missing").join("audit.log")),
+                .. Context::default()
+            };
+
+            env.create_script("example.sh", &[
+                r#"#!/bin/bash"#,
+                r#"echo "executed""#,
+            ])?;
+
+            // The job still succeeds if it can't be recorded
+            let job =
+                create_job(env, "example.sh", dummy_web_request().into())?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+            assert_eq!(result.stdout, "executed\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_success_signals() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            env.create_script_pair(
+                "without.sh", "with.sh", r#"{"success_signals": [15]}"#,
+                &["kill -TERM $$"],
+            )?;
+
+            // Jobs killed by a signal fail by default
+            let job = create_job(env, "without.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(!result.success);
+            assert_eq!(result.signal, Some(15));
+
+            // Listed signals mark the job as successful
+            let job = create_job(env, "with.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+            assert_eq!(result.exit_code, None);
+            assert_eq!(result.signal, Some(15));
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_max_env_size() {
+        test_wrapper(|env| {
+            env.create_script("example.sh", &[
+                r#"#!/bin/bash"#,
+                r#"echo "Hello world""#,
+            ])?;
+            let req = dummy_web_request().into();
+            let job = create_job(env, "example.sh", req)?;
+
+            let mut ctx = Context::default();
+            ctx.environment.insert("BIG".into(), "a".repeat(10000));
+
+            // Jobs with a bigger environment are rejected before running
+            ctx.max_env_size = Some(8192);
+            let err = job.process(&ctx).unwrap_err();
+            if let ErrorKind::JobEnvironmentTooLarge(size, max) = *err.kind() {
+                assert!(size > 10000);
+                assert_eq!(max, 8192);
+            } else {
+                panic!("wrong error: {}", err);
+            }
+
+            // Jobs within the limit are executed normally
+            ctx.max_env_size = Some(1024 * 1024);
+            assert!(job.process(&ctx)?.success);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_leaked_temp_dirs() {
+        test_wrapper(|env| {
+            let base = env.tempdir()?;
+
+            let removable = base.join("removable");
+            fs::create_dir(&removable)?;
+            File::create(removable.join("file"))?;
+            assert!(remove_temp_dir(&removable, 0));
+            assert!(!removable.exists());
+
+            // A file can't be removed as a directory, not even by root
+            let broken = base.join("broken");
+            File::create(&broken)?;
+
+            let started = Instant::now();
+            assert!(!remove_temp_dir(&broken, 2));
+
+            // The removal was tried again before giving up
+            assert!(started.elapsed() >= Duration::from_millis(200));
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_resource_usage() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            // Burn some CPU time, and allocate a few megabytes
+            env.create_script("usage.sh", &[
+                "#!/bin/bash",
+                "data=$(head -c 4000000 /dev/zero | tr '\\0' 'a')",
+                "for i in $(seq 1 20000); do :; done",
+            ])?;
+
+            let job = create_job(env, "usage.sh", req)?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+
+            let cpu_ms = result.cpu_ms.unwrap();
+            assert!(cpu_ms > 0 && cpu_ms < 60_000, "cpu_ms: {}", cpu_ms);
+            let max_rss_kb = result.max_rss_kb.unwrap();
+            assert!(max_rss_kb >= 4000, "max_rss_kb: {}", max_rss_kb);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_resource_usage_concurrent() {
+        test_wrapper(|env| {
+            let req: Request = dummy_web_request().into();
+
+            env.create_script("idle.sh", &["#!/bin/bash", "sleep 1"])?;
+            env.create_script("busy.sh", &[
+                "#!/bin/bash",
+                "for i in $(seq 1 20000); do :; done",
+            ])?;
+
+            let idle = create_job(env, "idle.sh", req.clone())?;
+            let idle = thread::spawn(move || {
+                idle.process(&Context::default()).unwrap()
+            });
+            let busy = create_job(env, "busy.sh", req)?
+                .process(&Context::default())?;
+            let idle = idle.join().unwrap();
+
+            // The usage of the job finished in the meantime isn't included
+            assert!(
+                idle.cpu_ms.unwrap() < busy.cpu_ms.unwrap(),
+                "idle: {:?}, busy: {:?}", idle.cpu_ms, busy.cpu_ms,
+            );
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_timeout() {
+        test_wrapper(|env| {
+            let mut ctx = Context::default();
+            ctx.default_timeout = Some(Duration::from_secs(1));
+            let req: Request = dummy_web_request().into();
+
+            env.create_script("default.sh", &[
+                "#!/bin/bash",
+                "sleep 30",
+            ])?;
+            env.create_script("unlimited.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"timeout": 0}"#,
+                "sleep 2",
+            ])?;
+
+            // Hooks without their own timeout are killed after the default
+            let start = Instant::now();
+            let job = create_job(env, "default.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(!result.success);
+            assert_eq!(result.signal, Some(9));
+            assert!(start.elapsed() < Duration::from_secs(10));
+
+            // Hooks can disable the timeout
+            let job = create_job(env, "unlimited.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_binary_output() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            env.create_script_pair(
+                "text.sh", "binary.sh", r#"{"binary_output": true}"#,
+                &[
+                    r#"printf 'a\xff\x00b'"#,
+                    r#"printf '\xfe' >&2"#,
+                ],
+            )?;
+
+            // Invalid UTF-8 is replaced by default
+            let job = create_job(env, "text.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.stdout, "a\u{fffd}\u{0}b");
+            assert_eq!(result.raw_stdout, None);
+            assert_eq!(result.stdout_bytes(), "a\u{fffd}\u{0}b".as_bytes());
+
+            // The exact bytes are kept for binary output
+            let job = create_job(env, "binary.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.stdout, "a\u{fffd}\u{0}b");
+            assert_eq!(result.stdout_bytes(), b"a\xff\x00b");
+            assert_eq!(result.stderr_bytes(), b"\xfe");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_strip_ansi() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            env.create_script_pair(
+                "colored.sh", "stripped.sh", r#"{"strip_ansi": true}"#,
+                &[
+                    r#"printf '\e[1;31mred\e[0m \e]0;title\atext\n'"#,
+                    r#"printf '\e[33mwarning\e[m' >&2"#,
+                ],
+            )?;
+
+            // The output is kept as is by default
+            let job = create_job(env, "colored.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(
+                result.stdout,
+                "\x1b[1;31mred\x1b[0m \x1b]0;title\x07text\n"
+            );
+
+            // The escape sequences are removed when enabled
+            let job = create_job(env, "stripped.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert_eq!(result.stdout, "red text\n");
+            assert_eq!(result.stderr, "warning");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_interpreter() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            env.create_script("no-shebang.sh", &["echo $0"])?;
+
+            // Scripts with an interpreter are executed through it
+            let mut script = env.load_script("no-shebang.sh")?;
+            script.set_interpreter(Some("/bin/sh".into()));
+            let job = Job::new(Arc::new(script), None, req);
+
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+            assert_eq!(
+                result.stdout.trim(),
+                env.scripts_dir().join("no-shebang.sh").to_str().unwrap()
+            );
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_pty() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            env.create_script_pair(
+                "pipe.sh", "pty.sh", r#"{"pty": true}"#,
+                &[
+                    "tty -s || exit 1",
+                    "echo out; echo err >&2",
+                ],
+            )?;
+
+            // Scripts normally don't have a TTY
+            let job = create_job(env, "pipe.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(!result.success);
+
+            // With the PTY mode the TTY is available, and the output of the
+            // script is captured from it
+            let job = create_job(env, "pty.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+            assert_eq!(result.stdout, "out\r\nerr\r\n");
+            assert_eq!(result.stderr, "");
+
+            // The script doesn't inherit the other end of the terminal
+            env.create_script("fds.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"pty": true}"#,
+                "ls -l /proc/$$/fd | grep -c ptmx || true",
+            ])?;
+            let job = create_job(env, "fds.sh", req.clone())?;
+            assert_eq!(job.process(&ctx)?.stdout, "0\r\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_parse_summary() {
+        assert_eq!(parse_summary(""), None);
+        assert_eq!(parse_summary("a\nb\n"), None);
+        assert_eq!(parse_summary(" FISHER_SUMMARY: a\n"), None);
+        assert_eq!(parse_summary("FISHER_SUMMARY:\n"), Some("".into()));
+        assert_eq!(
+            parse_summary("FISHER_SUMMARY: a\nb\nFISHER_SUMMARY:  c \nd"),
+            Some("c".into())
+        );
+    }
 }
@@ -22,5 +22,5 @@ mod script;
 
 pub use self::repository::{Blueprint, Repository};
 pub use self::repository::{ScriptsIter, StatusJobsIter};
-pub use self::script::{Script, ScriptProvider};
+pub use self::script::{Script, ScriptProvider, SigpipeHandling};
 pub use self::jobs::{Job, JobOutput, Context as JobContext, EnvBuilder};
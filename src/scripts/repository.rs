@@ -17,11 +17,13 @@ use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
+use ansi_term::Colour;
+
 use common::prelude::*;
 use common::state::{State, UniqueId};
-use providers::{Provider, StatusEvent, StatusEventKind};
-use requests::Request;
-use scripts::collector::Collector;
+use providers::{ExpiredJob, Provider, StatusEvent, StatusEventKind};
+use requests::{Request, ScheduledRequest};
+use scripts::collector::{Collector, ScriptsCache};
 use scripts::jobs::{Job, JobOutput};
 use scripts::script::{Script, ScriptProvider};
 
@@ -103,6 +105,7 @@ struct RepositoryInner {
     by_id: HashMap<UniqueId, Arc<Script>>,
     by_name: HashMap<String, Arc<Script>>,
     status_hooks: HashMap<StatusEventKind, Vec<ScriptProvider>>,
+    status_hooks_disabled: bool,
 }
 
 impl RepositoryInner {
@@ -112,6 +115,7 @@ impl RepositoryInner {
             by_id: HashMap::new(),
             by_name: HashMap::new(),
             status_hooks: HashMap::new(),
+            status_hooks_disabled: false,
         }
     }
 
@@ -122,16 +126,28 @@ impl RepositoryInner {
             .insert(script.name().to_string(), script.clone());
 
         for provider in &script.providers {
-            if let Provider::Status(ref status) = *provider.as_ref() {
+            if let Provider::Status(ref status, _) = *provider.as_ref() {
                 // Load all the kinds of events
                 for event in status.events() {
-                    self.status_hooks
-                        .entry(*event)
-                        .or_insert_with(Vec::new)
-                        .push(ScriptProvider {
-                            script: script.clone(),
-                            provider: provider.clone(),
-                        });
+                    let hooks = self.status_hooks
+                        .entry(event)
+                        .or_default();
+
+                    // Keep the status hooks sorted by priority and name, so
+                    // they're always dispatched in the same order regardless
+                    // of the order they were collected in
+                    let pos = hooks.binary_search_by(|existing| {
+                        script.priority()
+                            .cmp(&existing.script.priority())
+                            .then_with(|| {
+                                existing.script.name().cmp(script.name())
+                            })
+                    }).unwrap_or_else(|pos| pos);
+
+                    hooks.insert(pos, ScriptProvider {
+                        script: script.clone(),
+                        provider: provider.clone(),
+                    });
                 }
             }
         }
@@ -140,6 +156,10 @@ impl RepositoryInner {
     pub fn get_by_name(&self, name: &str) -> Option<Arc<Script>> {
         self.by_name.get(name).cloned()
     }
+
+    pub fn status_hooks_disabled(&self) -> bool {
+        self.status_hooks_disabled
+    }
 }
 
 
@@ -155,6 +175,13 @@ impl Repository {
             Err(poisoned) => poisoned.get_ref().get_by_name(name),
         }
     }
+
+    fn status_hooks_disabled(&self) -> bool {
+        match self.inner.read() {
+            Ok(inner) => inner.status_hooks_disabled(),
+            Err(poisoned) => poisoned.get_ref().status_hooks_disabled(),
+        }
+    }
 }
 
 impl ScriptsRepositoryTrait for Repository {
@@ -175,7 +202,7 @@ impl ScriptsRepositoryTrait for Repository {
     }
 
     fn jobs_after_output(&self, output: JobOutput) -> Option<StatusJobsIter> {
-        if !output.trigger_status_hooks {
+        if !output.trigger_status_hooks || self.status_hooks_disabled() {
             return None;
         }
 
@@ -187,6 +214,26 @@ impl ScriptsRepositoryTrait for Repository {
 
         Some(StatusJobsIter::new(self.inner.clone(), event))
     }
+
+    fn jobs_after_expired(&self, job: &Job) -> Option<StatusJobsIter> {
+        if !job.trigger_status_hooks() || self.status_hooks_disabled() {
+            return None;
+        }
+
+        let event = StatusEvent::JobExpired(ExpiredJob {
+            script_name: job.script_name().to_string(),
+            request_ip: job.request_ip(),
+        });
+
+        Some(StatusJobsIter::new(self.inner.clone(), event))
+    }
+
+    fn scheduled_job(&self, script: &Arc<Script>) -> Option<(Job, isize)> {
+        // Scheduled jobs don't go through the providers of the script
+        let req = ScheduledRequest::new();
+        let job = Job::new(script.clone(), None, Request::Scheduled(req));
+        Some((job, script.priority()))
+    }
 }
 
 
@@ -194,6 +241,15 @@ impl ScriptsRepositoryTrait for Repository {
 pub struct Blueprint {
     added: Vec<Arc<Script>>,
     collect_paths: Vec<(PathBuf, bool)>,
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+    skip_invalid: bool,
+    skipped: usize,
+    default_shell: Option<String>,
+    public_hooks: Option<Vec<String>>,
+    strict_permissions: bool,
+    disable_status_hooks: bool,
+    cache: ScriptsCache,
 
     inner: Arc<RwLock<RepositoryInner>>,
     state: Arc<State>,
@@ -204,9 +260,18 @@ impl Blueprint {
         Blueprint {
             added: Vec::new(),
             collect_paths: Vec::new(),
+            follow_symlinks: true,
+            max_depth: None,
+            skip_invalid: false,
+            skipped: 0,
+            default_shell: None,
+            public_hooks: None,
+            strict_permissions: false,
+            disable_status_hooks: false,
+            cache: HashMap::new(),
 
             inner: Arc::new(RwLock::new(RepositoryInner::new())),
-            state: state,
+            state,
         }
     }
 
@@ -223,6 +288,61 @@ impl Blueprint {
         Ok(())
     }
 
+    pub fn set_follow_symlinks(&mut self, follow: bool) {
+        self.follow_symlinks = follow;
+    }
+
+    /// Limit how many levels of subdirectories are searched by recursive
+    /// collections.
+    pub fn set_max_depth(&mut self, max: Option<usize>) {
+        self.max_depth = max;
+    }
+
+    /// Skip the scripts which can't be loaded, instead of failing the whole
+    /// reload.
+    pub fn set_skip_invalid(&mut self, skip: bool) {
+        self.skip_invalid = skip;
+    }
+
+    /// Get how many invalid scripts were skipped by the last reload.
+    pub fn skipped_count(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn set_default_shell(&mut self, shell: Option<String>) {
+        // Scripts without a shebang need to be loaded again with the new
+        // shell
+        if self.default_shell != shell {
+            self.cache.clear();
+        }
+        self.default_shell = shell;
+    }
+
+    /// Only allow the scripts in the list to be triggered without
+    /// authentication, or allow every script if there is no list.
+    pub fn set_public_hooks(&mut self, hooks: Option<Vec<String>>) {
+        // The scripts already loaded were not checked against the new list
+        if self.public_hooks != hooks {
+            self.cache.clear();
+        }
+        self.public_hooks = hooks;
+    }
+
+    /// Refuse to load the scripts if other users can change them.
+    pub fn set_strict_permissions(&mut self, strict: bool) {
+        self.strict_permissions = strict;
+    }
+
+    /// Refuse to load the scripts using the Status provider, and never
+    /// return any status job.
+    pub fn set_disable_status_hooks(&mut self, disable: bool) {
+        // The scripts already loaded were not checked for status hooks
+        if self.disable_status_hooks != disable {
+            self.cache.clear();
+        }
+        self.disable_status_hooks = disable;
+    }
+
     pub fn collect_path<P: AsRef<Path>>(
         &mut self,
         path: P,
@@ -237,25 +357,58 @@ impl Blueprint {
 
     pub fn reload(&mut self) -> Result<()> {
         let mut inner = RepositoryInner::new();
+        inner.status_hooks_disabled = self.disable_status_hooks;
 
         // Add manually added scripts
         for script in &self.added {
             inner.insert(script.clone());
         }
 
-        // Collect scripts from paths
+        // Collect scripts from paths, only loading again the ones changed
+        // since the last reload
+        let mut cache = HashMap::new();
+        let mut skipped = 0;
         let mut collector;
         for &(ref p, recursive) in &self.collect_paths {
-            collector = Collector::new(p, self.state.clone(), recursive)?;
-            for script in collector {
-                inner.insert(script?);
+            collector = Collector::new(
+                p, self.state.clone(), recursive, self.follow_symlinks,
+            )?;
+            collector.set_default_shell(self.default_shell.clone());
+            collector.set_public_hooks(self.public_hooks.clone());
+            collector.set_strict_permissions(self.strict_permissions);
+            collector.set_disable_status_hooks(self.disable_status_hooks);
+            collector.check_base_permissions()?;
+            collector.set_max_depth(self.max_depth);
+            collector.reuse_scripts(self.cache.clone());
+            for script in &mut collector {
+                match script {
+                    Ok(script) => inner.insert(script),
+                    Err(err) => {
+                        if !self.skip_invalid {
+                            return Err(err);
+                        }
+
+                        println!(
+                            "{} skipping an invalid script: {}",
+                            Colour::Yellow.bold().paint("Warning:"),
+                            err,
+                        );
+                        for cause in err.iter().skip(1) {
+                            println!("  caused by: {}", cause);
+                        }
+                        skipped += 1;
+                    }
+                }
             }
+            cache.extend(collector.into_cache());
         }
 
         {
             let mut to_update = self.inner.write()?;
             *to_update = inner;
         }
+        self.cache = cache;
+        self.skipped = skipped;
 
         Ok(())
     }
@@ -273,10 +426,14 @@ mod tests {
     use std::fs;
     use std::os::unix::fs as unix_fs;
     use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
 
     use common::prelude::*;
     use providers::StatusEventKind;
     use scripts::test_utils::*;
+    use utils::testing::{dummy_job_output, dummy_web_request};
+    use requests::{Request, RequestType};
+    use scripts::Job;
 
     use super::{Blueprint, Repository};
 
@@ -405,8 +562,136 @@ mod tests {
             assert!(repository.get_by_name("second.sh").is_none());
             assert!(repository.get_by_name("third.sh").is_some());
 
-            // Ensure the script IDs are different
-            assert_ne!(id_original, id_new);
+            // Ensure the script ID didn't change, since it wasn't modified
+            assert_eq!(id_original, id_new);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_blueprint_reloads_only_changed_scripts() {
+        test_wrapper(|env| {
+            for name in &["first.sh", "second.sh", "third.sh"] {
+                env.create_script(name, &[
+                    r#"#!/bin/bash"#,
+                    &format!(r#"echo "I'm {}""#, name),
+                ])?;
+            }
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.collect_path(&env.scripts_dir(), false)?;
+
+            let repository = blueprint.repository();
+            let id = |name| repository.get_by_name(name).unwrap().id();
+            let (first, second, third) =
+                (id("first.sh"), id("second.sh"), id("third.sh"));
+
+            // Change one of the scripts, moving its modification time
+            env.create_script("second.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"priority": 5}"#,
+                r#"echo "I changed""#,
+            ])?;
+            fs::File::open(env.scripts_dir().join("second.sh"))?
+                .set_modified(SystemTime::now() + Duration::from_secs(10))?;
+
+            blueprint.reload()?;
+
+            // Only the changed script was loaded again
+            assert_eq!(id("first.sh"), first);
+            assert_eq!(id("third.sh"), third);
+            assert_ne!(id("second.sh"), second);
+            assert_eq!(
+                repository.get_by_name("second.sh").unwrap().priority(),
+                5
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_blueprint_reloads_changed_sidecar_files() {
+        test_wrapper(|env| {
+            env.create_script("sidecar.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Standalone: @standalone.json"#,
+                r#"echo "ok""#,
+            ])?;
+            let sidecar = env.scripts_dir().join("standalone.json");
+            fs::write(&sidecar, r#"{"secret": "abcde"}"#)?;
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.collect_path(&env.scripts_dir(), false)?;
+
+            let repository = blueprint.repository();
+            let id = || repository.get_by_name("sidecar.sh").unwrap().id();
+            let original = id();
+
+            // Change only the sidecar file, moving its modification time
+            fs::write(&sidecar, r#"{"secret": "fghij"}"#)?;
+            fs::File::open(&sidecar)?
+                .set_modified(SystemTime::now() + Duration::from_secs(10))?;
+
+            blueprint.reload()?;
+
+            // The script was loaded again with the new configuration
+            assert_ne!(id(), original);
+            let mut req = dummy_web_request();
+            req.params.insert("secret".into(), "fghij".into());
+            let script = repository.get_by_name("sidecar.sh").unwrap();
+            assert_eq!(
+                script.validate(&req.into()).0, RequestType::ExecuteHook,
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_blueprint_skips_invalid_scripts() {
+        test_wrapper(|env| {
+            env.create_script("first.sh", &[
+                r#"#!/bin/bash"#,
+                r#"echo "I'm the first script""#,
+            ])?;
+            env.create_script("broken.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-InvalidProvider: {}"#,
+                r#"echo "I'm broken""#,
+            ])?;
+            env.create_script("second.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Testing: {}"#,
+                r#"echo "I'm the second script""#,
+            ])?;
+
+            // By default a single invalid script fails the whole collection
+            let mut blueprint = Blueprint::new(env.state());
+            assert!(blueprint.collect_path(&env.scripts_dir(), false).is_err());
+            assert!(blueprint.repository().get_by_name("first.sh").is_none());
+
+            // Invalid scripts can be skipped, loading the valid ones
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.set_skip_invalid(true);
+            blueprint.collect_path(&env.scripts_dir(), false)?;
+
+            let repository = blueprint.repository();
+            assert!(repository.get_by_name("first.sh").is_some());
+            assert!(repository.get_by_name("second.sh").is_some());
+            assert!(repository.get_by_name("broken.sh").is_none());
+            assert_eq!(blueprint.skipped_count(), 1);
+
+            // The count is updated once the script is fixed
+            env.create_script("broken.sh", &[
+                r#"#!/bin/bash"#,
+                r#"echo "I'm fixed""#,
+            ])?;
+            blueprint.reload()?;
+            assert!(repository.get_by_name("broken.sh").is_some());
+            assert_eq!(blueprint.skipped_count(), 0);
 
             Ok(())
         });
@@ -563,4 +848,181 @@ mod tests {
             Ok(())
         })
     }
+
+
+    #[test]
+    fn test_status_hooks_are_dispatched_in_a_stable_order() {
+        test_wrapper(|env| {
+            // Create some status hooks, with names not in collection order
+            for &(name, priority) in &[
+                ("c.sh", 0), ("a.sh", 0), ("high.sh", 10), ("b.sh", 0),
+                ("low.sh", -10),
+            ] {
+                env.create_script(name, &[
+                    r#"#!/bin/bash"#,
+                    &format!(r#"## Fisher: {{"priority": {}}}"#, priority),
+                    r#"## Fisher-Status: {"events": ["job-completed"]}"#,
+                    r#"echo "ok""#,
+                ])?;
+            }
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.collect_path(&env.scripts_dir(), false)?;
+            let repository = blueprint.repository();
+
+            // Jobs are returned sorted by priority, and then by name
+            for _ in 0..2 {
+                let names = repository
+                    .jobs_after_output(dummy_job_output())
+                    .unwrap()
+                    .map(|job| job.script_name().to_string())
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    names,
+                    vec!["high.sh", "a.sh", "b.sh", "c.sh", "low.sh"]
+                );
+
+                // The order must not change after a reload
+                blueprint.reload()?;
+            }
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_status_hooks_after_expired_jobs() {
+        test_wrapper(|env| {
+            env.create_script("hook.sh", &[r#"#!/bin/bash"#])?;
+            env.create_script("expired.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Status: {"events": ["job-expired"]}"#,
+            ])?;
+            env.create_script("failed.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Status: {"events": ["job-failed"]}"#,
+            ])?;
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.collect_path(&env.scripts_dir(), false)?;
+            let repository = blueprint.repository();
+
+            let job = Job::new(
+                repository.get_by_name("hook.sh").unwrap(),
+                None,
+                Request::Web(dummy_web_request()),
+            );
+            let names = repository
+                .jobs_after_expired(&job)
+                .unwrap()
+                .map(|job| job.script_name().to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec!["expired.sh"]);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_status_hooks_disabled() {
+        test_wrapper(|env| {
+            env.create_script("hook.sh", &[r#"#!/bin/bash"#])?;
+            env.create_script("status.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Status: {"events": ["job-completed"]}"#,
+            ])?;
+
+            // Status hooks are refused when they're disabled
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.set_disable_status_hooks(true);
+            assert_err!(
+                blueprint.collect_path(&env.scripts_dir(), false),
+                ErrorKind::ScriptStatusNotAllowed(..)
+            );
+
+            blueprint.set_skip_invalid(true);
+            blueprint.reload()?;
+            assert_eq!(blueprint.skipped_count(), 1);
+
+            // Manually added status hooks are never triggered either
+            let repository = blueprint.repository();
+            let status = env.load_script("status.sh")?;
+            blueprint.insert(Arc::new(status))?;
+            assert!(repository.get_by_name("status.sh").is_some());
+
+            let job = Job::new(
+                repository.get_by_name("hook.sh").unwrap(),
+                None,
+                Request::Web(dummy_web_request()),
+            );
+            let output = dummy_job_output();
+            assert!(repository.jobs_after_output(output).is_none());
+            assert!(repository.jobs_after_expired(&job).is_none());
+
+            // Status hooks are loaded again once they're enabled
+            blueprint.set_disable_status_hooks(false);
+            blueprint.reload()?;
+            assert_eq!(blueprint.skipped_count(), 0);
+            let output = dummy_job_output();
+            assert!(repository.jobs_after_output(output).is_some());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_status_hooks_after_naked_jobs() {
+        test_wrapper(|env| {
+            env.create_script("naked.sh", &[
+                r#"#!/bin/bash"#,
+                r#"exit 3"#,
+            ])?;
+            env.create_script("killed.sh", &[
+                r#"#!/bin/bash"#,
+                r#"kill -9 $$"#,
+            ])?;
+            env.create_script("status.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Status: {"events": ["job-failed"]}"#,
+                r#"echo "${FISHER_STATUS_SCRIPT_NAME}""#,
+                r#"echo "${FISHER_STATUS_EXIT_CODE}""#,
+                r#"echo "${FISHER_STATUS_SIGNAL}""#,
+            ])?;
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.collect_path(&env.scripts_dir(), false)?;
+            let repository = blueprint.repository();
+            let ctx = Default::default();
+
+            for &(name, expected) in &[
+                ("naked.sh", "naked.sh\n3\n\n"),
+                ("killed.sh", "killed.sh\n\n9\n"),
+            ] {
+                // Naked jobs don't have a provider, so the exit code and
+                // the signal must be forwarded from the job output
+                let job = Job::new(
+                    repository.get_by_name(name).unwrap(),
+                    None,
+                    Request::Web(dummy_web_request()),
+                );
+                let output = job.execute(&ctx)?;
+                assert!(output.trigger_status_hooks);
+
+                let mut status_jobs = repository
+                    .jobs_after_output(output)
+                    .unwrap()
+                    .collect::<Vec<_>>();
+                assert_eq!(status_jobs.len(), 1);
+
+                let status = status_jobs.pop().unwrap().execute(&ctx)?;
+                assert!(status.success);
+                assert_eq!(status.stdout, expected);
+            }
+
+            Ok(())
+        });
+    }
 }
@@ -13,9 +13,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use regex::Regex;
 use serde_json;
@@ -25,6 +27,7 @@ use common::state::{IdKind, State, UniqueId};
 
 use providers::Provider;
 use requests::{Request, RequestType};
+use utils::TimeString;
 
 
 #[derive(Debug, Clone)]
@@ -44,10 +47,41 @@ lazy_static! {
 }
 
 
+/// How the SIGPIPE signal should be handled by a script.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SigpipeHandling {
+    /// Use the default action, terminating the process.
+    Default,
+    /// Ignore the signal, making writes to closed pipes fail instead.
+    Ignore,
+}
+
+
 #[derive(Debug, Deserialize)]
 struct Preferences {
     priority: Option<isize>,
     parallel: Option<bool>,
+    sigpipe: Option<SigpipeHandling>,
+    clean_env: Option<bool>,
+    group: Option<String>,
+    binary_output: Option<bool>,
+    pty: Option<bool>,
+    raw_body: Option<bool>,
+    timeout: Option<u64>,
+    sync: Option<bool>,
+    strip_ansi: Option<bool>,
+    all_providers_must_match: Option<bool>,
+    isolate: Option<bool>,
+    parallel_key: Option<String>,
+    request_headers: Option<bool>,
+    normalize_event: Option<bool>,
+    new_session: Option<bool>,
+    ordered: Option<bool>,
+    require_body: Option<bool>,
+    success_signals: Option<Vec<i32>>,
+    schedule: Option<TimeString>,
+    response_template: Option<String>,
 }
 
 impl Preferences {
@@ -55,6 +89,26 @@ impl Preferences {
         Preferences {
             priority: None,
             parallel: None,
+            sigpipe: None,
+            clean_env: None,
+            group: None,
+            binary_output: None,
+            pty: None,
+            raw_body: None,
+            timeout: None,
+            sync: None,
+            strip_ansi: None,
+            all_providers_must_match: None,
+            isolate: None,
+            parallel_key: None,
+            request_headers: None,
+            normalize_event: None,
+            new_session: None,
+            ordered: None,
+            require_body: None,
+            success_signals: None,
+            schedule: None,
+            response_template: None,
         }
     }
 
@@ -67,12 +121,106 @@ impl Preferences {
     fn parallel(&self) -> bool {
         self.parallel.unwrap_or(true)
     }
+
+    #[inline]
+    fn sigpipe(&self) -> SigpipeHandling {
+        self.sigpipe.unwrap_or(SigpipeHandling::Default)
+    }
+
+    #[inline]
+    fn clean_env(&self) -> bool {
+        self.clean_env.unwrap_or(false)
+    }
+
+    #[inline]
+    fn binary_output(&self) -> bool {
+        self.binary_output.unwrap_or(false)
+    }
+
+    #[inline]
+    fn pty(&self) -> bool {
+        self.pty.unwrap_or(false)
+    }
+
+    #[inline]
+    fn raw_body(&self) -> bool {
+        self.raw_body.unwrap_or(false)
+    }
+
+    #[inline]
+    fn sync(&self) -> bool {
+        self.sync.unwrap_or(false)
+    }
+
+    #[inline]
+    fn strip_ansi(&self) -> bool {
+        self.strip_ansi.unwrap_or(false)
+    }
+
+    #[inline]
+    fn all_providers_must_match(&self) -> bool {
+        self.all_providers_must_match.unwrap_or(false)
+    }
+
+    #[inline]
+    fn isolate(&self) -> bool {
+        self.isolate.unwrap_or(false)
+    }
+
+    #[inline]
+    fn request_headers(&self) -> bool {
+        self.request_headers.unwrap_or(false)
+    }
+
+    #[inline]
+    fn normalize_event(&self) -> bool {
+        self.normalize_event.unwrap_or(false)
+    }
+
+    #[inline]
+    fn new_session(&self) -> bool {
+        self.new_session.unwrap_or(false)
+    }
+
+    #[inline]
+    fn ordered(&self) -> bool {
+        self.ordered.unwrap_or(false)
+    }
+
+    #[inline]
+    fn require_body(&self) -> bool {
+        self.require_body.unwrap_or(false)
+    }
+}
+
+
+/// Render the response template of a script, replacing `{{job_id}}` with the
+/// ID of the job.
+fn render_response_template(template: &str, job_id: &str) -> String {
+    template.replace("{{job_id}}", job_id)
 }
 
 
 struct LoadHeadersOutput {
     preferences: Preferences,
     providers: Vec<Arc<Provider>>,
+    sidecars: Vec<PathBuf>,
+}
+
+
+/// Get the path of the sidecar file a provider header points to (for example
+/// `@github.json`), if it points to one.
+fn sidecar_path(script: &str, data: &str) -> Option<PathBuf> {
+    let sidecar = data.strip_prefix('@')?;
+
+    // Relative paths are resolved against the script's directory
+    let mut path = Path::new(script)
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .to_path_buf();
+    path.push(sidecar.trim());
+
+    Some(path)
 }
 
 
@@ -83,6 +231,7 @@ fn load_headers(file: &str) -> Result<LoadHeadersOutput> {
     let mut content;
     let mut line_number: u32 = 0;
     let mut providers = vec![];
+    let mut sidecars = vec![];
     let mut preferences = None;
     for line in reader.lines() {
         line_number += 1;
@@ -95,7 +244,19 @@ fn load_headers(file: &str) -> Result<LoadHeadersOutput> {
 
         if preferences.is_none() {
             if let Some(cap) = PREFERENCES_HEADER_RE.captures(&content) {
-                preferences = Some(serde_json::from_str(&cap[1])?);
+                let parsed: Preferences = serde_json::from_str(&cap[1])?;
+
+                // Broken templates are rejected now, instead of sending
+                // invalid JSON to the clients
+                if let Some(ref template) = parsed.response_template {
+                    serde_json::from_str::<serde_json::Value>(
+                        &render_response_template(template, "id"),
+                    ).chain_err(|| ErrorKind::ScriptParsingError(
+                        file.into(), line_number,
+                    ))?;
+                }
+
+                preferences = Some(parsed);
                 continue; // Don't capture anything else for this line
             }
         }
@@ -104,7 +265,18 @@ fn load_headers(file: &str) -> Result<LoadHeadersOutput> {
             let name = &cap[1];
             let data = &cap[2];
 
-            match Provider::new(name, data) {
+            // The configuration might be loaded from a sidecar file
+            let config = if let Some(path) = sidecar_path(file, data) {
+                let config = fs::read_to_string(&path);
+                sidecars.push(path);
+                config.map_err(Error::from)
+            } else {
+                Ok(data.to_string())
+            };
+
+            let result = config
+                .and_then(|config| Provider::new(name, &config));
+            match result {
                 Ok(provider) => {
                     providers.push(Arc::new(provider));
                 }
@@ -123,7 +295,8 @@ fn load_headers(file: &str) -> Result<LoadHeadersOutput> {
         } else {
             Preferences::empty()
         },
-        providers: providers,
+        providers,
+        sidecars,
     })
 }
 
@@ -135,6 +308,28 @@ pub struct Script {
     exec: String,
     priority: isize,
     parallel: bool,
+    sigpipe: SigpipeHandling,
+    clean_env: bool,
+    group: Option<String>,
+    binary_output: bool,
+    pty: bool,
+    raw_body: bool,
+    timeout: Option<u64>,
+    sync: bool,
+    strip_ansi: bool,
+    all_providers_must_match: bool,
+    isolate: bool,
+    parallel_key: Option<String>,
+    request_headers: bool,
+    normalize_event: bool,
+    new_session: bool,
+    ordered: bool,
+    require_body: bool,
+    success_signals: Vec<i32>,
+    schedule: Option<Duration>,
+    response_template: Option<String>,
+    interpreter: Option<String>,
+    sidecars: Vec<PathBuf>,
     pub(crate) providers: Vec<Arc<Provider>>,
 }
 
@@ -148,10 +343,38 @@ impl Script {
 
         Ok(Script {
             id: state.next_id(IdKind::HookId),
-            name: name,
-            exec: exec,
+            name,
+            exec,
             priority: headers.preferences.priority(),
             parallel: headers.preferences.parallel(),
+            sigpipe: headers.preferences.sigpipe(),
+            clean_env: headers.preferences.clean_env(),
+            binary_output: headers.preferences.binary_output(),
+            pty: headers.preferences.pty(),
+            raw_body: headers.preferences.raw_body(),
+            timeout: headers.preferences.timeout,
+            sync: headers.preferences.sync(),
+            strip_ansi: headers.preferences.strip_ansi(),
+            all_providers_must_match:
+                headers.preferences.all_providers_must_match(),
+            isolate: headers.preferences.isolate(),
+            request_headers: headers.preferences.request_headers(),
+            normalize_event: headers.preferences.normalize_event(),
+            new_session: headers.preferences.new_session(),
+            ordered: headers.preferences.ordered(),
+            require_body: headers.preferences.require_body(),
+            parallel_key: headers.preferences.parallel_key,
+            success_signals: headers.preferences.success_signals
+                .unwrap_or_default(),
+            // A zero interval would run the script continuously
+            schedule: headers.preferences.schedule
+                .map(|interval| interval.as_u64())
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            response_template: headers.preferences.response_template,
+            interpreter: None,
+            group: headers.preferences.group,
+            sidecars: headers.sidecars,
             providers: headers.providers,
         })
     }
@@ -160,7 +383,22 @@ impl Script {
         &self,
         req: &Request,
     ) -> (RequestType, Option<Arc<Provider>>) {
-        if !self.providers.is_empty() {
+        if !self.providers.is_empty() && self.all_providers_must_match {
+            // Every provider must accept the request, and the first one is
+            // used to process it
+            let mut first = None;
+            for provider in &self.providers {
+                let result = provider.validate(req);
+
+                if result == RequestType::Invalid {
+                    return (RequestType::Invalid, None);
+                }
+                if first.is_none() {
+                    first = Some((result, Some(provider.clone())));
+                }
+            }
+            first.unwrap()
+        } else if !self.providers.is_empty() {
             // Check every provider if they're present
             for provider in &self.providers {
                 let result = provider.validate(req);
@@ -175,6 +413,49 @@ impl Script {
         }
     }
 
+    /// Check if anyone can trigger the script, because the providers
+    /// accepting its requests don't authenticate them.
+    pub fn is_public(&self) -> bool {
+        if self.all_providers_must_match {
+            !self.providers.is_empty()
+                && self.providers.iter().all(|p| p.is_public())
+        } else {
+            self.providers.iter().any(|p| p.is_public())
+        }
+    }
+
+    /// Check if validating the requests of the script might block for a while.
+    pub fn blocking_validation(&self) -> bool {
+        self.providers.iter().any(|p| p.blocking_validation())
+    }
+
+    /// Get the sidecar files the providers of the script are configured with.
+    pub fn sidecars(&self) -> &[PathBuf] {
+        &self.sidecars
+    }
+
+    /// Check if any provider of the script reads the path segment after the
+    /// name of the script.
+    pub fn reads_path_segment(&self) -> bool {
+        self.providers.iter().any(|p| p.reads_path_segment())
+    }
+
+    /// Check if any provider of the script is the Status provider.
+    pub fn uses_status_provider(&self) -> bool {
+        self.providers
+            .iter()
+            .any(|p| matches!(**p, Provider::Status(..)))
+    }
+
+    /// Check if the script can only be triggered by status events.
+    pub fn is_status_hook(&self) -> bool {
+        !self.providers.is_empty()
+            && self
+                .providers
+                .iter()
+                .all(|p| matches!(**p, Provider::Status(..)))
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -186,6 +467,113 @@ impl Script {
     pub fn priority(&self) -> isize {
         self.priority
     }
+
+    pub fn sigpipe(&self) -> SigpipeHandling {
+        self.sigpipe
+    }
+
+    pub fn clean_env(&self) -> bool {
+        self.clean_env
+    }
+
+    pub fn binary_output(&self) -> bool {
+        self.binary_output
+    }
+
+    pub fn pty(&self) -> bool {
+        self.pty
+    }
+
+    pub fn raw_body(&self) -> bool {
+        self.raw_body
+    }
+
+    /// Render the body returned when a job of the script is queued, if the
+    /// script has a response template.
+    pub fn render_response(&self, job_id: &str) -> Option<String> {
+        self.response_template.as_ref()
+            .map(|template| render_response_template(template, job_id))
+    }
+
+    /// The number of seconds the script is allowed to run for, if it
+    /// overrides the default timeout. Zero means it's never killed.
+    pub fn timeout(&self) -> Option<u64> {
+        self.timeout
+    }
+
+    /// Check if the script should be executed while the client waits, and
+    /// its output returned in the response.
+    pub fn sync(&self) -> bool {
+        self.sync
+    }
+
+    pub fn strip_ansi(&self) -> bool {
+        self.strip_ansi
+    }
+
+    /// Check if the script should run in its own mount namespace.
+    pub fn isolate(&self) -> bool {
+        self.isolate
+    }
+
+    /// Check if all the headers of the request should be provided to the
+    /// script.
+    pub fn request_headers(&self) -> bool {
+        self.request_headers
+    }
+
+    /// Check if the normalized name of the event should be provided to the
+    /// script.
+    pub fn normalize_event(&self) -> bool {
+        self.normalize_event
+    }
+
+    /// Check if the script should run in its own session, instead of only
+    /// in its own process group.
+    pub fn new_session(&self) -> bool {
+        self.new_session
+    }
+
+    /// Check if POST requests without a body should be rejected.
+    pub fn require_body(&self) -> bool {
+        self.require_body
+    }
+
+    /// Get the signals which don't mark the job as failed if it's killed by
+    /// one of them.
+    pub fn success_signals(&self) -> &[i32] {
+        &self.success_signals
+    }
+
+    /// Get the key of the request: jobs with different keys can run in
+    /// parallel, while the ones with the same key can't.
+    pub fn parallel_key(&self, req: &Request) -> Option<String> {
+        // Ordered scripts run one job at a time, regardless of the key
+        if self.ordered {
+            return None;
+        }
+
+        let pointer = self.parallel_key.as_ref()?;
+        let body = match *req {
+            Request::Web(ref web) => &web.body,
+            Request::Status(..) | Request::Scheduled(..) => return None,
+        };
+
+        let parsed: serde_json::Value = serde_json::from_str(body).ok()?;
+        match *parsed.pointer(pointer)? {
+            serde_json::Value::String(ref key) => Some(key.clone()),
+            ref other => Some(other.to_string()),
+        }
+    }
+
+    /// The program used to run the script, if it can't be executed directly.
+    pub fn interpreter(&self) -> Option<&str> {
+        self.interpreter.as_deref()
+    }
+
+    pub(in scripts) fn set_interpreter(&mut self, shell: Option<String>) {
+        self.interpreter = shell;
+    }
 }
 
 impl ScriptTrait for Script {
@@ -195,14 +583,32 @@ impl ScriptTrait for Script {
         self.id
     }
 
+    fn name(&self) -> &str {
+        &self.name
+    }
+
     fn can_be_parallel(&self) -> bool {
-        self.parallel
+        // Scripts with a parallel key are serialized for each key, while
+        // ordered scripts run their jobs one at a time
+        self.parallel && self.parallel_key.is_none() && !self.ordered
+    }
+
+    fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    fn schedule(&self) -> Option<Duration> {
+        self.schedule
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+
     use common::prelude::*;
     use requests::{Request, RequestType};
     use scripts::test_utils::*;
@@ -267,6 +673,20 @@ mod tests {
                 &[],
             )?;
 
+            // Ordered scripts can't be parallel
+            create_and_assert(
+                &env,
+                "ordered.sh",
+                &[
+                    r#"#!/bin/bash"#,
+                    r#"## Fisher: {"ordered": true}"#,
+                    r#"echo "This script is ordered""#,
+                ],
+                0,
+                false,
+                &[],
+            )?;
+
             // Check if scripts with one provider are loaded properly
             create_and_assert(
                 &env,
@@ -316,6 +736,51 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_provider_config_from_sidecar_file() {
+        test_wrapper(|env| {
+            env.create_script("sidecar.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Standalone: @standalone.json"#,
+                r#"echo "ok""#,
+            ])?;
+            let sidecar = env.scripts_dir().join("standalone.json");
+            fs::write(&sidecar, r#"{"secret": "abcde"}"#)?;
+
+            let script = env.load_script("sidecar.sh")?;
+            assert_eq!(script.providers.len(), 1);
+            assert_eq!(script.providers[0].name(), "Standalone");
+
+            // The configuration was loaded from the sidecar file
+            let mut req = dummy_web_request();
+            assert_eq!(
+                script.validate(&req.clone().into()).0,
+                RequestType::Invalid
+            );
+            req.params.insert("secret".into(), "abcde".into());
+            assert_eq!(script.validate(&req.into()).0, RequestType::ExecuteHook);
+
+            // Relative paths are resolved against the script's directory
+            fs::create_dir(env.scripts_dir().join("sub"))?;
+            env.create_script("sub/nested.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Standalone: @../standalone.json"#,
+                r#"echo "ok""#,
+            ])?;
+            assert_eq!(env.load_script("sub/nested.sh")?.providers.len(), 1);
+
+            // Missing and invalid sidecar files are errors
+            fs::remove_file(&sidecar)?;
+            assert!(env.load_script("sidecar.sh").is_err());
+
+            fs::write(&sidecar, "not json")?;
+            assert!(env.load_script("sidecar.sh").is_err());
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_requests_can_be_validated_against_scripts() {
         test_wrapper(|env| {
@@ -371,6 +836,136 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_all_providers_must_match() {
+        test_wrapper(|env| {
+            env.create_script("all.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"all_providers_must_match": true}"#,
+                r#"## Fisher-Standalone: {"from": ["127.0.0.1"]}"#,
+                r#"## Fisher-Standalone: {"secret": "abcde"}"#,
+                r#"echo "ok""#,
+            ])?;
+            let script = env.load_script("all.sh")?;
+
+            // Both the IP address and the secret must be correct
+            let mut req = dummy_web_request();
+            req.params.insert("secret".into(), "abcde".into());
+            let (result, provider) = script.validate(&req.clone().into());
+            assert_eq!(result, RequestType::ExecuteHook);
+            assert!(Arc::ptr_eq(&provider.unwrap(), &script.providers[0]));
+
+            // Failing any of the providers rejects the request
+            let mut wrong_secret = req.clone();
+            wrong_secret.params.insert("secret".into(), "wrong".into());
+            assert_eq!(
+                script.validate(&wrong_secret.into()).0,
+                RequestType::Invalid
+            );
+
+            let mut wrong_ip = req.clone();
+            wrong_ip.source = "10.0.0.1".parse().unwrap();
+            assert_eq!(
+                script.validate(&wrong_ip.into()).0,
+                RequestType::Invalid
+            );
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_ordered_scripts_ignore_the_parallel_key() {
+        test_wrapper(|env| {
+            env.create_script("keyed.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"parallel_key": "/repo"}"#,
+            ])?;
+            env.create_script("ordered.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"parallel_key": "/repo", "ordered": true}"#,
+            ])?;
+
+            let mut req = dummy_web_request();
+            req.body = r#"{"repo": "fisher"}"#.into();
+            let req: Request = req.into();
+
+            let keyed = env.load_script("keyed.sh")?;
+            assert_eq!(keyed.parallel_key(&req), Some("fisher".into()));
+
+            // Every job of ordered scripts waits for the previous one
+            let ordered = env.load_script("ordered.sh")?;
+            assert_eq!(ordered.parallel_key(&req), None);
+            assert!(!ordered.can_be_parallel());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_scheduled_scripts() {
+        test_wrapper(|env| {
+            for &(name, config) in &[
+                ("scheduled.sh", r#"## Fisher: {"schedule": "5m"}"#),
+                ("zero.sh", r#"## Fisher: {"schedule": "0s"}"#),
+                ("normal.sh", ""),
+                ("invalid.sh", r#"## Fisher: {"schedule": "5x"}"#),
+            ] {
+                env.create_script(name, &[r#"#!/bin/bash"#, config])?;
+            }
+
+            let scheduled = env.load_script("scheduled.sh")?;
+            assert_eq!(scheduled.schedule(), Some(Duration::from_secs(300)));
+
+            // A zero interval disables the schedule
+            assert_eq!(env.load_script("zero.sh")?.schedule(), None);
+            assert_eq!(env.load_script("normal.sh")?.schedule(), None);
+
+            assert!(env.load_script("invalid.sh").is_err());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_response_templates() {
+        test_wrapper(|env| {
+            for &(name, config) in &[
+                (
+                    "template.sh",
+                    concat!(
+                        r#"## Fisher: {"response_template": "#,
+                        r#""{\"id\": \"{{job_id}}\"}"}"#,
+                    ),
+                ),
+                ("normal.sh", ""),
+                (
+                    "invalid.sh",
+                    r#"## Fisher: {"response_template": "{\"id\": "}"#,
+                ),
+            ] {
+                env.create_script(name, &[r#"#!/bin/bash"#, config])?;
+            }
+
+            let template = env.load_script("template.sh")?;
+            assert_eq!(
+                template.render_response("abc-123"),
+                Some(r#"{"id": "abc-123"}"#.into()),
+            );
+            let normal = env.load_script("normal.sh")?;
+            assert_eq!(normal.render_response("abc-123"), None);
+
+            // Templates which aren't valid JSON are rejected
+            assert!(env.load_script("invalid.sh").is_err());
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_script_ids_are_unique() {
         test_wrapper(|env| {
@@ -17,6 +17,7 @@ use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -66,6 +67,24 @@ impl TestEnv {
         self.create_script_into(&self.scripts_dir, name, content)
     }
 
+    /// Create two bash scripts with the same body: `plain` without any
+    /// configuration comment, and `with` using the provided `preferences`.
+    pub fn create_script_pair(
+        &self,
+        plain: &str,
+        with: &str,
+        preferences: &str,
+        body: &[&str],
+    ) -> Result<()> {
+        let mut content = vec!["#!/bin/bash"];
+        content.extend_from_slice(body);
+        self.create_script(plain, &content)?;
+
+        let header = format!("## Fisher: {}", preferences);
+        content.insert(1, &header);
+        self.create_script(with, &content)
+    }
+
     pub fn create_script_into(
         &self,
         path: &PathBuf,
@@ -100,10 +119,20 @@ impl TestEnv {
 
 pub fn dummy_web_request() -> WebRequest {
     WebRequest {
+        id: "dummy-request-id".into(),
+        method: "GET".into(),
+        path: "/".into(),
         headers: HashMap::new(),
+        host: None,
         params: HashMap::new(),
+        path_segment: None,
         source: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
         body: String::new(),
+        raw_body: None,
+        received_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
     }
 }
 
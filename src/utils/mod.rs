@@ -21,6 +21,7 @@ mod parse_env;
 
 mod net;
 mod hex;
+mod compare;
 mod parse_time;
 
 
@@ -29,4 +30,5 @@ pub use utils::parse_env::parse_env;
 
 pub use utils::net::parse_forwarded_for;
 pub use utils::hex::from_hex;
+pub use utils::compare::constant_time_eq;
 pub use utils::parse_time::{parse_time, TimeString};
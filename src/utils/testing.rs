@@ -13,8 +13,10 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, TcpStream};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::path::PathBuf;
 use std::sync::{mpsc, Arc};
 use std::fs;
@@ -25,11 +27,17 @@ use tempdir::TempDir;
 
 use common::prelude::*;
 use common::state::State;
-use common::structs::HealthDetails;
+use common::structs::{
+    HealthDetails, HookMetrics, JobFailure, LoadSnapshot, Metrics,
+};
+#[cfg(feature = "diagnostics")]
+use common::structs::SerialDetails;
+#[cfg(feature = "diagnostics")]
+use common::serial::Serial;
 use common::config::{HttpConfig, RateLimitConfig};
 
 use scripts::{Blueprint as HooksBlueprint, Repository as Hooks};
-use scripts::{Job, JobOutput};
+use scripts::{Job, JobContext, JobOutput};
 use web::{WebApp, WebRequest};
 
 
@@ -76,10 +84,20 @@ macro_rules! hashmap {
 
 pub fn dummy_web_request() -> WebRequest {
     WebRequest {
+        id: "dummy-request-id".into(),
+        method: "GET".into(),
+        path: "/".into(),
         headers: HashMap::new(),
+        host: None,
         params: HashMap::new(),
+        path_segment: None,
         source: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
         body: String::new(),
+        raw_body: None,
+        received_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
     }
 }
 
@@ -88,13 +106,22 @@ pub fn dummy_job_output() -> JobOutput {
     JobOutput {
         stdout: "hello world".into(),
         stderr: "something happened".into(),
+        summary: None,
+
+        raw_stdout: None,
+        raw_stderr: None,
 
         success: true,
         exit_code: Some(0),
         signal: None,
 
+        cpu_ms: Some(150),
+        max_rss_kb: Some(2048),
+        leaked_dirs: 0,
+
         script_name: "test".into(),
         request_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        triggered_by: None,
 
         trigger_status_hooks: true,
     }
@@ -158,6 +185,7 @@ pub fn sample_hooks() -> PathBuf {
         r#"env > "${b}/env""#,
         r#"pwd > "${b}/pwd""#,
         r#"cat "${FISHER_REQUEST_BODY}" > "${b}/request_body""#,
+        r#"cat "FISHER_REQUEST_ID" > "${b}/request_id""#,
         r#"cat "prepared" > "${b}/prepared""#
     );
 
@@ -187,6 +215,32 @@ pub fn sample_hooks() -> PathBuf {
         r#"echo "triggering...";"#
     );
 
+    create_hook!(
+        tempdir,
+        "sync.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher: {"sync": true}"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"echo "Hello from ${FISHER_TESTING_ENV:-nobody}""#,
+        r#"[[ -z "${FISHER_TESTING_ENV}" ]] || exit 1"#
+    );
+
+    create_hook!(
+        tempdir,
+        "require-body.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher: {"require_body": true}"#,
+        r#"echo "Hello world""#
+    );
+
+    create_hook!(
+        tempdir,
+        "github.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-GitHub: {"secret": "secret"}"#,
+        r#"echo "Hello world""#
+    );
+
     create_hook!(
         tempdir,
         "status-example.sh",
@@ -198,6 +252,37 @@ pub fn sample_hooks() -> PathBuf {
         r#"echo "triggered!""#
     );
 
+    create_hook!(
+        tempdir,
+        "template.sh",
+        r#"#!/bin/bash"#,
+        concat!(
+            r#"## Fisher: {"response_template": "#,
+            r#""{\"queued\": true, \"job\": \"{{job_id}}\"}"}"#,
+        ),
+        r#"## Fisher-Testing: {}"#,
+        r#"echo "Hello world""#
+    );
+
+    create_hook!(
+        tempdir,
+        "exec.sh",
+        r#"#!/bin/bash"#,
+        concat!(
+            r#"## Fisher-Exec: {"command": "bash", "timeout": 5, "args": "#,
+            r#"["-c", "sleep 1; [[ ${FISHER_REQUEST_METHOD} == POST ]]"]}"#,
+        ),
+        r#"echo "Hello world""#
+    );
+
+    create_hook!(
+        tempdir,
+        "path-secret.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Standalone: {"secret": "abcde", "secret_in_path": true}"#,
+        r#"echo "Hello world""#
+    );
+
     fs::create_dir(&tempdir.join("sub")).unwrap();
     create_hook!(
         tempdir.join("sub"),
@@ -214,9 +299,14 @@ pub fn sample_hooks() -> PathBuf {
 pub enum ProcessorApiCall {
     Queue(Job, isize),
     HealthDetails,
+    LoadSnapshot,
+    Metrics,
+    LastErrors,
     Cleanup,
     Lock,
     Unlock,
+    SetMaxThreads(u16),
+    ResetRuntimeBudget,
 }
 
 
@@ -230,15 +320,73 @@ impl ProcessorApiTrait<Hooks> for FakeProcessorApi {
         Ok(())
     }
 
+    fn queue_sync(
+        &self, job: Job, priority: isize,
+    ) -> Result<SyncJobOutput<Hooks>> {
+        // The job is executed right away, since there is no real processor
+        let (reply_send, reply_recv) = mpsc::channel();
+        reply_send.send(job.execute(&JobContext::default()).ok())?;
+
+        self.sender.send(ProcessorApiCall::Queue(job, priority))?;
+        Ok(reply_recv)
+    }
+
     fn health_details(&self) -> Result<HealthDetails> {
         self.sender.send(ProcessorApiCall::HealthDetails)?;
+
+        let mut provider_jobs = BTreeMap::new();
+        provider_jobs.insert("GitHub".into(), 3);
         Ok(HealthDetails {
             queued_jobs: 1,
             busy_threads: 2,
             max_threads: 3,
+            draining: false,
+            provider_jobs: Some(provider_jobs),
+            loaded_hooks: None,
+            uptime_secs: None,
+            locked: None,
         })
     }
 
+    fn load_snapshot(&self) -> Result<LoadSnapshot> {
+        self.sender.send(ProcessorApiCall::LoadSnapshot)?;
+
+        let mut priorities = BTreeMap::new();
+        priorities.insert(0, 1);
+        Ok(LoadSnapshot {
+            queued_jobs: 1,
+            busy_threads: 2,
+            max_threads: 3,
+            priorities: priorities,
+            average_wait_ms: 500,
+        })
+    }
+
+    fn metrics(&self) -> Result<Metrics> {
+        self.sender.send(ProcessorApiCall::Metrics)?;
+
+        let mut metrics = Metrics::default();
+        metrics.hooks.insert("example.sh".into(), HookMetrics {
+            running: 1,
+            waiting: 2,
+        });
+        metrics.provider_jobs.insert("GitHub".into(), 3);
+        Ok(metrics)
+    }
+
+    fn last_errors(&self) -> Result<BTreeMap<String, JobFailure>> {
+        self.sender.send(ProcessorApiCall::LastErrors)?;
+
+        let mut errors = BTreeMap::new();
+        errors.insert("example.sh".into(), JobFailure {
+            exit_code: Some(1),
+            signal: None,
+            stderr: "failed!\n".into(),
+            timestamp: 1500000000,
+        });
+        Ok(errors)
+    }
+
     fn cleanup(&self) -> Result<()> {
         self.sender.send(ProcessorApiCall::Cleanup)?;
         Ok(())
@@ -253,6 +401,29 @@ impl ProcessorApiTrait<Hooks> for FakeProcessorApi {
         self.sender.send(ProcessorApiCall::Unlock)?;
         Ok(())
     }
+
+    fn accepting_jobs(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn reset_runtime_budget(&self) -> Result<()> {
+        self.sender.send(ProcessorApiCall::ResetRuntimeBudget)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "diagnostics")]
+    fn serials(&self) -> Result<SerialDetails> {
+        let current = Serial::zero().next().next();
+        Ok(SerialDetails {
+            current: current,
+            queued: vec![current],
+        })
+    }
+
+    fn set_max_threads(&self, max: u16) -> Result<()> {
+        self.sender.send(ProcessorApiCall::SetMaxThreads(max))?;
+        Ok(())
+    }
 }
 
 
@@ -266,25 +437,13 @@ pub struct WebAppInstance {
 }
 
 impl WebAppInstance {
-    pub fn new(hooks: Arc<Hooks>, health: bool, behind_proxies: u8) -> Self {
+    pub fn new(hooks: Arc<Hooks>, config: HttpConfig) -> Self {
         let (chan_send, chan_recv) = mpsc::channel();
         let fake_processor = FakeProcessorApi { sender: chan_send };
 
         // Start the web server
         // Create a new instance of WebApp
-        let inst = WebApp::new(
-            hooks,
-            &HttpConfig {
-                behind_proxies,
-                bind: "127.0.0.1:0".parse().unwrap(),
-                rate_limit: RateLimitConfig {
-                    allowed: ::std::u64::MAX,
-                    interval: ::std::u64::MAX.into(),
-                },
-                health_endpoint: health,
-            },
-            fake_processor,
-        ).unwrap();
+        let inst = WebApp::new(hooks, &config, fake_processor).unwrap();
 
         // Create the HTTP client
         let url = format!("http://{}", inst.addr());
@@ -308,6 +467,24 @@ impl WebAppInstance {
         self.client.request(method, &format!("{}{}", self.url, url))
     }
 
+    /// Get the full URL of a path on the server.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    /// Send a raw HTTP request, without any normalization of the URL done
+    /// by the client, returning the raw response.
+    pub fn raw_request(&self, request: &str) -> String {
+        let addr = self.url.trim_start_matches("http://");
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write_all(request.as_bytes()).unwrap();
+        conn.shutdown(Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        response
+    }
+
     pub fn processor_input(&self) -> Option<ProcessorApiCall> {
         if let Ok(result) = self.processor_api_call.try_recv() {
             Some(result)
@@ -366,6 +543,26 @@ impl TestingEnv {
         health: bool,
         behind_proxies: u8,
     ) -> WebAppInstance {
-        WebAppInstance::new(self.hooks.clone(), health, behind_proxies)
+        self.start_web_with(|config| {
+            config.health_endpoint = health;
+            config.behind_proxies = behind_proxies;
+        })
+    }
+
+    pub fn start_web_with<F: FnOnce(&mut HttpConfig)>(
+        &self,
+        customize: F,
+    ) -> WebAppInstance {
+        let mut config = HttpConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            rate_limit: RateLimitConfig {
+                allowed: ::std::u64::MAX,
+                interval: ::std::u64::MAX.into(),
+            },
+            .. HttpConfig::default()
+        };
+        customize(&mut config);
+
+        WebAppInstance::new(self.hooks.clone(), config)
     }
 }
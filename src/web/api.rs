@@ -15,52 +15,166 @@
 
 use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+
+use ansi_term::Colour;
+use serde_json;
 
 use common::prelude::*;
-use common::config::RateLimitConfig;
+use common::config::HttpConfig;
 
+use providers::Provider;
 use requests::{Request, RequestType};
-use scripts::{Repository, Job};
+use web::WebRequest;
+use scripts::{Repository, Job, Script};
 use web::rate_limits::RateLimiter;
-use web::responses::Response;
+use utils;
+use web::responses::{
+    Deferred, QueueDepth, Response, TriggerResult, TriggerStatus,
+};
+
+
+/// How many requests to hooks with blocking validators can be validated at
+/// the same time outside of the thread accepting new requests.
+const MAX_BLOCKING_VALIDATIONS: usize = 8;
 
 
-#[derive(Clone)]
 pub struct WebApi<A: ProcessorApiTrait<Repository>> {
     processor: Arc<Mutex<A>>,
     hooks: Arc<Repository>,
     locked: Arc<AtomicBool>,
     limiter: Arc<Mutex<RateLimiter<IpAddr>>>,
+    sync_jobs: Arc<AtomicUsize>,
+    validations: Arc<AtomicUsize>,
+    max_sync_jobs: usize,
+    max_queued_jobs: Option<usize>,
+    hide_forbidden_hooks: bool,
+    ping_no_content: bool,
+    log_accepted_hooks: bool,
+    redacted_params: Vec<String>,
 
     health_enabled: bool,
+    metrics_enabled: bool,
+    provider_labels: bool,
+    api_token: Option<String>,
+    started: Instant,
+}
+
+
+#[derive(Deserialize)]
+struct ThreadsConfig {
+    threads: u16,
 }
 
-impl<A: ProcessorApiTrait<Repository>> WebApi<A> {
+// The processor is shared, so it doesn't need to be cloned
+impl<A: ProcessorApiTrait<Repository>> Clone for WebApi<A> {
+    fn clone(&self) -> Self {
+        WebApi {
+            processor: self.processor.clone(),
+            hooks: self.hooks.clone(),
+            locked: self.locked.clone(),
+            limiter: self.limiter.clone(),
+            sync_jobs: self.sync_jobs.clone(),
+            validations: self.validations.clone(),
+            max_sync_jobs: self.max_sync_jobs,
+            max_queued_jobs: self.max_queued_jobs,
+            hide_forbidden_hooks: self.hide_forbidden_hooks,
+            ping_no_content: self.ping_no_content,
+            log_accepted_hooks: self.log_accepted_hooks,
+            redacted_params: self.redacted_params.clone(),
+            health_enabled: self.health_enabled,
+            metrics_enabled: self.metrics_enabled,
+            provider_labels: self.provider_labels,
+            api_token: self.api_token.clone(),
+            started: self.started,
+        }
+    }
+}
+
+impl<A: ProcessorApiTrait<Repository> + 'static> WebApi<A> {
     pub fn new(
         processor: A,
         hooks: Arc<Repository>,
         locked: Arc<AtomicBool>,
-        rate_limit_config: &RateLimitConfig,
-        health_enabled: bool,
+        config: &HttpConfig,
     ) -> Self {
         let limiter = Arc::new(Mutex::new(RateLimiter::new(
-            rate_limit_config.allowed,
-            rate_limit_config.interval.as_u64(),
+            config.rate_limit.allowed,
+            config.rate_limit.interval.as_u64(),
         )));
 
         WebApi {
             processor: Arc::new(Mutex::new(processor)),
-            hooks, locked, limiter, health_enabled,
+            hooks, locked, limiter,
+            sync_jobs: Arc::new(AtomicUsize::new(0)),
+            validations: Arc::new(AtomicUsize::new(0)),
+            max_sync_jobs: config.max_sync_jobs,
+            max_queued_jobs: config.max_queued_jobs,
+            hide_forbidden_hooks: config.hide_forbidden_hooks,
+            ping_no_content: config.ping_no_content,
+            log_accepted_hooks: config.log_accepted_hooks,
+            redacted_params: config.redacted_params.clone(),
+            health_enabled: config.health_endpoint,
+            metrics_enabled: config.metrics_endpoint,
+            provider_labels: config.provider_labels,
+            api_token: config.api_token.clone(),
+            started: Instant::now(),
         }
     }
 
+    /// Check if the request carries the configured API token. If no token is
+    /// configured all the requests are rejected.
+    fn is_authorized(&self, req: &Request) -> bool {
+        let token = if let Some(ref token) = self.api_token {
+            token
+        } else {
+            return false;
+        };
+
+        if let Ok(web) = req.web() {
+            if let Some(header) = web.header("Authorization") {
+                return utils::constant_time_eq(
+                    header.as_bytes(),
+                    format!("Bearer {}", token).as_bytes(),
+                );
+            }
+        }
+
+        false
+    }
+
+    /// Check if a request to a management endpoint can be processed,
+    /// returning the response to send back if it can't.
+    fn management_request<'a>(
+        &self, req: &'a Request,
+    ) -> ::std::result::Result<&'a WebRequest, Response> {
+        let web = if let Ok(web) = req.web() {
+            web
+        } else {
+            return Err(Response::Forbidden);
+        };
+
+        // Check if the user is not rate limited
+        let limited = self.limiter.lock().unwrap().is_limited(&web.source);
+        if let Some(until) = limited {
+            return Err(Response::TooManyRequests(until));
+        }
+
+        if !self.is_authorized(req) {
+            self.limiter.lock().unwrap().increment(web.source);
+            return Err(Response::Forbidden);
+        }
+
+        Ok(web)
+    }
+
     pub fn process_hook(&self, req: &Request, args: Vec<String>) -> Response {
         let hook_name = &args[0];
 
         // Don't process hooks if the web api is locked
         if self.locked.load(Ordering::Relaxed) {
-            return Response::Unavailable;
+            return Response::Unavailable(None);
         }
 
         // Check if the user is not rate limited
@@ -71,32 +185,154 @@ impl<A: ProcessorApiTrait<Repository>> WebApi<A> {
             }
         }
 
-        // Check if the hook exists
-        let hook;
-        if let Some(found) = self.hooks.get_by_name(hook_name) {
-            hook = found;
-        } else {
-            return Response::NotFound;
+        // Names that look like paths outside the scripts directory are never
+        // valid, even if they're not resolved to paths when looking them up
+        if is_suspicious_hook_name(hook_name) {
+            return Response::BadRequest(
+                ErrorKind::SuspiciousHookName(hook_name.clone()).into(),
+            );
         }
 
+        // Check if the hook exists, optionally followed by a path segment
+        // if the hook's providers read it
+        let (hook, segment) =
+            if let Some(found) = self.hooks.get_by_name(hook_name) {
+                (found, None)
+            } else if let Some(found) = self.hook_with_segment(hook_name) {
+                (found.0, Some(found.1))
+            } else {
+                // Probing missing hooks must be limited like invalid
+                // requests, or clients could still tell them apart
+                if self.hide_forbidden_hooks {
+                    if let Ok(r) = req.web() {
+                        self.limiter.lock().unwrap().increment(r.source);
+                    }
+                }
+                return Response::NotFound;
+            };
+
+        // The path segment is passed to the providers with the request
+        let with_segment;
+        let req = match (segment, req.web()) {
+            (Some(segment), Ok(web)) => {
+                let mut web = web.clone();
+                web.path_segment = Some(segment.to_string());
+                with_segment = Request::Web(web);
+                &with_segment
+            },
+            _ => req,
+        };
+
+        // Validators which might block are run in another thread, so they
+        // don't delay the other requests. Once too many of them are running
+        // the request is rejected, since validating it here would stall the
+        // thread accepting new requests
+        if hook.blocking_validation() {
+            let running = self.validations.fetch_add(1, Ordering::SeqCst);
+            if running < MAX_BLOCKING_VALIDATIONS {
+                let api = self.clone();
+                let req = req.clone();
+                return Response::Deferred(Deferred::new(move || {
+                    let response = match api.handle_hook(&hook, &req) {
+                        Response::Deferred(deferred) => deferred.wait(),
+                        response => response,
+                    };
+                    api.validations.fetch_sub(1, Ordering::SeqCst);
+                    response
+                }));
+            }
+            self.validations.fetch_sub(1, Ordering::SeqCst);
+            return Response::Unavailable(None);
+        }
+
+        self.handle_hook(&hook, req)
+    }
+
+    /// Validate a request to a hook, and queue its job if it's accepted.
+    fn handle_hook(&self, hook: &Arc<Script>, req: &Request) -> Response {
         // Validate the hook
         let (request_type, provider) = hook.validate(req);
 
         // Change behavior based on the request type
         match request_type {
             // Don't do anything if it's only a ping
-            RequestType::Ping => Response::Ok,
+            RequestType::Ping => if self.ping_no_content {
+                Response::NoContent
+            } else {
+                Response::Ok
+            },
 
             // Queue a job if the hook should be executed
             RequestType::ExecuteHook => {
+                // Reject POST requests without a body if the hook needs one
+                if hook.require_body() {
+                    if let Ok(r) = req.web() {
+                        if r.method == "POST" && r.body.is_empty() {
+                            return Response::BadRequest(
+                                ErrorKind::RequestBodyMissing.into(),
+                            );
+                        }
+                    }
+                }
+
+                let processor = self.processor.lock().unwrap();
+
+                // Reject the job if the runtime budget is used up
+                if !processor.accepting_jobs().unwrap() {
+                    return Response::Unavailable(None);
+                }
+
+                // Reject the job if the queue is full, telling the client
+                // how full it is so it can slow down
+                if let Some(max) = self.max_queued_jobs {
+                    let current = processor.health_details().unwrap()
+                        .queued_jobs;
+                    if current >= max {
+                        return Response::Unavailable(Some(QueueDepth {
+                            current, max,
+                        }));
+                    }
+                }
+
+                // The message is prepared before the provider is moved into
+                // the job, but it's logged only once the job is queued
+                let accepted = if self.log_accepted_hooks {
+                    Some(accepted_hook_message(
+                        &hook,
+                        provider.as_deref(),
+                        req,
+                        &self.redacted_params,
+                    ))
+                } else {
+                    None
+                };
+
                 let job = Job::new(hook.clone(), provider, req.clone());
-                self.processor
-                    .lock()
-                    .unwrap()
-                    .queue(job, hook.priority())
-                    .unwrap();
+                if hook.sync() {
+                    return self.process_sync(&*processor, job, hook, accepted);
+                }
 
-                Response::Ok
+                let rendered = job.request_id()
+                    .and_then(|id| hook.render_response(id));
+
+                match processor.queue(job, hook.priority()) {
+                    Ok(()) => {
+                        log_accepted_hook(accepted);
+                        match rendered {
+                            Some(body) => Response::Rendered(body),
+                            None => Response::Ok,
+                        }
+                    },
+                    // The scheduler can't keep up with the incoming jobs
+                    Err(Error(ErrorKind::SchedulerBusy(..), _)) => {
+                        Response::Unavailable(None)
+                    },
+                    // The scheduler isn't running anymore
+                    Err(err) => {
+                        err.pretty_print();
+                        Response::Unavailable(None)
+                    },
+                }
             },
 
             RequestType::Invalid => {
@@ -105,18 +341,389 @@ impl<A: ProcessorApiTrait<Repository>> WebApi<A> {
                     self.limiter.lock().unwrap().increment(r.source);
                 }
 
-                Response::Forbidden
+                // Don't let clients find out which hooks exist
+                if self.hide_forbidden_hooks {
+                    Response::NotFound
+                } else {
+                    Response::Forbidden
+                }
             },
         }
     }
 
+    /// Find the hook named as the path without its last segment, if its
+    /// providers read that segment.
+    fn hook_with_segment<'a>(
+        &self, path: &'a str,
+    ) -> Option<(Arc<Script>, &'a str)> {
+        let (name, segment) = path.rsplit_once('/')?;
+        let hook = self.hooks.get_by_name(name)?;
+
+        if hook.reads_path_segment() && !segment.is_empty() {
+            Some((hook, segment))
+        } else {
+            None
+        }
+    }
+
+    /// Queue the job, and return its output in the response once it's
+    /// executed.
+    fn process_sync(
+        &self,
+        processor: &A,
+        job: Job,
+        hook: &Script,
+        accepted: Option<String>,
+    ) -> Response {
+        // Limit the number of requests waiting for their job
+        let running = self.sync_jobs.fetch_add(1, Ordering::SeqCst);
+        if running >= self.max_sync_jobs {
+            self.sync_jobs.fetch_sub(1, Ordering::SeqCst);
+            return Response::Unavailable(None);
+        }
+
+        let output = match processor.queue_sync(job, hook.priority()) {
+            Ok(output) => {
+                log_accepted_hook(accepted);
+                output
+            },
+            Err(err) => {
+                self.sync_jobs.fetch_sub(1, Ordering::SeqCst);
+                // The scheduler isn't running anymore if it's not just busy
+                match *err.kind() {
+                    ErrorKind::SchedulerBusy(..) => {},
+                    _ => err.pretty_print(),
+                }
+                return Response::Unavailable(None);
+            },
+        };
+
+        let sync_jobs = self.sync_jobs.clone();
+        Response::Deferred(Deferred::new(move || {
+            let response = match output.recv() {
+                Ok(Some(output)) => {
+                    Response::HookOutput(output.success, output.stdout)
+                },
+                // The error was already logged by the processor
+                Ok(None) => Response::HookOutput(false, String::new()),
+                // The job was dropped without being executed
+                Err(..) => Response::Unavailable(None),
+            };
+
+            sync_jobs.fetch_sub(1, Ordering::SeqCst);
+            response
+        }))
+    }
+
+    pub fn trigger_hooks(&self, req: &Request, _args: Vec<String>) -> Response {
+        let web = match self.management_request(req) {
+            Ok(web) => web,
+            Err(response) => return response,
+        };
+
+        // Don't process hooks if the web api is locked
+        if self.locked.load(Ordering::Relaxed) {
+            return Response::Unavailable(None);
+        }
+
+        let names: Vec<String> = match serde_json::from_str(&web.body) {
+            Ok(names) => names,
+            Err(err) => return Response::BadRequest(err.into()),
+        };
+
+        let processor = self.processor.lock().unwrap();
+
+        let mut results = Vec::with_capacity(names.len());
+        for name in names {
+            let status = if let Some(hook) = self.hooks.get_by_name(&name) {
+                // Status hooks can only be triggered by Fisher itself, and
+                // the request doesn't carry a body for the hooks needing one
+                if hook.is_status_hook() || hook.require_body() {
+                    TriggerStatus::Forbidden
+                } else if !self.can_queue(&*processor) {
+                    TriggerStatus::Unavailable
+                } else {
+                    // The request was already authorized with the token, so
+                    // the hook's providers are not involved
+                    let job = Job::new(hook.clone(), None, req.clone());
+                    let result = processor.queue(job, hook.priority());
+
+                    match result {
+                        Ok(()) => TriggerStatus::Queued,
+                        Err(Error(ErrorKind::SchedulerBusy(..), _)) => {
+                            TriggerStatus::Unavailable
+                        },
+                        Err(err) => {
+                            err.pretty_print();
+                            TriggerStatus::Unavailable
+                        },
+                    }
+                }
+            } else {
+                TriggerStatus::NotFound
+            };
+
+            results.push(TriggerResult {
+                hook: name,
+                status,
+            });
+        }
+
+        Response::TriggerResults(results)
+    }
+
+    /// Check if the processor accepts new jobs, with the same limits applied
+    /// to the jobs queued by requests to the hooks.
+    fn can_queue(&self, processor: &A) -> bool {
+        if !processor.accepting_jobs().unwrap() {
+            return false;
+        }
+
+        if let Some(max) = self.max_queued_jobs {
+            processor.health_details().unwrap().queued_jobs < max
+        } else {
+            true
+        }
+    }
+
+    pub fn set_threads(&self, req: &Request, _args: Vec<String>) -> Response {
+        let web = match self.management_request(req) {
+            Ok(web) => web,
+            Err(response) => return response,
+        };
+
+        let config: ThreadsConfig = match serde_json::from_str(&web.body) {
+            Ok(config) => config,
+            Err(err) => return Response::BadRequest(err.into()),
+        };
+        if config.threads == 0 {
+            return Response::BadRequest(ErrorKind::ThreadsCountZero.into());
+        }
+
+        self.processor
+            .lock()
+            .unwrap()
+            .set_max_threads(config.threads)
+            .unwrap();
+
+        Response::Ok
+    }
+
+    pub fn hook_errors(&self, req: &Request, _args: Vec<String>) -> Response {
+        if let Err(response) = self.management_request(req) {
+            return response;
+        }
+
+        Response::HookErrors(
+            self.processor.lock().unwrap().last_errors().unwrap(),
+        )
+    }
+
+    pub fn reset_runtime_budget(
+        &self, req: &Request, _args: Vec<String>,
+    ) -> Response {
+        if let Err(response) = self.management_request(req) {
+            return response;
+        }
+
+        self.processor.lock().unwrap().reset_runtime_budget().unwrap();
+        Response::Ok
+    }
+
+    #[cfg(feature = "diagnostics")]
+    pub fn debug(&self, req: &Request, _args: Vec<String>) -> Response {
+        if let Err(response) = self.management_request(req) {
+            return response;
+        }
+
+        Response::Serials(self.processor.lock().unwrap().serials().unwrap())
+    }
+
     pub fn get_health(&self, _req: &Request, _args: Vec<String>) -> Response {
         if self.health_enabled {
-            Response::HealthStatus(
-                self.processor.lock().unwrap().health_details().unwrap(),
+            let mut details =
+                self.processor.lock().unwrap().health_details().unwrap();
+            if !self.provider_labels {
+                details.provider_jobs = None;
+            }
+            details.loaded_hooks = Some(self.hooks.iter().count());
+            details.uptime_secs = Some(self.started.elapsed().as_secs());
+            details.locked = Some(self.locked.load(Ordering::Relaxed));
+
+            Response::HealthStatus(details)
+        } else {
+            Response::Forbidden
+        }
+    }
+
+    pub fn get_load(&self, _req: &Request, _args: Vec<String>) -> Response {
+        if self.health_enabled {
+            Response::Load(
+                self.processor.lock().unwrap().load_snapshot().unwrap(),
             )
         } else {
             Response::Forbidden
         }
     }
+
+    pub fn get_metrics(&self, _req: &Request, _args: Vec<String>) -> Response {
+        if self.metrics_enabled {
+            let mut metrics = self.processor.lock().unwrap().metrics().unwrap();
+            if !self.provider_labels {
+                metrics.provider_jobs.clear();
+            }
+
+            Response::Metrics(metrics)
+        } else {
+            Response::Forbidden
+        }
+    }
+}
+
+
+/// Check if the hook name is absolute or contains `..` components.
+fn is_suspicious_hook_name(name: &str) -> bool {
+    name.starts_with('/') || name.split('/').any(|part| part == "..")
+}
+
+
+/// Describe an accepted hook, along with the details used to schedule it.
+fn accepted_hook_message(
+    hook: &Script,
+    provider: Option<&Provider>,
+    req: &Request,
+    redacted_params: &[String],
+) -> String {
+    let mut message = format!(
+        "accepted hook {} (priority: {}, parallel: {}, provider: {})",
+        hook.name(),
+        hook.priority(),
+        hook.can_be_parallel(),
+        provider.map_or("none", |provider| provider.name()),
+    );
+    if let Ok(web) = req.web() {
+        // The secrets checked by the providers of the hook are always hidden
+        let mut redacted = redacted_params.to_vec();
+        for provider in &hook.providers {
+            redacted.extend(provider.secret_params());
+        }
+
+        message.push_str(" from ");
+        message.push_str(&web.redacted_url(&redacted));
+    }
+    message
+}
+
+
+/// Log the message describing an accepted hook, if there is one.
+fn log_accepted_hook(message: Option<String>) {
+    if let Some(message) = message {
+        println!("{} {}", Colour::Blue.bold().paint("Info:"), message);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+
+    use common::state::State;
+    use requests::RequestType;
+    use scripts::Script;
+    use utils::testing::*;
+
+    use super::accepted_hook_message;
+
+
+    #[test]
+    fn test_accepted_hook_message() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let path = dir.path().join("prefs.sh");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(0o755)
+            .open(&path)
+            .unwrap();
+        writeln!(file, "#!/bin/bash").unwrap();
+        writeln!(file, r#"## Fisher: {{"parallel": false, "priority": 5}}"#)
+            .unwrap();
+        writeln!(file, "## Fisher-Testing: {{}}").unwrap();
+
+        let hook = Script::load(
+            "prefs.sh".into(),
+            path.to_str().unwrap().into(),
+            &Arc::new(State::new()),
+        ).unwrap();
+        let mut web = dummy_web_request();
+        web.path = "/hook/prefs.sh".into();
+        web.params.insert("secret".into(), "testing".into());
+        web.params.insert("ref".into(), "master".into());
+        let req = web.into();
+        let (request_type, provider) = hook.validate(&req);
+        assert_eq!(request_type, RequestType::ExecuteHook);
+
+        // The log line includes the details resolved from the script, and
+        // the secrets in the URL are hidden
+        let redacted = vec!["secret".to_string()];
+        assert_eq!(
+            accepted_hook_message(&hook, provider.as_deref(), &req, &redacted),
+            "accepted hook prefs.sh (priority: 5, parallel: false, \
+             provider: Testing) from /hook/prefs.sh?ref=master&\
+             secret=REDACTED",
+        );
+
+        // Secrets in the path are hidden too
+        let mut web = dummy_web_request();
+        web.path = "/hook/prefs.sh/abcde".into();
+        web.path_segment = Some("abcde".into());
+        assert_eq!(
+            accepted_hook_message(&hook, None, &web.into(), &redacted),
+            "accepted hook prefs.sh (priority: 5, parallel: false, \
+             provider: none) from /hook/prefs.sh/REDACTED",
+        );
+
+        // The values of the other params can't add spaces or lines
+        let mut web = dummy_web_request();
+        web.path = "/hook/prefs.sh".into();
+        web.params.insert("ref".into(), "a b\nc=d&e".into());
+        assert_eq!(
+            accepted_hook_message(&hook, None, &web.into(), &redacted),
+            "accepted hook prefs.sh (priority: 5, parallel: false, \
+             provider: none) from /hook/prefs.sh?ref=a+b%0Ac%3Dd%26e",
+        );
+
+        // The secret params of the providers are hidden automatically
+        let path = dir.path().join("standalone.sh");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(0o755)
+            .open(&path)
+            .unwrap();
+        writeln!(file, "#!/bin/bash").unwrap();
+        writeln!(
+            file,
+            r#"## Fisher-Standalone: {{"secret": "a", "param_name": "k"}}"#,
+        ).unwrap();
+        let hook = Script::load(
+            "standalone.sh".into(),
+            path.to_str().unwrap().into(),
+            &Arc::new(State::new()),
+        ).unwrap();
+
+        let mut web = dummy_web_request();
+        web.path = "/hook/standalone.sh".into();
+        web.params.insert("k".into(), "a".into());
+        assert_eq!(
+            accepted_hook_message(&hook, None, &web.into(), &[]),
+            "accepted hook standalone.sh (priority: 0, parallel: true, \
+             provider: none) from /hook/standalone.sh?k=REDACTED",
+        );
+    }
 }
@@ -15,12 +15,12 @@
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::net::SocketAddr;
+use std::time::Duration;
 
 use tiny_http::Method;
 
 use common::prelude::*;
-use common::config::HttpConfig;
+use common::config::{BindAddr, HttpConfig};
 
 use scripts::Repository;
 use web::http::HttpServer;
@@ -29,7 +29,7 @@ use web::api::WebApi;
 
 pub struct WebApp<A: ProcessorApiTrait<Repository> + 'static> {
     server: HttpServer<WebApi<A>>,
-    addr: SocketAddr,
+    addr: BindAddr,
     locked: Arc<AtomicBool>,
 }
 
@@ -42,14 +42,28 @@ impl<A: ProcessorApiTrait<Repository>> WebApp<A> {
         let locked = Arc::new(AtomicBool::new(false));
 
         // Create the web api
-        let api = WebApi::new(
-            processor, hooks, locked.clone(), &config.rate_limit,
-            config.health_endpoint,
-        );
+        let api = WebApi::new(processor, hooks, locked.clone(), config);
 
         // Create the HTTP server
         let mut server = HttpServer::new(api, config.behind_proxies);
+        server.set_allowed_hosts(config.allowed_hosts.clone());
+        server.set_allowed_content_types(
+            config.allowed_content_types.clone(),
+        );
+        server.set_headers_limits(config.max_headers, config.max_headers_size);
+        server.set_max_body_size(config.max_body_size);
+        server.set_client_ip_header(config.client_ip_header.clone());
+        server.set_max_bodies_size(config.max_bodies_size);
+        if let Some(ref timeout) = config.read_timeout {
+            server.set_read_timeout(Duration::from_secs(timeout.as_u64()));
+        }
         server.add_route(Method::Get, "/health", Box::new(WebApi::get_health));
+        server.add_route(Method::Get, "/load", Box::new(WebApi::get_load));
+        server.add_route(
+            Method::Get,
+            "/metrics",
+            Box::new(WebApi::get_metrics),
+        );
         server.add_route(
             Method::Get,
             "/hook/?",
@@ -60,17 +74,39 @@ impl<A: ProcessorApiTrait<Repository>> WebApp<A> {
             "/hook/?",
             Box::new(WebApi::process_hook),
         );
+        server.add_route(
+            Method::Get,
+            "/hooks/errors",
+            Box::new(WebApi::hook_errors),
+        );
+        server.add_route(
+            Method::Post,
+            "/hooks/trigger",
+            Box::new(WebApi::trigger_hooks),
+        );
+        server.add_route(
+            Method::Post,
+            "/reset",
+            Box::new(WebApi::reset_runtime_budget),
+        );
+        server.add_route(
+            Method::Post,
+            "/config/threads",
+            Box::new(WebApi::set_threads),
+        );
+        #[cfg(feature = "diagnostics")]
+        server.add_route(Method::Get, "/debug", Box::new(WebApi::debug));
 
-        let socket = server.listen(config.bind)?;
+        let socket = server.listen(&config.bind)?;
 
         Ok(WebApp {
-            server: server,
+            server,
             addr: socket,
-            locked: locked,
+            locked,
         })
     }
 
-    pub fn addr(&self) -> &SocketAddr {
+    pub fn addr(&self) -> &BindAddr {
         &self.addr
     }
 
@@ -90,15 +126,23 @@ impl<A: ProcessorApiTrait<Repository>> WebApp<A> {
 
 #[cfg(test)]
 mod tests {
+    use std::fs::File;
     use std::io::Read;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use serde_json;
+    use tempdir::TempDir;
+    use hyper;
+    use hyper::client::Body;
     use hyper::status::StatusCode;
     use hyper::method::Method;
     use hyper::header::Headers;
 
     use common::prelude::*;
+    use common::config::RateLimitConfig;
 
+    use scripts::JobContext;
     use utils::testing::*;
 
 
@@ -211,6 +255,357 @@ mod tests {
         testing_env.cleanup();
     }
 
+    #[test]
+    fn test_sync_hook() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // The output of the hook is returned in the response
+        let mut res = inst.request(Method::Get, "/hook/sync.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "Hello from nobody\n");
+
+        // Failed hooks return an error status code, along with their output
+        let mut res = inst.request(Method::Get, "/hook/sync.sh?env=fisher")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::InternalServerError);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "Hello from fisher\n");
+
+        // The job goes through the processor like every other job
+        assert!(inst.processor_input().is_some());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_blocking_validation() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Start validating a request with a slow validator
+        let url = inst.url("/hook/exec.sh");
+        let slow = thread::spawn(move || {
+            hyper::Client::new().post(&url).send().unwrap().status
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        // Other requests are processed while the validator is running
+        let started = Instant::now();
+        let res = inst.request(Method::Get, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        // The validator still decides if the hook is executed
+        assert_eq!(slow.join().unwrap(), StatusCode::Ok);
+        let res = inst.request(Method::Get, "/hook/exec.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_blocking_validation_limit() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Fill all the slots for blocking validations. The connections are
+        // opened one at a time, since tiny_http might otherwise queue some of
+        // them behind the others instead of starting new threads
+        let url = inst.url("/hook/exec.sh");
+        let slow = (0..8).map(|_| {
+            let url = url.clone();
+            let thread = thread::spawn(move || {
+                hyper::Client::new().post(&url).send().unwrap().status
+            });
+            thread::sleep(Duration::from_millis(50));
+            thread
+        }).collect::<Vec<_>>();
+
+        // Requests past the limit are rejected without being validated
+        let started = Instant::now();
+        let res = inst.request(Method::Post, "/hook/exec.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::ServiceUnavailable);
+
+        // Other requests are still processed
+        let res = inst.request(Method::Get, "/health").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        for thread in slow {
+            assert_eq!(thread.join().unwrap(), StatusCode::Ok);
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_suspicious_hook_names() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        for url in &[
+            "/hook/../etc/passwd",
+            "/hook/sub/../../example.sh",
+            "/hook/sub/..",
+            "/hook//etc/passwd",
+        ] {
+            let res = inst.raw_request(&format!(
+                "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", url,
+            ));
+            assert!(res.starts_with("HTTP/1.0 400"), "{}: {}", url, res);
+            assert!(res.contains("suspicious hook name"), "{}", res);
+            assert!(inst.processor_input().is_none());
+        }
+
+        // Dots are still allowed in the normal names
+        let res = inst.request(Method::Get, "/hook/sub/hook.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_response_template() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Request-Id", vec![b"abc-123".to_vec()]);
+        let mut res = inst.request(Method::Get, "/hook/template.sh")
+            .headers(headers)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // The template is returned with the ID of the job
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        assert_eq!(body, r#"{"queued": true, "job": "abc-123"}"#);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_secret_in_path() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // The secret can be the path segment after the hook name
+        let res = inst.request(Method::Get, "/hook/path-secret.sh/abcde")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        if let Some(ProcessorApiCall::Queue(job, _)) = inst.processor_input() {
+            assert_eq!(job.script_name(), "path-secret.sh");
+        } else {
+            panic!("Wrong processor input received");
+        }
+
+        // Wrong secrets are rejected
+        let res = inst.request(Method::Get, "/hook/path-secret.sh/wrong")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+
+        // Hooks not reading the path segment don't accept it
+        let res = inst.request(Method::Get, "/hook/example.sh/abcde")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_require_body() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // POST requests without a body are rejected
+        let res = inst.request(Method::Post, "/hook/require-body.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::BadRequest);
+        assert!(inst.processor_input().is_none());
+
+        // POST requests with a body are accepted
+        let res = inst.request(Method::Post, "/hook/require-body.sh")
+            .body(r#"{"hello": "world"}"#)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // GET requests are not affected
+        let res = inst.request(Method::Get, "/hook/require-body.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_hide_forbidden_hooks() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.hide_forbidden_hooks = true;
+        });
+
+        let mut response = |url: &str| {
+            let mut res = inst.request(Method::Get, url).send().unwrap();
+            let mut content = String::new();
+            res.read_to_string(&mut content).unwrap();
+            (res.status, content)
+        };
+
+        // Missing hooks and invalid requests get the same response
+        let missing = response("/hook/missing.sh");
+        let forbidden = response("/hook/example.sh?secret=invalid");
+        assert_eq!(missing.0, StatusCode::NotFound);
+        assert_eq!(missing, forbidden);
+
+        // Valid requests are still processed
+        let valid = response("/hook/example.sh?secret=testing");
+        assert_eq!(valid.0, StatusCode::Ok);
+
+        inst.stop();
+
+        // Probing missing hooks and invalid requests are rate limited the
+        // same way
+        let statuses = |url: &str| {
+            let mut inst = testing_env.start_web_with(|config| {
+                config.hide_forbidden_hooks = true;
+                config.rate_limit = RateLimitConfig {
+                    allowed: 2,
+                    interval: 60.into(),
+                };
+            });
+            let statuses = (0..4).map(|_| {
+                inst.request(Method::Get, url).send().unwrap().status
+            }).collect::<Vec<_>>();
+            inst.stop();
+            statuses
+        };
+        let missing = statuses("/hook/missing.sh");
+        assert_eq!(missing.last(), Some(&StatusCode::TooManyRequests));
+        assert_eq!(missing, statuses("/hook/example.sh?secret=invalid"));
+
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_ping_no_content() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.ping_no_content = true;
+        });
+
+        // Pings are acknowledged without a body
+        let mut res =
+            inst.request(Method::Get, "/hook/example.sh?request_type=ping")
+                .send()
+                .unwrap();
+        assert_eq!(res.status, StatusCode::NoContent);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "");
+        assert!(inst.processor_input().is_none());
+
+        // Other requests are not affected
+        let res = inst.request(Method::Get, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_max_queued_jobs() {
+        // The fake processor always reports one queued job
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.max_queued_jobs = Some(1);
+        });
+
+        // The job is rejected, telling the client how full the queue is
+        let mut res = inst.request(Method::Get, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::ServiceUnavailable);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data, json!({
+            "status": "unavailable",
+            "queue": {
+                "current": 1,
+                "max": 1,
+            },
+        }));
+
+        assert!(match inst.processor_input() {
+            Some(ProcessorApiCall::HealthDetails) => true,
+            _ => false,
+        });
+        assert!(inst.processor_input().is_none());
+        inst.stop();
+
+        // Jobs are queued as long as there is room for them
+        let mut inst = testing_env.start_web_with(|config| {
+            config.max_queued_jobs = Some(2);
+        });
+
+        let res = inst.request(Method::Get, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        assert!(match inst.processor_input() {
+            Some(ProcessorApiCall::HealthDetails) => true,
+            _ => false,
+        });
+        assert!(match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(..)) => true,
+            _ => false,
+        });
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
     #[test]
     fn test_health_disabled() {
         // Create the instance with disabled health status
@@ -256,6 +651,140 @@ mod tests {
             3 as u64
         );
 
+        // Providers are not included by default
+        assert!(result.get("provider_jobs").is_none());
+
+        // The details about the instance itself are added by the web app
+        assert!(result.get("loaded_hooks").unwrap().as_u64().unwrap() > 0);
+        assert!(result.get("uptime_secs").unwrap().as_u64().unwrap() < 60);
+        assert!(!result.get("locked").unwrap().as_bool().unwrap());
+
+        // The lock status of the instance is reported
+        for &locked in &[true, false] {
+            if locked {
+                inst.lock();
+            } else {
+                inst.unlock();
+            }
+
+            let mut res = inst.request(Method::Get, "/health").send().unwrap();
+            let mut content = String::new();
+            res.read_to_string(&mut content).unwrap();
+            let data: serde_json::Value =
+                serde_json::from_str(&content).unwrap();
+            assert_eq!(data["result"]["locked"].as_bool(), Some(locked));
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_load() {
+        let testing_env = TestingEnv::new();
+
+        // The load is hidden along with the health status
+        let mut inst = testing_env.start_web(false, 0);
+        let res = inst.request(Method::Get, "/load").send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+        inst.stop();
+
+        let mut inst = testing_env.start_web(true, 0);
+        let mut res = inst.request(Method::Get, "/load").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data, json!({
+            "status": "ok",
+            "result": {
+                "queued_jobs": 1,
+                "busy_threads": 2,
+                "max_threads": 3,
+                "priorities": {"0": 1},
+                "average_wait_ms": 500,
+            },
+        }));
+
+        assert!(match inst.processor_input() {
+            Some(ProcessorApiCall::LoadSnapshot) => true,
+            _ => false,
+        });
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_provider_labels() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.metrics_endpoint = true;
+            config.provider_labels = true;
+        });
+
+        let mut res = inst.request(Method::Get, "/health").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data["result"]["provider_jobs"], json!({"GitHub": 3}));
+
+        let mut res = inst.request(Method::Get, "/metrics").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert!(content.lines().any(|line| {
+            line == r#"fisher_provider_jobs_total{provider="GitHub"} 3"#
+        }));
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_metrics() {
+        let testing_env = TestingEnv::new();
+
+        // The metrics are disabled by default
+        let mut inst = testing_env.start_web_with(|_| {});
+        let res = inst.request(Method::Get, "/metrics").send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        inst.stop();
+
+        let mut inst = testing_env.start_web_with(|config| {
+            config.metrics_endpoint = true;
+        });
+        let mut res = inst.request(Method::Get, "/metrics").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert_eq!(
+            res.headers.get_raw("Content-Type").unwrap(),
+            &[b"text/plain; version=0.0.4".to_vec()]
+        );
+
+        // The metrics of each hook are labeled with its name
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert!(lines.contains(
+            &r#"fisher_hook_running_jobs{hook="example.sh"} 1"#
+        ));
+        assert!(lines.contains(
+            &r#"fisher_hook_waiting_jobs{hook="example.sh"} 2"#
+        ));
+
+        // Providers are not included by default
+        assert!(!content.contains("fisher_provider_jobs_total"));
+
+        // The leaked directories are always included
+        assert!(lines.iter().any(|line| {
+            line.starts_with("fisher_leaked_dirs_total ")
+        }));
+
         inst.stop();
         testing_env.cleanup();
     }
@@ -290,4 +819,411 @@ mod tests {
         inst.stop();
         testing_env.cleanup();
     }
+
+
+    #[test]
+    fn test_trigger_hooks() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.api_token = Some("token".into());
+        });
+
+        let body = concat!(
+            r#"["example.sh", "invalid.sh", "sub/hook.sh", "#,
+            r#""require-body.sh"]"#,
+        );
+
+        // The endpoint can't be used without the token
+        let res = inst.request(Method::Post, "/hooks/trigger")
+            .body(body)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+
+        // Trigger the hooks with the right token
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer token".to_vec()]);
+        let mut res = inst.request(Method::Post, "/hooks/trigger")
+            .headers(headers)
+            .body(body)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data, json!({
+            "status": "ok",
+            "results": [
+                {"hook": "example.sh", "status": "queued"},
+                {"hook": "invalid.sh", "status": "not_found"},
+                {"hook": "sub/hook.sh", "status": "queued"},
+                {"hook": "require-body.sh", "status": "forbidden"},
+            ],
+        }));
+
+        // Only the two valid hooks should be queued
+        for name in &["example.sh", "sub/hook.sh"] {
+            if let Some(ProcessorApiCall::Queue(job, _)) =
+                inst.processor_input()
+            {
+                assert_eq!(job.script_name(), *name);
+            } else {
+                panic!("Wrong processor input received");
+            }
+        }
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_trigger_hooks_queue_full() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.api_token = Some("token".into());
+            // The fake processor always has a job in the queue
+            config.max_queued_jobs = Some(1);
+        });
+
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer token".to_vec()]);
+        let mut res = inst.request(Method::Post, "/hooks/trigger")
+            .headers(headers)
+            .body(r#"["example.sh"]"#)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data: serde_json::Value = serde_json::from_str(&content)
+            .unwrap();
+        assert_eq!(data, json!({
+            "status": "ok",
+            "results": [
+                {"hook": "example.sh", "status": "unavailable"},
+            ],
+        }));
+
+        // The hook wasn't queued
+        if let Some(ProcessorApiCall::HealthDetails) = inst.processor_input() {
+        } else {
+            panic!("Wrong processor input received");
+        }
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_hook_errors() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.api_token = Some("token".into());
+        });
+
+        // The endpoint can't be used without the token
+        let res = inst.request(Method::Get, "/hooks/errors").send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer token".to_vec()]);
+        let mut res = inst.request(Method::Get, "/hooks/errors")
+            .headers(headers)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data, json!({
+            "status": "ok",
+            "errors": {
+                "example.sh": {
+                    "exit_code": 1,
+                    "signal": null,
+                    "stderr": "failed!\n",
+                    "timestamp": 1500000000,
+                },
+            },
+        }));
+
+        if let Some(ProcessorApiCall::LastErrors) = inst.processor_input() {
+        } else {
+            panic!("Wrong processor input received");
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_reset_runtime_budget() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.api_token = Some("token".into());
+        });
+
+        // The endpoint can't be used without the token
+        let res = inst.request(Method::Post, "/reset").send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer token".to_vec()]);
+        let res = inst.request(Method::Post, "/reset")
+            .headers(headers)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        if let Some(ProcessorApiCall::ResetRuntimeBudget) =
+            inst.processor_input()
+        {
+        } else {
+            panic!("Wrong processor input received");
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn test_debug() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.api_token = Some("token".into());
+        });
+
+        // The endpoint can't be used without the token
+        let res = inst.request(Method::Get, "/debug").send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer token".to_vec()]);
+        let mut res = inst.request(Method::Get, "/debug")
+            .headers(headers)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data, json!({
+            "status": "ok",
+            "serials": {
+                "current": {"increment": 2, "alternate": false},
+                "queued": [{"increment": 2, "alternate": false}],
+            },
+        }));
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_set_threads() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.api_token = Some("token".into());
+        });
+
+        // The endpoint can't be used without the token
+        let res = inst.request(Method::Post, "/config/threads")
+            .body(r#"{"threads": 4}"#)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer token".to_vec()]);
+
+        // Invalid counts are rejected
+        for body in &[r#"{"threads": 0}"#, r#"{"threads": -1}"#, "4"] {
+            let res = inst.request(Method::Post, "/config/threads")
+                .headers(headers.clone())
+                .body(*body)
+                .send()
+                .unwrap();
+            assert_eq!(res.status, StatusCode::BadRequest);
+            assert!(inst.processor_input().is_none());
+        }
+
+        // Change the number of threads with the right token
+        let res = inst.request(Method::Post, "/config/threads")
+            .headers(headers)
+            .body(r#"{"threads": 4}"#)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        if let Some(ProcessorApiCall::SetMaxThreads(max)) =
+            inst.processor_input()
+        {
+            assert_eq!(max, 4);
+        } else {
+            panic!("Wrong processor input received");
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_chunked_body() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(|config| {
+            config.max_body_size = 64;
+        });
+
+        let payload = r#"{"zen": "Keep it logically awesome."}"#;
+        let signature = "sha1=d7fd73c5b98346ecf0434d67561ec20f9c79ad5a";
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-GitHub-Event", vec![b"push".to_vec()]);
+        headers.set_raw("X-GitHub-Delivery", vec![b"12345".to_vec()]);
+        headers.set_raw("X-Hub-Signature", vec![signature.into()]);
+
+        // Send the body with chunked encoding, so the signature is validated
+        // over the reassembled body
+        let mut body = payload.as_bytes();
+        let res = inst.request(Method::Post, "/hook/github.sh")
+            .headers(headers.clone())
+            .body(Body::ChunkedBody(&mut body))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // Chunked bodies bigger than the limit are rejected
+        let big = format!("[{}]", vec![payload; 4].join(","));
+        let mut body = big.as_bytes();
+        let res = inst.request(Method::Post, "/hook/github.sh")
+            .headers(headers)
+            .body(Body::ChunkedBody(&mut body))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::PayloadTooLarge);
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_provider_methods() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        let payload = r#"{"zen": "Keep it logically awesome."}"#;
+        let signature = "sha1=d7fd73c5b98346ecf0434d67561ec20f9c79ad5a";
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-GitHub-Event", vec![b"push".to_vec()]);
+        headers.set_raw("X-GitHub-Delivery", vec![b"12345".to_vec()]);
+        headers.set_raw("X-Hub-Signature", vec![signature.into()]);
+
+        // GitHub never sends webhooks with GET, even if they're signed
+        let res = inst.request(Method::Get, "/hook/github.sh")
+            .headers(headers.clone())
+            .body(payload)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+
+        let res = inst.request(Method::Post, "/hook/github.sh")
+            .headers(headers)
+            .body(payload)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_request_id() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        let out = TempDir::new("fisher-tests").unwrap();
+        let url = format!(
+            "/hook/jobs-details.sh?env={}", out.path().to_str().unwrap(),
+        );
+
+        // A new ID is generated if the client doesn't provide one
+        let res = inst.request(Method::Get, &url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let id = String::from_utf8(
+            res.headers.get_raw("X-Request-Id").unwrap()[0].clone(),
+        ).unwrap();
+        assert_eq!(id.len(), 32);
+
+        // Execute the queued job, and ensure it received the same ID
+        let ctx = JobContext {
+            audit_log: Some(out.path().join("audit.log")),
+            .. JobContext::default()
+        };
+        if let Some(ProcessorApiCall::Queue(job, _)) = inst.processor_input() {
+            job.execute(&ctx).unwrap();
+        } else {
+            panic!("Wrong processor input received");
+        }
+
+        let read = |name: &str| {
+            let mut content = String::new();
+            File::open(out.path().join(name))
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        };
+        let env = read("env");
+        assert!(env.lines().any(|l| l == format!("FISHER_REQUEST_ID={}", id)));
+        assert_eq!(read("request_id"), format!("{}\n", id));
+
+        // The job was recorded in the audit log with the same ID
+        let audit = read("audit.log");
+        assert_eq!(audit.lines().count(), 1);
+        assert!(audit.contains(&format!(
+            " request={} hook=jobs-details.sh ip=127.0.0.1 exit=", id,
+        )));
+        assert!(audit.trim_end().ends_with(" user=-"));
+
+        // The ID provided by the client is used if it's present
+        let mut headers = Headers::new();
+        headers.set_raw("X-Request-Id", vec![b"abc-123".to_vec()]);
+        let res = inst.request(Method::Get, "/hook/example.sh")
+            .headers(headers)
+            .send()
+            .unwrap();
+        assert_eq!(
+            res.headers.get_raw("X-Request-Id").unwrap()[0],
+            b"abc-123".to_vec()
+        );
+
+        inst.stop();
+        testing_env.cleanup();
+    }
 }
@@ -13,25 +13,43 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::HashSet;
 use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::io::Write;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use rand;
 use regex::{self, Regex};
 use tiny_http::{self, Method};
 
 use common::prelude::*;
+use common::config::BindAddr;
 use requests::Request;
+use web::requests::{convert_request, request_id, WebRequest};
 use web::responses::Response;
 use web::proxies::ProxySupport;
+use web::unix::{BridgeClients, UnixBridge};
+
+
+/// How many connections received on a Unix socket can be forwarded to the
+/// internal TCP server at the same time.
+const MAX_UNIX_CONNECTIONS: usize = 256;
 
 
 pub type RequestHandler<App> = Box<fn(&App, &Request, Vec<String>) -> Response>;
 
 
+macro_rules! header {
+    ($value:expr) => {
+        $value.parse::<tiny_http::Header>().unwrap()
+    };
+}
+
+
 struct Route {
     method: Method,
     regex: Regex,
@@ -42,7 +60,7 @@ impl Route {
         let regex = Self::regex_from_url(url);
 
         Route {
-            method: method,
+            method,
             regex: Regex::new(&regex).unwrap(),
         }
     }
@@ -99,8 +117,8 @@ struct Handler<App: Send + Sync + 'static> {
 impl<App: Send + Sync + 'static> Handler<App> {
     fn new(handler: RequestHandler<App>, route: Route) -> Self {
         Handler {
-            handler: handler,
-            route: route,
+            handler,
+            route,
         }
     }
 
@@ -114,14 +132,271 @@ impl<App: Send + Sync + 'static> Handler<App> {
 }
 
 
+fn host_allowed(allowed: &Option<Vec<String>>, host: Option<&str>) -> bool {
+    let allowed = if let Some(ref allowed) = *allowed {
+        allowed
+    } else {
+        // Every host is allowed if no list is configured
+        return true;
+    };
+
+    let host = if let Some(host) = host {
+        host.trim().to_lowercase()
+    } else {
+        return false;
+    };
+
+    // Strip the port from the host, handling IPv6 addresses too
+    let without_port = match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[..pos],
+        _ => host.as_str(),
+    };
+
+    allowed.iter().map(|a| a.to_lowercase()).any(|allowed| {
+        allowed == host || allowed == without_port
+    })
+}
+
+
+/// Check if the content type of the request is in the allowed list. GET
+/// requests without a body are always allowed.
+fn content_type_allowed(
+    allowed: &Option<Vec<String>>, req: &WebRequest,
+) -> bool {
+    let allowed = if let Some(ref allowed) = *allowed {
+        allowed
+    } else {
+        // Every content type is allowed if no list is configured
+        return true;
+    };
+
+    if req.method == "GET" && req.body.is_empty() {
+        return true;
+    }
+
+    // Parameters like the charset are ignored
+    let content_type = if let Some(header) = req.header("Content-Type") {
+        header.split(';').next().unwrap().trim().to_lowercase()
+    } else {
+        return false;
+    };
+
+    allowed.iter().any(|a| a.trim().to_lowercase() == content_type)
+}
+
+
+/// Limits applied to the headers of incoming requests. tiny_http reads all
+/// the headers before the request is handed over, so they're checked when
+/// the headers are already in memory, but before they're copied around.
+#[derive(Debug, Copy, Clone)]
+struct HeadersLimits {
+    count: usize,
+    size: usize,
+}
+
+impl HeadersLimits {
+    fn allows(&self, headers: &[tiny_http::Header]) -> bool {
+        if headers.len() > self.count {
+            return false;
+        }
+
+        // Count the size of the headers as they're sent over the wire
+        let size = headers.iter().fold(0, |acc, header| {
+            acc + header.field.as_str().len() + header.value.len() + 4
+        });
+
+        size <= self.size
+    }
+}
+
+
+/// Budget of bytes shared by all the request bodies kept in memory at the
+/// same time.
+#[derive(Debug, Clone)]
+struct BodiesBudget {
+    max: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl BodiesBudget {
+    fn new(max: usize) -> Self {
+        BodiesBudget {
+            max,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Reserve some bytes from the budget, returning None if there isn't
+    /// enough space left. The bytes are released when the guard is dropped.
+    fn reserve(&self, bytes: usize) -> Option<BodiesBudgetGuard> {
+        let mut used = self.used.load(Ordering::SeqCst);
+        loop {
+            if used + bytes > self.max {
+                return None;
+            }
+
+            match self.used.compare_exchange(
+                used, used + bytes, Ordering::SeqCst, Ordering::SeqCst,
+            ) {
+                Ok(..) => break,
+                Err(current) => used = current,
+            }
+        }
+
+        Some(BodiesBudgetGuard {
+            used: self.used.clone(),
+            bytes,
+        })
+    }
+}
+
+struct BodiesBudgetGuard {
+    used: Arc<AtomicUsize>,
+    bytes: usize,
+}
+
+impl Drop for BodiesBudgetGuard {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+
+/// Everything needed to read and handle a request once it's accepted, shared
+/// by all the threads doing that.
+struct Worker<App: Send + Sync + 'static> {
+    app: Arc<App>,
+    handlers: Arc<Vec<Handler<App>>>,
+    proxy_support: Arc<ProxySupport>,
+    allowed_hosts: Arc<Option<Vec<String>>>,
+    allowed_content_types: Arc<Option<Vec<String>>>,
+    bridge_clients: Option<BridgeClients>,
+    headers_limits: Option<HeadersLimits>,
+    max_body_size: Option<usize>,
+    bodies_budget: Option<BodiesBudget>,
+    read_timeout: Option<Duration>,
+    stop_method: Method,
+}
+
+impl<App: Send + Sync + 'static> Worker<App> {
+    /// Read the body of the request and handle it. The bytes reserved for
+    /// the body are stored in `reserved`, so they're kept until deferred
+    /// responses are ready.
+    fn handle(
+        &self,
+        request: &mut tiny_http::Request,
+        id: &str,
+        reserved: &mut Option<BodiesBudgetGuard>,
+    ) -> Response {
+        if *request.method() == self.stop_method {
+            // This request comes with the non-standard method used to shut
+            // the server down -- no client should be using it
+            return Response::Forbidden;
+        }
+
+        // When listening on a Unix socket only the connections forwarded
+        // from it are allowed
+        if let Some(ref clients) = self.bridge_clients {
+            if !clients.lock().unwrap().contains(request.remote_addr()) {
+                return Response::Forbidden;
+            }
+        }
+
+        // Reject the request before its headers are copied
+        if let Some(limits) = self.headers_limits {
+            if !limits.allows(request.headers()) {
+                return Response::HeadersTooLarge;
+            }
+        }
+
+        // Reserve the declared size of the body before reading it. Bodies
+        // over the maximum size are rejected later anyway.
+        if let Some(ref budget) = self.bodies_budget {
+            let length = request.body_length().filter(|length| {
+                self.max_body_size.is_none_or(|max| *length <= max)
+            });
+            if let Some(length) = length {
+                *reserved = budget.reserve(length);
+                if reserved.is_none() {
+                    return Response::Unavailable(None);
+                }
+            }
+        }
+
+        let deadline = self.read_timeout.map(|timeout| {
+            Instant::now() + timeout
+        });
+        let converted = convert_request(
+            request, id.to_string(), self.max_body_size, deadline,
+        );
+        let mut req = match converted {
+            Ok(req) => Request::Web(req),
+            Err(err) => {
+                return match *err.kind() {
+                    ErrorKind::RequestBodyTooLarge(..) => {
+                        Response::PayloadTooLarge
+                    }
+                    ErrorKind::RequestBodyTimeout => Response::RequestTimeout,
+                    _ => Response::BadRequest(err),
+                };
+            }
+        };
+
+        // Bodies without a declared length can only be accounted for after
+        // they're read (up to the maximum body size)
+        if let Some(ref budget) = self.bodies_budget {
+            if reserved.is_none() {
+                let length = req.web().unwrap().body_bytes().len();
+                *reserved = budget.reserve(length);
+                if reserved.is_none() {
+                    return Response::Unavailable(None);
+                }
+            }
+        }
+
+        if !host_allowed(
+            &self.allowed_hosts, req.web().unwrap().host.as_deref(),
+        ) {
+            Response::Forbidden
+        } else if !content_type_allowed(
+            &self.allowed_content_types, req.web().unwrap(),
+        ) {
+            Response::UnsupportedMediaType
+        } else if let Err(e) = self.proxy_support.fix_request(&mut req) {
+            Response::BadRequest(e)
+        } else {
+            let method = request.method();
+            let url = request.url();
+
+            for handler in self.handlers.iter() {
+                if let Some(args) = handler.matches(method, url) {
+                    return handler.call(&self.app, &req, args);
+                }
+            }
+
+            Response::NotFound
+        }
+    }
+}
+
+
 pub struct HttpServer<App: Send + Sync + 'static> {
     app: Arc<App>,
-    handlers: Arc<Mutex<Vec<Handler<App>>>>,
+    handlers: Arc<Vec<Handler<App>>>,
     proxy_support: Arc<ProxySupport>,
+    allowed_hosts: Arc<Option<Vec<String>>>,
+    allowed_content_types: Arc<Option<Vec<String>>>,
+    headers_limits: Option<HeadersLimits>,
+    max_body_size: Option<usize>,
+    bodies_budget: Option<BodiesBudget>,
+    read_timeout: Option<Duration>,
 
     should_stop: Arc<AtomicBool>,
+    stop_timeout: Duration,
+    stop_method: String,
 
     listening_to: Option<SocketAddr>,
+    unix_bridge: Option<UnixBridge>,
     stop_wait: Option<mpsc::Receiver<()>>,
 }
 
@@ -129,12 +404,27 @@ impl<App: Send + Sync + 'static> HttpServer<App> {
     pub fn new(app: App, proxies_count: u8) -> Self {
         HttpServer {
             app: Arc::new(app),
-            handlers: Arc::new(Mutex::new(Vec::new())),
+            handlers: Arc::new(Vec::new()),
             proxy_support: Arc::new(ProxySupport::new(proxies_count)),
+            allowed_hosts: Arc::new(None),
+            allowed_content_types: Arc::new(None),
+            headers_limits: None,
+            max_body_size: None,
+            bodies_budget: None,
+            read_timeout: None,
 
             should_stop: Arc::new(AtomicBool::new(false)),
+            stop_timeout: Duration::from_secs(5),
+            // The method used to stop the server is random, so clients
+            // can't find out about it
+            stop_method: format!(
+                "X_FISHER_STOP_{:016x}{:016x}",
+                rand::random::<u64>(),
+                rand::random::<u64>(),
+            ),
 
             listening_to: None,
+            unix_bridge: None,
             stop_wait: None,
         }
     }
@@ -146,129 +436,152 @@ impl<App: Send + Sync + 'static> HttpServer<App> {
         handler: RequestHandler<App>,
     ) {
         let route = Route::new(method, url);
-        self.handlers
-            .try_lock()
-            .unwrap()
+        Arc::get_mut(&mut self.handlers)
+            .expect("routes can't be added while listening")
             .push(Handler::new(handler, route));
     }
 
-    pub fn listen(&mut self, bind: SocketAddr) -> Result<SocketAddr> {
-        macro_rules! header {
-            ($value:expr) => {
-                $value.parse::<tiny_http::Header>().unwrap()
-            };
-        }
+    pub fn set_client_ip_header(&mut self, header: Option<String>) {
+        Arc::make_mut(&mut self.proxy_support).set_client_ip_header(header);
+    }
+
+    pub fn set_allowed_hosts(&mut self, hosts: Option<Vec<String>>) {
+        self.allowed_hosts = Arc::new(hosts);
+    }
+
+    pub fn set_allowed_content_types(&mut self, types: Option<Vec<String>>) {
+        self.allowed_content_types = Arc::new(types);
+    }
+
+    pub fn set_headers_limits(&mut self, count: usize, size: usize) {
+        self.headers_limits = Some(HeadersLimits { count, size });
+    }
+
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.max_body_size = Some(size);
+    }
+
+    pub fn set_max_bodies_size(&mut self, size: usize) {
+        self.bodies_budget = Some(BodiesBudget::new(size));
+    }
+
+    /// Set how long to wait for the client to send the body of a request.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = Some(timeout);
+    }
+
+    /// Set how long stopping the server waits for it to actually stop.
+    #[cfg(test)]
+    pub fn set_stop_timeout(&mut self, timeout: Duration) {
+        self.stop_timeout = timeout;
+    }
+
+    pub fn listen(&mut self, bind: &BindAddr) -> Result<BindAddr> {
+        // Unix sockets are served through an internal TCP server, which
+        // listens on a random port of localhost
+        let tcp_bind = match *bind {
+            BindAddr::Tcp(addr) => addr,
+            BindAddr::Unix(..) => "127.0.0.1:0".parse().unwrap(),
+        };
 
         // This will move to the thread, and the server will be stopped when
         // the thread exits
-        let server = tiny_http::Server::http(bind)?;
+        let server = tiny_http::Server::http(tcp_bind)?;
 
         // Store the server address into the struct
         self.listening_to = Some(server.server_addr());
 
+        let bridge_clients = if let BindAddr::Unix(ref path) = *bind {
+            let clients = Arc::new(Mutex::new(HashSet::new()));
+            self.unix_bridge = Some(UnixBridge::start(
+                path,
+                server.server_addr(),
+                clients.clone(),
+                MAX_UNIX_CONNECTIONS,
+            )?);
+            Some(clients)
+        } else {
+            None
+        };
+
         let (stop_send, stop_recv) = mpsc::channel();
         self.stop_wait = Some(stop_recv);
 
-        let app = self.app.clone();
-        let handlers_arc = self.handlers.clone();
-        let proxy_support = self.proxy_support.clone();
+        let worker = Arc::new(Worker {
+            app: self.app.clone(),
+            handlers: self.handlers.clone(),
+            proxy_support: self.proxy_support.clone(),
+            allowed_hosts: self.allowed_hosts.clone(),
+            allowed_content_types: self.allowed_content_types.clone(),
+            bridge_clients,
+            headers_limits: self.headers_limits,
+            max_body_size: self.max_body_size,
+            bodies_budget: self.bodies_budget.clone(),
+            read_timeout: self.read_timeout,
+            stop_method: Method::NonStandard(
+                self.stop_method.parse().unwrap(),
+            ),
+        });
         let should_stop = self.should_stop.clone();
         thread::spawn(move || {
-            // Get a reference to the handlers
-            let handlers = &*handlers_arc.lock().unwrap();
-
-            // Prepare some headers which will be sent everytime
-            let server_header = header!(
-                format!("Server: Fisher/{}", env!("CARGO_PKG_VERSION"))
-            );
-            let content_type = header!("Content-Type: application/json");
-
-            let ignored_method =
-                Method::NonStandard("X_FISHER_IGNORE_THIS".parse().unwrap());
-
             for mut request in server.incoming_requests() {
                 // Don't accept any request anymore
                 if should_stop.load(Ordering::Relaxed) {
                     break;
                 }
 
-                // Convert the request to a Fisher request
-                let mut req = Request::Web((&mut request).into());
-
-                let response = (|| {
-                    if *request.method() == ignored_method {
-                        // This request comes with the non-standard method used
-                        // to shut the server down -- no client should be using
-                        // it
-                        Response::Forbidden
-                    } else if let Err(e) = proxy_support.fix_request(&mut req) {
-                        Response::BadRequest(e)
-                    } else {
-                        let method = request.method();
-                        let url = request.url();
-
-                        for handler in handlers {
-                            if let Some(args) = handler.matches(method, url) {
-                                return handler.call(&app, &req, args);
-                            }
-                        }
-
-                        Response::NotFound
-                    }
-                })();
-
-                let mut tiny_response =
-                    tiny_http::Response::from_data(
-                        response.json().into_bytes(),
-                    ).with_status_code(response.status());
-
-                // Add custom headers from the response
-                if let Some(headers) = response.headers() {
-                    for header in &headers {
-                        tiny_response.add_header(header!(header));
-                    }
-                }
-
-                tiny_response.add_header(server_header.clone());
-                tiny_response.add_header(content_type.clone());
-
-                let _ = request.respond(tiny_response);
+                // Each request is handled in its own thread, since even
+                // answering it waits for the client to send the whole body
+                let worker = worker.clone();
+                thread::spawn(move || {
+                    let id = request_id(&request);
+                    let mut reserved = None;
+                    let response =
+                        match worker.handle(&mut request, &id, &mut reserved) {
+                            Response::Deferred(deferred) => deferred.wait(),
+                            response => response,
+                        };
+
+                    // The body isn't needed anymore to send the response
+                    drop(reserved);
+                    respond(request, &response, &id);
+                });
             }
 
-            stop_send.send(()).unwrap();
+            // The server might not be waited anymore if stopping it timed out
+            let _ = stop_send.send(());
         });
 
-        Ok(self.listening_to.unwrap())
+        Ok(match *bind {
+            BindAddr::Tcp(..) => BindAddr::Tcp(self.listening_to.unwrap()),
+            BindAddr::Unix(ref path) => BindAddr::Unix(path.clone()),
+        })
     }
 
     pub fn stop(&mut self) -> bool {
         if self.stop_wait.is_some() {
+            // Stop accepting connections from the Unix socket
+            if let Some(bridge) = self.unix_bridge.take() {
+                bridge.stop();
+            }
+
             // Tell the server to stop
             self.should_stop.store(true, Ordering::Relaxed);
 
-            // Send an HTTP request to force stopping the server
-            match TcpStream::connect(self.listening_to.unwrap()) {
-                Ok(mut conn) => {
-                    (writeln!(conn, "X_FISHER_IGNORE_THIS / HTTP/1.0\r\n\r\n"))
-                        .unwrap();
-                    conn.shutdown(Shutdown::Both).unwrap();
-                }
-                Err(..) => {
-                    return false;
-                }
-            }
+            // Send an HTTP request to force stopping the server. Errors are
+            // ignored, since the server might have stopped already
+            let _ = send_stop_request(
+                self.listening_to.unwrap(), &self.stop_method,
+            );
 
-            if let Some(ref stop_wait) = self.stop_wait {
-                // Wait for the http server to stop
-                stop_wait.recv().unwrap();
-            } else {
-                unreachable!();
-            }
+            // Wait for the http server to stop
+            let stopped = self.stop_wait.take().unwrap()
+                .recv_timeout(self.stop_timeout)
+                .is_ok();
 
-            self.stop_wait = None;
             self.listening_to = None;
 
-            true
+            stopped
         } else {
             false
         }
@@ -276,17 +589,61 @@ impl<App: Send + Sync + 'static> HttpServer<App> {
 }
 
 
+fn respond(request: tiny_http::Request, response: &Response, id: &str) {
+    let mut tiny_response =
+        tiny_http::Response::from_data(
+            response.body().into_bytes(),
+        ).with_status_code(response.status());
+
+    // Add custom headers from the response
+    if let Some(headers) = response.headers() {
+        for header in &headers {
+            tiny_response.add_header(header!(header));
+        }
+    }
+
+    tiny_response.add_header(header!(format!("X-Request-Id: {}", id)));
+    tiny_response.add_header(header!(
+        format!("Server: Fisher/{}", env!("CARGO_PKG_VERSION"))
+    ));
+    if response.has_body() {
+        tiny_response.add_header(header!(
+            format!("Content-Type: {}", response.content_type())
+        ));
+    }
+
+    let _ = request.respond(tiny_response);
+}
+
+
+fn send_stop_request(addr: SocketAddr, method: &str) -> Result<()> {
+    let mut conn = TcpStream::connect(addr)?;
+    writeln!(conn, "{} / HTTP/1.0\r\n\r\n", method)?;
+    conn.shutdown(Shutdown::Both)?;
+    Ok(())
+}
+
+
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
-
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use tempdir::TempDir;
     use tiny_http::Method;
     use hyper;
     use hyper::status::StatusCode;
 
+    use common::config::BindAddr;
     use requests::Request;
-    use web::responses::Response;
+    use web::responses::{Deferred, Response};
     use utils::testing::*;
+    use super::{content_type_allowed, host_allowed};
     use super::{Handler, HttpServer, Route};
 
 
@@ -391,7 +748,7 @@ mod tests {
         server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
 
         // Start the server
-        let addr = server.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
 
         let url = format!("http://{}", addr);
         let mut client = hyper::Client::new();
@@ -415,4 +772,439 @@ mod tests {
                 .is_err()
         );
     }
+
+
+    #[test]
+    fn test_host_allowed() {
+        let allowed = Some(vec!["example.com".into(), "[::1]".into()]);
+
+        assert!(host_allowed(&allowed, Some("example.com")));
+        assert!(host_allowed(&allowed, Some("EXAMPLE.com:8000")));
+        assert!(host_allowed(&allowed, Some("[::1]:8000")));
+        assert!(!host_allowed(&allowed, Some("example.org")));
+        assert!(!host_allowed(&allowed, Some("sub.example.com")));
+        assert!(!host_allowed(&allowed, None));
+
+        // Every host is allowed if the list isn't configured
+        assert!(host_allowed(&None, Some("example.org")));
+        assert!(host_allowed(&None, None));
+    }
+
+
+    #[test]
+    fn test_content_type_allowed() {
+        let allowed = Some(vec!["application/json".into()]);
+
+        let mut req = dummy_web_request();
+        req.method = "POST".into();
+        req.body = "{}".into();
+        req.headers.insert(
+            "Content-Type".into(), "Application/JSON; charset=utf-8".into(),
+        );
+        assert!(content_type_allowed(&allowed, &req));
+
+        req.headers.insert(
+            "Content-Type".into(), "application/x-www-form-urlencoded".into(),
+        );
+        assert!(!content_type_allowed(&allowed, &req));
+
+        // Requests without a content type are rejected, unless they're GET
+        // requests without a body
+        req.headers.remove("Content-Type");
+        assert!(!content_type_allowed(&allowed, &req));
+        req.method = "GET".into();
+        assert!(!content_type_allowed(&allowed, &req));
+        req.body = String::new();
+        assert!(content_type_allowed(&allowed, &req));
+
+        // Every content type is allowed if the list isn't configured
+        req.method = "POST".into();
+        assert!(content_type_allowed(&None, &req));
+    }
+
+
+    #[test]
+    fn test_server_allowed_content_types() {
+        macro_rules! req {
+            ($client:expr, $url:expr, $method:expr, $ct:expr) => {{
+                let mut headers = hyper::header::Headers::new();
+                headers.set_raw("Content-Type", vec![$ct.as_bytes().to_vec()]);
+
+                $client.request($method, &$url)
+                       .headers(headers)
+                       .body("{}")
+                       .send()
+                       .unwrap()
+                       .status
+            }};
+        }
+
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        server.add_route(Method::Post, "/?", Box::new(dummy_handler_fn));
+        server.set_allowed_content_types(Some(vec![
+            "application/json".into(),
+        ]));
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let url = format!("http://{}/test", addr);
+        let client = hyper::Client::new();
+        let post = hyper::method::Method::Post;
+
+        assert_eq!(
+            req!(client, url, post.clone(), "application/json"),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            req!(client, url, post.clone(), "text/plain"),
+            StatusCode::UnsupportedMediaType
+        );
+
+        // GET requests without a body don't need a content type
+        let res = client.get(&url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        server.stop();
+    }
+
+
+    #[test]
+    fn test_server_allowed_hosts() {
+        macro_rules! req {
+            ($client:expr, $url:expr, $host:expr) => {{
+                let mut headers = hyper::header::Headers::new();
+                headers.set_raw("Host", vec![$host.as_bytes().to_vec()]);
+
+                $client.request(hyper::method::Method::Get, &$url)
+                       .headers(headers)
+                       .send()
+                       .unwrap()
+                       .status
+            }};
+        }
+
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        server.set_allowed_hosts(Some(vec!["hooks.example.com".into()]));
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let url = format!("http://{}/test", addr);
+        let client = hyper::Client::new();
+
+        // The allowed host is accepted, with or without the port
+        assert_eq!(req!(client, url, "hooks.example.com"), StatusCode::Ok);
+        assert_eq!(
+            req!(client, url, "hooks.example.com:8000"),
+            StatusCode::Ok
+        );
+
+        // Other hosts are rejected before the request is routed
+        assert_eq!(
+            req!(client, url, "example.com"),
+            StatusCode::Forbidden
+        );
+
+        server.stop();
+
+        // Without a configured list every host is accepted
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let url = format!("http://{}/test", addr);
+        assert_eq!(req!(client, url, "example.com"), StatusCode::Ok);
+
+        server.stop();
+    }
+
+
+    #[test]
+    fn test_server_headers_limits() {
+        macro_rules! req {
+            ($client:expr, $url:expr, $count:expr, $size:expr) => {{
+                let mut headers = hyper::header::Headers::new();
+                for i in 0..$count {
+                    headers.set_raw(
+                        format!("X-Header-{}", i),
+                        vec![vec![b'a'; $size]],
+                    );
+                }
+
+                $client.request(hyper::method::Method::Get, &$url)
+                       .headers(headers)
+                       .send()
+                       .unwrap()
+                       .status
+            }};
+        }
+
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        server.set_headers_limits(10, 1024);
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let url = format!("http://{}/test", addr);
+        let client = hyper::Client::new();
+
+        // A request within the limits is accepted
+        assert_eq!(req!(client, url, 2, 10), StatusCode::Ok);
+
+        // Too many headers are rejected
+        assert_eq!(
+            req!(client, url, 20, 1),
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+
+        // Headers too big are rejected
+        assert_eq!(
+            req!(client, url, 2, 1024),
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+
+        server.stop();
+    }
+
+
+    #[test]
+    fn test_server_bodies_budget() {
+        macro_rules! req {
+            ($client:expr, $url:expr, $size:expr) => {{
+                let body = "a".repeat($size);
+                $client.request(hyper::method::Method::Post, &$url)
+                       .body(body.as_str())
+                       .send()
+                       .unwrap()
+                       .status
+            }};
+        }
+
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Post, "/?", Box::new(dummy_handler_fn));
+        server.set_max_body_size(10000);
+        server.set_max_bodies_size(5000);
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let url = format!("http://{}/test", addr);
+        let mut client = hyper::Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(5)));
+        let budget = server.bodies_budget.clone().unwrap();
+
+        // A body within the budget is accepted
+        assert_eq!(req!(client, url, 3000), StatusCode::Ok);
+
+        // Start a request without sending the whole body, which is big
+        // enough not to be buffered by tiny_http
+        let mut slow = TcpStream::connect(addr.to_string()).unwrap();
+        slow.write_all(concat!(
+            "POST /test HTTP/1.1\r\n",
+            "Host: localhost\r\n",
+            "Connection: close\r\n",
+            "Content-Length: 3000\r\n\r\n",
+        ).as_bytes()).unwrap();
+        slow.write_all(&[b'a'; 1000]).unwrap();
+
+        // Wait for the body to be reserved by the server
+        let started = Instant::now();
+        while budget.used.load(Ordering::SeqCst) != 3000 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // While the other body is in flight the remaining budget is smaller
+        assert_eq!(req!(client, url, 3000), StatusCode::ServiceUnavailable);
+        assert_eq!(req!(client, url, 1500), StatusCode::Ok);
+
+        // The budget is freed once the other request is answered
+        slow.write_all(&[b'a'; 2000]).unwrap();
+        slow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), response);
+        assert_eq!(req!(client, url, 3000), StatusCode::Ok);
+
+        // Bodies bigger than the whole budget are never accepted, but the
+        // maximum body size is still enforced first
+        assert_eq!(req!(client, url, 6000), StatusCode::ServiceUnavailable);
+        assert_eq!(req!(client, url, 20000), StatusCode::PayloadTooLarge);
+
+        server.stop();
+
+        assert_eq!(budget.used.load(Ordering::SeqCst), 0);
+    }
+
+
+    #[test]
+    fn test_server_read_timeout() {
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Post, "/?", Box::new(dummy_handler_fn));
+        server.set_read_timeout(Duration::from_millis(200));
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        // Start a request without sending the whole body, which is big
+        // enough not to be buffered by tiny_http
+        let mut stalled = TcpStream::connect(addr.to_string()).unwrap();
+        stalled.write_all(concat!(
+            "POST /test HTTP/1.1\r\n",
+            "Host: localhost\r\n",
+            "Connection: close\r\n",
+            "Content-Length: 4096\r\n\r\n",
+            "partial body",
+        ).as_bytes()).unwrap();
+
+        // Other requests are processed while the client is stalled
+        let mut client = hyper::Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(5)));
+        let url = format!("http://{}/test", addr);
+        let res = client.post(&url).body("hello").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        // The body sent after the timeout is rejected
+        thread::sleep(Duration::from_millis(300));
+        stalled.write_all(&[b'a'; 4096 - 12]).unwrap();
+        stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        stalled.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408"), response);
+
+        server.stop();
+    }
+
+
+    #[test]
+    fn test_server_deferred_responses() {
+        struct Deferrer {
+            started: Mutex<mpsc::Sender<()>>,
+            finish: Arc<Mutex<mpsc::Receiver<()>>>,
+        }
+
+        fn deferred_handler(
+            data: &Deferrer, _req: &Request, _args: Vec<String>,
+        ) -> Response {
+            data.started.lock().unwrap().send(()).unwrap();
+            let finish = data.finish.clone();
+            Response::Deferred(Deferred::new(move || {
+                finish.lock().unwrap().recv().unwrap();
+                Response::Ok
+            }))
+        }
+
+        let (started_send, started_recv) = mpsc::channel();
+        let (send, recv) = mpsc::channel();
+        let mut server = HttpServer::new(Deferrer {
+            started: Mutex::new(started_send),
+            finish: Arc::new(Mutex::new(recv)),
+        }, 0);
+        server.add_route(Method::Get, "/slow", Box::new(deferred_handler));
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("http://{}", addr);
+
+        let slow_url = url.clone();
+        let slow = thread::spawn(move || {
+            let mut client = hyper::Client::new();
+            client.set_read_timeout(Some(Duration::from_secs(5)));
+            client.get(&format!("{}/slow", slow_url)).send().unwrap().status
+        });
+        started_recv.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Other requests are processed while the response is being waited
+        let mut client = hyper::Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(5)));
+        for _ in 0..2 {
+            let res = client.get(&format!("{}/other", url)).send().unwrap();
+            assert_eq!(res.status, StatusCode::NotFound);
+        }
+
+        // The deferred response is sent once it's available
+        send.send(()).unwrap();
+        assert_eq!(slow.join().unwrap(), StatusCode::Ok);
+
+        server.stop();
+    }
+
+
+    #[test]
+    fn test_server_stop_method() {
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("http://{}/test", addr);
+
+        // The method used to stop the server is different for each instance
+        let other = HttpServer::new(DummyData(vec![]), 0);
+        assert_ne!(server.stop_method, other.stop_method);
+
+        // Guessed methods are treated as normal requests
+        let client = hyper::Client::new();
+        for method in &["X_FISHER_IGNORE_THIS", "X_FISHER_STOP"] {
+            let res = client.request(
+                hyper::method::Method::Extension(method.to_string()), &url,
+            ).send().unwrap();
+            assert_eq!(res.status, StatusCode::NotFound);
+        }
+
+        // The server is still running, and can be stopped
+        assert_eq!(client.get(&url).send().unwrap().status, StatusCode::Ok);
+        assert!(server.stop());
+    }
+
+
+    #[test]
+    fn test_server_stop_failed_request() {
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        server.set_stop_timeout(Duration::from_millis(100));
+        let addr = server.listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        // Point the stop request to a port nothing is listening on
+        let real_addr = server.listening_to.unwrap();
+        let unused = TcpListener::bind("127.0.0.1:0").unwrap();
+        server.listening_to = Some(unused.local_addr().unwrap());
+        drop(unused);
+
+        // The failed request doesn't panic, but the server is not stopped
+        assert!(!server.stop());
+        assert!(!server.stop());
+
+        // The server still notices it should stop with the next request
+        let url = format!("http://{}/test", addr);
+        let _ = hyper::Client::new().get(&url).send();
+        for _ in 0..100 {
+            if TcpStream::connect(real_addr).is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the server didn't stop");
+    }
+
+
+    #[test]
+    fn test_server_unix_socket() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let path = dir.path().join("fisher.sock");
+
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        let addr = server.listen(&BindAddr::Unix(path.clone())).unwrap();
+        assert_eq!(addr, BindAddr::Unix(path.clone()));
+
+        // Make a request over the Unix socket
+        let mut conn = UnixStream::connect(&path).unwrap();
+        conn.write_all(b"GET /test HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200"), response);
+        assert!(response.ends_with(r#"{"status":"ok"}"#), response);
+
+        // The internal TCP server can't be used directly
+        let url = format!("http://{}/test", server.listening_to.unwrap());
+        let res = hyper::Client::new().get(&url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+
+        // Stopping the server removes the socket
+        assert!(server.stop());
+        assert!(!path.exists());
+        assert!(UnixStream::connect(&path).is_err());
+    }
 }
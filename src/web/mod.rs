@@ -19,10 +19,11 @@ mod rate_limits;
 mod requests;
 mod responses;
 mod proxies;
+mod unix;
 
 // Parts of the webapp
 mod api;
 
 pub use self::http::HttpServer;
 pub use self::app::WebApp;
-pub use self::requests::WebRequest;
+pub use self::requests::{random_request_id, WebRequest};
@@ -23,11 +23,21 @@ use utils;
 #[derive(Debug, PartialEq, Clone)]
 pub struct ProxySupport {
     behind: u8,
+    client_ip_header: Option<String>,
 }
 
 impl ProxySupport {
     pub fn new(behind: u8) -> Self {
-        ProxySupport { behind: behind }
+        ProxySupport {
+            behind,
+            client_ip_header: None,
+        }
+    }
+
+    /// Read the client IP from a custom header (like `CF-Connecting-IP`)
+    /// instead of X-Forwarded-For.
+    pub fn set_client_ip_header(&mut self, header: Option<String>) {
+        self.client_ip_header = header;
     }
 
     pub fn source_ip(&self, req: &Request) -> Result<IpAddr> {
@@ -39,6 +49,15 @@ impl ProxySupport {
             return Ok(original);
         }
 
+        // The header set by the proxy contains only the client IP
+        if let Some(ref name) = self.client_ip_header {
+            return if let Some(value) = req.header(name) {
+                Ok(value.trim().parse()?)
+            } else {
+                Err(ErrorKind::NotBehindProxy.into())
+            };
+        }
+
         // Parse the X-Forwarded-For header
         let mut forwarded_ips = utils::parse_forwarded_for(&req.headers)?;
 
@@ -158,6 +177,69 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_source_ip_custom_header() {
+        macro_rules! req_header {
+            ($name:expr, $value:expr) => {{
+                let mut req = req!("127.2.2.2");
+                if let Request::Web(ref mut inner) = req {
+                    inner.headers.insert($name.into(), $value.into());
+                }
+                req
+            }};
+        }
+
+        let mut p = ProxySupport::new(1);
+        p.set_client_ip_header(Some("CF-Connecting-IP".into()));
+
+        // The IP is taken from the custom header, ignoring X-Forwarded-For
+        assert_eq!(
+            p.source_ip(&req_header!("CF-Connecting-IP", "127.3.3.3"))
+                .unwrap(),
+            IpAddr::from_str("127.3.3.3").unwrap()
+        );
+        assert_eq!(
+            p.source_ip(&req_header!("cf-connecting-ip", " ::1 ")).unwrap(),
+            IpAddr::from_str("::1").unwrap()
+        );
+
+        // The custom header must be present and valid
+        assert_err!(p.source_ip(&req!("127.2.2.2")), ErrorKind::NotBehindProxy);
+        assert_err!(
+            p.source_ip(&req_header!("CF-Connecting-IP", "invalid")),
+            ErrorKind::AddrParse(..)
+        );
+
+        // The header is ignored if the proxy support is disabled
+        let mut p = ProxySupport::new(0);
+        p.set_client_ip_header(Some("CF-Connecting-IP".into()));
+        assert_eq!(
+            p.source_ip(&req_header!("CF-Connecting-IP", "127.3.3.3"))
+                .unwrap(),
+            IpAddr::from_str("127.1.1.1").unwrap()
+        );
+    }
+
+
+    #[test]
+    fn test_source_ip_ignores_params() {
+        // The `ip` param is only used by the Testing provider to check the
+        // source IP, and must never change it
+        for behind in 0..2 {
+            let p = ProxySupport::new(behind);
+            let mut req = req!("127.2.2.2");
+            if let Request::Web(ref mut inner) = req {
+                inner.params.insert("ip".into(), "127.3.3.3".into());
+            }
+
+            assert_ne!(
+                p.source_ip(&req).unwrap(),
+                IpAddr::from_str("127.3.3.3").unwrap()
+            );
+        }
+    }
+
+
     #[test]
     fn test_fix_request() {
         let proxy = ProxySupport::new(1);
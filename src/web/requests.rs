@@ -15,54 +15,236 @@
 
 use std::net::IpAddr;
 use std::collections::HashMap;
+use std::io::{self, Read};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+use rand;
 use tiny_http;
 use url::form_urlencoded;
 
+use common::prelude::*;
+
 
 #[derive(Debug, Clone)]
 pub struct WebRequest {
+    pub id: String,
     pub source: IpAddr,
+    pub method: String,
+    pub path: String,
     pub headers: HashMap<String, String>,
+    pub host: Option<String>,
     pub params: HashMap<String, String>,
+    /// The path segment after the name of the hook, if the request has one
+    /// and a provider of the hook reads it.
+    pub path_segment: Option<String>,
     pub body: String,
+    /// The original bytes of the body, kept only if they're not valid UTF-8
+    /// (since `body` has the invalid sequences replaced).
+    pub raw_body: Option<Vec<u8>>,
+    pub received_at: u64,
 }
 
 
-impl<'a> From<&'a mut tiny_http::Request> for WebRequest {
-    fn from(origin: &'a mut tiny_http::Request) -> WebRequest {
-        // Get the source IP
-        let source = origin.remote_addr().ip();
+impl WebRequest {
+    /// Get the value of an header, ignoring the case of its name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Get the URL of the request suitable to be logged, with the values of
+    /// the `redacted` query params and the path segment (which might contain
+    /// a secret) replaced with `REDACTED`. The other params are encoded
+    /// again, so they can't add spaces or lines to the log.
+    pub fn redacted_url(&self, redacted: &[String]) -> String {
+        let mut url = if self.path_segment.is_some() {
+            let hook = self.path.rsplit_once('/').map_or("", |(hook, _)| hook);
+            format!("{}/REDACTED", hook)
+        } else {
+            self.path.clone()
+        };
 
-        // Get the headers
-        let mut headers = HashMap::new();
-        for header in origin.headers() {
-            headers.insert(
-                header.field.as_str().as_str().to_string(),
-                header.value.as_str().to_string(),
-            );
+        // Params are sorted to always log them in the same order
+        let mut params = self.params.iter().collect::<Vec<_>>();
+        params.sort();
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in params {
+            if redacted.contains(name) {
+                query.append_pair(name, "REDACTED");
+            } else {
+                query.append_pair(name, value);
+            }
         }
 
-        // Get the body
-        let mut body = String::new();
-        origin.as_reader().read_to_string(&mut body).unwrap();
+        let query = query.finish();
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
 
-        // Get the querystring
-        let url = origin.url();
-        let params = if url.contains('?') {
-            let query = url.rsplitn(2, '?').nth(0).unwrap();
-            params_from_query(query)
-        } else {
-            HashMap::new()
+        url
+    }
+
+    /// Get the exact bytes of the body, as they were received.
+    pub fn body_bytes(&self) -> &[u8] {
+        match self.raw_body {
+            Some(ref raw) => raw,
+            None => self.body.as_bytes(),
+        }
+    }
+}
+
+
+/// Convert a tiny_http request into a WebRequest, reading at most
+/// `max_body_size` bytes of the body (if a limit is provided) before the
+/// `deadline` (if one is provided).
+///
+/// tiny_http already decodes chunked bodies, so they're read the same way as
+/// the ones with a Content-Length.
+pub fn convert_request(
+    origin: &mut tiny_http::Request,
+    id: String,
+    max_body_size: Option<usize>,
+    deadline: Option<Instant>,
+) -> Result<WebRequest> {
+    // The time is taken before reading the body, since big bodies can take
+    // a while to be received
+    let received_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+
+    // Get the source IP
+    let source = origin.remote_addr().ip();
+
+    // Get the headers
+    let mut headers = HashMap::new();
+    let mut host = None;
+    for header in origin.headers() {
+        // The Host header is stored separately, since it's needed before
+        // the request is routed
+        if header.field.equiv("Host") {
+            host = Some(header.value.as_str().to_string());
+        }
+
+        headers.insert(
+            header.field.as_str().as_str().to_string(),
+            header.value.as_str().to_string(),
+        );
+    }
+
+    // Reject the request early if the declared length is too big
+    if let (Some(max), Some(length)) = (max_body_size, origin.body_length()) {
+        if length > max {
+            return Err(ErrorKind::RequestBodyTooLarge(max).into());
+        }
+    }
+
+    // Get the body, reading one byte more than the limit to detect bodies
+    // without a declared length being too big
+    let mut raw_body = Vec::new();
+    if let Some(max) = max_body_size {
+        read_body(
+            origin.as_reader().take(max as u64 + 1), &mut raw_body, deadline,
+        )?;
+
+        if raw_body.len() > max {
+            return Err(ErrorKind::RequestBodyTooLarge(max).into());
+        }
+    } else {
+        read_body(origin.as_reader(), &mut raw_body, deadline)?;
+    }
+    // Bodies which aren't valid UTF-8 are converted lossily, keeping the
+    // original bytes for the providers which need them, for example to
+    // verify signatures
+    let (body, raw_body) = match String::from_utf8(raw_body) {
+        Ok(body) => (body, None),
+        Err(err) => {
+            let raw = err.into_bytes();
+            (String::from_utf8_lossy(&raw).into_owned(), Some(raw))
+        }
+    };
+
+    // Get the querystring
+    let url = origin.url();
+    let path = url.split('?').next().unwrap().to_string();
+    let params = if url.contains('?') {
+        let query = url.rsplitn(2, '?').next().unwrap();
+        params_from_query(query)
+    } else {
+        HashMap::new()
+    };
+
+    Ok(WebRequest {
+        id,
+        source,
+        method: origin.method().as_str().to_string(),
+        path,
+        headers,
+        host,
+        params,
+        path_segment: None,
+        body,
+        raw_body,
+        received_at,
+    })
+}
+
+
+/// Read the whole body into `into`, failing if it isn't received before the
+/// deadline. tiny_http doesn't allow to interrupt a read, so the deadline is
+/// checked every time some data arrives.
+fn read_body<R: Read>(
+    mut reader: R, into: &mut Vec<u8>, deadline: Option<Instant>,
+) -> Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {
+                continue;
+            }
+            Err(err) => return Err(err.into()),
         };
 
-        WebRequest {
-            source: source,
-            headers: headers,
-            params: params,
-            body: body,
+        if let Some(deadline) = deadline {
+            if Instant::now() > deadline {
+                return Err(ErrorKind::RequestBodyTimeout.into());
+            }
+        }
+
+        into.extend_from_slice(&buffer[..read]);
+    }
+}
+
+
+/// Get the ID of the request from the X-Request-Id header, generating a new
+/// random one if the header is missing or contains an invalid ID.
+pub fn request_id(origin: &tiny_http::Request) -> String {
+    let provided = origin.headers()
+        .iter()
+        .find(|header| header.field.equiv("X-Request-Id"))
+        .map(|header| header.value.as_str());
+
+    if let Some(id) = provided {
+        let valid = !id.is_empty() && id.len() <= 128 && id.chars().all(|c| {
+            c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+        });
+
+        if valid {
+            return id.to_string();
         }
     }
+
+    random_request_id()
+}
+
+
+pub fn random_request_id() -> String {
+    format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>())
 }
 
 
@@ -13,12 +13,65 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::time::Duration;
 
 use serde_json;
 
 use common::prelude::*;
-use common::structs::HealthDetails;
+use common::structs::{
+    HealthDetails, HookMetrics, JobFailure, LoadSnapshot, Metrics,
+};
+#[cfg(feature = "diagnostics")]
+use common::structs::SerialDetails;
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerStatus {
+    Queued,
+    NotFound,
+    Forbidden,
+    Unavailable,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TriggerResult {
+    pub hook: String,
+    pub status: TriggerStatus,
+}
+
+
+/// How many jobs are waiting in the queue, and how many are allowed to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct QueueDepth {
+    pub current: usize,
+    pub max: usize,
+}
+
+
+/// A response which is only available later, for example after a job is
+/// executed. It's waited for outside of the thread accepting the requests.
+pub struct Deferred(Box<dyn FnOnce() -> Response + Send>);
+
+impl Deferred {
+    pub fn new<F: FnOnce() -> Response + Send + 'static>(func: F) -> Self {
+        Deferred(Box::new(func))
+    }
+
+    /// Wait for the actual response.
+    pub fn wait(self) -> Response {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Deferred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Deferred")
+    }
+}
 
 
 #[derive(Debug)]
@@ -27,9 +80,25 @@ pub enum Response {
     Forbidden,
     BadRequest(Error),
     TooManyRequests(Duration),
-    Unavailable,
+    PayloadTooLarge,
+    RequestTimeout,
+    HeadersTooLarge,
+    UnsupportedMediaType,
+    Unavailable(Option<QueueDepth>),
     Ok,
+    /// A successful response with a JSON body rendered from the response
+    /// template of the hook.
+    Rendered(String),
+    NoContent,
     HealthStatus(HealthDetails),
+    Load(LoadSnapshot),
+    Metrics(Metrics),
+    HookErrors(BTreeMap<String, JobFailure>),
+    TriggerResults(Vec<TriggerResult>),
+    HookOutput(bool, String),
+    Deferred(Deferred),
+    #[cfg(feature = "diagnostics")]
+    Serials(SerialDetails),
 }
 
 impl Response {
@@ -39,7 +108,13 @@ impl Response {
             Response::Forbidden => 403,
             Response::BadRequest(..) => 400,
             Response::TooManyRequests(..) => 429,
-            Response::Unavailable => 503,
+            Response::PayloadTooLarge => 413,
+            Response::RequestTimeout => 408,
+            Response::HeadersTooLarge => 431,
+            Response::UnsupportedMediaType => 415,
+            Response::Unavailable(..) => 503,
+            Response::HookOutput(false, _) => 500,
+            Response::NoContent => 204,
             _ => 200,
         }
     }
@@ -50,6 +125,23 @@ impl Response {
                 "status": "ok",
                 "result": details,
             }),
+            Response::Load(ref snapshot) => json!({
+                "status": "ok",
+                "result": snapshot,
+            }),
+            Response::HookErrors(ref errors) => json!({
+                "status": "ok",
+                "errors": errors,
+            }),
+            Response::TriggerResults(ref results) => json!({
+                "status": "ok",
+                "results": results,
+            }),
+            #[cfg(feature = "diagnostics")]
+            Response::Serials(ref serials) => json!({
+                "status": "ok",
+                "serials": serials,
+            }),
             Response::BadRequest(ref error) => json!({
                 "status": "bad_request",
                 "error_msg": format!("{}", error),
@@ -58,19 +150,70 @@ impl Response {
                 "status": "too_many_requests",
                 "retry_after": until.as_secs(),
             }),
+            Response::Unavailable(Some(ref depth)) => json!({
+                "status": "unavailable",
+                "queue": depth,
+            }),
             _ => json!({
                 "status": match *self {
                     Response::NotFound => "not_found",
                     Response::Forbidden => "forbidden",
                     Response::BadRequest(..) => "bad_request",
                     Response::TooManyRequests(..) => "too_many_requests",
-                    Response::Unavailable => "unavailable",
-                    Response::Ok | Response::HealthStatus(..) => "ok",
+                    Response::PayloadTooLarge => "payload_too_large",
+                    Response::RequestTimeout => "request_timeout",
+                    Response::HeadersTooLarge => "headers_too_large",
+                    Response::UnsupportedMediaType => {
+                        "unsupported_media_type"
+                    }
+                    Response::Unavailable(..) => "unavailable",
+                    Response::Ok
+                    | Response::Rendered(..)
+                    | Response::NoContent
+                    | Response::HealthStatus(..)
+                    | Response::Load(..)
+                    | Response::Metrics(..)
+                    | Response::HookErrors(..)
+                    | Response::TriggerResults(..) => "ok",
+                    Response::HookOutput(success, _) => if success {
+                        "ok"
+                    } else {
+                        "hook_failed"
+                    },
+                    // The HTTP server waits for the actual response
+                    Response::Deferred(..) => "deferred",
+                    #[cfg(feature = "diagnostics")]
+                    Response::Serials(..) => "ok",
                 },
             }),
         }).unwrap()
     }
 
+    /// Check if the response has a body, since some statuses can't have it.
+    pub fn has_body(&self) -> bool {
+        !matches!(*self, Response::NoContent)
+    }
+
+    /// Get the body of the response, which is JSON for everything except
+    /// the metrics and the output of sync hooks.
+    pub fn body(&self) -> String {
+        match *self {
+            Response::Metrics(ref metrics) => render_metrics(metrics),
+            Response::HookOutput(_, ref stdout) => stdout.clone(),
+            Response::Rendered(ref body) => body.clone(),
+            Response::NoContent => String::new(),
+            _ => self.json(),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            Response::Metrics(..) => "text/plain; version=0.0.4",
+            Response::HookOutput(..) => "text/plain; charset=utf-8",
+            _ => "application/json",
+        }
+    }
+
     pub fn headers(&self) -> Option<Vec<String>> {
         match *self {
             Response::TooManyRequests(ref duration) => {
@@ -84,16 +227,103 @@ impl Response {
 }
 
 
+type HookMetricValue = fn(&HookMetrics) -> usize;
+
+/// Render the metrics in the Prometheus text format.
+fn render_metrics(metrics: &Metrics) -> String {
+    fn label(value: &str) -> String {
+        value.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+
+    let mut result = String::new();
+
+    let hooks: &[(&str, &str, HookMetricValue)] = &[
+        (
+            "fisher_hook_running_jobs",
+            "Number of jobs currently running for each hook.",
+            |hook| hook.running,
+        ),
+        (
+            "fisher_hook_waiting_jobs",
+            "Number of jobs waiting to be run for each hook.",
+            |hook| hook.waiting,
+        ),
+    ];
+    for &(name, help, value) in hooks {
+        result.push_str(&format!("# HELP {} {}\n", name, help));
+        result.push_str(&format!("# TYPE {} gauge\n", name));
+        for (hook, hook_metrics) in &metrics.hooks {
+            result.push_str(&format!(
+                "{}{{hook=\"{}\"}} {}\n",
+                name, label(hook), value(hook_metrics),
+            ));
+        }
+    }
+
+    // Providers are only included if they're enabled
+    if !metrics.provider_jobs.is_empty() {
+        let name = "fisher_provider_jobs_total";
+        result.push_str(&format!(
+            "# HELP {} Number of jobs received through each provider.\n",
+            name,
+        ));
+        result.push_str(&format!("# TYPE {} counter\n", name));
+        for (provider, jobs) in &metrics.provider_jobs {
+            result.push_str(&format!(
+                "{}{{provider=\"{}\"}} {}\n",
+                name, label(provider), jobs,
+            ));
+        }
+    }
+
+    let name = "fisher_leaked_dirs_total";
+    result.push_str(&format!(
+        "# HELP {} Number of temporary directories which couldn't be \
+         removed.\n",
+        name,
+    ));
+    result.push_str(&format!("# TYPE {} counter\n", name));
+    result.push_str(&format!("{} {}\n", name, metrics.leaked_dirs));
+
+    // Prometheus histograms use seconds and cumulative buckets
+    let seconds = |ms: u64| ms as f64 / 1000.0;
+    let wait_times = &metrics.wait_times;
+    let name = "fisher_job_wait_seconds";
+    result.push_str(&format!(
+        "# HELP {} Time jobs waited in the queue before starting.\n",
+        name,
+    ));
+    result.push_str(&format!("# TYPE {} histogram\n", name));
+    for &(max, jobs) in &wait_times.buckets {
+        result.push_str(&format!(
+            "{}_bucket{{le=\"{}\"}} {}\n", name, seconds(max), jobs,
+        ));
+    }
+    result.push_str(&format!(
+        "{}_bucket{{le=\"+Inf\"}} {}\n", name, wait_times.count,
+    ));
+    result.push_str(&format!(
+        "{}_sum {}\n", name, seconds(wait_times.sum_ms),
+    ));
+    result.push_str(&format!("{}_count {}\n", name, wait_times.count));
+
+    result
+}
+
+
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
     use std::time::Duration;
 
     use serde_json;
 
     use common::prelude::*;
-    use common::structs::HealthDetails;
+    use common::structs::{HealthDetails, HookMetrics, JobFailure, Metrics};
 
-    use super::Response;
+    use super::{QueueDepth, Response, TriggerResult, TriggerStatus};
 
 
     #[inline]
@@ -138,6 +368,20 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_hook_output() {
+        let response = Response::HookOutput(true, "hello\n".into());
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "hello\n");
+        assert_eq!(response.content_type(), "text/plain; charset=utf-8");
+
+        // Failed hooks still return their output
+        let response = Response::HookOutput(false, "failed\n".into());
+        assert_eq!(response.status(), 500);
+        assert_eq!(response.body(), "failed\n");
+    }
+
+
     #[test]
     fn test_bad_request() {
         // This is just a dummy error
@@ -183,9 +427,57 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_payload_too_large() {
+        let response = Response::PayloadTooLarge;
+        assert_eq!(response.status(), 413);
+        assert!(response.headers().is_none());
+
+        assert_eq!(j(response.json()), json!({
+            "status": "payload_too_large",
+        }));
+    }
+
+
+    #[test]
+    fn test_request_timeout() {
+        let response = Response::RequestTimeout;
+        assert_eq!(response.status(), 408);
+        assert!(response.headers().is_none());
+
+        assert_eq!(j(response.json()), json!({
+            "status": "request_timeout",
+        }));
+    }
+
+
+    #[test]
+    fn test_headers_too_large() {
+        let response = Response::HeadersTooLarge;
+        assert_eq!(response.status(), 431);
+        assert!(response.headers().is_none());
+
+        assert_eq!(j(response.json()), json!({
+            "status": "headers_too_large",
+        }));
+    }
+
+
+    #[test]
+    fn test_unsupported_media_type() {
+        let response = Response::UnsupportedMediaType;
+        assert_eq!(response.status(), 415);
+        assert!(response.headers().is_none());
+
+        assert_eq!(j(response.json()), json!({
+            "status": "unsupported_media_type",
+        }));
+    }
+
+
     #[test]
     fn test_unavailable() {
-        let response = Response::Unavailable;
+        let response = Response::Unavailable(None);
         assert_eq!(response.status(), 503);
         assert!(response.headers().is_none());
 
@@ -201,6 +493,25 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_unavailable_queue_full() {
+        let response = Response::Unavailable(Some(QueueDepth {
+            current: 12,
+            max: 10,
+        }));
+        assert_eq!(response.status(), 503);
+        assert!(response.headers().is_none());
+
+        assert_eq!(j(response.json()), json!({
+            "status": "unavailable",
+            "queue": {
+                "current": 12,
+                "max": 10,
+            },
+        }));
+    }
+
+
     #[test]
     fn test_ok() {
         let response = Response::Ok;
@@ -219,12 +530,37 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_rendered() {
+        let response = Response::Rendered(r#"{"job": "abc-123"}"#.into());
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.content_type(), "application/json");
+        assert!(response.headers().is_none());
+        assert_eq!(response.body(), r#"{"job": "abc-123"}"#);
+    }
+
+
+    #[test]
+    fn test_no_content() {
+        let response = Response::NoContent;
+        assert_eq!(response.status(), 204);
+        assert!(response.headers().is_none());
+        assert!(!response.has_body());
+        assert_eq!(response.body(), "");
+    }
+
+
     #[test]
     fn test_health_status() {
         let response = Response::HealthStatus(HealthDetails {
             queued_jobs: 1,
             busy_threads: 2,
             max_threads: 3,
+            draining: false,
+            provider_jobs: None,
+            loaded_hooks: None,
+            uptime_secs: None,
+            locked: None,
         });
 
         // The result must be an object
@@ -256,6 +592,153 @@ mod tests {
         assert_eq!(
             result.get("max_threads").unwrap().as_u64().unwrap(),
             3 as u64
-        )
+        );
+        assert_eq!(
+            result.get("draining").unwrap().as_bool().unwrap(),
+            false
+        );
+    }
+
+
+    #[test]
+    fn test_trigger_results() {
+        let response = Response::TriggerResults(vec![
+            TriggerResult {
+                hook: "a.sh".into(),
+                status: TriggerStatus::Queued,
+            },
+            TriggerResult {
+                hook: "b.sh".into(),
+                status: TriggerStatus::NotFound,
+            },
+        ]);
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().is_none());
+
+        assert_eq!(j(response.json()), json!({
+            "status": "ok",
+            "results": [
+                {"hook": "a.sh", "status": "queued"},
+                {"hook": "b.sh", "status": "not_found"},
+            ],
+        }));
+    }
+
+
+    const EMPTY_WAIT_TIMES: &str = concat!(
+        "# HELP fisher_job_wait_seconds Time jobs waited in the queue before ",
+        "starting.\n",
+        "# TYPE fisher_job_wait_seconds histogram\n",
+        "fisher_job_wait_seconds_bucket{le=\"0.1\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"0.5\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"1\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"5\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"10\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"30\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"60\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"300\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"900\"} 0\n",
+        "fisher_job_wait_seconds_bucket{le=\"+Inf\"} 0\n",
+        "fisher_job_wait_seconds_sum 0\n",
+        "fisher_job_wait_seconds_count 0\n",
+    );
+
+
+    #[test]
+    fn test_metrics() {
+        let mut metrics = Metrics::default();
+        metrics.hooks.insert("a.sh".into(), HookMetrics {
+            running: 1,
+            waiting: 2,
+        });
+        metrics.hooks.insert("b\"c.sh".into(), HookMetrics::default());
+
+        let response = Response::Metrics(metrics);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.content_type(), "text/plain; version=0.0.4");
+        assert!(response.headers().is_none());
+
+        assert_eq!(response.body(), format!("{}{}", concat!(
+            "# HELP fisher_hook_running_jobs Number of jobs currently ",
+            "running for each hook.\n",
+            "# TYPE fisher_hook_running_jobs gauge\n",
+            "fisher_hook_running_jobs{hook=\"a.sh\"} 1\n",
+            "fisher_hook_running_jobs{hook=\"b\\\"c.sh\"} 0\n",
+            "# HELP fisher_hook_waiting_jobs Number of jobs waiting to be ",
+            "run for each hook.\n",
+            "# TYPE fisher_hook_waiting_jobs gauge\n",
+            "fisher_hook_waiting_jobs{hook=\"a.sh\"} 2\n",
+            "fisher_hook_waiting_jobs{hook=\"b\\\"c.sh\"} 0\n",
+            "# HELP fisher_leaked_dirs_total Number of temporary directories ",
+            "which couldn't be removed.\n",
+            "# TYPE fisher_leaked_dirs_total counter\n",
+            "fisher_leaked_dirs_total 0\n",
+        ), EMPTY_WAIT_TIMES));
+
+        // Providers are included only if there are some
+        let mut metrics = Metrics::default();
+        metrics.provider_jobs.insert("GitHub".into(), 3);
+        metrics.provider_jobs.insert("Standalone".into(), 1);
+
+        assert_eq!(Response::Metrics(metrics).body(), format!("{}{}", concat!(
+            "# HELP fisher_hook_running_jobs Number of jobs currently ",
+            "running for each hook.\n",
+            "# TYPE fisher_hook_running_jobs gauge\n",
+            "# HELP fisher_hook_waiting_jobs Number of jobs waiting to be ",
+            "run for each hook.\n",
+            "# TYPE fisher_hook_waiting_jobs gauge\n",
+            "# HELP fisher_provider_jobs_total Number of jobs received through ",
+            "each provider.\n",
+            "# TYPE fisher_provider_jobs_total counter\n",
+            "fisher_provider_jobs_total{provider=\"GitHub\"} 3\n",
+            "fisher_provider_jobs_total{provider=\"Standalone\"} 1\n",
+            "# HELP fisher_leaked_dirs_total Number of temporary directories ",
+            "which couldn't be removed.\n",
+            "# TYPE fisher_leaked_dirs_total counter\n",
+            "fisher_leaked_dirs_total 0\n",
+        ), EMPTY_WAIT_TIMES));
+
+        // The buckets of the wait times are cumulative
+        let mut metrics = Metrics::default();
+        metrics.wait_times.record(Duration::from_millis(50));
+        metrics.wait_times.record(Duration::from_millis(2500));
+        let body = Response::Metrics(metrics).body();
+        for line in &[
+            "fisher_job_wait_seconds_bucket{le=\"0.1\"} 1\n",
+            "fisher_job_wait_seconds_bucket{le=\"1\"} 1\n",
+            "fisher_job_wait_seconds_bucket{le=\"5\"} 2\n",
+            "fisher_job_wait_seconds_bucket{le=\"+Inf\"} 2\n",
+            "fisher_job_wait_seconds_sum 2.55\n",
+            "fisher_job_wait_seconds_count 2\n",
+        ] {
+            assert!(body.contains(line), "missing {}", line);
+        }
+    }
+
+
+    #[test]
+    fn test_hook_errors() {
+        let mut errors = BTreeMap::new();
+        errors.insert("a.sh".to_string(), JobFailure {
+            exit_code: Some(1),
+            signal: None,
+            stderr: "failed!\n".into(),
+            timestamp: 1500000000,
+        });
+
+        let response = Response::HookErrors(errors);
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().is_none());
+        assert_eq!(j(response.json()), json!({
+            "status": "ok",
+            "errors": {
+                "a.sh": {
+                    "exit_code": 1,
+                    "signal": null,
+                    "stderr": "failed!\n",
+                    "timestamp": 1500000000,
+                },
+            },
+        }));
     }
 }
@@ -0,0 +1,213 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! tiny_http is only able to listen on TCP sockets, so Unix domain sockets
+//! are supported by forwarding each connection received on them to an
+//! internal TCP server listening on localhost.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use common::prelude::*;
+
+
+/// The local addresses of the connections made by the bridge to the internal
+/// server. Connections from other addresses must be rejected, since they
+/// don't come from the Unix socket.
+pub type BridgeClients = Arc<Mutex<HashSet<SocketAddr>>>;
+
+
+/// Count a connection forwarded by the bridge until both its threads exit.
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+pub struct UnixBridge {
+    path: PathBuf,
+    should_stop: Arc<AtomicBool>,
+    stop_wait: mpsc::Receiver<()>,
+}
+
+impl UnixBridge {
+    /// Start forwarding the connections received on `path` to `target`.
+    /// Each connection uses two threads, so at most `max_connections` are
+    /// forwarded at the same time, and the other ones are closed right away.
+    pub fn start(
+        path: &Path,
+        target: SocketAddr,
+        clients: BridgeClients,
+        max_connections: usize,
+    ) -> Result<Self> {
+        // Remove stale sockets left behind by previous instances
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(path)?;
+
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let (stop_send, stop_recv) = mpsc::channel();
+
+        let thread_should_stop = should_stop.clone();
+        thread::spawn(move || {
+            let active = Arc::new(AtomicUsize::new(0));
+
+            for conn in listener.incoming() {
+                if thread_should_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                // Errors only affect the current connection
+                if let Ok(conn) = conn {
+                    if active.load(Ordering::SeqCst) >= max_connections {
+                        continue;
+                    }
+
+                    active.fetch_add(1, Ordering::SeqCst);
+                    let guard = Arc::new(ConnectionGuard(active.clone()));
+                    let _ = forward(conn, target, &clients, guard);
+                }
+            }
+
+            let _ = stop_send.send(());
+        });
+
+        Ok(UnixBridge {
+            path: path.to_path_buf(),
+            should_stop,
+            stop_wait: stop_recv,
+        })
+    }
+
+    pub fn stop(self) {
+        self.should_stop.store(true, Ordering::SeqCst);
+
+        // Wake the listener up, so it notices it should stop
+        if UnixStream::connect(&self.path).is_ok() {
+            let _ = self.stop_wait.recv();
+        }
+
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+fn forward(
+    unix: UnixStream,
+    target: SocketAddr,
+    clients: &BridgeClients,
+    guard: Arc<ConnectionGuard>,
+) -> Result<()> {
+    let tcp = TcpStream::connect(target)?;
+
+    // The address must be allowed before any data is sent to the server
+    let local = tcp.local_addr()?;
+    clients.lock()?.insert(local);
+
+    let mut unix_read = unix.try_clone()?;
+    let mut tcp_write = tcp.try_clone()?;
+    let read_guard = guard.clone();
+    thread::spawn(move || {
+        let _ = io::copy(&mut unix_read, &mut tcp_write);
+        let _ = tcp_write.shutdown(Shutdown::Write);
+
+        drop(read_guard);
+    });
+
+    let clients = clients.clone();
+    let (mut tcp_read, mut unix_write) = (tcp, unix);
+    thread::spawn(move || {
+        let _ = io::copy(&mut tcp_read, &mut unix_write);
+
+        // Once the server closed the connection nothing else can be sent to
+        // it, so the other thread is stopped too
+        let _ = unix_write.shutdown(Shutdown::Both);
+
+        clients.lock().unwrap().remove(&local);
+        drop(guard);
+    });
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use super::UnixBridge;
+
+
+    #[test]
+    fn test_bridge_max_connections() {
+        let dir = TempDir::new("fisher-tests").unwrap();
+        let path = dir.path().join("fisher.sock");
+
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let clients = Arc::new(Mutex::new(HashSet::new()));
+        let bridge = UnixBridge::start(
+            &path, target.local_addr().unwrap(), clients, 1,
+        ).unwrap();
+
+        // The first connection is forwarded to the target
+        let mut first = UnixStream::connect(&path).unwrap();
+        first.write_all(b"a").unwrap();
+        let (mut forwarded, _) = target.accept().unwrap();
+        let mut buf = [0; 1];
+        forwarded.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"a");
+
+        // Connections past the limit are closed right away
+        let mut second = UnixStream::connect(&path).unwrap();
+        assert_eq!(second.read(&mut buf).unwrap(), 0);
+
+        // New connections are accepted once the first one is closed
+        drop(first);
+        drop(forwarded);
+        thread::sleep(Duration::from_millis(200));
+        let mut third = UnixStream::connect(&path).unwrap();
+        third.write_all(b"b").unwrap();
+        let (mut forwarded, _) = target.accept().unwrap();
+        forwarded.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"b");
+
+        drop(third);
+        drop(forwarded);
+        bridge.stop();
+    }
+}